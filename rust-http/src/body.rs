@@ -0,0 +1,195 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+
+// Size of the pieces a stream of unknown length is sent in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Body of a response: bytes in memory, or content written out as it is read
+pub enum Body {
+    Bytes(Vec<u8>),
+    // Read until it ends; sent chunked unless the response says its `Content-Length`.
+    // Exports and multipart ranges use it. Generated listings stay in memory, as the
+    // cache only keeps bodies it holds.
+    Stream(Box<dyn Read + Send>),
+    // `length` bytes of a file from `offset` on, handed to the kernel with `sendfile` on Linux
+    File { file: File, offset: u64, length: u64 },
+}
+
+impl Body {
+    // The whole of an open file
+    pub fn file(file: File) -> io::Result<Body> {
+        let length = file.metadata()?.len();
+        Ok(Body::File { file, offset: 0, length })
+    }
+
+    pub fn stream(reader: impl Read + Send + 'static) -> Body {
+        Body::Stream(Box::new(reader))
+    }
+
+    // Length of the body, `None` for streams
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Stream(_) => None,
+            Body::File { length, .. } => Some(*length),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    // The body when it is held in memory
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    // Send the body after its response head; `chunked` frames a stream as chunks
+    pub fn write_to(&mut self, out: &mut TcpStream, chunked: bool) -> io::Result<()> {
+        match self {
+            Body::Bytes(bytes) => out.write_all(bytes),
+            Body::Stream(reader) if chunked => write_chunked(reader, out),
+            Body::Stream(reader) => io::copy(reader, out).map(|_| ()),
+            Body::File { file, offset, length } => send_file(file, *offset, *length, out),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Self {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            Body::Stream(_) => f.write_str("Stream"),
+            Body::File { offset, length, .. } => f.debug_struct("File").field("offset", offset).field("length", length).finish(),
+        }
+    }
+}
+
+fn write_chunked(reader: &mut impl Read, out: &mut impl Write) -> io::Result<()> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let bytes_read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(bytes_read) => bytes_read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        out.write_all(format!("{:x}\r\n", bytes_read).as_bytes())?;
+        out.write_all(&buffer[..bytes_read])?;
+        out.write_all(b"\r\n")?;
+    }
+    out.write_all(b"0\r\n\r\n")
+}
+
+// Copy part of a file to the socket without passing it through user space where the kernel
+// allows, falling back to reading it for file systems `sendfile` does not support
+#[cfg(target_os = "linux")]
+fn send_file(file: &File, offset: u64, length: u64, out: &mut TcpStream) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut position = offset as libc::off_t;
+    let mut remaining = length;
+    while remaining > 0 {
+        // The kernel moves at most about 2 GiB per call
+        let count = remaining.min(0x7fff_f000) as usize;
+        let sent = unsafe { libc::sendfile(out.as_raw_fd(), file.as_raw_fd(), &mut position, count) };
+        match sent {
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File ended before its length")),
+            sent if sent > 0 => remaining -= sent as u64,
+            _ => {
+                let error = io::Error::last_os_error();
+                match error.raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    Some(libc::EINVAL) | Some(libc::ENOSYS) => return copy_file(file, position as u64, remaining, out),
+                    _ => return Err(error),
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_file(file: &File, offset: u64, length: u64, out: &mut TcpStream) -> io::Result<()> {
+    copy_file(file, offset, length, out)
+}
+
+fn copy_file(mut file: &File, offset: u64, length: u64, out: &mut impl Write) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    if io::copy(&mut file.take(length), out)? < length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File ended before its length"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Cursor;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Write `body` to a socket and return what arrives on the other end
+    fn transfer(mut body: Body, chunked: bool) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut reader = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut writer, _) = listener.accept().unwrap();
+        let sender = thread::spawn(move || body.write_to(&mut writer, chunked).unwrap());
+
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).unwrap();
+        sender.join().unwrap();
+        received
+    }
+
+    #[test]
+    fn test_files_are_sent_from_an_offset() {
+        let path = "./files/test_body_file.bin";
+        let contents: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(path, &contents).unwrap();
+
+        let whole = Body::file(File::open(path).unwrap()).unwrap();
+        assert_eq!(whole.len(), Some(200_000));
+        assert_eq!(transfer(whole, false), contents);
+
+        let part = Body::File { file: File::open(path).unwrap(), offset: 1000, length: 150_000 };
+        assert_eq!(transfer(part, false), &contents[1000..151_000]);
+
+        // A file shorter than the length promised in the headers
+        let mut too_long = Body::File { file: File::open(path).unwrap(), offset: 199_000, length: 5000 };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _reader = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut writer, _) = listener.accept().unwrap();
+        assert_eq!(too_long.write_to(&mut writer, false).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_streams_are_chunked() {
+        let body = Body::stream(Cursor::new(b"hello world".to_vec()));
+        assert_eq!(body.len(), None);
+        assert_eq!(transfer(body, true), b"b\r\nhello world\r\n0\r\n\r\n");
+
+        let body = Body::stream(Cursor::new(b"as is".to_vec()));
+        assert_eq!(transfer(body, false), b"as is");
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde_json::Value;
+use uuid::Uuid;
+use crate::methods::{
+    apply_merge_patch, handle_delete, handle_patch, handle_post, handle_put, is_reserved_id, error_response,
+    reserved_id_response, storage_unavailable, validate_against_schema, Context,
+};
+use crate::response::HttpResponse;
+use crate::transaction::{Transaction, TransactionError};
+use crate::trash;
+use crate::wal;
+
+// Path of the bulk endpoint
+pub const BULK_PATH: &str = "/_bulk";
+
+// Function to handle `POST /_bulk`
+// The body is a JSON array or NDJSON of `{"op", "path", "body"}` items where `op` is one of
+// create, replace, merge or delete. With `?atomic=true` either every item is applied or none.
+pub fn handle_bulk(body: &str, query: &HashMap<String, String>, ctx: &Context) -> HttpResponse {
+    println!("Handling bulk request");
+
+    let operations = match parse_operations(body) {
+        Ok(operations) => operations,
+        Err(message) => return error_response(400, &message),
+    };
+    let atomic = query.get("atomic").map(|v| v == "true" || v == "1").unwrap_or(false);
+
+    let (status_code, items) = if atomic {
+        run_atomic(&operations, ctx)
+    } else {
+        (200, operations.iter().enumerate().map(|(index, operation)| run_single(index, operation, ctx)).collect())
+    };
+
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(), "application/json".to_string());
+    HttpResponse::new(status_code, headers, Some(serde_json::json!({
+        "status_code": status_code,
+        "atomic": atomic,
+        "items": items
+    }).to_string()))
+}
+
+// Accept either a JSON array of operations or one operation per line
+pub(crate) fn parse_operations(body: &str) -> Result<Vec<Value>, String> {
+    let trimmed = body.trim();
+    if trimmed.is_empty() {
+        return Err("Missing bulk operations".to_string());
+    }
+
+    if trimmed.starts_with('[') {
+        return match serde_json::from_str::<Value>(trimmed) {
+            Ok(Value::Array(operations)) => Ok(operations),
+            _ => Err("Invalid JSON array of operations".to_string()),
+        };
+    }
+
+    trimmed.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            serde_json::from_str(line).map_err(|_| format!("Invalid NDJSON at line {}", number + 1))
+        })
+        .collect()
+}
+
+// Run one item through the regular handlers, each item succeeding or failing on its own
+fn run_single(index: usize, operation: &Value, ctx: &Context) -> Value {
+    let op = operation["op"].as_str().unwrap_or_default();
+    let path = operation["path"].as_str().unwrap_or_default();
+    let body = operation.get("body");
+
+    let response = match op {
+        _ if path.is_empty() => error_response(400, "Missing path"),
+        "create" => handle_post(path, body, ctx),
+        "replace" => handle_put(path, body, ctx),
+        "merge" => handle_patch(path, body, None, ctx),
+        "delete" => handle_delete(path, ctx),
+        _ => error_response(400, &format!("Unknown operation '{}'", op)),
+    };
+    item_result(index, operation, &response)
+}
+
+// Stage every item in a transaction and commit them as a single WAL record
+fn run_atomic(operations: &[Value], ctx: &Context) -> (u16, Vec<Value>) {
+    let mut transaction = Transaction::new();
+    let mut results = Vec::new();
+
+    for (index, operation) in operations.iter().enumerate() {
+        match stage_operation(&mut transaction, operation, ctx) {
+            Ok(response) => results.push(item_result(index, operation, &response)),
+            Err(response) => {
+                // Nothing is written: report the failing item and mark the rest as not applied
+                let items = operations.iter().enumerate().map(|(other, operation)| {
+                    if other == index {
+                        item_result(other, operation, &response)
+                    } else {
+                        item_result(other, operation, &error_response(424, "Not applied: another operation in the batch failed"))
+                    }
+                }).collect();
+                return (response.status_code, items);
+            },
+        }
+    }
+
+    let failure = match transaction.commit(ctx) {
+        Ok(_) => return (200, results),
+        Err(TransactionError::Conflict(path)) => error_response(409, &format!("Batch conflicts with a concurrent change to {}", path.display())),
+        Err(TransactionError::Io(e)) if wal::is_unavailable(&e) => storage_unavailable(&e),
+        Err(TransactionError::Io(e)) => {
+            println!("Failed to commit bulk operations: {}", e);
+            error_response(500, "Failed to commit bulk operations")
+        },
+    };
+    (failure.status_code, operations.iter().enumerate().map(|(index, operation)| item_result(index, operation, &failure)).collect())
+}
+
+// Describe the outcome of one item using the body of the response it produced
+pub(crate) fn item_result(index: usize, operation: &Value, response: &HttpResponse) -> Value {
+    let mut item = response.json()
+        .filter(Value::is_object)
+        .unwrap_or_else(|| serde_json::json!({}));
+
+    item["index"] = Value::from(index);
+    item["op"] = operation.get("op").cloned().unwrap_or(Value::Null);
+    item["path"] = operation.get("path").cloned().unwrap_or(Value::Null);
+    item["status_code"] = Value::from(response.status_code);
+    item
+}
+
+// Check one operation against what the transaction sees and stage it, returning the response it will produce
+pub(crate) fn stage_operation(transaction: &mut Transaction, operation: &Value, ctx: &Context) -> Result<HttpResponse, HttpResponse> {
+    let op = operation["op"].as_str().unwrap_or_default();
+    let id = operation["path"].as_str().unwrap_or_default();
+    if id.is_empty() {
+        return Err(error_response(400, "Missing path"));
+    }
+    if is_reserved_id(id) {
+        return Err(reserved_id_response());
+    }
+    let read = |transaction: &mut Transaction, path: &Path| {
+        transaction.read_path(path).map_err(|e| {
+            println!("Failed to read file: {}", e);
+            error_response(500, "Failed to read file")
+        })
+    };
+    let staging_failed = |e: io::Error| {
+        println!("Failed to stage operation: {}", e);
+        error_response(500, "Failed to stage operation")
+    };
+
+    match op {
+        "create" => {
+            let data = object_body(operation)?;
+            // Crear en una colección genera el ID como en `handle_post`
+            let id = if Path::new(&format!("./files/{}", id)).is_dir() {
+                format!("{}/{}", id.trim_end_matches('/'), Uuid::new_v4())
+            } else {
+                id.to_string()
+            };
+            let file_path = PathBuf::from(format!("./files/{}.json", id));
+            if read(transaction, &file_path)?.is_some() && !ctx.config.allow_overwrite {
+                return Err(error_response(409, "File already exists"));
+            }
+            validate_against_schema(&file_path.to_string_lossy(), data)?;
+            transaction.stage_document(file_path, Some(data.clone()), ctx.expires_at).map_err(staging_failed)?;
+
+            let location = format!("/{}", id.trim_start_matches('/'));
+            let mut headers = HashMap::new();
+            headers.insert("Location".to_string(), location.clone());
+            Ok(HttpResponse::new(201, headers, Some(serde_json::json!({
+                "status_code": 201,
+                "message": "File created successfully",
+                "location": location
+            }).to_string())))
+        },
+        "replace" | "merge" => {
+            let data = operation.get("body").ok_or_else(|| error_response(400, "Missing JSON body"))?;
+            let file_path = PathBuf::from(format!("./files/{}.json", id));
+            let existing = read(transaction, &file_path)?.ok_or_else(|| error_response(404, "File not found"))?;
+
+            let document = if op == "replace" {
+                object_body(operation)?.clone()
+            } else {
+                apply_merge_patch(existing, data)?
+            };
+            validate_against_schema(&file_path.to_string_lossy(), &document)?;
+            transaction.stage_document(file_path, Some(document), ctx.expires_at).map_err(staging_failed)?;
+
+            let message = if op == "replace" { "File updated successfully" } else { "File patched successfully" };
+            Ok(error_response(200, message))
+        },
+        "delete" => {
+            let file_path = PathBuf::from(format!("./files/{}.json", id));
+            let existing = read(transaction, &file_path)?.ok_or_else(|| error_response(404, "File not found"))?;
+
+            if ctx.config.soft_delete {
+                let trash_path = trash::trash_path(&file_path).ok_or_else(|| error_response(500, "Failed to delete file"))?;
+                transaction.stage_path(trash_path, Some(trash::trash_entry(existing, &ctx.session_id))).map_err(staging_failed)?;
+            }
+            transaction.stage_document(file_path, None, None).map_err(staging_failed)?;
+            Ok(error_response(200, "File deleted successfully"))
+        },
+        _ => Err(error_response(400, &format!("Unknown operation '{}'", op))),
+    }
+}
+
+fn object_body(operation: &Value) -> Result<&Value, HttpResponse> {
+    match operation.get("body") {
+        Some(body) if body.is_object() => Ok(body),
+        Some(_) => Err(error_response(400, "Invalid JSON data: must be an object")),
+        None => Err(error_response(400, "Missing JSON body")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use serde_json::json;
+
+    fn atomic() -> HashMap<String, String> {
+        [("atomic".to_string(), "true".to_string())].into_iter().collect()
+    }
+
+    #[test]
+    fn test_bulk_ndjson_reports_each_item() {
+        let dir = "./files/test_bulk_items";
+        let body = [
+            json!({"op": "create", "path": "test_bulk_items/1", "body": {"v": 1}}).to_string(),
+            json!({"op": "merge", "path": "test_bulk_items/1", "body": {"v": 2}}).to_string(),
+            json!({"op": "replace", "path": "test_bulk_items/missing", "body": {"v": 3}}).to_string(),
+            json!({"op": "delete", "path": "test_bulk_items/1"}).to_string(),
+        ].join("\n");
+
+        let response = handle_bulk(&body, &HashMap::new(), &Context::default());
+
+        assert_eq!(response.status_code, 200);
+        let result: Value = serde_json::from_str(&response.text().unwrap()).unwrap();
+        let statuses: Vec<u64> = result["items"].as_array().unwrap().iter().map(|i| i["status_code"].as_u64().unwrap()).collect();
+        assert_eq!(statuses, vec![201, 200, 404, 200]);
+        assert!(!Path::new("./files/test_bulk_items/1.json").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_bulk_atomic_all_or_nothing() {
+        let dir = "./files/test_bulk_atomic";
+        fs::create_dir_all(dir).unwrap();
+        let failing = json!([
+            {"op": "create", "path": "test_bulk_atomic/1", "body": {"v": 1}},
+            {"op": "merge", "path": "test_bulk_atomic/1", "body": {"missing": true}}
+        ]).to_string();
+
+        let response = handle_bulk(&failing, &atomic(), &Context::default());
+
+        assert_eq!(response.status_code, 400);
+        let result: Value = serde_json::from_str(&response.text().unwrap()).unwrap();
+        assert_eq!(result["items"][0]["status_code"], 424);
+        assert!(!Path::new("./files/test_bulk_atomic/1.json").exists(), "Nothing should be written");
+
+        let passing = json!([
+            {"op": "create", "path": "test_bulk_atomic/1", "body": {"v": 1}},
+            {"op": "merge", "path": "test_bulk_atomic/1", "body": {"v": 2}},
+            {"op": "create", "path": "test_bulk_atomic", "body": {"v": 3}}
+        ]).to_string();
+
+        let response = handle_bulk(&passing, &atomic(), &Context::default());
+
+        assert_eq!(response.status_code, 200);
+        let saved: Value = serde_json::from_str(&fs::read_to_string("./files/test_bulk_atomic/1.json").unwrap()).unwrap();
+        assert_eq!(saved, json!({"v": 2}));
+        let documents = fs::read_dir(dir).unwrap().filter(|entry| entry.as_ref().unwrap().path().is_file()).count();
+        assert_eq!(documents, 2, "Collection create should generate a document");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_bulk_invalid_body() {
+        let response = handle_bulk("{not json", &HashMap::new(), &Context::default());
+
+        assert_eq!(response.status_code, 400);
+    }
+}
@@ -0,0 +1,545 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+use serde_json::Value;
+use crate::body::Body;
+use crate::events;
+use crate::methods::error_response;
+use crate::request::{find_header, HttpRequest};
+use crate::response::HttpResponse;
+use crate::transaction::version_of;
+use crate::ttl;
+
+// Path of the endpoint reporting cache statistics
+pub const CACHE_PATH: &str = "/_cache";
+// Memory the cache may use when the configuration does not say
+pub const DEFAULT_SIZE: usize = 64 * 1024 * 1024;
+// Responses larger than this share of the cache are not kept
+const MAX_ENTRY_SHARE: usize = 4;
+
+// A stored response and what is needed to decide whether it can still be used
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // Request path, with the query, the response belongs to
+    path: String,
+    stored_at: Instant,
+    // `None` keeps a response until a write invalidates it
+    expires_at: Option<Instant>,
+    tick: u64,
+}
+
+impl CachedResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    // Whether the client already has this response, going by its `If-None-Match`
+    pub fn not_modified_for(&self, request: &HttpRequest) -> bool {
+        etag_matches(request, self.header("ETag"))
+    }
+
+    // Seconds since the response was stored, for the `Age` header
+    pub fn age(&self) -> u64 {
+        self.stored_at.elapsed().as_secs()
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.expires_at.is_none_or(|expires_at| Instant::now() < expires_at)
+    }
+
+    fn size(&self) -> usize {
+        self.body.len() + self.path.len() + self.headers.iter().map(|(key, value)| key.len() + value.len()).sum::<usize>()
+    }
+
+    fn to_response(&self) -> HttpResponse {
+        let headers = self.headers.iter().cloned().collect();
+        let body = if self.body.is_empty() { None } else { Some(Body::Bytes(self.body.clone())) };
+        HttpResponse { status_code: self.status_code, headers, body }
+    }
+}
+
+// In-memory response cache bounded by `max_size` bytes, evicting the least recently used
+// responses first. Responses are stored per path, and per value of the request headers
+// their `Vary` header names.
+#[derive(Debug)]
+pub struct ResponseCache {
+    entries: HashMap<String, CachedResponse>,
+    // Keys by last use, oldest first
+    recency: BTreeMap<u64, String>,
+    // Request headers the responses of a path vary on
+    vary: HashMap<String, Vec<String>>,
+    tick: u64,
+    size: usize,
+    max_size: usize,
+    // Bumped by every invalidation, so a response generated before a write is not stored after it
+    generation: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl ResponseCache {
+    pub fn new(max_size: usize) -> Self {
+        ResponseCache {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            vary: HashMap::new(),
+            tick: 0,
+            size: 0,
+            max_size,
+            generation: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    // Key of the response to `request`: its path and the values of the headers responses vary on
+    fn key(&self, request: &HttpRequest) -> String {
+        let mut key = request.path.clone();
+        for name in self.vary.get(&request.path).into_iter().flatten() {
+            key.push('\n');
+            key.push_str(request.header(name).unwrap_or_default());
+        }
+        key
+    }
+
+    // The fresh response stored for `request`, counted as a hit or a miss
+    pub fn get(&mut self, request: &HttpRequest) -> Option<CachedResponse> {
+        let key = self.key(request);
+        let fresh = self.entries.get(&key).map(CachedResponse::is_fresh);
+        match fresh {
+            Some(true) => {
+                self.hits += 1;
+                self.tick += 1;
+                let entry = self.entries.get_mut(&key)?;
+                self.recency.remove(&entry.tick);
+                entry.tick = self.tick;
+                self.recency.insert(self.tick, key);
+                Some(entry.clone())
+            },
+            Some(false) => {
+                self.misses += 1;
+                self.remove(&key);
+                None
+            },
+            None => {
+                self.misses += 1;
+                None
+            },
+        }
+    }
+
+    // Store the response to `request`, unless the cache was invalidated since `generation`
+    pub fn insert(&mut self, request: &HttpRequest, response: CachedResponse, generation: u64) {
+        if generation != self.generation || response.size() > self.max_size / MAX_ENTRY_SHARE {
+            return;
+        }
+
+        let vary: Vec<String> = response.header("Vary")
+            .map(|vary| vary.split(',').map(|name| name.trim().to_ascii_lowercase()).filter(|name| !name.is_empty()).collect())
+            .unwrap_or_default();
+        self.vary.insert(request.path.clone(), vary);
+        let key = self.key(request);
+        self.remove(&key);
+
+        self.tick += 1;
+        let entry = CachedResponse { tick: self.tick, ..response };
+        self.size += entry.size();
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, entry);
+
+        while self.size > self.max_size {
+            let Some((_, oldest)) = self.recency.pop_first() else { break };
+            self.remove(&oldest);
+            self.evictions += 1;
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.tick);
+            self.size -= entry.size();
+        }
+    }
+
+    // Drop the responses a write to the document at `path` makes stale: the document,
+    // anything below it, and the listings of the collections above it
+    pub fn invalidate(&mut self, path: &str) {
+        self.generation += 1;
+        let below = format!("{}/", path.trim_end_matches('/'));
+        let stale: Vec<String> = self.entries.iter()
+            .filter(|(_, entry)| {
+                let entry_path = entry.path.split('?').next().unwrap_or_default().trim_end_matches('/');
+                let entry_path = if entry_path.is_empty() { "/" } else { entry_path };
+                entry_path == path || entry_path.starts_with(&below) || path.starts_with(&format!("{}/", entry_path.trim_end_matches('/')))
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            self.remove(&key);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.generation += 1;
+        self.entries.clear();
+        self.recency.clear();
+        self.vary.clear();
+        self.size = 0;
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn stats(&self) -> Value {
+        serde_json::json!({
+            "hits": self.hits,
+            "misses": self.misses,
+            "evictions": self.evictions,
+            "entries": self.entries.len(),
+            "size": self.size,
+            "max_size": self.max_size
+        })
+    }
+}
+
+fn cache() -> &'static Mutex<ResponseCache> {
+    static CACHE: OnceLock<Mutex<ResponseCache>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(ResponseCache::new(DEFAULT_SIZE)))
+}
+
+// The cache shared by the local handlers and the proxy
+pub fn shared() -> MutexGuard<'static, ResponseCache> {
+    cache().lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Resize the shared cache, emptying it; a size of 0 turns caching off
+pub fn configure(max_size: usize) {
+    *shared() = ResponseCache::new(max_size);
+}
+
+// Invalidate what a write to `file_path`, relative to the data directory, makes stale
+pub fn invalidate_file(file_path: &Path) {
+    shared().invalidate(&events::request_path(file_path));
+}
+
+// `Cache-Control` directives, lowercased, with their values
+fn directives(value: Option<&str>) -> Vec<(String, Option<String>)> {
+    value.unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), Some(value.trim().trim_matches('"').to_string())),
+            None => (directive.to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+// What the request allows: (use a stored response, store the new one)
+fn request_policy(request: &HttpRequest) -> (bool, bool) {
+    if request.method != "GET" || request.header("Authorization").is_some() {
+        return (false, false);
+    }
+    let directives = directives(request.header("Cache-Control"));
+    let has = |name: &str| directives.iter().any(|(directive, _)| directive == name);
+    if has("no-store") {
+        return (false, false);
+    }
+    let revalidate = has("no-cache")
+        || directives.iter().any(|(directive, value)| directive == "max-age" && value.as_deref() == Some("0"))
+        || request.header("Pragma").is_some_and(|pragma| pragma.eq_ignore_ascii_case("no-cache"));
+    (!revalidate, true)
+}
+
+// How long a response may be served from the cache, or `None` if it may not be stored.
+// Local responses without freshness information are kept until a write invalidates them;
+// upstream responses need `max-age`, `s-maxage` or `Expires`. Responses that must be
+// revalidated (`no-cache`) are not stored, as the cache does not revalidate.
+fn freshness(status_code: u16, header: impl Fn(&str) -> Option<String>, local: bool) -> Option<Option<Duration>> {
+    if status_code != 200 || header("Set-Cookie").is_some() || header("Vary").is_some_and(|vary| vary.trim() == "*") {
+        return None;
+    }
+    let directives = directives(header("Cache-Control").as_deref());
+    if directives.iter().any(|(name, _)| matches!(name.as_str(), "no-store" | "no-cache" | "private")) {
+        return None;
+    }
+
+    let max_age = ["s-maxage", "max-age"].iter().find_map(|wanted| {
+        directives.iter().find(|(name, _)| name == wanted).and_then(|(_, value)| value.as_deref()?.parse::<u64>().ok())
+    });
+    let lifetime = max_age.or_else(|| {
+        let expires = ttl::parse_http_date(&header("Expires")?)?;
+        Some(expires.saturating_sub(ttl::now()))
+    });
+    match lifetime {
+        Some(0) => None,
+        Some(seconds) => Some(Some(Duration::from_secs(seconds))),
+        None if local => Some(None),
+        None => None,
+    }
+}
+
+fn etag_matches(request: &HttpRequest, etag: Option<&str>) -> bool {
+    match (request.header("If-None-Match"), etag) {
+        (Some(wanted), Some(etag)) => wanted.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == etag.trim_start_matches("W/")
+        }),
+        _ => false,
+    }
+}
+
+fn not_modified(etag: &str) -> HttpResponse {
+    let mut headers = HashMap::new();
+    headers.insert("ETag".to_string(), etag.to_string());
+    HttpResponse::new(304, headers, None)
+}
+
+// Answer a GET handled locally from the cache when possible, otherwise with `generate`,
+// storing the result. Responses get an `ETag` so clients can revalidate with `If-None-Match`.
+pub fn serve_local(request: &HttpRequest, generate: impl FnOnce() -> HttpResponse) -> HttpResponse {
+    let (use_stored, store) = request_policy(request);
+    if !store {
+        return generate();
+    }
+
+    let generation = {
+        let mut cache = shared();
+        if cache.max_size == 0 {
+            drop(cache);
+            return generate();
+        }
+        if use_stored {
+            if let Some(hit) = cache.get(request) {
+                drop(cache);
+                if let Some(etag) = hit.header("ETag").filter(|etag| etag_matches(request, Some(etag))) {
+                    return not_modified(etag);
+                }
+                let mut response = hit.to_response();
+                response.headers.insert("Age".to_string(), hit.age().to_string());
+                response.headers.insert("X-Cache".to_string(), "HIT".to_string());
+                return response;
+            }
+        }
+        cache.generation()
+    };
+
+    // Streamed bodies are neither hashed nor kept
+    let mut response = generate();
+    let contents = match &response.body {
+        Some(body) => body.as_bytes().map(<[u8]>::to_vec),
+        None => Some(Vec::new()),
+    };
+    let Some(contents) = contents else {
+        return response;
+    };
+    if response.status_code == 200 && response.header("ETag").is_none() {
+        let etag = format!("\"{}\"", version_of(&contents));
+        response.headers.insert("ETag".to_string(), etag);
+    }
+
+    let lifetime = freshness(response.status_code, |name| response.header(name).map(str::to_string), true);
+    if let Some(lifetime) = lifetime {
+        let now = Instant::now();
+        let entry = CachedResponse {
+            status_code: response.status_code,
+            headers: response.headers.iter().map(|(key, value)| (key.clone(), value.clone())).collect(),
+            body: contents,
+            path: request.path.clone(),
+            stored_at: now,
+            // A lifetime past what `Instant` can hold is kept until invalidated
+            expires_at: lifetime.and_then(|lifetime| now.checked_add(lifetime)),
+            tick: 0,
+        };
+        shared().insert(request, entry, generation);
+    }
+
+    if let Some(etag) = response.header("ETag").filter(|etag| etag_matches(request, Some(etag))) {
+        return not_modified(etag);
+    }
+    response.headers.insert("X-Cache".to_string(), "MISS".to_string());
+    response
+}
+
+// A fresh stored response to a proxied request, if the request allows using one.
+// Range requests go to the upstream, which answers them with the part asked for.
+pub fn lookup(request: &HttpRequest) -> Option<CachedResponse> {
+    let mut cache = shared();
+    match request_policy(request) {
+        (true, _) if cache.max_size > 0 && request.header("Range").is_none() => cache.get(request),
+        _ => None,
+    }
+}
+
+// The generation to store the upstream response to `request` under, if it may be stored.
+// Taken before the request goes upstream, so a write meanwhile keeps the answer out.
+pub fn generation_for(request: &HttpRequest) -> Option<u64> {
+    let cache = shared();
+    (request_policy(request).1 && cache.max_size > 0).then_some(cache.generation())
+}
+
+// Largest response the cache keeps, so a relayed body is not buffered past it
+pub fn max_entry_size() -> usize {
+    shared().max_size / MAX_ENTRY_SHARE
+}
+
+// How long an upstream response may be stored, `None` if it may not be
+pub fn upstream_lifetime(status_code: u16, headers: &[String]) -> Option<Duration> {
+    freshness(status_code, |name| find_header(headers, name).map(str::to_string), false).flatten()
+}
+
+// Store an upstream response: its end-to-end header lines and the body as it was relayed
+pub fn store_upstream(request: &HttpRequest, headers: &[String], body: Vec<u8>, lifetime: Duration, generation: u64) {
+    let now = Instant::now();
+    let entry = CachedResponse {
+        status_code: 200,
+        headers: headers.iter()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect(),
+        body,
+        path: request.path.clone(),
+        stored_at: now,
+        expires_at: now.checked_add(lifetime),
+        tick: 0,
+    };
+    shared().insert(request, entry, generation);
+}
+
+// Drop what is stored for `path` and around it, after a write that did not go through the WAL
+pub fn invalidate(path: &str) {
+    shared().invalidate(path);
+}
+
+// Function to handle `/_cache`: GET reports hits, misses and size, DELETE empties the cache
+pub fn handle_cache_request(method: &str) -> HttpResponse {
+    println!("Handling cache request: {}", method);
+
+    match method {
+        "GET" => {
+            let mut headers = HashMap::new();
+            headers.insert("Content-Type".to_string(), "application/json".to_string());
+            HttpResponse::new(200, headers, Some(shared().stats().to_string()))
+        },
+        "DELETE" => {
+            shared().clear();
+            error_response(200, "Cache cleared successfully")
+        },
+        _ => error_response(405, "Method not allowed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(path: &str, headers: &[&str]) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            _headers: headers.iter().map(|h| h.to_string()).collect(),
+            body: Vec::new(),
+            cookie: None,
+        }
+    }
+
+    fn entry(path: &str, body: &str, headers: &[(&str, &str)]) -> CachedResponse {
+        CachedResponse {
+            status_code: 200,
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            body: body.as_bytes().to_vec(),
+            path: path.to_string(),
+            stored_at: Instant::now(),
+            expires_at: None,
+            tick: 0,
+        }
+    }
+
+    #[test]
+    fn test_lru_eviction_and_invalidation() {
+        let mut cache = ResponseCache::new(400);
+        for id in 1..=4 {
+            let path = format!("/users/{}", id);
+            cache.insert(&get(&path, &[]), entry(&path, &"x".repeat(90), &[]), cache.generation());
+        }
+        cache.get(&get("/users/1", &[])).unwrap();
+
+        // The fifth response pushes out the least recently used one, /users/2
+        cache.insert(&get("/users/5", &[]), entry("/users/5", &"x".repeat(90), &[]), cache.generation());
+        assert!(cache.get(&get("/users/2", &[])).is_none());
+        assert!(cache.get(&get("/users/1", &[])).is_some());
+        assert_eq!(cache.stats()["evictions"], 1);
+
+        cache.insert(&get("/users?limit=2", &[]), entry("/users?limit=2", "[]", &[]), cache.generation());
+        let stale = cache.generation();
+        cache.invalidate("/users/1");
+        assert!(cache.get(&get("/users/1", &[])).is_none());
+        assert!(cache.get(&get("/users?limit=2", &[])).is_none(), "Collection listings are invalidated too");
+        assert!(cache.get(&get("/users/4", &[])).is_some());
+
+        // A response generated before the write is not stored after it
+        cache.insert(&get("/users/1", &[]), entry("/users/1", "old", &[]), stale);
+        assert!(cache.get(&get("/users/1", &[])).is_none());
+        assert_eq!(cache.stats()["hits"], 3);
+    }
+
+    #[test]
+    fn test_vary_and_freshness() {
+        let mut cache = ResponseCache::new(DEFAULT_SIZE);
+        let generation = cache.generation();
+        cache.insert(&get("/x", &["Accept-Language: es"]), entry("/x", "hola", &[("Vary", "Accept-Language")]), generation);
+        cache.insert(&get("/x", &["Accept-Language: en"]), entry("/x", "hello", &[("Vary", "Accept-Language")]), generation);
+
+        assert_eq!(cache.get(&get("/x", &["Accept-Language: es"])).unwrap().body, b"hola");
+        assert_eq!(cache.get(&get("/x", &["accept-language: en"])).unwrap().body, b"hello");
+        assert!(cache.get(&get("/x", &["Accept-Language: fr"])).is_none());
+
+        let header = |headers: &'static [(&'static str, &'static str)]| {
+            move |name: &str| headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.to_string())
+        };
+        assert_eq!(freshness(200, header(&[("Cache-Control", "public, max-age=60")]), false), Some(Some(Duration::from_secs(60))));
+        assert_eq!(freshness(200, header(&[("Cache-Control", "max-age=60, s-maxage=5")]), false), Some(Some(Duration::from_secs(5))));
+        assert_eq!(freshness(200, header(&[]), false), None, "Upstream responses need explicit freshness");
+        assert_eq!(freshness(200, header(&[]), true), Some(None), "Local responses live until invalidated");
+        assert_eq!(freshness(200, header(&[("Cache-Control", "no-store")]), true), None);
+        assert_eq!(freshness(200, header(&[("Cache-Control", "private, max-age=60")]), false), None);
+        assert_eq!(freshness(200, header(&[("Expires", "Sun, 06 Nov 1994 08:49:37 GMT")]), true), None);
+        assert_eq!(freshness(404, header(&[]), true), None);
+
+        assert_eq!(request_policy(&get("/x", &["Cache-Control: no-cache"])), (false, true));
+        assert_eq!(request_policy(&get("/x", &["Cache-Control: no-store"])), (false, false));
+        assert_eq!(request_policy(&get("/x", &["Authorization: Bearer t"])), (false, false));
+    }
+
+    #[test]
+    fn test_serve_local_uses_etags() {
+        let path = "/test_cache_serve_local/1";
+        let calls = std::cell::Cell::new(0);
+        let generate = || {
+            calls.set(calls.get() + 1);
+            HttpResponse::new(200, HashMap::new(), Some("{\"a\":1}".to_string()))
+        };
+
+        let first = serve_local(&get(path, &[]), generate);
+        let etag = first.header("ETag").unwrap().to_string();
+        let second = serve_local(&get(path, &[]), generate);
+        let revalidated = serve_local(&get(path, &[&format!("If-None-Match: {}", etag)]), generate);
+
+        assert_eq!(first.header("X-Cache"), Some("MISS"));
+        assert_eq!(second.header("X-Cache"), Some("HIT"));
+        assert_eq!(second.text().as_deref(), Some("{\"a\":1}"));
+        assert_eq!(revalidated.status_code, 304);
+        assert_eq!(calls.get(), 1);
+
+        invalidate_file(Path::new("test_cache_serve_local/1.json"));
+        serve_local(&get(path, &[]), generate);
+        assert_eq!(calls.get(), 2, "A write sends the next request to the handler");
+    }
+}
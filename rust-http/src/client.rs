@@ -1,11 +1,30 @@
 use std::sync::{Arc, Mutex};
 use crate::server::Server;
-use crate::request::HttpRequest;
-use serde_json;
-use crate::methods::{handle_get, handle_post, handle_put,handle_delete, handle_patch, handle_method_not_allowed};
-use std::io::{Read, Write};
+use crate::request::{find_header, head_length, parse_head, HttpRequest};
+use crate::bulk::{handle_bulk, BULK_PATH};
+use crate::cache::{self, handle_cache_request, CACHE_PATH};
+use crate::events::{stream_changes, CHANGES_PATH};
+use crate::export::{handle_export, handle_import, EXPORT_PATH, IMPORT_PATH};
+use crate::range;
+use crate::search::{handle_search, SEARCH_PATH};
+use crate::subscriptions::{self, WS_PATH};
+use crate::snapshot::{handle_snapshot_request, SNAPSHOTS_PATH};
+use crate::static_files::{handle_static, is_static_path};
+use crate::transaction::{handle_transaction_request, TRANSACTIONS_PATH};
+use crate::methods::{handle_get, handle_post, handle_put,handle_delete, handle_patch, handle_method_not_allowed, error_response, Context};
+use crate::ttl;
+use crate::websocket;
+use std::io::{BufReader, Cursor, ErrorKind, Read, Write};
 use std::net::TcpStream;
 
+// Largest request, headers and body included, the server accepts
+const MAX_REQUEST_SIZE: usize = 16 * 1024 * 1024;
+
+// Whether `path` is the endpoint at `base` or below it, so `/_transactionsX` is not taken for `/_transactions`
+fn is_endpoint(path: &str, base: &str) -> bool {
+    path.strip_prefix(base).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
 // Struct to represent a client
 pub struct Client {
     pub stream: TcpStream,
@@ -14,105 +33,197 @@ pub struct Client {
 impl Client {
     // Handle the client connection
     pub fn handle(&mut self, server: Arc<Mutex<Server>>) {
-        if let Some(request) = self.parse_request() {
+        if let Some(request) = self.parse_request(&server) {
+            // The change feed keeps the connection open, and is handed to the subscription hub
+            if request.method == "GET" && request.path_only() == CHANGES_PATH {
+                if let Err(e) = self.stream.try_clone().and_then(|stream| stream_changes(stream, &request)) {
+                    println!("Change feed closed: {}", e);
+                }
+                return;
+            }
+
+            // Upgraded connections are handed to the subscription hub and leave the pool
+            if request.path_only() == WS_PATH {
+                self.upgrade(&request);
+                return;
+            }
+
             // Handle the session cookie
             let mut server_lock = server.lock().unwrap();
             let session_id = server_lock.handle_cookie(&request);
+            let config = server_lock.config.clone();
             drop(server_lock);
 
-            // Parse JSON body if present
-            let json_body = if !request.body.is_empty() {
-                serde_json::from_str(&request.body).ok()
-            } else {
-                None
+
+            // Expiry requested for the documents being written, only read on writes that create or replace them
+            let expiry = match request.method.as_str() {
+                "POST" | "PUT" => ttl::expiry_from_headers(request.header("X-TTL"), request.header("Expires")),
+                _ => Ok(None),
             };
+            let ctx = Context {
+                config,
+                session_id: session_id.clone(),
+                expires_at: expiry.clone().unwrap_or_default(),
+            };
+
+            // Parse JSON body if present
+            let json_body = request.json();
 
             // Handle request based on method
-            let mut response = match request.method.as_str() {
-                "GET" => handle_get(&request.path),
-                "POST" => handle_post(&request.path, json_body.as_ref()),
-                "PUT" => handle_put(&request.path, json_body.as_ref()),
-                "DELETE" => handle_delete(&request.path),
-                "PATCH" => handle_patch(&request.path, json_body.as_ref()),
+            let mut response = match (request.method.as_str(), request.path_only()) {
+                _ if expiry.is_err() => error_response(400, &expiry.unwrap_err()),
+                ("POST", BULK_PATH) => handle_bulk(&request.text(), &request.query_params(), &ctx),
+                ("GET", SEARCH_PATH) => handle_search(&request.query_params()),
+                ("GET", EXPORT_PATH) => handle_export(),
+                (method, path) if is_endpoint(path, SNAPSHOTS_PATH) => {
+                    handle_snapshot_request(method, path, &request.query_params(), &ctx.config)
+                },
+                (method, path) if is_endpoint(path, TRANSACTIONS_PATH) => {
+                    handle_transaction_request(method, path, &request.text(), &server, &ctx)
+                },
+                (method, CACHE_PATH) => handle_cache_request(method),
+                (method, path) if is_static_path(&ctx.config, path) => handle_static(method, &request.path, &ctx.config),
+                ("GET", path) => cache::serve_local(&request, || handle_get(path, &request.query_params())),
+                ("POST", path) => handle_post(path, json_body.as_ref(), &ctx),
+                ("PUT", path) => handle_put(path, json_body.as_ref(), &ctx),
+                ("DELETE", path) => handle_delete(path, &ctx),
+                ("PATCH", path) => handle_patch(path, json_body.as_ref(), request.header("Content-Type"), &ctx),
                 _ => handle_method_not_allowed(),
             };
 
+            // Answer `Range` requests with the parts asked for
+            response = range::apply(&request, response);
+
             // Add Set-Cookie header if session ID is new
             response.headers.insert("Set-Cookie".to_string(), format!("sessionId={}; Path=/", session_id));
 
-            let full_response = response.to_string();
-
-            // Send the response back to the client
-            if let Err(e) = self.send_response(&full_response) {
+            // Send the response back to the client, the head first and then the body as it is read
+            if let Err(e) = response.write_to(&mut self.stream) {
                 eprintln!("Failed to send response: {}", e);
             }
 
             // Log the response
             
-            println!("Sent Response: {}", full_response);
+            println!("Sent Response: {}", response);
         }
     }
 
-    // Parse the incoming request and extract cookie if available
-    fn parse_request(&mut self) -> Option<HttpRequest> {
-        let mut buffer = [0; 1024];
-        let bytes_read = match self.stream.read(&mut buffer) {
-            Ok(bytes_read) => bytes_read,
-            Err(e) => {
-                eprintln!("Failed to read from stream: {}", e);
-                return None;
+    // Complete the WebSocket handshake and pass the connection on to the subscription hub
+    fn upgrade(&mut self, request: &HttpRequest) {
+        let response = match websocket::handshake(request) {
+            Ok(response) => response,
+            Err(response) => {
+                if let Err(e) = self.send_response(response.to_bytes()) {
+                    eprintln!("Failed to send response: {}", e);
+                }
+                return;
             }
         };
 
-        let request_str = String::from_utf8_lossy(&buffer[..bytes_read]);
-        let mut headers_and_body = request_str.split("\r\n\r\n");
+        let attached = self.send_response(response.to_bytes())
+            .and_then(|_| self.stream.try_clone())
+            .and_then(subscriptions::attach);
+        match attached {
+            Ok(()) => println!("WebSocket subscriber connected"),
+            Err(e) => eprintln!("Failed to upgrade connection: {}", e),
+        }
+    }
 
-        let header_part = headers_and_body.next().unwrap_or_default();
-        if header_part.is_empty() {
-            // Malformed request: No headers
-            eprintln!("Malformed request: No headers.");
+    // Parse the incoming request and extract cookie if available.
+    // Requests for proxied paths are forwarded as soon as their head has arrived, and
+    // imports are run as their body arrives, leaving nothing to handle here.
+    fn parse_request(&mut self, server: &Arc<Mutex<Server>>) -> Option<HttpRequest> {
+        let data = self.read_head()?;
+        let head = HttpRequest::parse(&data)?;
+        let proxy = Arc::clone(&server.lock().unwrap().proxy);
+        if proxy.forward(&mut self.stream, &head, &data) {
+            return None;
+        }
+        if head.method == "POST" && head.path_only() == IMPORT_PATH {
+            self.import(&head, &data);
             return None;
         }
 
-        let body_part = headers_and_body.next().unwrap_or_default().to_string();
+        let raw_request = self.read_body(data)?;
+        HttpRequest::parse(&raw_request)
+    }
 
-        let mut header_lines = header_part.lines();
-        let request_line = header_lines.next().unwrap_or_default();
+    // Run an import reading its body straight from the connection, so it is not limited
+    // to `MAX_REQUEST_SIZE` like buffered requests
+    fn import(&mut self, head: &HttpRequest, data: &[u8]) {
+        let head_length = head_length(data).unwrap_or(data.len());
+        let content_length = head.header("Content-Length").and_then(|value| value.parse::<u64>().ok()).unwrap_or(0);
+        let body = Cursor::new(data[head_length..].to_vec()).chain(&self.stream).take(content_length);
 
-        let mut request_parts = request_line.split_whitespace();
-        let method = request_parts.next().unwrap_or("").to_string();
-        if method.is_empty() {
-            // Malformed request: No HTTP method
-            eprintln!("Malformed request: No HTTP method.");
-            return None;
+        let mut response = handle_import(BufReader::new(body), &head.query_params());
+        if let Err(e) = response.write_to(&mut self.stream) {
+            eprintln!("Failed to send response: {}", e);
         }
+        println!("Sent Response: {}", response);
+    }
 
-        let path = request_parts.next().unwrap_or("").to_string();
-        let _headers: Vec<String> = header_lines.map(|h| h.to_string()).collect();
+    // Read until the headers are complete, keeping whatever part of the body came with them
+    fn read_head(&mut self) -> Option<Vec<u8>> {
+        let mut data = Vec::new();
+        while head_length(&data).is_none() {
+            if self.read_more(&mut data)? == 0 {
+                break;
+            }
+        }
+        Some(data)
+    }
 
-        // Extract cookie from headers if present
-        let cookie_header = _headers.iter().find(|h| h.starts_with("Cookie"));
-        let cookie = cookie_header.and_then(|h| {
-            h.split('=').nth(1).map(|c| c.trim().to_string()) // Extract the sessionId value
-        });
+    // Read the rest of the body `Content-Length` announces
+    fn read_body(&mut self, mut data: Vec<u8>) -> Option<Vec<u8>> {
+        let Some(head_length) = head_length(&data) else {
+            return Some(data);
+        };
+        let (_, headers) = parse_head(&String::from_utf8_lossy(&data[..head_length]));
+        let content_length = find_header(&headers, "Content-Length")
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        while data.len() < head_length + content_length {
+            if self.read_more(&mut data)? == 0 {
+                break;
+            }
+        }
+        Some(data)
+    }
 
-        Some(HttpRequest {
-            method,
-            path,
-            _headers,
-            body: body_part,
-            cookie, // Include the cookie if available
-        })
+    // Append the next bytes from the stream to `data`, returning how many arrived
+    fn read_more(&mut self, data: &mut Vec<u8>) -> Option<usize> {
+        let mut buffer = [0; 1024];
+        let bytes_read = match self.stream.read(&mut buffer) {
+            Ok(bytes_read) => bytes_read,
+            // The read timeout set when the connection was accepted ran out
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                eprintln!("Client stopped sending, closing connection.");
+                return None;
+            }
+            Err(e) => {
+                eprintln!("Failed to read from stream: {}", e);
+                return None;
+            }
+        };
+        data.extend_from_slice(&buffer[..bytes_read]);
+
+        if data.len() > MAX_REQUEST_SIZE {
+            eprintln!("Request too large, closing connection.");
+            return None;
+        }
+        Some(bytes_read)
     }
 
     // Send the response back to the client
-    fn send_response(&mut self, response: &str) -> std::io::Result<()> {
-        self.stream.write_all(response.as_bytes())?;
+    fn send_response(&mut self, response: impl AsRef<[u8]>) -> std::io::Result<()> {
+        self.stream.write_all(response.as_ref())?;
         self.stream.flush()
     }
 }
 
 #[cfg(test)]
+#[allow(unused_imports)]
 mod test {
     use super::*;
     use std::net::{TcpListener, TcpStream};
@@ -171,7 +282,7 @@ mod test {
         let stream = TcpStream::connect(addr).unwrap();
         let mut client = Client { stream };
 
-        let parsed_request = client.parse_request().unwrap();
+        let parsed_request = client.parse_request(&Arc::new(Mutex::new(Server::new()))).unwrap();
 
         assert_eq!(parsed_request.method, "GET");
         assert_eq!(parsed_request.path, "/get");
@@ -181,6 +292,38 @@ mod test {
     }
 
 
+    #[test]
+    // Verify that a client that stops sending in the middle of the head is dropped once the read timeout runs out
+    fn test_partial_request_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"GET /get HTTP/1.1\r\nHost: loc").unwrap();
+            std::thread::sleep(std::time::Duration::from_secs(1));
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(std::time::Duration::from_millis(100))).unwrap();
+        let mut client = Client { stream };
+
+        assert!(client.parse_request(&Arc::new(Mutex::new(Server::new()))).is_none());
+
+        handle.join().unwrap();
+    }
+
+
+    #[test]
+    // Verify that endpoints only match their own path and the paths below it
+    fn test_is_endpoint() {
+        assert!(is_endpoint("/_transactions", TRANSACTIONS_PATH));
+        assert!(is_endpoint("/_transactions/abc/commit", TRANSACTIONS_PATH));
+        assert!(!is_endpoint("/_transactionsX", TRANSACTIONS_PATH));
+        assert!(!is_endpoint("/_admin/snapshots-old", SNAPSHOTS_PATH));
+    }
+
+
     #[test]
     // Verify that the function send_response() writes the data to the client's stream and ensures that the response is delivered correctly 
     fn test_send_response() {
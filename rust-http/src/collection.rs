@@ -0,0 +1,404 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use serde_json::Value;
+use crate::index;
+use crate::methods::error_response;
+use crate::pointer;
+use crate::request::percent_encode;
+use crate::response::HttpResponse;
+use crate::ttl;
+
+// Page size used when the client does not send `limit`
+const DEFAULT_LIMIT: usize = 100;
+// Largest page a client may ask for
+const MAX_LIMIT: usize = 1000;
+// Query parameters with a meaning of their own, every other parameter is a filter
+const RESERVED_PARAMS: [&str; 6] = ["limit", "cursor", "sort", "ids", "explain", "fields"];
+
+// Comparison used by a filter such as `age[gte]=18`
+#[derive(Debug, PartialEq)]
+enum FilterOp {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+#[derive(Debug)]
+struct Filter {
+    field: String,
+    op: FilterOp,
+    value: String,
+}
+
+// Read every document of a collection directory as (id, document) pairs sorted by id.
+// Files starting with `_` or `.` are reserved for metadata and never listed.
+pub fn read_documents(dir: &Path) -> io::Result<Vec<(String, Value)>> {
+    let mut documents = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let id = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(id) if !id.starts_with('_') && !id.starts_with('.') => id.to_string(),
+            _ => continue,
+        };
+
+        match fs::read_to_string(&path).map(|contents| serde_json::from_str::<Value>(&contents)) {
+            Ok(Ok(document)) => documents.push((id, document)),
+            Ok(Err(e)) => println!("Skipping invalid JSON document {}: {}", path.display(), e),
+            Err(e) => println!("Skipping unreadable document {}: {}", path.display(), e),
+        }
+    }
+
+    documents.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(documents)
+}
+
+// Read the documents of a collection with the given ids, skipping ids without a document
+fn read_selected(dir: &Path, ids: &BTreeSet<String>) -> io::Result<Vec<(String, Value)>> {
+    let mut documents = Vec::new();
+    for id in ids {
+        match fs::read_to_string(dir.join(format!("{}.json", id))) {
+            Ok(contents) => match serde_json::from_str::<Value>(&contents) {
+                Ok(document) => documents.push((id.clone(), document)),
+                Err(e) => println!("Skipping invalid JSON document {}: {}", id, e),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(documents)
+}
+
+// Value of a field named by a top-level key (`email`) or a JSON Pointer (`/address/city`)
+pub fn field_value<'a>(document: &'a Value, field: &str) -> Option<&'a Value> {
+    if field.starts_with('/') {
+        document.pointer(field)
+    } else {
+        document.get(field)
+    }
+}
+
+// List the documents of a collection honoring filters, `sort`, `limit`, `cursor`, `ids` and `fields`.
+// With `explain=true` the response describes how the query ran instead of returning documents.
+pub fn list_collection(collection: &str, dir: &Path, query: &HashMap<String, String>) -> HttpResponse {
+    println!("Listing collection: {}", collection);
+
+    let limit = match query.get("limit").map(|l| l.parse::<usize>()) {
+        None => DEFAULT_LIMIT,
+        Some(Ok(limit)) if limit > 0 => limit.min(MAX_LIMIT),
+        Some(_) => return error_response(400, "Invalid limit: must be a positive number"),
+    };
+    let offset = match query.get("cursor").map(|c| c.parse::<usize>()) {
+        None => 0,
+        Some(Ok(offset)) => offset,
+        Some(Err(_)) => return error_response(400, "Invalid cursor"),
+    };
+    let filters = match parse_filters(query) {
+        Ok(filters) => filters,
+        Err(message) => return error_response(400, &message),
+    };
+
+    // Equality filters on indexed fields narrow the documents to read
+    let indexes = match index::load_indexes(dir) {
+        Ok(indexes) => indexes,
+        Err(e) => {
+            println!("Failed to read indexes: {}", e);
+            Vec::new()
+        },
+    };
+    let mut used_indexes = Vec::new();
+    let mut candidates: Option<BTreeSet<String>> = None;
+    for filter in filters.iter().filter(|filter| filter.op == FilterOp::Eq) {
+        if let Some(index) = indexes.iter().find(|index| index.field == filter.field && !index.stale) {
+            let ids = index.lookup(&filter.value);
+            candidates = Some(match candidates {
+                Some(previous) => previous.intersection(&ids).cloned().collect(),
+                None => ids,
+            });
+            used_indexes.push(index.name.clone());
+        }
+    }
+
+    let documents = match &candidates {
+        Some(ids) => read_selected(dir, ids),
+        None => read_documents(dir),
+    };
+    let mut documents = match documents {
+        Ok(documents) => documents,
+        Err(e) => {
+            println!("Failed to read collection: {}", e);
+            return error_response(500, "Failed to read collection");
+        },
+    };
+    let examined = documents.len();
+
+    // Expired documents stay hidden until the sweeper removes them
+    let expired = ttl::expired_ids(dir);
+    if !expired.is_empty() {
+        documents.retain(|(id, _)| !expired.contains(id));
+    }
+
+    // Index candidates are checked again: every filter applies and stale entries drop out
+    documents.retain(|(_, document)| filters.iter().all(|filter| matches_filter(document, filter)));
+
+    if query.get("explain").map(|v| v == "true" || v == "1").unwrap_or(false) {
+        let mut headers = HashMap::new();
+        headers.insert("Content-Type".to_string(), "application/json".to_string());
+        return HttpResponse::new(200, headers, Some(serde_json::json!({
+            "strategy": if candidates.is_some() { "index" } else { "scan" },
+            "indexes": used_indexes,
+            "examined": examined,
+            "matched": documents.len()
+        }).to_string()));
+    }
+
+    if let Some(sort) = query.get("sort") {
+        let (field, descending) = match sort.strip_prefix('-') {
+            Some(field) => (field, true),
+            None => (sort.as_str(), false),
+        };
+        documents.sort_by(|a, b| {
+            let ordering = compare_fields(field_value(&a.1, field), field_value(&b.1, field)).then_with(|| a.0.cmp(&b.0));
+            if descending { ordering.reverse() } else { ordering }
+        });
+    }
+
+    let total = documents.len();
+    let page: Vec<(String, Value)> = documents.into_iter().skip(offset).take(limit).collect();
+
+    let ids_only = query.get("ids").map(|v| v == "true" || v == "1").unwrap_or(false);
+    let body = if ids_only {
+        Value::Array(page.into_iter().map(|(id, _)| Value::String(id)).collect())
+    } else {
+        let fields: Option<Vec<&str>> = query.get("fields").map(|fields| pointer::parse_fields(fields));
+        Value::Array(page.into_iter().map(|(id, document)| serde_json::json!({
+            "id": id,
+            "document": match &fields {
+                Some(fields) => pointer::project(&document, fields),
+                None => document,
+            }
+        })).collect())
+    };
+
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(), "application/json".to_string());
+    headers.insert("X-Total-Count".to_string(), total.to_string());
+    // Saturating, so a huge cursor is just a page past the end
+    let next = offset.saturating_add(limit);
+    if next < total {
+        headers.insert("Link".to_string(), next_link(collection, query, next));
+    }
+
+    HttpResponse::new(200, headers, Some(body.to_string()))
+}
+
+// Turn every non reserved query parameter into a filter
+fn parse_filters(query: &HashMap<String, String>) -> Result<Vec<Filter>, String> {
+    let mut filters = Vec::new();
+
+    for (key, value) in query {
+        if RESERVED_PARAMS.contains(&key.as_str()) {
+            continue;
+        }
+
+        let (field, op) = match key.split_once('[') {
+            Some((field, rest)) => {
+                let op = match rest.strip_suffix(']') {
+                    Some("eq") => FilterOp::Eq,
+                    Some("ne") => FilterOp::Ne,
+                    Some("gt") => FilterOp::Gt,
+                    Some("gte") => FilterOp::Gte,
+                    Some("lt") => FilterOp::Lt,
+                    Some("lte") => FilterOp::Lte,
+                    _ => return Err(format!("Invalid filter operator in '{}'", key)),
+                };
+                (field, op)
+            },
+            None => (key.as_str(), FilterOp::Eq),
+        };
+
+        filters.push(Filter { field: field.to_string(), op, value: value.clone() });
+    }
+
+    Ok(filters)
+}
+
+fn matches_filter(document: &Value, filter: &Filter) -> bool {
+    let ordering = field_value(document, &filter.field).and_then(|actual| compare_to_query(actual, &filter.value));
+
+    match filter.op {
+        FilterOp::Eq => ordering == Some(Ordering::Equal),
+        FilterOp::Ne => ordering != Some(Ordering::Equal),
+        FilterOp::Gt => ordering == Some(Ordering::Greater),
+        FilterOp::Gte => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        FilterOp::Lt => ordering == Some(Ordering::Less),
+        FilterOp::Lte => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+    }
+}
+
+// Compare a stored value with the raw text of a query parameter, using the stored value's type
+pub fn compare_to_query(actual: &Value, raw: &str) -> Option<Ordering> {
+    match actual {
+        Value::Number(number) => number.as_f64()?.partial_cmp(&raw.parse::<f64>().ok()?),
+        Value::String(text) => Some(text.as_str().cmp(raw)),
+        Value::Bool(flag) => Some(flag.cmp(&raw.parse::<bool>().ok()?)),
+        Value::Null if raw == "null" => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+// Order two optional field values; missing fields go last and different types are ranked by type
+fn compare_fields(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            Value::Array(_) => 4,
+            Value::Object(_) => 5,
+        }
+    }
+
+    match (a, b) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(_), None) => Ordering::Less,
+        (Some(Value::Number(x)), Some(Value::Number(y))) => {
+            x.as_f64().partial_cmp(&y.as_f64()).unwrap_or(Ordering::Equal)
+        },
+        (Some(Value::String(x)), Some(Value::String(y))) => x.cmp(y),
+        (Some(Value::Bool(x)), Some(Value::Bool(y))) => x.cmp(y),
+        (Some(x), Some(y)) => rank(x).cmp(&rank(y)),
+    }
+}
+
+// Build the `Link` header pointing at the next page, keeping the other parameters
+fn next_link(collection: &str, query: &HashMap<String, String>, next_cursor: usize) -> String {
+    let mut params: Vec<(String, String)> = query.iter()
+        .filter(|(key, _)| key.as_str() != "cursor")
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    params.push(("cursor".to_string(), next_cursor.to_string()));
+    params.sort();
+
+    let query_string: Vec<String> = params.iter()
+        .map(|(key, value)| format!("{}={}", percent_encode(key), percent_encode(value)))
+        .collect();
+    let path = if collection.starts_with('/') { collection.to_string() } else { format!("/{}", collection) };
+
+    format!("<{}?{}>; rel=\"next\"", path, query_string.join("&"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Create a collection directory with a few users for a test
+    fn create_collection(name: &str) -> String {
+        let dir = format!("./files/{}", name);
+        fs::create_dir_all(&dir).unwrap();
+        let users = [
+            ("1", serde_json::json!({"name": "Ana", "age": 31, "role": "admin"})),
+            ("2", serde_json::json!({"name": "Luis", "age": 25, "role": "user"})),
+            ("3", serde_json::json!({"name": "Carla", "age": 40, "role": "user"})),
+        ];
+        for (id, user) in users {
+            fs::write(format!("{}/{}.json", dir, id), user.to_string()).unwrap();
+        }
+        fs::write(format!("{}/_meta.json", dir), "{}").unwrap();
+        dir
+    }
+
+    fn query(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_list_collection_ids() {
+        let dir = create_collection("test_list_ids");
+
+        let response = list_collection("/test_list_ids", Path::new(&dir), &query(&[("ids", "true")]));
+
+        assert_eq!(response.status_code, 200);
+        let body: Value = serde_json::from_str(&response.text().unwrap()).unwrap();
+        assert_eq!(body, serde_json::json!(["1", "2", "3"]), "Reserved files should not be listed");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_collection_filter_and_sort() {
+        let dir = create_collection("test_list_filter");
+
+        let params = query(&[("role", "user"), ("age[gte]", "20"), ("sort", "-age")]);
+        let response = list_collection("/test_list_filter", Path::new(&dir), &params);
+
+        let body: Value = serde_json::from_str(&response.text().unwrap()).unwrap();
+        let names: Vec<&str> = body.as_array().unwrap().iter()
+            .map(|item| item["document"]["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["Carla", "Luis"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_collection_pagination_link() {
+        let dir = create_collection("test_list_pages");
+
+        let response = list_collection("/test_list_pages", Path::new(&dir), &query(&[("limit", "2"), ("ids", "true")]));
+
+        assert_eq!(response.headers.get("X-Total-Count").unwrap(), "3");
+        assert_eq!(response.headers.get("Link").unwrap(), "</test_list_pages?cursor=2&ids=true&limit=2>; rel=\"next\"");
+
+        let response = list_collection("/test_list_pages", Path::new(&dir), &query(&[("limit", "2"), ("ids", "true"), ("cursor", "2")]));
+        let body: Value = serde_json::from_str(&response.text().unwrap()).unwrap();
+        assert_eq!(body, serde_json::json!(["3"]));
+        assert!(!response.headers.contains_key("Link"), "Last page should not link further");
+
+        let response = list_collection("/test_list_pages", Path::new(&dir), &query(&[("ids", "true"), ("cursor", "18446744073709551615")]));
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.text().unwrap(), "[]", "A cursor past the end should be an empty page");
+        assert!(!response.headers.contains_key("Link"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_collection_uses_index() {
+        let dir = create_collection("test_list_index");
+        let params = query(&[("role", "user"), ("explain", "true")]);
+
+        let body: Value = serde_json::from_str(&list_collection("/test_list_index", Path::new(&dir), &params).text().unwrap()).unwrap();
+        assert_eq!(body["strategy"], "scan");
+        assert_eq!(body["examined"], 3);
+
+        index::handle_index_request("PUT", "test_list_index", "role", Some(&serde_json::json!({"field": "role"})));
+
+        let body: Value = serde_json::from_str(&list_collection("/test_list_index", Path::new(&dir), &params).text().unwrap()).unwrap();
+        assert_eq!(body, serde_json::json!({"strategy": "index", "indexes": ["role"], "examined": 2, "matched": 2}));
+
+        let params = query(&[("role", "user"), ("age[lt]", "30"), ("ids", "true")]);
+        let body: Value = serde_json::from_str(&list_collection("/test_list_index", Path::new(&dir), &params).text().unwrap()).unwrap();
+        assert_eq!(body, serde_json::json!(["2"]));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_collection_invalid_limit() {
+        let response = list_collection("/users", Path::new("./files/users"), &query(&[("limit", "zero")]));
+
+        assert_eq!(response.status_code, 400);
+    }
+}
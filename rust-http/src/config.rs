@@ -0,0 +1,125 @@
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::history;
+use crate::proxy::{self, Balance, ProxyRoute};
+
+// Settings that change how the server and the request handlers behave
+#[derive(Debug, Clone)]
+pub struct Config {
+    // Let POST replace an existing document instead of answering 409 Conflict
+    pub allow_overwrite: bool,
+    // Revisions kept per document by PUT, PATCH and POST, `history::DEFAULT_LIMIT` unless set; 0 disables the history
+    pub history_limit: usize,
+    // DELETE moves documents to the collection trash instead of removing them
+    pub soft_delete: bool,
+    // How long trashed documents are kept before the purge job removes them, `None` keeps them forever
+    pub trash_retention: Option<Duration>,
+    // Path prefixes forwarded to upstream servers instead of served from `./files`
+    pub proxy_routes: Vec<ProxyRoute>,
+    // How long the proxy waits on an upstream, `None` uses `proxy::DEFAULT_TIMEOUT`
+    pub proxy_timeout: Option<Duration>,
+    // Memory for cached GET responses in bytes, `None` uses `cache::DEFAULT_SIZE` and 0 disables caching
+    pub cache_size: Option<usize>,
+    // Path prefix serving the files of `static_root` as they are, `None` serves no static files
+    pub static_prefix: Option<String>,
+    // Directory served below `static_prefix`, `None` uses `static_files::DEFAULT_ROOT`
+    pub static_root: Option<PathBuf>,
+    // Render an HTML listing for static directories without an `index.html`
+    pub static_listing: bool,
+    // Allow restoring and deleting snapshots through `/_admin/snapshots`, which has no access control
+    pub snapshot_admin: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            allow_overwrite: false,
+            history_limit: history::DEFAULT_LIMIT,
+            soft_delete: false,
+            trash_retention: None,
+            proxy_routes: Vec::new(),
+            proxy_timeout: None,
+            cache_size: None,
+            static_prefix: None,
+            static_root: None,
+            static_listing: false,
+            snapshot_admin: false,
+        }
+    }
+}
+
+impl Config {
+    // Build the configuration from `RUST_HTTP_*` environment variables, using defaults for the rest
+    pub fn from_env() -> Self {
+        let mut config = Config::default();
+        if let Some(value) = env_flag("RUST_HTTP_ALLOW_OVERWRITE") {
+            config.allow_overwrite = value;
+        }
+        if let Some(value) = env_number("RUST_HTTP_HISTORY_LIMIT") {
+            config.history_limit = value;
+        }
+        if let Some(value) = env_flag("RUST_HTTP_SOFT_DELETE") {
+            config.soft_delete = value;
+        }
+        if let Some(value) = env_number("RUST_HTTP_TRASH_RETENTION_SECS") {
+            config.trash_retention = Some(Duration::from_secs(value as u64));
+        }
+        if let Ok(spec) = env::var("RUST_HTTP_PROXY") {
+            let balance = match env::var("RUST_HTTP_PROXY_BALANCE") {
+                Ok(name) => Balance::parse(&name).unwrap_or_else(|| {
+                    println!("Ignoring invalid value '{}' for RUST_HTTP_PROXY_BALANCE", name);
+                    Balance::default()
+                }),
+                Err(_) => Balance::default(),
+            };
+            match proxy::parse_routes(&spec, balance) {
+                Ok(routes) => config.proxy_routes = routes,
+                Err(e) => println!("Ignoring RUST_HTTP_PROXY: {}", e),
+            }
+        }
+        if let Some(value) = env_number("RUST_HTTP_PROXY_TIMEOUT_SECS") {
+            config.proxy_timeout = Some(Duration::from_secs(value as u64));
+        }
+        if let Some(value) = env_number("RUST_HTTP_CACHE_SIZE") {
+            config.cache_size = Some(value);
+        }
+        if let Ok(prefix) = env::var("RUST_HTTP_STATIC_PREFIX") {
+            config.static_prefix = Some(format!("/{}", prefix.trim_matches('/')));
+        }
+        if let Ok(root) = env::var("RUST_HTTP_STATIC_ROOT") {
+            config.static_root = Some(PathBuf::from(root));
+        }
+        if let Some(value) = env_flag("RUST_HTTP_STATIC_LISTING") {
+            config.static_listing = value;
+        }
+        if let Some(value) = env_flag("RUST_HTTP_SNAPSHOT_ADMIN") {
+            config.snapshot_admin = value;
+        }
+        config
+    }
+}
+
+// Read a numeric environment variable
+fn env_number(name: &str) -> Option<usize> {
+    let value = env::var(name).ok()?;
+    match value.parse() {
+        Ok(number) => Some(number),
+        Err(_) => {
+            println!("Ignoring invalid value '{}' for {}", value, name);
+            None
+        },
+    }
+}
+
+// Read a boolean environment variable ("1"/"true" or "0"/"false")
+fn env_flag(name: &str) -> Option<bool> {
+    match env::var(name).ok()?.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        other => {
+            println!("Ignoring invalid value '{}' for {}", other, name);
+            None
+        },
+    }
+}
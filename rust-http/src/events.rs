@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::path::{Component, Path};
+use std::sync::{Condvar, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_json::Value;
+use crate::request::HttpRequest;
+use crate::subscriptions;
+
+// Path of the change feed endpoint
+pub const CHANGES_PATH: &str = "/_changes";
+// Events kept in memory for clients resuming with `Last-Event-ID`
+const BUFFER_SIZE: usize = 1024;
+// How long an idle feed waits before sending a keep-alive comment
+pub(crate) const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+// What happened to a document
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    Create,
+    Update,
+    Delete,
+}
+
+impl ChangeKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChangeKind::Create => "create",
+            ChangeKind::Update => "update",
+            ChangeKind::Delete => "delete",
+        }
+    }
+}
+
+// A committed change to a document, numbered in publication order
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    pub seq: u64,
+    pub kind: ChangeKind,
+    // Request path of the document: `/users/420`
+    pub path: String,
+    pub timestamp: u64,
+    pub session_id: Option<String>,
+    // Contents after the change, `None` for deletions
+    pub document: Option<Value>,
+}
+
+impl ChangeEvent {
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "seq": self.seq,
+            "type": self.kind.as_str(),
+            "path": self.path,
+            "timestamp": self.timestamp,
+            "session_id": self.session_id,
+            "document": self.document
+        })
+    }
+
+    // The event as a `text/event-stream` message
+    pub fn to_sse(&self) -> String {
+        format!("id: {}\nevent: {}\ndata: {}\n\n", self.seq, self.kind.as_str(), self.to_json())
+    }
+
+    // Whether the event is about `prefix` or a document below it
+    pub fn matches(&self, prefix: &str) -> bool {
+        let prefix = prefix.trim_end_matches('/');
+        prefix.is_empty() || self.path == prefix || self.path.starts_with(&format!("{}/", prefix))
+    }
+}
+
+// In-process bus every successful mutation is published to.
+// The latest events are kept so a reconnecting client can catch up.
+#[derive(Debug, Default)]
+pub struct EventBus {
+    events: VecDeque<ChangeEvent>,
+    next_seq: u64,
+}
+
+impl EventBus {
+    pub fn publish(&mut self, kind: ChangeKind, path: String, document: Option<Value>, session_id: &str) -> u64 {
+        self.next_seq += 1;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        self.events.push_back(ChangeEvent {
+            seq: self.next_seq,
+            kind,
+            path,
+            timestamp,
+            session_id: if session_id.is_empty() { None } else { Some(session_id.to_string()) },
+            document,
+        });
+        if self.events.len() > BUFFER_SIZE {
+            self.events.pop_front();
+        }
+        self.next_seq
+    }
+
+    // Buffered events after `seq`
+    pub fn since(&self, seq: u64) -> Vec<ChangeEvent> {
+        self.events.iter().filter(|event| event.seq > seq).cloned().collect()
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    // Oldest event still buffered, if any
+    pub fn first_seq(&self) -> Option<u64> {
+        self.events.front().map(|event| event.seq)
+    }
+}
+
+fn bus() -> &'static (Mutex<EventBus>, Condvar) {
+    static BUS: OnceLock<(Mutex<EventBus>, Condvar)> = OnceLock::new();
+    BUS.get_or_init(|| (Mutex::new(EventBus::default()), Condvar::new()))
+}
+
+fn lock() -> MutexGuard<'static, EventBus> {
+    bus().0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Publish a change to the document stored at `file_path` and wake every waiting feed
+pub fn publish(kind: ChangeKind, file_path: &Path, document: Option<&Value>, session_id: &str) -> u64 {
+    let seq = lock().publish(kind, request_path(file_path), document.cloned(), session_id);
+    bus().1.notify_all();
+    seq
+}
+
+// Sequence number of the latest published event
+pub fn last_seq() -> u64 {
+    lock().last_seq()
+}
+
+// Events after `seq`, waiting up to `timeout` for one to arrive
+pub fn wait_since(seq: u64, timeout: Duration) -> Vec<ChangeEvent> {
+    let guard = lock();
+    let (guard, _) = bus().1
+        .wait_timeout_while(guard, timeout, |bus| bus.last_seq() <= seq)
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    guard.since(seq)
+}
+
+// Function to handle `GET /_changes?prefix=/users`
+// Streams events as `text/event-stream` until the client goes away, starting after the
+// `Last-Event-ID` the client sends or with the next change when it sends none.
+// Once the head is sent the connection is served by the subscription hub, not a pool thread.
+pub fn stream_changes(mut stream: TcpStream, request: &HttpRequest) -> io::Result<()> {
+    println!("Streaming changes");
+
+    let query = request.query_params();
+    let prefix = query.get("prefix").map(|p| format!("/{}", p.trim_matches('/'))).unwrap_or_default();
+    let (last_seq, first_seq, current) = {
+        let bus = lock();
+        let last_seq = match request.header("Last-Event-ID").map(|id| id.trim().parse::<u64>()) {
+            // An ID from before a restart can not be resumed
+            Some(Ok(id)) if id <= bus.last_seq() => id,
+            _ => bus.last_seq(),
+        };
+        (last_seq, bus.first_seq(), bus.last_seq())
+    };
+
+    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n")?;
+    if let Some(first_seq) = first_seq {
+        if last_seq + 1 < first_seq && last_seq < current {
+            // The client missed events that are no longer buffered
+            stream.write_all(format!("event: reset\ndata: {{\"first_seq\": {}}}\n\n", first_seq).as_bytes())?;
+        }
+    }
+    stream.flush()?;
+
+    subscriptions::attach_feed(stream, prefix, last_seq)
+}
+
+// Request path of a document file below `./files`: `./files//users/420.json` is `/users/420`
+pub(crate) fn request_path(file_path: &Path) -> String {
+    let relative = file_path.strip_prefix("./files").unwrap_or(file_path).with_extension("");
+    let segments: Vec<String> = relative.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+    format!("/{}", segments.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_bus_buffers_and_resumes() {
+        let mut bus = EventBus::default();
+        for id in 0..BUFFER_SIZE + 2 {
+            bus.publish(ChangeKind::Create, format!("/users/{}", id), None, "");
+        }
+
+        assert_eq!(bus.first_seq(), Some(3), "Oldest events should be dropped");
+        let missed = bus.since(bus.last_seq() - 1);
+        assert_eq!(missed.len(), 1);
+        assert_eq!(missed[0].path, format!("/users/{}", BUFFER_SIZE + 1));
+    }
+
+    #[test]
+    fn test_event_format_and_prefix() {
+        let event = ChangeEvent {
+            seq: 7,
+            kind: ChangeKind::Update,
+            path: request_path(Path::new("./files//users/420.json")),
+            timestamp: 0,
+            session_id: None,
+            document: Some(json!({"name": "Ana"})),
+        };
+
+        assert_eq!(event.path, "/users/420");
+        assert!(event.to_sse().starts_with("id: 7\nevent: update\ndata: {"));
+        assert!(event.to_sse().ends_with("}\n\n"));
+        assert!(event.matches("/users") && event.matches("/users/") && event.matches(""));
+        assert!(!event.matches("/use"));
+    }
+
+    #[test]
+    fn test_publish_wakes_waiters() {
+        let start = lock().last_seq();
+        let waiter = std::thread::spawn(move || wait_since(start, Duration::from_secs(5)));
+
+        publish(ChangeKind::Delete, Path::new("./files/test_events_wake/1.json"), None, "abc");
+
+        let events = waiter.join().unwrap();
+        assert!(events.iter().any(|e| e.path == "/test_events_wake/1" && e.kind == ChangeKind::Delete));
+    }
+}
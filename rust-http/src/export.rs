@@ -0,0 +1,489 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Cursor, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use serde_json::Value;
+use uuid::Uuid;
+use crate::body::Body;
+use crate::methods::{error_response, storage_unavailable};
+use crate::response::HttpResponse;
+use crate::transaction::version_of;
+use crate::wal::{self, encode_mutation, read_spool, Mutation, Wal};
+
+// Paths of the export and import endpoints
+pub const EXPORT_PATH: &str = "/_export";
+pub const IMPORT_PATH: &str = "/_import";
+// Longest NDJSON line an import accepts, one document
+const MAX_LINE: u64 = 16 * 1024 * 1024;
+
+// What to do with an imported document whose path already exists
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportMode {
+    Skip,
+    Overwrite,
+    Fail,
+}
+
+impl ImportMode {
+    pub fn parse(mode: &str) -> Option<ImportMode> {
+        match mode {
+            "skip" => Some(ImportMode::Skip),
+            "overwrite" => Some(ImportMode::Overwrite),
+            "fail" => Some(ImportMode::Fail),
+            _ => None,
+        }
+    }
+}
+
+// Documents written and skipped by an import
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub skipped: usize,
+}
+
+// Why an import was rejected
+#[derive(Debug)]
+pub enum ImportError {
+    // A line is not a valid `{path, body}` entry, nothing was written
+    Invalid { line: usize, message: String },
+    // Paths that already exist in `fail` mode, nothing was written
+    Conflict(Vec<String>),
+    Io(io::Error),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Invalid { line, message } => write!(f, "Line {}: {}", line, message),
+            ImportError::Conflict(paths) => write!(f, "Documents already exist: {}", paths.join(", ")),
+            ImportError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ImportError {
+    fn from(e: io::Error) -> Self {
+        ImportError::Io(e)
+    }
+}
+
+// Write every document below `data_dir` as one NDJSON line of `{"path", "body", "etag"}`,
+// sorted by path. Reserved `_` entries (schemas, history, trash, the WAL) are left out.
+// Returns how many documents were written.
+pub fn export_documents(data_dir: &Path, out: &mut impl Write) -> io::Result<usize> {
+    let mut files = Vec::new();
+    collect_documents(data_dir, &mut files)?;
+    let mut reader = ExportReader::new(data_dir.to_path_buf(), files, false);
+    io::copy(&mut reader, out)?;
+    Ok(reader.exported)
+}
+
+// The export as it is read: documents are turned into NDJSON lines one at a time, so an
+// export of any size is never held in memory. A staging directory it reads from is
+// removed once the reader is dropped.
+pub struct ExportReader {
+    root: PathBuf,
+    files: std::vec::IntoIter<PathBuf>,
+    line: Cursor<Vec<u8>>,
+    exported: usize,
+    staging: bool,
+}
+
+impl ExportReader {
+    fn new(root: PathBuf, mut files: Vec<PathBuf>, staging: bool) -> ExportReader {
+        files.sort();
+        ExportReader { root, files: files.into_iter(), line: Cursor::new(Vec::new()), exported: 0, staging }
+    }
+
+    // Line of the next document that is valid JSON, `None` once every one was read
+    fn next_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        for file in self.files.by_ref() {
+            let contents = fs::read(&file)?;
+            let body: Value = match serde_json::from_slice(&contents) {
+                Ok(body) => body,
+                Err(e) => {
+                    println!("Skipping invalid JSON document {}: {}", file.display(), e);
+                    continue;
+                },
+            };
+            let line = serde_json::json!({
+                "path": document_path(&self.root, &file),
+                "body": body,
+                "etag": version_of(&contents)
+            });
+            self.exported += 1;
+            return Ok(Some(format!("{}\n", line).into_bytes()));
+        }
+        Ok(None)
+    }
+}
+
+impl Read for ExportReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.line.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.next_line()? {
+                Some(line) => self.line = Cursor::new(line),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+impl Drop for ExportReader {
+    fn drop(&mut self) {
+        if self.staging {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+}
+
+// Hard link every document into a staging directory inside `data_dir`, copying when
+// linking is not possible. Writers replace documents by renaming and never change a file
+// in place, so the staged links keep the contents of the moment they were made.
+fn stage_documents(data_dir: &Path) -> io::Result<ExportReader> {
+    let staging = data_dir.join(format!(".export-{}", Uuid::new_v4().simple()));
+    let mut files = Vec::new();
+    collect_documents(data_dir, &mut files)?;
+
+    // Created first so a failure below still removes the staging directory
+    let mut reader = ExportReader::new(staging.clone(), Vec::new(), true);
+    let mut staged = Vec::new();
+    for file in files {
+        let target = staging.join(file.strip_prefix(data_dir).unwrap_or(&file));
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if fs::hard_link(&file, &target).is_err() {
+            fs::copy(&file, &target)?;
+        }
+        staged.push(target);
+    }
+    staged.sort();
+    reader.files = staged.into_iter();
+    Ok(reader)
+}
+
+// Ingest an NDJSON export into the data directory of `log`.
+// Every line is checked before anything is written, so invalid input or a conflict in
+// `fail` mode leaves the store untouched. Documents are restored as exported: schemas
+// are not enforced and no revisions are recorded.
+pub fn import_documents(log: &mut Wal, input: impl BufRead, mode: ImportMode) -> Result<ImportSummary, ImportError> {
+    let spool = spool_import(log.data_dir(), input)?;
+    apply_import(log, &spool, mode)
+}
+
+// Validated entries of an import, kept as WAL mutations in a file inside the data
+// directory until they are applied. The file is removed once the spool is dropped.
+pub struct ImportSpool {
+    path: PathBuf,
+}
+
+impl Drop for ImportSpool {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Read and check an NDJSON import line by line, spooling it to disk rather than memory.
+// Needs no lock, so a slow upload does not hold up writers.
+pub fn spool_import(data_dir: &Path, mut input: impl BufRead) -> Result<ImportSpool, ImportError> {
+    fs::create_dir_all(data_dir)?;
+    let spool = ImportSpool { path: data_dir.join(format!(".import-{}.ndjson", Uuid::new_v4().simple())) };
+    let mut out = BufWriter::new(File::create(&spool.path)?);
+
+    let mut line = Vec::new();
+    let mut number = 0;
+    loop {
+        line.clear();
+        if (&mut input).take(MAX_LINE + 1).read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        number += 1;
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let invalid = |message: &str| ImportError::Invalid { line: number, message: message.to_string() };
+        if line.len() as u64 > MAX_LINE {
+            return Err(invalid("Line too long"));
+        }
+
+        let entry: Value = serde_json::from_slice(&line).map_err(|_| invalid("Invalid JSON"))?;
+        let path = entry["path"].as_str().ok_or_else(|| invalid("Missing path"))?;
+        let relative = relative_file(path).ok_or_else(|| invalid("Invalid path"))?;
+        let body = match entry.get("body") {
+            Some(body) if body.is_object() => body.clone(),
+            _ => return Err(invalid("Invalid body: must be an object")),
+        };
+        writeln!(out, "{}", encode_mutation(&Mutation::Put { path: relative, document: body }))?;
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(spool)
+}
+
+// Write a spooled import through `log` as a single record, so it is applied all or none
+pub fn apply_import(log: &mut Wal, spool: &ImportSpool, mode: ImportMode) -> Result<ImportSummary, ImportError> {
+    let mut summary = ImportSummary::default();
+    let mut conflicts = Vec::new();
+    // In `skip` mode the documents that are left go to a second spool
+    let kept = ImportSpool { path: spool.path.with_extension("kept") };
+    let mut out = BufWriter::new(File::create(&kept.path)?);
+    for mutation in read_spool(&spool.path)? {
+        let mutation = mutation?;
+        if log.data_dir().join(mutation.path()).exists() {
+            match mode {
+                ImportMode::Skip => {
+                    summary.skipped += 1;
+                    continue;
+                },
+                ImportMode::Fail => conflicts.push(document_path(Path::new(""), mutation.path())),
+                ImportMode::Overwrite => {},
+            }
+        }
+        writeln!(out, "{}", encode_mutation(&mutation))?;
+        summary.imported += 1;
+    }
+    drop(out);
+    if !conflicts.is_empty() {
+        return Err(ImportError::Conflict(conflicts));
+    }
+
+    if summary.imported > 0 {
+        log.commit_spooled(&kept.path)?;
+    }
+    Ok(summary)
+}
+
+// Function to handle `GET /_export`
+// Writers are held up only while the documents are linked into a staging directory;
+// the export is then streamed from there as it is sent.
+pub fn handle_export() -> HttpResponse {
+    println!("Handling export request");
+
+    let staged = match wal::shared() {
+        Ok(log) => stage_documents(log.data_dir()),
+        Err(e) => return storage_unavailable(&e),
+    };
+    let reader = match staged {
+        Ok(reader) => reader,
+        Err(e) => {
+            println!("Failed to export documents: {}", e);
+            return error_response(500, "Failed to export documents");
+        },
+    };
+
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(), "application/x-ndjson".to_string());
+    HttpResponse::with_body(200, headers, Body::stream(reader))
+}
+
+// Function to handle `POST /_import?mode=skip|overwrite|fail`, `skip` being the default
+// The body is spooled to disk as it is read, and the log is only taken to apply it.
+pub fn handle_import(body: impl BufRead, query: &HashMap<String, String>) -> HttpResponse {
+    println!("Handling import request");
+
+    let mode = match query.get("mode").map(|mode| ImportMode::parse(mode)) {
+        None => ImportMode::Skip,
+        Some(Some(mode)) => mode,
+        Some(None) => return error_response(400, "Invalid mode: must be skip, overwrite or fail"),
+    };
+
+    let imported = spool_import(Path::new("./files"), body).and_then(|spool| {
+        let mut log = wal::shared()?;
+        apply_import(&mut log, &spool, mode)
+    });
+    match imported {
+        Ok(summary) => HttpResponse::new(200, HashMap::new(), Some(serde_json::json!({
+            "status_code": 200,
+            "message": "Documents imported successfully",
+            "imported": summary.imported,
+            "skipped": summary.skipped
+        }).to_string())),
+        Err(ImportError::Invalid { line, message }) => HttpResponse::new(400, HashMap::new(), Some(serde_json::json!({
+            "status_code": 400,
+            "message": message,
+            "line": line
+        }).to_string())),
+        Err(ImportError::Conflict(paths)) => HttpResponse::new(409, HashMap::new(), Some(serde_json::json!({
+            "status_code": 409,
+            "message": "Documents already exist",
+            "conflicts": paths
+        }).to_string())),
+        Err(ImportError::Io(e)) if wal::is_unavailable(&e) => storage_unavailable(&e),
+        Err(ImportError::Io(e)) => {
+            println!("Failed to import documents: {}", e);
+            error_response(500, "Failed to import documents")
+        },
+    }
+}
+
+fn collect_documents(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let reserved = path.file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.starts_with('_') || n.starts_with('.'))
+            .unwrap_or(true);
+        if reserved {
+            continue;
+        }
+
+        if path.is_dir() {
+            collect_documents(&path, files)?;
+        } else if path.extension().and_then(|e| e.to_str()) == Some("json") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Request path of a document file: `<data_dir>/users/1.json` is `/users/1`
+fn document_path(data_dir: &Path, file: &Path) -> String {
+    let relative = file.strip_prefix(data_dir).unwrap_or(file).with_extension("");
+    let segments: Vec<String> = relative.components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    format!("/{}", segments.join("/"))
+}
+
+// File of an imported path relative to the data directory, rejecting paths that would
+// leave it or land on a reserved `_` name
+fn relative_file(path: &str) -> Option<PathBuf> {
+    let relative = Path::new(path.trim_start_matches('/'));
+    let safe = relative.components().all(|c| match c {
+        Component::Normal(name) => !name.to_string_lossy().starts_with(['_', '.']),
+        _ => false,
+    });
+    if !safe || relative.as_os_str().is_empty() {
+        return None;
+    }
+    Some(PathBuf::from(format!("{}.json", relative.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_export_then_import_round_trip() {
+        let source = Path::new("./files/test_export_source");
+        fs::create_dir_all(source.join("users").join("_history")).unwrap();
+        fs::write(source.join("users").join("1.json"), json!({"name": "Ada"}).to_string()).unwrap();
+        fs::write(source.join("users").join("_schema.json"), json!({"type": "object"}).to_string()).unwrap();
+        fs::write(source.join("users").join("_history").join("1.json"), json!({}).to_string()).unwrap();
+
+        let mut out = Vec::new();
+        let exported = export_documents(source, &mut out).unwrap();
+
+        assert_eq!(exported, 1, "Reserved files should not be exported");
+        let line: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(line["path"], "/users/1");
+        assert_eq!(line["body"], json!({"name": "Ada"}));
+        assert_eq!(line["etag"].as_str().unwrap().len(), 16);
+
+        let target = Path::new("./files/test_export_target");
+        let mut log = Wal::open(target).unwrap();
+        let summary = import_documents(&mut log, out.as_slice(), ImportMode::Fail).unwrap();
+
+        assert_eq!(summary, ImportSummary { imported: 1, skipped: 0 });
+        let saved: Value = serde_json::from_str(&fs::read_to_string(target.join("users").join("1.json")).unwrap()).unwrap();
+        assert_eq!(saved, json!({"name": "Ada"}));
+
+        fs::remove_dir_all(source).unwrap();
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_import_conflict_modes() {
+        let target = Path::new("./files/test_import_modes");
+        fs::create_dir_all(target.join("users")).unwrap();
+        fs::write(target.join("users").join("1.json"), json!({"v": 0}).to_string()).unwrap();
+        let mut log = Wal::open(target).unwrap();
+        let input = [
+            json!({"path": "/users/1", "body": {"v": 1}}).to_string(),
+            json!({"path": "/users/2", "body": {"v": 2}}).to_string(),
+        ].join("\n");
+
+        match import_documents(&mut log, input.as_bytes(), ImportMode::Fail) {
+            Err(ImportError::Conflict(paths)) => assert_eq!(paths, vec!["/users/1"]),
+            other => panic!("Expected a conflict, got {:?}", other),
+        }
+        assert!(!target.join("users").join("2.json").exists(), "Nothing should be written on conflict");
+
+        let summary = import_documents(&mut log, input.as_bytes(), ImportMode::Skip).unwrap();
+        assert_eq!(summary, ImportSummary { imported: 1, skipped: 1 });
+
+        let summary = import_documents(&mut log, input.as_bytes(), ImportMode::Overwrite).unwrap();
+        assert_eq!(summary, ImportSummary { imported: 2, skipped: 0 });
+        let saved: Value = serde_json::from_str(&fs::read_to_string(target.join("users").join("1.json")).unwrap()).unwrap();
+        assert_eq!(saved, json!({"v": 1}));
+
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_import_rejects_invalid_lines() {
+        let target = Path::new("./files/test_import_invalid");
+        let mut log = Wal::open(target).unwrap();
+
+        for input in ["{not json", r#"{"path": "/../escape", "body": {}}"#, r#"{"path": "/users/_schema", "body": {}}"#, r#"{"path": "/users/1", "body": []}"#] {
+            match import_documents(&mut log, input.as_bytes(), ImportMode::Overwrite) {
+                Err(ImportError::Invalid { line, .. }) => assert_eq!(line, 1),
+                other => panic!("Expected invalid input for {}, got {:?}", input, other),
+            }
+        }
+
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_overwrite_import_is_all_or_nothing() {
+        let target = Path::new("./files/test_import_atomic");
+        let _ = fs::remove_dir_all(target);
+        fs::create_dir_all(target.join("users")).unwrap();
+        fs::write(target.join("users").join("1.json"), json!({"v": 0}).to_string()).unwrap();
+        let mut log = Wal::open(target).unwrap();
+        // The last document can not be written
+        fs::create_dir_all(target.join("users").join(".3.json.tmp")).unwrap();
+        let input: Vec<String> = (1..=3).map(|id| json!({"path": format!("/users/{}", id), "body": {"v": id}}).to_string()).collect();
+
+        assert!(matches!(import_documents(&mut log, input.join("\n").as_bytes(), ImportMode::Overwrite), Err(ImportError::Io(_))));
+
+        let saved: Value = serde_json::from_str(&fs::read_to_string(target.join("users").join("1.json")).unwrap()).unwrap();
+        assert_eq!(saved, json!({"v": 0}), "Overwritten documents should be put back");
+        assert!(!target.join("users").join("2.json").exists());
+        let spools = fs::read_dir(target).unwrap().filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with(".import")).count();
+        assert_eq!(spools, 0, "Spool files should be removed");
+
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_staged_export_is_a_snapshot() {
+        let dir = Path::new("./files/test_export_staged");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir.join("users")).unwrap();
+        fs::write(dir.join("users").join("1.json"), json!({"v": 1}).to_string()).unwrap();
+
+        let mut reader = stage_documents(dir).unwrap();
+        // Later writes replace documents by renaming, as the WAL does
+        wal::write_atomic(&dir.join("users").join("1.json"), json!({"v": 2}).to_string().as_bytes()).unwrap();
+        fs::write(dir.join("users").join("2.json"), json!({"v": 2}).to_string()).unwrap();
+
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+        let lines: Vec<Value> = out.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["body"], json!({"v": 1}));
+        drop(reader);
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 1, "Staging directory should be removed");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
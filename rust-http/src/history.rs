@@ -0,0 +1,176 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json::Value;
+use crate::cache;
+
+// Directory, next to the documents of a collection, that keeps their revisions
+pub const HISTORY_DIR: &str = "_history";
+// Revisions kept per document unless the configuration says otherwise
+pub const DEFAULT_LIMIT: usize = 10;
+
+// Directory holding the revisions of one document: `<collection>/_history/<id>/`
+pub fn history_dir(file_path: &Path) -> Option<PathBuf> {
+    let parent = file_path.parent()?;
+    let stem = file_path.file_stem()?;
+    Some(parent.join(HISTORY_DIR).join(stem))
+}
+
+// Revision numbers stored for a document, oldest first
+pub fn revisions(file_path: &Path) -> io::Result<Vec<u64>> {
+    let dir = match history_dir(file_path) {
+        Some(dir) if dir.is_dir() => dir,
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut revisions: Vec<u64> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                return None;
+            }
+            path.file_stem()?.to_str()?.parse().ok()
+        })
+        .collect();
+    revisions.sort_unstable();
+    Ok(revisions)
+}
+
+// Keep the current contents of a document that has no history yet as revision 1,
+// so the first write after enabling history does not lose the previous version
+pub fn ensure_baseline(file_path: &Path, limit: usize) -> io::Result<()> {
+    if limit == 0 || !file_path.is_file() || !revisions(file_path)?.is_empty() {
+        return Ok(());
+    }
+
+    let contents = fs::read_to_string(file_path)?;
+    let document: Value = serde_json::from_str(&contents)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_revision(file_path, 1, &document, None)
+}
+
+// Store a new revision of a document and drop the oldest ones beyond `limit`.
+// Returns the new revision number, or `None` when history is disabled (`limit` is 0).
+// Numbers come from the revisions on disk, so callers hold the write-ahead log while recording.
+pub fn record(file_path: &Path, document: &Value, session_id: &str, limit: usize) -> io::Result<Option<u64>> {
+    if limit == 0 {
+        return Ok(None);
+    }
+
+    let existing = revisions(file_path)?;
+    let revision = existing.last().map(|last| last + 1).unwrap_or(1);
+    let session = if session_id.is_empty() { None } else { Some(session_id) };
+    write_revision(file_path, revision, document, session)?;
+
+    // Retention: only the newest `limit` revisions are kept
+    let total = existing.len() + 1;
+    if total > limit {
+        if let Some(dir) = history_dir(file_path) {
+            for old in existing.iter().take(total - limit) {
+                fs::remove_file(dir.join(format!("{}.json", old)))?;
+            }
+        }
+    }
+
+    // Revisions are written after the commit that invalidated the document, so its
+    // `_history` listing is dropped again
+    cache::invalidate_file(file_path);
+    Ok(Some(revision))
+}
+
+// Read a stored revision: `{"rev", "timestamp", "session_id", "document"}`
+pub fn read_revision(file_path: &Path, revision: u64) -> io::Result<Option<Value>> {
+    let path = match history_dir(file_path) {
+        Some(dir) => dir.join(format!("{}.json", revision)),
+        None => return Ok(None),
+    };
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    let contents = fs::read_to_string(path)?;
+    serde_json::from_str(&contents)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Metadata of every stored revision, oldest first, without the documents themselves
+pub fn list(file_path: &Path) -> io::Result<Vec<Value>> {
+    let mut entries = Vec::new();
+    for revision in revisions(file_path)? {
+        if let Some(mut entry) = read_revision(file_path, revision)? {
+            if let Some(object) = entry.as_object_mut() {
+                object.remove("document");
+            }
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+fn write_revision(file_path: &Path, revision: u64, document: &Value, session_id: Option<&str>) -> io::Result<()> {
+    let dir = history_dir(file_path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid document path"))?;
+    fs::create_dir_all(&dir)?;
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    let entry = serde_json::json!({
+        "rev": revision,
+        "timestamp": timestamp,
+        "session_id": session_id,
+        "document": document
+    });
+    let json_string = serde_json::to_string_pretty(&entry)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    fs::write(dir.join(format!("{}.json", revision)), json_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_record_and_read_revisions() {
+        let dir = Path::new("./files/test_history_record");
+        fs::create_dir_all(dir).unwrap();
+        let file_path = dir.join("doc.json");
+        fs::write(&file_path, json!({"v": 0}).to_string()).unwrap();
+
+        ensure_baseline(&file_path, 10).unwrap();
+        let revision = record(&file_path, &json!({"v": 1}), "abc", 10).unwrap();
+
+        assert_eq!(revision, Some(2));
+        assert_eq!(read_revision(&file_path, 1).unwrap().unwrap()["document"], json!({"v": 0}));
+        let entry = read_revision(&file_path, 2).unwrap().unwrap();
+        assert_eq!(entry["document"], json!({"v": 1}));
+        assert_eq!(entry["session_id"], "abc");
+        assert_eq!(list(&file_path).unwrap().len(), 2);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_retention_limit() {
+        let dir = Path::new("./files/test_history_retention");
+        let file_path = dir.join("doc.json");
+
+        for v in 0..5 {
+            record(&file_path, &json!({"v": v}), "", 3).unwrap();
+        }
+
+        assert_eq!(revisions(&file_path).unwrap(), vec![3, 4, 5]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_disabled_history() {
+        let file_path = Path::new("./files/test_history_disabled/doc.json");
+
+        assert_eq!(record(file_path, &json!({}), "", 0).unwrap(), None);
+        assert!(!Path::new("./files/test_history_disabled").exists());
+    }
+}
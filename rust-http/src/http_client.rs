@@ -0,0 +1,741 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use serde_json::Value;
+use crate::request::{find_header, head_length, parse_head};
+use crate::body::Body;
+use crate::response::HttpResponse;
+use crate::ttl;
+
+// Largest response head the client accepts
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+// Settings of an `HttpClient`
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub connect_timeout: Duration,
+    // How long a request may wait on the server, `None` waits forever
+    pub timeout: Option<Duration>,
+    // Redirects followed per request, 0 hands the redirect response back
+    pub max_redirects: usize,
+    // Idle keep-alive connections kept per host
+    pub max_idle_per_host: usize,
+    // Keep cookies set by responses and send them back on later requests
+    pub cookies: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            connect_timeout: Duration::from_secs(10),
+            timeout: Some(Duration::from_secs(30)),
+            max_redirects: 10,
+            max_idle_per_host: 8,
+            cookies: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    InvalidUrl(String),
+    Io(io::Error),
+    Timeout,
+    InvalidResponse(String),
+    TooManyRedirects,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::InvalidUrl(message) => write!(f, "Invalid URL: {}", message),
+            ClientError::Io(e) => write!(f, "{}", e),
+            ClientError::Timeout => write!(f, "Request timed out"),
+            ClientError::InvalidResponse(message) => write!(f, "Invalid response: {}", message),
+            ClientError::TooManyRedirects => write!(f, "Too many redirects"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ClientError::Timeout,
+            _ => ClientError::Io(e),
+        }
+    }
+}
+
+// An `http://` URL; the path keeps its query string
+#[derive(Debug, Clone, PartialEq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Url, ClientError> {
+        let rest = url.strip_prefix("http://")
+            .ok_or_else(|| ClientError::InvalidUrl(format!("{}: only http:// URLs are supported", url)))?;
+        let rest = rest.split('#').next().unwrap_or_default();
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(position) => (&rest[..position], &rest[position..]),
+            None => (rest, "/"),
+        };
+
+        let (host, port) = match authority.strip_prefix('[') {
+            // IPv6 literal: `[::1]:8080`
+            Some(literal) => {
+                let (host, port) = literal.split_once(']').ok_or_else(|| ClientError::InvalidUrl(url.to_string()))?;
+                (host, port.strip_prefix(':'))
+            },
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| ClientError::InvalidUrl(format!("{}: invalid port", url)))?,
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(ClientError::InvalidUrl(format!("{}: missing host", url)));
+        }
+
+        let path = if path.starts_with('?') { format!("/{}", path) } else { path.to_string() };
+        Ok(Url { host: host.to_string(), port, path })
+    }
+
+    // Resolve a `Location` header against this URL
+    pub fn join(&self, location: &str) -> Result<Url, ClientError> {
+        if location.starts_with("http://") {
+            return Url::parse(location);
+        }
+        if let Some(rest) = location.strip_prefix("//") {
+            return Url::parse(&format!("http://{}", rest));
+        }
+        if location.contains("://") {
+            return Err(ClientError::InvalidUrl(format!("{}: only http:// URLs are supported", location)));
+        }
+
+        let path = if location.starts_with('/') {
+            location.to_string()
+        } else {
+            let base = self.path.split('?').next().unwrap_or_default();
+            let directory = base.rsplit_once('/').map(|(directory, _)| directory).unwrap_or_default();
+            format!("{}/{}", directory, location)
+        };
+        Ok(Url { host: self.host.clone(), port: self.port, path })
+    }
+
+    // Value of the `Host` header
+    fn authority(&self) -> String {
+        let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+        if self.port == 80 { host } else { format!("{}:{}", host, self.port) }
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}{}", self.authority(), self.path)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Cookie {
+    name: String,
+    value: String,
+    domain: String,
+    // Set without a `Domain` attribute: only sent back to the host that set it
+    host_only: bool,
+    path: String,
+    expires_at: Option<u64>,
+}
+
+impl Cookie {
+    fn matches(&self, url: &Url) -> bool {
+        let domain_matches = url.host.eq_ignore_ascii_case(&self.domain)
+            || (!self.host_only && url.host.to_ascii_lowercase().ends_with(&format!(".{}", self.domain)));
+        let path = url.path.split('?').next().unwrap_or_default();
+        let path_matches = path == self.path
+            || (path.starts_with(&self.path) && (self.path.ends_with('/') || path[self.path.len()..].starts_with('/')));
+        domain_matches && path_matches && self.expires_at.is_none_or(|expires_at| expires_at > ttl::now())
+    }
+}
+
+// Cookies received from servers, sent back to the hosts and paths they apply to
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+impl CookieJar {
+    // Store a `Set-Cookie` header received from `url`. An expiry in the past removes the cookie.
+    pub fn store(&mut self, url: &Url, set_cookie: &str) {
+        let mut attributes = set_cookie.split(';');
+        let Some((name, value)) = attributes.next().and_then(|pair| pair.split_once('=')) else {
+            return;
+        };
+        let default_path = match url.path.split('?').next().unwrap_or_default().rsplit_once('/') {
+            Some((directory, _)) if !directory.is_empty() => directory.to_string(),
+            _ => "/".to_string(),
+        };
+        let mut cookie = Cookie {
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+            domain: url.host.to_ascii_lowercase(),
+            host_only: true,
+            path: default_path,
+            expires_at: None,
+        };
+        if cookie.name.is_empty() {
+            return;
+        }
+
+        let mut max_age = None;
+        for attribute in attributes {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "domain" if !value.is_empty() => {
+                    let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                    // A server may only set cookies for its own domain
+                    if cookie.domain != domain && !cookie.domain.ends_with(&format!(".{}", domain)) {
+                        return;
+                    }
+                    cookie.domain = domain;
+                    cookie.host_only = false;
+                },
+                "path" if value.starts_with('/') => cookie.path = value.to_string(),
+                "max-age" => max_age = value.parse::<i64>().ok(),
+                "expires" => cookie.expires_at = ttl::parse_http_date(value),
+                _ => {},
+            }
+        }
+        // Max-Age wins over Expires
+        if let Some(seconds) = max_age {
+            cookie.expires_at = Some(if seconds <= 0 { 0 } else { ttl::now() + seconds as u64 });
+        }
+
+        self.cookies.retain(|c| !(c.name == cookie.name && c.domain == cookie.domain && c.path == cookie.path));
+        if cookie.expires_at.is_none_or(|expires_at| expires_at > ttl::now()) {
+            self.cookies.push(cookie);
+        }
+    }
+
+    // Value of the `Cookie` header for a request to `url`, longest paths first
+    pub fn header_for(&self, url: &Url) -> Option<String> {
+        let mut cookies: Vec<&Cookie> = self.cookies.iter().filter(|cookie| cookie.matches(url)).collect();
+        if cookies.is_empty() {
+            return None;
+        }
+        cookies.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+        Some(cookies.iter().map(|cookie| format!("{}={}", cookie.name, cookie.value)).collect::<Vec<_>>().join("; "))
+    }
+
+    // Value of the cookie `name` that would be sent to `url`
+    pub fn get(&self, url: &Url, name: &str) -> Option<&str> {
+        self.cookies.iter().find(|cookie| cookie.name == name && cookie.matches(url)).map(|cookie| cookie.value.as_str())
+    }
+}
+
+// A connection to a server with the bytes read past the last response
+struct Connection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl Connection {
+    // Read more from the server, returning how many bytes arrived
+    fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0; 8192];
+        let bytes_read = self.stream.read(&mut chunk)?;
+        self.buffer.extend_from_slice(&chunk[..bytes_read]);
+        Ok(bytes_read)
+    }
+
+    fn take(&mut self, length: usize) -> Result<Vec<u8>, ClientError> {
+        while self.buffer.len() < length {
+            if self.fill()? == 0 {
+                return Err(ClientError::InvalidResponse("Connection closed before the body ended".to_string()));
+            }
+        }
+        Ok(self.buffer.drain(..length).collect())
+    }
+
+    fn take_line(&mut self) -> Result<String, ClientError> {
+        loop {
+            if let Some(position) = self.buffer.windows(2).position(|window| window == b"\r\n") {
+                let line = self.take(position + 2)?;
+                return Ok(String::from_utf8_lossy(&line[..position]).into_owned());
+            }
+            if self.buffer.len() > MAX_HEAD_SIZE || self.fill()? == 0 {
+                return Err(ClientError::InvalidResponse("Invalid chunked body".to_string()));
+            }
+        }
+    }
+
+    fn read_chunked(&mut self) -> Result<Vec<u8>, ClientError> {
+        let mut body = Vec::new();
+        loop {
+            let line = self.take_line()?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| ClientError::InvalidResponse(format!("Invalid chunk size: {}", line)))?;
+            if size == 0 {
+                // Skip the trailer fields up to the blank line
+                while !self.take_line()?.is_empty() {}
+                return Ok(body);
+            }
+            body.extend(self.take(size)?);
+            self.take_line()?;
+        }
+    }
+
+    // Read one response to a `method` request: the status line, the headers and the body,
+    // delimited by `Content-Length`, chunked encoding or the end of the connection
+    fn read_response(&mut self, method: &str) -> Result<RawResponse, ClientError> {
+        loop {
+            let head_length = loop {
+                if let Some(head_length) = head_length(&self.buffer) {
+                    break head_length;
+                }
+                if self.buffer.len() > MAX_HEAD_SIZE {
+                    return Err(ClientError::InvalidResponse("Response head too large".to_string()));
+                }
+                if self.fill()? == 0 {
+                    return Err(if self.buffer.is_empty() {
+                        ClientError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed without a response"))
+                    } else {
+                        ClientError::InvalidResponse("Connection closed in the response head".to_string())
+                    });
+                }
+            };
+            let head: Vec<u8> = self.buffer.drain(..head_length).collect();
+            let head = String::from_utf8_lossy(&head);
+            let (status_line, headers) = parse_head(&head);
+
+            let mut parts = status_line.split_whitespace();
+            let version = parts.next().unwrap_or_default().to_string();
+            let status_code: u16 = parts.next().and_then(|code| code.parse().ok())
+                .ok_or_else(|| ClientError::InvalidResponse(format!("Invalid status line: {}", status_line)))?;
+            // Interim responses such as `100 Continue` are followed by the real one
+            if (100..200).contains(&status_code) {
+                continue;
+            }
+
+            let connection = find_header(&headers, "Connection").unwrap_or_default().to_ascii_lowercase();
+            let mut keep_alive = match version.as_str() {
+                "HTTP/1.0" => connection.contains("keep-alive"),
+                _ => !connection.contains("close"),
+            };
+            let chunked = find_header(&headers, "Transfer-Encoding").is_some_and(|value| value.to_ascii_lowercase().contains("chunked"));
+            let content_length = find_header(&headers, "Content-Length").and_then(|value| value.parse::<usize>().ok());
+
+            let body = if method == "HEAD" || status_code == 204 || status_code == 304 {
+                Vec::new()
+            } else if chunked {
+                self.read_chunked()?
+            } else if let Some(length) = content_length {
+                self.take(length)?
+            } else {
+                // Without a length the body ends with the connection
+                keep_alive = false;
+                while self.fill()? > 0 {}
+                std::mem::take(&mut self.buffer)
+            };
+
+            return Ok(RawResponse { status_code, headers, body, keep_alive });
+        }
+    }
+}
+
+struct RawResponse {
+    status_code: u16,
+    headers: Vec<String>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+impl RawResponse {
+    // Repeated headers are joined with commas
+    fn into_response(self) -> HttpResponse {
+        let mut headers: HashMap<String, String> = HashMap::new();
+        for line in &self.headers {
+            if let Some((key, value)) = line.split_once(':') {
+                headers.entry(key.trim().to_string())
+                    .and_modify(|existing| *existing = format!("{}, {}", existing, value.trim()))
+                    .or_insert_with(|| value.trim().to_string());
+            }
+        }
+        let body = if self.body.is_empty() { None } else { Some(Body::Bytes(self.body)) };
+        HttpResponse { status_code: self.status_code, headers, body }
+    }
+}
+
+// Blocking HTTP/1.1 client. Connections are kept alive and reused per host,
+// redirects are followed and cookies are kept across requests.
+pub struct HttpClient {
+    config: ClientConfig,
+    idle: Mutex<HashMap<(String, u16), Vec<Connection>>>,
+    cookies: Mutex<CookieJar>,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpClient {
+    pub fn new() -> Self {
+        Self::with_config(ClientConfig::default())
+    }
+
+    pub fn with_config(config: ClientConfig) -> Self {
+        HttpClient {
+            config,
+            idle: Mutex::new(HashMap::new()),
+            cookies: Mutex::new(CookieJar::default()),
+        }
+    }
+
+    pub fn request(&self, method: &str, url: &str) -> RequestBuilder<'_> {
+        RequestBuilder {
+            client: self,
+            method: method.to_string(),
+            url: url.to_string(),
+            headers: Vec::new(),
+            body: None,
+            timeout: self.config.timeout,
+        }
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder<'_> {
+        self.request("GET", url)
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder<'_> {
+        self.request("POST", url)
+    }
+
+    pub fn put(&self, url: &str) -> RequestBuilder<'_> {
+        self.request("PUT", url)
+    }
+
+    pub fn patch(&self, url: &str) -> RequestBuilder<'_> {
+        self.request("PATCH", url)
+    }
+
+    pub fn delete(&self, url: &str) -> RequestBuilder<'_> {
+        self.request("DELETE", url)
+    }
+
+    pub fn cookies(&self) -> MutexGuard<'_, CookieJar> {
+        self.cookies.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Send a request, following redirects: 303, and 301/302 after a POST, continue with a GET
+    // without body, while 307 and 308 repeat the request as it was
+    fn execute(&self, request: RequestBuilder) -> Result<HttpResponse, ClientError> {
+        let RequestBuilder { method, url, mut headers, mut body, timeout, .. } = request;
+        let mut method = method;
+        let mut url = Url::parse(&url)?;
+
+        let mut redirects = 0;
+        loop {
+            let response = self.send_once(&method, &url, &headers, body.as_deref(), timeout)?;
+            let location = match response.status_code {
+                301 | 302 | 303 | 307 | 308 => response.header("Location").map(str::to_string),
+                _ => None,
+            };
+            let Some(location) = location else {
+                return Ok(response);
+            };
+            if self.config.max_redirects == 0 {
+                return Ok(response);
+            }
+            if redirects == self.config.max_redirects {
+                return Err(ClientError::TooManyRedirects);
+            }
+            redirects += 1;
+
+            let next = url.join(&location)?;
+            if next.host != url.host || next.port != url.port {
+                // Credentials are not handed to another server
+                headers.retain(|(key, _)| !key.eq_ignore_ascii_case("Authorization") && !key.eq_ignore_ascii_case("Cookie"));
+            }
+            if response.status_code == 303 || (matches!(response.status_code, 301 | 302) && method == "POST") {
+                method = "GET".to_string();
+                body = None;
+                headers.retain(|(key, _)| !key.eq_ignore_ascii_case("Content-Type"));
+            }
+            url = next;
+        }
+    }
+
+    // One request and its response, on an idle connection to the host when there is one
+    fn send_once(&self, method: &str, url: &Url, headers: &[(String, String)], body: Option<&[u8]>, timeout: Option<Duration>) -> Result<HttpResponse, ClientError> {
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", method, url.path, url.authority());
+        for (key, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        let has_header = |name: &str| headers.iter().any(|(key, _)| key.eq_ignore_ascii_case(name));
+        if self.config.cookies && !has_header("Cookie") {
+            if let Some(cookies) = self.cookies().header_for(url) {
+                request.push_str(&format!("Cookie: {}\r\n", cookies));
+            }
+        }
+        if body.is_some() || matches!(method, "POST" | "PUT" | "PATCH") {
+            request.push_str(&format!("Content-Length: {}\r\n", body.map(<[u8]>::len).unwrap_or(0)));
+        }
+        request.push_str("\r\n");
+        let mut request = request.into_bytes();
+        request.extend_from_slice(body.unwrap_or_default());
+
+        let key = (url.host.clone(), url.port);
+        loop {
+            let (mut connection, reused) = match self.take_idle(&key) {
+                Some(connection) => (connection, true),
+                None => (self.connect(url)?, false),
+            };
+            connection.stream.set_read_timeout(timeout)?;
+            connection.stream.set_write_timeout(timeout)?;
+
+            let result = connection.stream.write_all(&request)
+                .map_err(ClientError::from)
+                .and_then(|_| connection.read_response(method));
+            match result {
+                Ok(response) => {
+                    if self.config.cookies {
+                        let mut jar = self.cookies();
+                        for line in &response.headers {
+                            if let Some((key, value)) = line.split_once(':') {
+                                if key.trim().eq_ignore_ascii_case("Set-Cookie") {
+                                    jar.store(url, value.trim());
+                                }
+                            }
+                        }
+                    }
+                    if response.keep_alive {
+                        self.release(key, connection);
+                    }
+                    return Ok(response.into_response());
+                },
+                // The server closed an idle connection: try again on a new one
+                Err(ClientError::Io(e)) if reused && matches!(
+                    e.kind(),
+                    io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+                ) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn connect(&self, url: &Url) -> Result<Connection, ClientError> {
+        let addresses = (url.host.as_str(), url.port).to_socket_addrs()
+            .map_err(|e| ClientError::InvalidUrl(format!("{}: {}", url.host, e)))?;
+        let mut last_error = ClientError::InvalidUrl(format!("{}: no addresses", url.host));
+        for address in addresses {
+            match TcpStream::connect_timeout(&address, self.config.connect_timeout) {
+                Ok(stream) => return Ok(Connection { stream, buffer: Vec::new() }),
+                Err(e) => last_error = e.into(),
+            }
+        }
+        Err(last_error)
+    }
+
+    fn take_idle(&self, key: &(String, u16)) -> Option<Connection> {
+        self.idle.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).get_mut(key)?.pop()
+    }
+
+    fn release(&self, key: (String, u16), connection: Connection) {
+        let mut idle = self.idle.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let connections = idle.entry(key).or_default();
+        if connections.len() < self.config.max_idle_per_host {
+            connections.push(connection);
+        }
+    }
+}
+
+// A request being put together: `client.post(url).json(&body).send()`
+pub struct RequestBuilder<'a> {
+    client: &'a HttpClient,
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Option<Vec<u8>>,
+    timeout: Option<Duration>,
+}
+
+impl RequestBuilder<'_> {
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    pub fn json(self, body: &Value) -> Self {
+        self.header("Content-Type", "application/json").body(body.to_string())
+    }
+
+    // How long this request may wait on the server, instead of the client default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn send(self) -> Result<HttpResponse, ClientError> {
+        let client = self.client;
+        client.execute(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use serde_json::json;
+    use crate::request::HttpRequest;
+
+    // Local server taking `connections` connections and answering every request on them with
+    // `respond`, or only the first one with `close` set; the number of the connection is passed
+    // along with the request
+    fn serve<F>(connections: usize, close: bool, respond: F) -> String
+    where
+        F: Fn(usize, &HttpRequest) -> String + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for number in 0..connections {
+                let (stream, _) = listener.accept().unwrap();
+                let mut connection = Connection { stream, buffer: Vec::new() };
+                loop {
+                    let head_length = loop {
+                        if let Some(head_length) = head_length(&connection.buffer) {
+                            break Some(head_length);
+                        }
+                        if connection.fill().unwrap_or(0) == 0 {
+                            break None;
+                        }
+                    };
+                    let Some(head_length) = head_length else { break };
+                    let (_, headers) = parse_head(&String::from_utf8_lossy(&connection.buffer[..head_length]));
+                    let length = find_header(&headers, "Content-Length").and_then(|l| l.parse().ok()).unwrap_or(0);
+                    let raw = connection.take(head_length + length).unwrap();
+                    let request = HttpRequest::parse(&raw).unwrap();
+                    connection.stream.write_all(respond(number, &request).as_bytes()).unwrap();
+                    if close {
+                        break;
+                    }
+                }
+            }
+        });
+        base
+    }
+
+    #[test]
+    fn test_url_parse_and_join() {
+        let url = Url::parse("http://localhost:8080/users/420?fields=name#top").unwrap();
+        assert_eq!(url, Url { host: "localhost".to_string(), port: 8080, path: "/users/420?fields=name".to_string() });
+        assert_eq!(Url::parse("http://[::1]?a=1").unwrap().path, "/?a=1");
+        assert_eq!(Url::parse("http://[::1]:81/").unwrap().to_string(), "http://[::1]:81/");
+        assert!(matches!(Url::parse("https://example.com"), Err(ClientError::InvalidUrl(_))));
+
+        assert_eq!(url.join("/login").unwrap().to_string(), "http://localhost:8080/login");
+        assert_eq!(url.join("421").unwrap().path, "/users/421");
+        assert_eq!(url.join("//other/x").unwrap().host, "other");
+    }
+
+    #[test]
+    fn test_cookie_jar() {
+        let mut jar = CookieJar::default();
+        let url = Url::parse("http://api.example.com/users/420").unwrap();
+        jar.store(&url, "session=abc; Path=/; HttpOnly");
+        jar.store(&url, "scoped=1");
+        jar.store(&url, "shared=2; Domain=example.com; Path=/; Max-Age=60");
+        jar.store(&url, "foreign=3; Domain=other.com");
+
+        assert_eq!(jar.header_for(&url).unwrap(), "scoped=1; session=abc; shared=2");
+        assert_eq!(jar.header_for(&Url::parse("http://www.example.com/").unwrap()).unwrap(), "shared=2");
+        assert_eq!(jar.header_for(&Url::parse("http://api.example.com/teams").unwrap()).unwrap(), "session=abc; shared=2");
+        assert_eq!(jar.header_for(&Url::parse("http://other.com/").unwrap()), None);
+
+        jar.store(&url, "session=gone; Path=/; Max-Age=0");
+        assert_eq!(jar.get(&url, "session"), None);
+        jar.store(&url, "shared=old; Domain=example.com; Path=/; Expires=Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(jar.get(&url, "shared"), None);
+    }
+
+    #[test]
+    fn test_keep_alive_connections_are_reused() {
+        // A single connection serves both requests; the first answer is chunked
+        let base = serve(1, false, |_, request| match request.path.as_str() {
+            "/chunked" => "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n5;x=1\r\npedia\r\n0\r\n\r\n".to_string(),
+            _ => format!("HTTP/1.1 201 Created\r\nContent-Length: {}\r\n\r\n{}", request.body.len(), request.text()),
+        });
+        let client = HttpClient::new();
+
+        let first = client.get(&format!("{}/chunked", base)).send().unwrap();
+        let second = client.post(&format!("{}/echo", base)).json(&json!({"a": 1})).send().unwrap();
+
+        assert_eq!((first.status_code, first.text().as_deref()), (200, Some("Wikipedia")));
+        assert_eq!((second.status_code, second.text().as_deref()), (201, Some(r#"{"a":1}"#)));
+    }
+
+    #[test]
+    fn test_closed_idle_connections_are_replaced() {
+        // Like this crate's server, the stub hangs up after each response without saying so
+        let base = serve(2, true, |number, _| format!("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n{}", number));
+        let client = HttpClient::new();
+
+        assert_eq!(client.get(&base).send().unwrap().text().as_deref(), Some("0"));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(client.get(&base).send().unwrap().text().as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn test_redirects_and_cookies() {
+        let (sender, receiver) = mpsc::channel();
+        let base = serve(1, false, move |_, request| {
+            sender.send((request.method.clone(), request.path.clone(), request.header("Cookie").map(str::to_string))).unwrap();
+            match request.path.as_str() {
+                "/login" => "HTTP/1.1 303 See Other\r\nLocation: /home\r\nSet-Cookie: session=abc; Path=/\r\nContent-Length: 0\r\n\r\n".to_string(),
+                "/loop" => "HTTP/1.1 307 Temporary Redirect\r\nLocation: /loop\r\nContent-Length: 0\r\n\r\n".to_string(),
+                _ => "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_string(),
+            }
+        });
+        let client = HttpClient::with_config(ClientConfig { max_redirects: 3, ..ClientConfig::default() });
+
+        let response = client.post(&format!("{}/login", base)).body("user=ana").send().unwrap();
+
+        assert_eq!(response.text().as_deref(), Some("ok"));
+        assert_eq!(receiver.recv().unwrap(), ("POST".to_string(), "/login".to_string(), None));
+        assert_eq!(receiver.recv().unwrap(), ("GET".to_string(), "/home".to_string(), Some("session=abc".to_string())));
+        assert!(matches!(client.get(&format!("{}/loop", base)).send(), Err(ClientError::TooManyRedirects)));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let client = HttpClient::new();
+
+        let result = client.get(&url).timeout(Duration::from_millis(100)).send();
+
+        assert!(matches!(result, Err(ClientError::Timeout)), "Got {:?}", result);
+    }
+}
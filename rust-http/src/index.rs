@@ -0,0 +1,457 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use serde_json::Value;
+use crate::cache;
+use crate::collection::{field_value, read_documents};
+use crate::methods::{error_response, storage_unavailable};
+use crate::response::HttpResponse;
+use crate::wal::{self, write_atomic};
+
+// Directory, next to the documents of a collection, that keeps its secondary indexes
+pub const INDEX_DIR: &str = "_indexes";
+// Bytes of changes an index log may gather past twice its compacted size before it is rewritten
+const COMPACT_SLACK: u64 = 64 * 1024;
+
+// Secondary index of a collection: document IDs by the value of one field.
+// `field` is a top-level key (`email`) or a JSON Pointer (`/address/city`).
+// Only scalar values are indexed; documents without the field are left out.
+//
+// An index is kept in `_indexes/{name}.ndjson`: a `{"name", "field", "size"}` header, then
+// one `{"id", "key"}` line per indexed document. Writes append the new key of the document
+// they changed, a `null` key taking it out, and once the file outgrows twice its `size`
+// it is compacted back to one line per document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Index {
+    pub name: String,
+    pub field: String,
+    // A change could not be recorded; queries scan until the index is built again
+    pub stale: bool,
+    entries: BTreeMap<String, BTreeSet<String>>,
+    // Key each indexed ID is filed under, to move it when its document changes
+    keys: HashMap<String, String>,
+}
+
+impl Index {
+    // Build an index over the documents currently in `collection_dir`
+    pub fn build(collection_dir: &Path, name: &str, field: &str) -> io::Result<Index> {
+        let mut index = Index { name: name.to_string(), field: field.to_string(), stale: false, entries: BTreeMap::new(), keys: HashMap::new() };
+        for (id, document) in read_documents(collection_dir)? {
+            index.set(&id, document_key(&document, field));
+        }
+        Ok(index)
+    }
+
+    // IDs whose field equals the raw text of a query parameter, compared like collection filters
+    pub fn lookup(&self, raw: &str) -> BTreeSet<String> {
+        query_keys(raw).iter()
+            .filter_map(|key| self.entries.get(key))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "name": self.name,
+            "field": self.field,
+            "keys": self.key_count(),
+            "stale": self.stale
+        })
+    }
+
+    // File `id` under `key`, or under nothing for `None`, taking it out of its previous key
+    fn set(&mut self, id: &str, key: Option<String>) {
+        if let Some(previous) = self.keys.remove(id) {
+            if let Some(ids) = self.entries.get_mut(&previous) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.entries.remove(&previous);
+                }
+            }
+        }
+        if let Some(key) = key {
+            self.entries.entry(key.clone()).or_default().insert(id.to_string());
+            self.keys.insert(id.to_string(), key);
+        }
+    }
+
+    // Write the whole index, dropping the changes appended since it was last written
+    fn save(&self, collection_dir: &Path) -> io::Result<()> {
+        let mut lines = String::new();
+        for (key, ids) in &self.entries {
+            for id in ids {
+                lines.push_str(&serde_json::json!({"id": id, "key": key}).to_string());
+                lines.push('\n');
+            }
+        }
+        let header = serde_json::json!({"name": self.name, "field": self.field, "size": lines.len()});
+        let path = index_path(collection_dir, &self.name);
+        write_atomic(&path, format!("{}\n{}", header, lines).as_bytes())?;
+        // Written outside the WAL: the index listing and the collection queries are stale
+        cache::invalidate_file(&path);
+        match fs::remove_file(stale_path(collection_dir, &self.name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn load(path: &Path) -> io::Result<Index> {
+        let header = read_header(path)?;
+        let mut index = Index { name: header.name, field: header.field, stale: false, entries: BTreeMap::new(), keys: HashMap::new() };
+        index.stale = path.with_extension("stale").exists();
+
+        for line in BufReader::new(File::open(path)?).lines().skip(1) {
+            // A change cut short by a crash ends the log; the index was marked stale then
+            let change: Value = match serde_json::from_str(&line?) {
+                Ok(change) => change,
+                Err(_) => break,
+            };
+            let Some(id) = change["id"].as_str() else {
+                break;
+            };
+            index.set(id, change["key"].as_str().map(str::to_string));
+        }
+        Ok(index)
+    }
+}
+
+// First line of an index file, all a write needs to know about the index
+struct Header {
+    name: String,
+    field: String,
+    size: u64,
+}
+
+fn read_header(path: &Path) -> io::Result<Header> {
+    let mut line = String::new();
+    BufReader::new(File::open(path)?).read_line(&mut line)?;
+    let header: Value = serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid index file {}", path.display()));
+    Ok(Header {
+        name: header["name"].as_str().ok_or_else(invalid)?.to_string(),
+        field: header["field"].as_str().ok_or_else(invalid)?.to_string(),
+        size: header["size"].as_u64().ok_or_else(invalid)?,
+    })
+}
+
+// Index files of a collection
+fn index_files(collection_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let dir = collection_dir.join(INDEX_DIR);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) == Some("ndjson") {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+// Indexes declared on a collection, sorted by name
+pub fn load_indexes(collection_dir: &Path) -> io::Result<Vec<Index>> {
+    let mut indexes = index_files(collection_dir)?.iter().map(|path| Index::load(path)).collect::<io::Result<Vec<Index>>>()?;
+    indexes.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(indexes)
+}
+
+// Record the contents a write left at `file_path`, `None` once it is deleted, in every index
+// of its collection. The WAL calls this for each mutation it applies or rolls back, before
+// the record is marked done, so a crash replays the change. Reserved `_` files are never
+// indexed. Failures do not fail the write: they are reported and leave the index stale.
+pub fn update(file_path: &Path, current: Option<&Value>) {
+    let reserved = file_path.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.starts_with('_') || n.starts_with('.'))
+        .unwrap_or(true);
+    let (Some(collection_dir), Some(id)) = (file_path.parent(), file_path.file_stem().and_then(|s| s.to_str())) else {
+        return;
+    };
+    if reserved {
+        return;
+    }
+
+    let files = match index_files(collection_dir) {
+        Ok(files) => files,
+        Err(e) => {
+            println!("Failed to read indexes of {}: {}", collection_dir.display(), e);
+            return;
+        },
+    };
+    for path in files {
+        if path.with_extension("stale").exists() {
+            continue;
+        }
+        if let Err(e) = append_change(collection_dir, &path, id, current) {
+            println!("Failed to update index {}, it is stale until built again: {}", path.display(), e);
+            if let Err(e) = fs::write(path.with_extension("stale"), b"") {
+                println!("Failed to mark index {} as stale: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+// Append the key `current` gives `id` to an index file, compacting the file when it grew too long
+fn append_change(collection_dir: &Path, path: &Path, id: &str, current: Option<&Value>) -> io::Result<()> {
+    let header = read_header(path)?;
+    let key = current.and_then(|document| document_key(document, &header.field));
+
+    let mut file = OpenOptions::new().append(true).open(path)?;
+    file.write_all(format!("{}\n", serde_json::json!({"id": id, "key": key})).as_bytes())?;
+    file.sync_data()?;
+    if file.metadata()?.len() > 2 * header.size + COMPACT_SLACK {
+        Index::load(path)?.save(collection_dir)?;
+    }
+    Ok(())
+}
+
+// Function to handle requests on the indexes of a collection
+//   GET    /{collection}/_indexes         list the indexes
+//   PUT    /{collection}/_indexes/{name}  declare an index: `{"field": "email"}`, built right away
+//   DELETE /{collection}/_indexes/{name}  drop an index
+pub fn handle_index_request(method: &str, collection: &str, name: &str, body: Option<&Value>) -> HttpResponse {
+    println!("Handling index request: {} {}/{}/{}", method, collection, INDEX_DIR, name);
+
+    let collection_dir = PathBuf::from(format!("./files/{}", collection));
+    if !collection_dir.is_dir() {
+        return error_response(404, "Collection not found");
+    }
+    if !name.is_empty() && !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return error_response(400, "Invalid index name: use letters, digits, '-' and '_'");
+    }
+
+    match (method, name) {
+        ("GET", "") => match load_indexes(&collection_dir) {
+            Ok(indexes) => {
+                let mut headers = HashMap::new();
+                headers.insert("Content-Type".to_string(), "application/json".to_string());
+                let indexes: Vec<Value> = indexes.iter().map(Index::to_json).collect();
+                HttpResponse::new(200, headers, Some(Value::from(indexes).to_string()))
+            },
+            Err(e) => {
+                println!("Failed to read indexes: {}", e);
+                error_response(500, "Failed to read indexes")
+            },
+        },
+        ("PUT", name) if !name.is_empty() => {
+            let field = match body.and_then(|body| body["field"].as_str()) {
+                Some(field) if !field.is_empty() => field,
+                _ => return error_response(400, "Missing field: expected {\"field\": \"name\"} or a JSON Pointer"),
+            };
+
+            // Holding the log keeps writers out while the index is built
+            let _log = match wal::shared() {
+                Ok(log) => log,
+                Err(e) => return storage_unavailable(&e),
+            };
+            let result = Index::build(&collection_dir, name, field).and_then(|index| index.save(&collection_dir).map(|_| index));
+            match result {
+                Ok(index) => {
+                    let mut body = index.to_json();
+                    body["status_code"] = Value::from(201);
+                    body["message"] = Value::from("Index created successfully");
+                    HttpResponse::new(201, HashMap::new(), Some(body.to_string()))
+                },
+                Err(e) => {
+                    println!("Failed to build index: {}", e);
+                    error_response(500, "Failed to build index")
+                },
+            }
+        },
+        ("DELETE", name) if !name.is_empty() => {
+            let _log = match wal::shared() {
+                Ok(log) => log,
+                Err(e) => return storage_unavailable(&e),
+            };
+            let _ = fs::remove_file(stale_path(&collection_dir, name));
+            let path = index_path(&collection_dir, name);
+            let removed = fs::remove_file(&path);
+            cache::invalidate_file(&path);
+            match removed {
+                Ok(_) => error_response(200, "Index deleted successfully"),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => error_response(404, "Index not found"),
+                Err(e) => {
+                    println!("Failed to delete index: {}", e);
+                    error_response(500, "Failed to delete index")
+                },
+            }
+        },
+        _ => error_response(405, "Method not allowed"),
+    }
+}
+
+// Split `users/_indexes/email` into the collection and the index name
+pub fn split_index_path(id: &str) -> Option<(&str, &str)> {
+    let marker = format!("/{}", INDEX_DIR);
+    let position = id.find(&marker)?;
+    let (collection, rest) = (&id[..position], &id[position + marker.len()..]);
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    Some((collection, rest.trim_start_matches('/')))
+}
+
+fn index_path(collection_dir: &Path, name: &str) -> PathBuf {
+    collection_dir.join(INDEX_DIR).join(format!("{}.ndjson", name))
+}
+
+// Marker of an index that missed a change
+fn stale_path(collection_dir: &Path, name: &str) -> PathBuf {
+    collection_dir.join(INDEX_DIR).join(format!("{}.stale", name))
+}
+
+// Key a document is indexed under for `field`
+fn document_key(document: &Value, field: &str) -> Option<String> {
+    field_value(document, field).and_then(index_key)
+}
+
+// Key of an indexed value; numbers share one key whatever their JSON spelling (`1`, `1.0`)
+fn index_key(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(format!("s:{}", text)),
+        Value::Number(number) => number.as_f64().map(number_key),
+        Value::Bool(flag) => Some(format!("b:{}", flag)),
+        Value::Null => Some("null".to_string()),
+        _ => None,
+    }
+}
+
+// Keys a query value could match, as `compare_to_query` reads it in the stored value's type
+fn query_keys(raw: &str) -> Vec<String> {
+    let mut keys = vec![format!("s:{}", raw)];
+    if let Ok(number) = raw.parse::<f64>() {
+        keys.push(number_key(number));
+    }
+    if let Ok(flag) = raw.parse::<bool>() {
+        keys.push(format!("b:{}", flag));
+    }
+    if raw == "null" {
+        keys.push("null".to_string());
+    }
+    keys
+}
+
+fn number_key(number: f64) -> String {
+    // `-0` and `0` compare equal
+    format!("n:{}", if number == 0.0 { 0.0 } else { number })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::wal::{Mutation, Wal};
+
+    #[test]
+    fn test_index_follows_writes() {
+        let dir = Path::new("./files/test_index_writes");
+        let users = dir.join("users");
+        fs::create_dir_all(&users).unwrap();
+        fs::write(users.join("1.json"), json!({"email": "a@x", "address": {"city": "Lima"}}).to_string()).unwrap();
+        Index::build(&users, "email", "email").unwrap().save(&users).unwrap();
+        Index::build(&users, "city", "/address/city").unwrap().save(&users).unwrap();
+
+        let mut log = Wal::open(dir).unwrap();
+        log.commit(&[
+            Mutation::Put { path: PathBuf::from("users/1.json"), document: json!({"email": "b@x", "address": {"city": "Lima"}}) },
+            Mutation::Put { path: PathBuf::from("users/2.json"), document: json!({"email": "a@x", "address": {"city": "Quito"}}) },
+        ]).unwrap();
+
+        let indexes = load_indexes(&users).unwrap();
+        let (city, email) = (&indexes[0], &indexes[1]);
+        assert_eq!(email.lookup("a@x"), BTreeSet::from(["2".to_string()]));
+        assert_eq!(email.lookup("b@x"), BTreeSet::from(["1".to_string()]));
+        assert_eq!(city.lookup("Lima"), BTreeSet::from(["1".to_string()]));
+
+        log.commit(&[Mutation::Delete { path: PathBuf::from("users/2.json") }]).unwrap();
+        let indexes = load_indexes(&users).unwrap();
+        assert!(indexes[1].lookup("a@x").is_empty());
+        assert!(indexes[0].lookup("Quito").is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_changes_are_appended_and_compacted() {
+        let dir = Path::new("./files/test_index_appends");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("1.json"), json!({"v": "a"}).to_string()).unwrap();
+        Index::build(dir, "v", "v").unwrap().save(dir).unwrap();
+        let path = index_path(dir, "v");
+
+        update(&dir.join("1.json"), Some(&json!({"v": "b"})));
+        update(&dir.join("2.json"), Some(&json!({"v": "b"})));
+        update(&dir.join("2.json"), None);
+
+        let lines = || fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(lines(), 5, "Each change should be one appended line");
+        let index = Index::load(&path).unwrap();
+        assert!(index.lookup("a").is_empty());
+        assert_eq!(index.lookup("b"), BTreeSet::from(["1".to_string()]));
+
+        index.save(dir).unwrap();
+        assert_eq!(lines(), 2);
+        assert_eq!(Index::load(&path).unwrap(), index);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_update_marks_index_stale() {
+        let dir = Path::new("./files/test_index_stale");
+        let _ = fs::remove_dir_all(dir);
+        let users = dir.join("users");
+        fs::create_dir_all(&users).unwrap();
+        Index::build(&users, "email", "email").unwrap().save(&users).unwrap();
+        // The index file can not be appended to
+        fs::remove_file(index_path(&users, "email")).unwrap();
+        fs::create_dir_all(index_path(&users, "email")).unwrap();
+
+        let mut log = Wal::open(dir).unwrap();
+        log.commit(&[Mutation::Put { path: PathBuf::from("users/1.json"), document: json!({"email": "a@x"}) }]).unwrap();
+
+        assert!(stale_path(&users, "email").exists(), "The write should go through and the index be marked stale");
+        fs::remove_dir_all(index_path(&users, "email")).unwrap();
+        Index::build(&users, "email", "email").unwrap().save(&users).unwrap();
+        assert!(!stale_path(&users, "email").exists(), "Building the index again should clear the mark");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_lookup_matches_query_types() {
+        let dir = Path::new("./files/test_index_types");
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("1.json"), json!({"v": 18}).to_string()).unwrap();
+        fs::write(dir.join("2.json"), json!({"v": "18"}).to_string()).unwrap();
+        fs::write(dir.join("3.json"), json!({"v": true}).to_string()).unwrap();
+        fs::write(dir.join("4.json"), json!({"v": [1]}).to_string()).unwrap();
+
+        let index = Index::build(dir, "v", "v").unwrap();
+
+        assert_eq!(index.lookup("18.0"), BTreeSet::from(["1".to_string()]));
+        assert_eq!(index.lookup("18"), BTreeSet::from(["1".to_string(), "2".to_string()]));
+        assert_eq!(index.lookup("true"), BTreeSet::from(["3".to_string()]));
+        assert_eq!(index.key_count(), 3, "Arrays should not be indexed");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_split_index_path() {
+        assert_eq!(split_index_path("/users/_indexes"), Some(("/users", "")));
+        assert_eq!(split_index_path("/users/_indexes/email"), Some(("/users", "email")));
+        assert_eq!(split_index_path("/users/_indexesx"), None);
+        assert_eq!(split_index_path("/users/1"), None);
+    }
+}
@@ -0,0 +1,232 @@
+use serde_json::Value;
+
+// Error produced when a JSON Patch (RFC 6902) document can not be applied
+#[derive(Debug, PartialEq)]
+pub struct PatchError {
+    pub index: usize,
+    pub status_code: u16,
+    pub message: String,
+}
+
+impl PatchError {
+    // Malformed operation: the patch document itself is wrong
+    fn invalid(index: usize, message: impl Into<String>) -> Self {
+        PatchError { index, status_code: 422, message: message.into() }
+    }
+
+    // Well formed operation that conflicts with the current document
+    fn conflict(index: usize, message: impl Into<String>) -> Self {
+        PatchError { index, status_code: 409, message: message.into() }
+    }
+}
+
+// Apply a list of operations to a copy of the document.
+// The original document is never touched, so a failing operation leaves nothing half-applied.
+pub fn apply(document: &Value, operations: &[Value]) -> Result<Value, PatchError> {
+    let mut patched = document.clone();
+
+    for (index, operation) in operations.iter().enumerate() {
+        apply_operation(&mut patched, index, operation)?;
+    }
+
+    Ok(patched)
+}
+
+fn apply_operation(doc: &mut Value, index: usize, operation: &Value) -> Result<(), PatchError> {
+    let op = operation.get("op")
+        .and_then(Value::as_str)
+        .ok_or_else(|| PatchError::invalid(index, "Operation is missing 'op'"))?;
+    let path = pointer_member(operation, "path", index)?;
+
+    match op {
+        "add" => {
+            let value = value_member(operation, index)?;
+            add(doc, path, value.clone(), index)
+        },
+        "remove" => remove(doc, path, index).map(|_| ()),
+        "replace" => {
+            let value = value_member(operation, index)?;
+            let target = doc.pointer_mut(path)
+                .ok_or_else(|| PatchError::conflict(index, format!("Path '{}' does not exist", path)))?;
+            *target = value.clone();
+            Ok(())
+        },
+        "move" => {
+            let from = pointer_member(operation, "from", index)?;
+            if path != from && path.starts_with(from) && path[from.len()..].starts_with('/') {
+                return Err(PatchError::invalid(index, format!("Can not move '{}' into one of its children", from)));
+            }
+            let value = remove(doc, from, index)?;
+            add(doc, path, value, index)
+        },
+        "copy" => {
+            let from = pointer_member(operation, "from", index)?;
+            let value = doc.pointer(from)
+                .cloned()
+                .ok_or_else(|| PatchError::conflict(index, format!("Path '{}' does not exist", from)))?;
+            add(doc, path, value, index)
+        },
+        "test" => {
+            let value = value_member(operation, index)?;
+            match doc.pointer(path) {
+                Some(current) if current == value => Ok(()),
+                Some(_) => Err(PatchError::conflict(index, format!("Test failed: value at '{}' does not match", path))),
+                None => Err(PatchError::conflict(index, format!("Test failed: path '{}' does not exist", path))),
+            }
+        },
+        other => Err(PatchError::invalid(index, format!("Unknown operation '{}'", other))),
+    }
+}
+
+// Read a JSON Pointer member ("path" or "from") of an operation and check its syntax
+fn pointer_member<'a>(operation: &'a Value, name: &str, index: usize) -> Result<&'a str, PatchError> {
+    let pointer = operation.get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| PatchError::invalid(index, format!("Operation is missing '{}'", name)))?;
+
+    if !pointer.is_empty() && !pointer.starts_with('/') {
+        return Err(PatchError::invalid(index, format!("Invalid JSON Pointer '{}'", pointer)));
+    }
+    Ok(pointer)
+}
+
+fn value_member(operation: &Value, index: usize) -> Result<&Value, PatchError> {
+    operation.get("value")
+        .ok_or_else(|| PatchError::invalid(index, "Operation is missing 'value'"))
+}
+
+// Split a pointer into the pointer of its parent and the unescaped last token
+fn split_pointer(path: &str) -> (&str, String) {
+    let position = path.rfind('/').unwrap_or(0);
+    let token = path[position + 1..].replace("~1", "/").replace("~0", "~");
+    (&path[..position], token)
+}
+
+// Parse an array index token, rejecting leading zeros and signs as the RFC requires
+fn array_index(token: &str) -> Option<usize> {
+    if token.is_empty() || (token.len() > 1 && token.starts_with('0')) || !token.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    token.parse().ok()
+}
+
+fn add(doc: &mut Value, path: &str, value: Value, index: usize) -> Result<(), PatchError> {
+    if path.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+
+    let (parent_path, token) = split_pointer(path);
+    let parent = doc.pointer_mut(parent_path)
+        .ok_or_else(|| PatchError::conflict(index, format!("Parent of '{}' does not exist", path)))?;
+
+    match parent {
+        Value::Object(map) => {
+            map.insert(token, value);
+            Ok(())
+        },
+        Value::Array(items) => {
+            if token == "-" {
+                items.push(value);
+                return Ok(());
+            }
+            match array_index(&token) {
+                Some(position) if position <= items.len() => {
+                    items.insert(position, value);
+                    Ok(())
+                },
+                _ => Err(PatchError::conflict(index, format!("Array index '{}' is out of bounds", token))),
+            }
+        },
+        _ => Err(PatchError::conflict(index, format!("Parent of '{}' is not a container", path))),
+    }
+}
+
+fn remove(doc: &mut Value, path: &str, index: usize) -> Result<Value, PatchError> {
+    if path.is_empty() {
+        return Err(PatchError::conflict(index, "Can not remove the whole document"));
+    }
+
+    let (parent_path, token) = split_pointer(path);
+    let missing = || PatchError::conflict(index, format!("Path '{}' does not exist", path));
+
+    match doc.pointer_mut(parent_path) {
+        Some(Value::Object(map)) => map.remove(&token).ok_or_else(missing),
+        Some(Value::Array(items)) => match array_index(&token) {
+            Some(position) if position < items.len() => Ok(items.remove(position)),
+            _ => Err(missing()),
+        },
+        _ => Err(missing()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn ops(value: Value) -> Vec<Value> {
+        value.as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn test_apply_all_operations() {
+        let document = json!({"name": "Ana", "tags": ["a", "b"], "address": {"city": "Cartago"}});
+        let patch = ops(json!([
+            {"op": "test", "path": "/name", "value": "Ana"},
+            {"op": "replace", "path": "/name", "value": "Ana Maria"},
+            {"op": "add", "path": "/tags/1", "value": "x"},
+            {"op": "add", "path": "/tags/-", "value": "z"},
+            {"op": "remove", "path": "/tags/0"},
+            {"op": "copy", "from": "/address/city", "path": "/city"},
+            {"op": "move", "from": "/address", "path": "/home"}
+        ]));
+
+        let patched = apply(&document, &patch).unwrap();
+
+        assert_eq!(patched, json!({
+            "name": "Ana Maria",
+            "tags": ["x", "b", "z"],
+            "city": "Cartago",
+            "home": {"city": "Cartago"}
+        }));
+    }
+
+    #[test]
+    fn test_failed_test_operation_reports_index() {
+        let document = json!({"key": 1});
+        let patch = ops(json!([
+            {"op": "replace", "path": "/key", "value": 2},
+            {"op": "test", "path": "/key", "value": 1}
+        ]));
+
+        let error = apply(&document, &patch).unwrap_err();
+
+        assert_eq!(error.index, 1);
+        assert_eq!(error.status_code, 409);
+        // The original document must stay untouched
+        assert_eq!(document, json!({"key": 1}));
+    }
+
+    #[test]
+    fn test_malformed_operation_is_unprocessable() {
+        let document = json!({});
+        let patch = ops(json!([{"op": "explode", "path": "/key"}]));
+
+        let error = apply(&document, &patch).unwrap_err();
+
+        assert_eq!(error.status_code, 422);
+        assert_eq!(error.index, 0);
+    }
+
+    #[test]
+    fn test_escaped_pointer_tokens() {
+        let document = json!({"a/b": 1, "c~d": 2});
+        let patch = ops(json!([
+            {"op": "remove", "path": "/a~1b"},
+            {"op": "replace", "path": "/c~0d", "value": 3}
+        ]));
+
+        assert_eq!(apply(&document, &patch).unwrap(), json!({"c~d": 3}));
+    }
+}
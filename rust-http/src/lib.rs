@@ -1,5 +1,30 @@
+pub mod config;
 pub mod methods;
+pub mod json_patch;
+pub mod pointer;
+pub mod collection;
+pub mod schema;
+pub mod history;
+pub mod index;
+pub mod search;
+pub mod trash;
+pub mod ttl;
+pub mod wal;
+pub mod events;
+pub mod websocket;
+pub mod subscriptions;
+pub mod webhooks;
+pub mod bulk;
+pub mod transaction;
+pub mod export;
+pub mod snapshot;
+pub mod static_files;
 pub mod request;
+pub mod body;
 pub mod response;
+pub mod range;
 pub mod client;
+pub mod http_client;
+pub mod proxy;
+pub mod cache;
 pub mod server;
\ No newline at end of file
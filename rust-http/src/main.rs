@@ -1,15 +1,47 @@
 use std::sync::{Arc, Mutex};
 use std::env;
+use std::io;
+use std::path::Path;
+use std::process;
 use log::error;
+#[allow(clippy::single_component_path_imports)]
 use env_logger;
+use rust_http::config::Config;
+use rust_http::export::{export_documents, import_documents, ImportMode};
 use rust_http::server::Server;
+use rust_http::snapshot::{self, SnapshotFormat, SNAPSHOT_DIR};
+use rust_http::wal::Wal;
+
+// Data directory used by the offline subcommands when none is given
+const DEFAULT_DATA_DIR: &str = "./files";
+
+const USAGE: &str = "Usage: rust-http [COMMAND]
+  export [DATA_DIR]                                       write every document as NDJSON to stdout
+  import [--mode skip|overwrite|fail] [DATA_DIR]          read NDJSON documents from stdin
+  snapshot create [--format tar|zstd] [--name NAME] [DATA_DIR]
+  snapshot list
+  snapshot restore NAME [DATA_DIR]
+Without a command the server is started.";
 
 fn main() {
     // Initialize logger
     env_logger::init();
 
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("export") => process::exit(run_export(&args[1..])),
+        Some("import") => process::exit(run_import(&args[1..])),
+        Some("snapshot") => process::exit(run_snapshot(&args[1..])),
+        Some(other) => {
+            eprintln!("Unknown command '{}'", other);
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        },
+        None => {},
+    }
+
     // Use Arc and Mutex to share the server across threads
-    let server = Arc::new(Mutex::new(Server::new()));
+    let server = Arc::new(Mutex::new(Server::with_config(Config::from_env())));
 
     println!("Current working directory: {:?}", env::current_dir().unwrap());
 
@@ -17,3 +49,118 @@ fn main() {
         error!("Server error: {}", e);
     }
 }
+
+// `rust-http export [DATA_DIR]`: write every document as NDJSON to stdout
+fn run_export(args: &[String]) -> i32 {
+    let data_dir = args.first().map(String::as_str).unwrap_or(DEFAULT_DATA_DIR);
+
+    match export_documents(Path::new(data_dir), &mut io::stdout().lock()) {
+        Ok(exported) => {
+            eprintln!("Exported {} documents", exported);
+            0
+        },
+        Err(e) => {
+            eprintln!("Failed to export documents: {}", e);
+            1
+        },
+    }
+}
+
+// `rust-http import [--mode skip|overwrite|fail] [DATA_DIR]`: read NDJSON from stdin
+fn run_import(args: &[String]) -> i32 {
+    let mut mode = ImportMode::Skip;
+    let mut data_dir = DEFAULT_DATA_DIR;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--mode" {
+            match args.next().and_then(|m| ImportMode::parse(m)) {
+                Some(parsed) => mode = parsed,
+                None => {
+                    eprintln!("Invalid mode: must be skip, overwrite or fail");
+                    return 2;
+                },
+            }
+        } else {
+            data_dir = arg;
+        }
+    }
+
+    let result = Wal::open(Path::new(data_dir))
+        .map_err(Into::into)
+        .and_then(|mut log| import_documents(&mut log, io::stdin().lock(), mode));
+    match result {
+        Ok(summary) => {
+            eprintln!("Imported {} documents, skipped {}", summary.imported, summary.skipped);
+            0
+        },
+        Err(e) => {
+            eprintln!("Failed to import documents: {}", e);
+            1
+        },
+    }
+}
+
+// `rust-http snapshot create|list|restore`, snapshots are kept in `./snapshots`
+fn run_snapshot(args: &[String]) -> i32 {
+    let snapshot_dir = Path::new(SNAPSHOT_DIR);
+    let mut format = SnapshotFormat::TarZstd;
+    let mut name = None;
+    let mut positional = Vec::new();
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--format" => match rest.next().and_then(|f| SnapshotFormat::parse(f)) {
+                Some(parsed) => format = parsed,
+                None => {
+                    eprintln!("Invalid format: must be tar or zstd");
+                    return 2;
+                },
+            },
+            "--name" => name = rest.next().map(String::as_str),
+            _ => positional.push(arg.as_str()),
+        }
+    }
+
+    let result = match args.first().map(String::as_str) {
+        Some("create") => {
+            let data_dir = positional.first().copied().unwrap_or(DEFAULT_DATA_DIR);
+            Wal::open(Path::new(data_dir))
+                .and_then(|log| snapshot::create_snapshot(&log, snapshot_dir, name, format))
+                .map(|snapshot| println!("{}", snapshot.path.display()))
+        },
+        Some("list") => snapshot::list_snapshots(snapshot_dir).map(|snapshots| {
+            for snapshot in snapshots {
+                println!("{}", snapshot.to_json());
+            }
+        }),
+        Some("restore") => {
+            let Some(name) = positional.first() else {
+                eprintln!("{}", USAGE);
+                return 2;
+            };
+            let data_dir = positional.get(1).copied().unwrap_or(DEFAULT_DATA_DIR);
+            match snapshot::find_snapshot(snapshot_dir, name) {
+                Ok(Some(found)) => Wal::open(Path::new(data_dir))
+                    .and_then(|mut log| snapshot::restore_snapshot(&mut log, &found))
+                    .map(|_| eprintln!("Restored snapshot {}", found.name)),
+                Ok(None) => {
+                    eprintln!("Snapshot {} not found", name);
+                    return 1;
+                },
+                Err(e) => Err(e),
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        },
+    };
+
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Snapshot command failed: {}", e);
+            1
+        },
+    }
+}
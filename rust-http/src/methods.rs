@@ -1,23 +1,103 @@
 use std::fs;
+use std::io;
 use std::path::Path;
+use uuid::Uuid;
 use serde_json::Value;
 use std::collections::HashMap;
+use crate::config::Config;
 use crate::response::HttpResponse;
+use crate::json_patch;
+use crate::pointer;
+use crate::collection;
+use crate::events::{self, ChangeKind};
+use crate::schema;
+use crate::history;
+use crate::index;
+use crate::trash;
+use crate::ttl;
+use crate::webhooks;
+use crate::wal::{self, Mutation};
+
+// Per-request information the handlers need besides the path and the body
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    pub config: Config,
+    pub session_id: String,
+    // Expiry asked for through `X-TTL` or `Expires`, as Unix seconds
+    pub expires_at: Option<u64>,
+}
+
+// Path suffix of the endpoint that restores a soft-deleted document
+const UNDELETE_SUFFIX: &str = "/_undelete";
+
+// How `store_document` treats a file that already exists
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WriteMode {
+    CreateNew,
+    Overwrite,
+}
+
+// Whether a document ID reaches into the files the server keeps next to the documents.
+// Names starting with `_` hold schemas, indexes, expiries and webhooks with their secrets,
+// so the document endpoints never read or write them.
+pub(crate) fn is_reserved_id(id: &str) -> bool {
+    id.split('/').any(|segment| segment.starts_with('_'))
+}
+
+pub(crate) fn reserved_id_response() -> HttpResponse {
+    error_response(400, "Invalid document ID: names starting with '_' are reserved")
+}
 
 // Function to handle GET requests
-pub fn handle_get(id: &str) -> HttpResponse {
+// A path naming a directory instead of a document lists the collection
+pub fn handle_get(id: &str, query: &HashMap<String, String>) -> HttpResponse {
     println!("Handling GET request for user with ID: {}", id);
     
+    // Historial de revisiones: `/users/420/_history`
+    if let Some(document_id) = id.strip_suffix(&format!("/{}", history::HISTORY_DIR)) {
+        return handle_get_history(document_id);
+    }
+
+    // Índices secundarios de la colección: `/users/_indexes`
+    if let Some((collection, name)) = index::split_index_path(id) {
+        return index::handle_index_request("GET", collection, name, None);
+    }
+
+    // Webhooks de la colección: `/users/_webhooks`
+    if let Some((collection, webhook_id)) = webhooks::split_webhook_path(id) {
+        return webhooks::handle_webhook_request("GET", collection, webhook_id, None);
+    }
+
+    // Los archivos reservados no son documentos
+    if is_reserved_id(id) {
+        return error_response(404, "File not found");
+    }
+
     // Construir la ruta del archivo dentro de la carpeta `files`
     let file_path = format!("./files/{}.json", id);
+    let dir_path = format!("./files/{}", id);
 
-    // Verificar si el archivo existe
-    if Path::new(&file_path).exists() {
+    // Lectura de una revisión anterior: `/users/420?rev=3`
+    if let Some(revision) = query.get("rev") {
+        return handle_get_revision(&file_path, revision);
+    }
+
+    // Verificar si el archivo existe y no ha expirado
+    if Path::new(&file_path).exists() && !ttl::is_expired(Path::new(&file_path)) {
         // Intentar leer el contenido del archivo
         match fs::read_to_string(&file_path) {
             Ok(contents) => {
                 let mut headers = HashMap::new();
                 headers.insert("Content-Type".to_string(), "application/json".to_string());
+                if let Ok(Some(expires_at)) = ttl::expires_at(Path::new(&file_path)) {
+                    headers.insert("Expires".to_string(), ttl::format_http_date(expires_at));
+                }
+
+                // Proyección de campos: `/users/420?fields=name,email`
+                let contents = match (query.get("fields"), serde_json::from_str::<Value>(&contents)) {
+                    (Some(fields), Ok(document)) => pointer::project(&document, &pointer::parse_fields(fields)).to_string(),
+                    _ => contents,
+                };
                 HttpResponse::new(200, headers, Some(contents))
             },
             Err(e) => {
@@ -28,6 +108,14 @@ pub fn handle_get(id: &str) -> HttpResponse {
                 }).to_string()))
             },
         }
+    } else if Path::new(&dir_path).is_dir() {
+        collection::list_collection(id, Path::new(&dir_path), query)
+    } else if Path::new(&file_path).exists() {
+        // Documento expirado que el barrido aún no ha eliminado
+        error_response(404, "File not found")
+    } else if let Some((document_id, node)) = pointer::resolve(id) {
+        // Un nodo dentro del documento: `/users/420/address/city`
+        handle_get_node(&document_id, &node)
     } else {
         HttpResponse::new(404, HashMap::new(), Some(serde_json::json!({
             "status_code": 404,
@@ -37,9 +125,35 @@ pub fn handle_get(id: &str) -> HttpResponse {
 }
 
 // Function to handle POST requests
-pub fn handle_post(id: &str, json_body: Option<&serde_json::Value>) -> HttpResponse {
+// Posting to a collection directory stores the body under a generated ID, posting to an
+// explicit path creates that document and fails with 409 if it already exists
+pub fn handle_post(id: &str, json_body: Option<&serde_json::Value>, ctx: &Context) -> HttpResponse {
     println!("Handling POST request for user with ID: {}", id);
 
+    // Los índices sólo se declaran con PUT
+    if let Some((collection, name)) = index::split_index_path(id) {
+        return index::handle_index_request("POST", collection, name, json_body);
+    }
+
+    // Registrar un webhook: `POST /users/_webhooks`
+    if let Some((collection, webhook_id)) = webhooks::split_webhook_path(id) {
+        return webhooks::handle_webhook_request("POST", collection, webhook_id, json_body);
+    }
+
+    // Restaurar una revisión: `POST /users/420/_history/3`
+    if let Some((document_id, revision)) = id.rsplit_once(&format!("/{}/", history::HISTORY_DIR)) {
+        return handle_restore_revision(document_id, revision, ctx);
+    }
+
+    // Recuperar un documento de la papelera: `POST /users/420/_undelete`
+    if let Some(document_id) = id.strip_suffix(UNDELETE_SUFFIX) {
+        return handle_undelete(document_id, ctx);
+    }
+
+    if is_reserved_id(id) {
+        return reserved_id_response();
+    }
+
     if let Some(data) = json_body {
         // Check if the JSON body is a valid object
         if !data.is_object() {
@@ -49,8 +163,16 @@ pub fn handle_post(id: &str, json_body: Option<&serde_json::Value>) -> HttpRespo
             }).to_string()));
         }
 
+        // Si la ruta es una colección, el servidor genera el ID del documento
+        let dir_path = format!("./files/{}", id);
+        let document_id = if Path::new(&dir_path).is_dir() {
+            format!("{}/{}", id.trim_end_matches('/'), Uuid::new_v4())
+        } else {
+            id.to_string()
+        };
+
         // Construir la ruta completa usando la carpeta 'files' y el ID como nombre del archivo
-        let file_path = format!("./files/{}.json", id);
+        let file_path = format!("./files/{}.json", document_id);
         let path_parent = Path::new(&file_path).parent();
 
         // Crear el directorio padre si no existe
@@ -64,26 +186,35 @@ pub fn handle_post(id: &str, json_body: Option<&serde_json::Value>) -> HttpRespo
             }
         }
 
-        // Convertir el cuerpo JSON a un string formateado y escribirlo en el archivo
-        match serde_json::to_string_pretty(data) {
-            Ok(json_string) => match fs::write(&file_path, json_string) {
-                Ok(_) => HttpResponse::new(201, HashMap::new(), Some(serde_json::json!({
+        // Validar contra el esquema de la colección antes de escribir
+        if let Err(response) = validate_against_schema(&file_path, data) {
+            return response;
+        }
+
+        // Sin `allow_overwrite` el archivo se crea de forma exclusiva para no reemplazar datos
+        let mode = if ctx.config.allow_overwrite { WriteMode::Overwrite } else { WriteMode::CreateNew };
+        let result = store_document(&file_path, data, mode, ctx);
+
+        match result {
+            Ok(_) => {
+                let location = format!("/{}", document_id.trim_start_matches('/'));
+                let mut headers = HashMap::new();
+                headers.insert("Location".to_string(), location.clone());
+                HttpResponse::new(201, headers, Some(serde_json::json!({
                     "status_code": 201,
-                    "message": "File created successfully"
-                }).to_string())),
-                Err(e) => {
-                    println!("Failed to create file: {}", e);
-                    HttpResponse::new(500, HashMap::new(), Some(serde_json::json!({
-                        "status_code": 500,
-                        "message": format!("Failed to create file: {}", e)
-                    }).to_string()))
-                },
+                    "message": "File created successfully",
+                    "location": location
+                }).to_string()))
             },
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                error_response(409, "File already exists")
+            },
+            Err(e) if wal::is_unavailable(&e) => storage_unavailable(&e),
             Err(e) => {
-                println!("Failed to serialize JSON: {}", e);
+                println!("Failed to create file: {}", e);
                 HttpResponse::new(500, HashMap::new(), Some(serde_json::json!({
                     "status_code": 500,
-                    "message": "Failed to serialize JSON"
+                    "message": format!("Failed to create file: {}", e)
                 }).to_string()))
             },
         }
@@ -96,12 +227,32 @@ pub fn handle_post(id: &str, json_body: Option<&serde_json::Value>) -> HttpRespo
 }
 
 // Function to handle PUT requests
-pub fn handle_put(id: &str, json_body: Option<&serde_json::Value>) -> HttpResponse {
+pub fn handle_put(id: &str, json_body: Option<&serde_json::Value>, ctx: &Context) -> HttpResponse {
     println!("Handling PUT request for user with ID: {}", id);
-    
+
+    // Declarar un índice secundario: `/users/_indexes/email`
+    if let Some((collection, name)) = index::split_index_path(id) {
+        return index::handle_index_request("PUT", collection, name, json_body);
+    }
+
+    if let Some((collection, webhook_id)) = webhooks::split_webhook_path(id) {
+        return webhooks::handle_webhook_request("PUT", collection, webhook_id, json_body);
+    }
+
+    if is_reserved_id(id) {
+        return reserved_id_response();
+    }
+
     // Construir la ruta del archivo dentro de la carpeta `files`
     let file_path = format!("./files/{}.json", id);
 
+    // Reemplazar sólo un nodo del documento: `/users/420/address/city`
+    if !Path::new(&file_path).exists() {
+        if let Some((document_id, node)) = pointer::resolve(id) {
+            return handle_put_node(&document_id, &node, json_body, ctx);
+        }
+    }
+
     // Verificar si el cuerpo JSON está presente
     if let Some(data) = json_body {
         // Verificar si el JSON es un objeto
@@ -112,28 +263,25 @@ pub fn handle_put(id: &str, json_body: Option<&serde_json::Value>) -> HttpRespon
             }).to_string()));
         }
 
-        // Verificar si el archivo existe antes de intentar actualizarlo
-        if Path::new(&file_path).exists() {
-            // Convertir el cuerpo JSON a un string formateado y escribirlo en el archivo
-            match serde_json::to_string_pretty(data) {
-                Ok(json_string) => match fs::write(&file_path, json_string) {
-                    Ok(_) => HttpResponse::new(200, HashMap::new(), Some(serde_json::json!({
-                        "status_code": 200,
-                        "message": "File updated successfully"
-                    }).to_string())),
-                    Err(e) => {
-                        println!("Failed to update file: {}", e);
-                        HttpResponse::new(500, HashMap::new(), Some(serde_json::json!({
-                            "status_code": 500,
-                            "message": format!("Failed to update file: {}", e)
-                        }).to_string()))
-                    },
-                },
+        // Verificar si el archivo existe antes de intentar actualizarlo; uno caducado ya no cuenta
+        if Path::new(&file_path).exists() && !ttl::is_expired(Path::new(&file_path)) {
+            // Validar contra el esquema de la colección antes de escribir
+            if let Err(response) = validate_against_schema(&file_path, data) {
+                return response;
+            }
+
+            // Escribir el documento y guardar la revisión
+            match store_document(&file_path, data, WriteMode::Overwrite, ctx) {
+                Ok(_) => HttpResponse::new(200, HashMap::new(), Some(serde_json::json!({
+                    "status_code": 200,
+                    "message": "File updated successfully"
+                }).to_string())),
+                Err(e) if wal::is_unavailable(&e) => storage_unavailable(&e),
                 Err(e) => {
-                    println!("Failed to serialize JSON: {}", e);
+                    println!("Failed to update file: {}", e);
                     HttpResponse::new(500, HashMap::new(), Some(serde_json::json!({
                         "status_code": 500,
-                        "message": "Failed to serialize JSON"
+                        "message": format!("Failed to update file: {}", e)
                     }).to_string()))
                 },
             }
@@ -152,20 +300,61 @@ pub fn handle_put(id: &str, json_body: Option<&serde_json::Value>) -> HttpRespon
 }
 
 // Function to handle DELETE requests
-pub fn handle_delete(id: &str) -> HttpResponse {
+// With `soft_delete` enabled the document is moved to the collection trash instead of removed
+pub fn handle_delete(id: &str, ctx: &Context) -> HttpResponse {
     println!("Handling DELETE request for user with ID: {}", id);
-    
+
+    // Eliminar un índice secundario
+    if let Some((collection, name)) = index::split_index_path(id) {
+        return index::handle_index_request("DELETE", collection, name, None);
+    }
+
+    if let Some((collection, webhook_id)) = webhooks::split_webhook_path(id) {
+        return webhooks::handle_webhook_request("DELETE", collection, webhook_id, None);
+    }
+
+    if is_reserved_id(id) {
+        return reserved_id_response();
+    }
+
     // Construye la ruta del archivo dentro de la carpeta `files`
     let file_path = format!("./files/{}.json", id);
 
+    // Eliminar sólo un nodo del documento
+    if !Path::new(&file_path).exists() {
+        if let Some((document_id, node)) = pointer::resolve(id) {
+            return handle_delete_node(&document_id, &node, ctx);
+        }
+    }
+
     // Verifica si el archivo existe
     if Path::new(&file_path).exists() {
-        // Intenta eliminar el archivo
-        match fs::remove_file(&file_path) {
-            Ok(_) => HttpResponse::new(200, HashMap::new(), Some(serde_json::json!({
-                "status_code": 200,
-                "message": "File deleted successfully"
-            }).to_string())),
+        // Se publica con el log aún tomado, para que los eventos salgan en el orden de los commits
+        let mut log = match wal::shared() {
+            Ok(log) => log,
+            Err(e) => return storage_unavailable(&e),
+        };
+        // Intenta eliminar el archivo, o moverlo a la papelera
+        let result = if ctx.config.soft_delete {
+            trash::move_to_trash(&mut log, Path::new(&file_path), &ctx.session_id)
+        } else {
+            let mut mutations = vec![Mutation::Delete { path: Path::new(&file_path).to_path_buf() }];
+            mutations.extend(ttl::clear_mutation(Path::new(&file_path)));
+            log.commit_store(mutations).map(|_| ())
+        };
+        if result.is_ok() {
+            events::publish(ChangeKind::Delete, Path::new(&file_path), None, &ctx.session_id);
+        }
+        drop(log);
+
+        match result {
+            Ok(_) => {
+                HttpResponse::new(200, HashMap::new(), Some(serde_json::json!({
+                    "status_code": 200,
+                    "message": "File deleted successfully"
+                }).to_string()))
+            },
+            Err(e) if wal::is_unavailable(&e) => storage_unavailable(&e),
             Err(e) => {
                 println!("Failed to delete file: {}", e);
                 HttpResponse::new(500, HashMap::new(), Some(serde_json::json!({
@@ -183,99 +372,420 @@ pub fn handle_delete(id: &str) -> HttpResponse {
 }
 
 // Function to handle PATCH requests
-pub fn handle_patch(id: &str, json_body: Option<&Value>) -> HttpResponse {
+// The Content-Type selects how the body is applied: `application/json-patch+json` is a list of
+// RFC 6902 operations, while plain JSON or merge patch bodies replace top-level keys
+pub fn handle_patch(id: &str, json_body: Option<&Value>, content_type: Option<&str>, ctx: &Context) -> HttpResponse {
     println!("Handling PATCH request for user with ID: {}", id);
 
+    if let Some((collection, name)) = index::split_index_path(id) {
+        return index::handle_index_request("PATCH", collection, name, json_body);
+    }
+
+    if let Some((collection, webhook_id)) = webhooks::split_webhook_path(id) {
+        return webhooks::handle_webhook_request("PATCH", collection, webhook_id, json_body);
+    }
+
+    if is_reserved_id(id) {
+        return reserved_id_response();
+    }
+
     // Construir la ruta del archivo dentro de la carpeta `files`
     let file_path = format!("./files/{}.json", id);
 
-    
     // Verificar si el cuerpo JSON está presente
-    if let Some(data) = json_body {
-        // Verificar si el archivo existe antes de intentar actualizarlo
-        if Path::new(&file_path).exists() {
-            // Leer el contenido existente del archivo
-            match fs::read_to_string(&file_path) {
-                Ok(existing_content) => {
-                    // Intentar parsear el contenido existente como JSON
-                    let mut existing_json: Value = match serde_json::from_str(&existing_content) {
-                        Ok(json) => json,
-                        Err(e) => {
-                            println!("Failed to parse existing JSON: {}", e);
-                            return HttpResponse::new(500, HashMap::new(), Some(serde_json::json!({
-                                "status_code": 500,
-                                "message": "Failed to parse existing file"
-                            }).to_string()));
-                        }
-                    };
-
-                    // Verificar si el JSON existente y el patch son objetos
-                    if let (Value::Object(ref mut obj), Value::Object(ref patch)) = (&mut existing_json, data) {
-                        // Verificar si todas las claves del patch existen en el objeto original
-                        for key in patch.keys() {
-                            if !obj.contains_key(key) {
-                                return HttpResponse::new(400, HashMap::new(), Some(serde_json::json!({
-                                    "status_code": 400,
-                                    "message": format!("Key '{}' does not exist in the original JSON", key)
-                                }).to_string()));
-                            }
-                        }
-
-                        // Extender el JSON original solo si todas las claves existen
-                        obj.extend(patch.clone());
-
-                        // Convertir el JSON actualizado a string y escribirlo en el archivo
-                        match serde_json::to_string_pretty(&existing_json) {
-                            Ok(json_string) => match fs::write(&file_path, json_string) {
-                                Ok(_) => HttpResponse::new(200, HashMap::new(), Some(serde_json::json!({
-                                    "status_code": 200,
-                                    "message": "File patched successfully"
-                                }).to_string())),
-                                Err(e) => {
-                                    println!("Failed to write updated file: {}", e);
-                                    HttpResponse::new(500, HashMap::new(), Some(serde_json::json!({
-                                        "status_code": 500,
-                                        "message": "Failed to patch file"
-                                    }).to_string()))
-                                },
-                            },
-                            Err(e) => {
-                                println!("Failed to serialize updated JSON: {}", e);
-                                HttpResponse::new(400, HashMap::new(), Some(serde_json::json!({
-                                    "status_code": 400,
-                                    "message": "Invalid JSON data"
-                                }).to_string()))
-                            },
-                        }
-                    } else {
-                        HttpResponse::new(400, HashMap::new(), Some(serde_json::json!({
-                            "status_code": 400,
-                            "message": "Existing data and patch must be JSON objects"
-                        }).to_string()))
-                    }
-                },
-                Err(e) => {
-                    println!("Failed to read file: {}", e);
-                    HttpResponse::new(500, HashMap::new(), Some(serde_json::json!({
-                        "status_code": 500,
-                        "message": "Failed to read file"
-                    }).to_string()))
-                },
+    let data = match json_body {
+        Some(data) => data,
+        None => return error_response(400, "Missing JSON body"),
+    };
+
+    // Elegir el tipo de patch según el Content-Type
+    let media_type = content_type.map(|value| value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase());
+    let is_json_patch = match media_type.as_deref() {
+        None | Some("application/json") | Some("application/merge-patch+json") => false,
+        Some("application/json-patch+json") => true,
+        Some(other) => return error_response(415, &format!("Unsupported patch format '{}'", other)),
+    };
+
+    // Verificar si el archivo existe antes de intentar actualizarlo; uno caducado ya no cuenta
+    if !Path::new(&file_path).exists() || ttl::is_expired(Path::new(&file_path)) {
+        return error_response(404, "File not found");
+    }
+
+    // Leer el contenido existente del archivo
+    let existing_content = match fs::read_to_string(&file_path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            println!("Failed to read file: {}", e);
+            return error_response(500, "Failed to read file");
+        },
+    };
+
+    // Intentar parsear el contenido existente como JSON
+    let existing_json: Value = match serde_json::from_str(&existing_content) {
+        Ok(json) => json,
+        Err(e) => {
+            println!("Failed to parse existing JSON: {}", e);
+            return error_response(500, "Failed to parse existing file");
+        }
+    };
+
+    // El documento completo se calcula en memoria antes de escribir nada
+    let patched = if is_json_patch {
+        apply_json_patch(&existing_json, data)
+    } else {
+        apply_merge_patch(existing_json, data)
+    };
+    let patched = match patched {
+        Ok(patched) => patched,
+        Err(response) => return response,
+    };
+
+    // Validar el resultado contra el esquema de la colección antes de escribir
+    if let Err(response) = validate_against_schema(&file_path, &patched) {
+        return response;
+    }
+
+    // Escribir el documento actualizado y guardar la revisión
+    match store_document(&file_path, &patched, WriteMode::Overwrite, ctx) {
+        Ok(_) => HttpResponse::new(200, HashMap::new(), Some(serde_json::json!({
+            "status_code": 200,
+            "message": "File patched successfully"
+        }).to_string())),
+        Err(e) if wal::is_unavailable(&e) => storage_unavailable(&e),
+        Err(e) => {
+            println!("Failed to write updated file: {}", e);
+            error_response(500, "Failed to patch file")
+        },
+    }
+}
+
+// Merge the top-level keys of the patch into the document; every key must already exist
+pub(crate) fn apply_merge_patch(mut existing_json: Value, data: &Value) -> Result<Value, HttpResponse> {
+    // Verificar si el JSON existente y el patch son objetos
+    if let (Value::Object(ref mut obj), Value::Object(ref patch)) = (&mut existing_json, data) {
+        // Verificar si todas las claves del patch existen en el objeto original
+        for key in patch.keys() {
+            if !obj.contains_key(key) {
+                return Err(error_response(400, &format!("Key '{}' does not exist in the original JSON", key)));
             }
-        } else {
-            HttpResponse::new(404, HashMap::new(), Some(serde_json::json!({
-                "status_code": 404,
-                "message": "File not found"
-            }).to_string()))
         }
+
+        // Extender el JSON original solo si todas las claves existen
+        obj.extend(patch.clone());
+        Ok(existing_json)
     } else {
-        HttpResponse::new(400, HashMap::new(), Some(serde_json::json!({
-            "status_code": 400,
-            "message": "Missing JSON body"
+        Err(error_response(400, "Existing data and patch must be JSON objects"))
+    }
+}
+
+// Apply an RFC 6902 operation list; failures point at the index of the failing operation
+fn apply_json_patch(existing_json: &Value, data: &Value) -> Result<Value, HttpResponse> {
+    let operations = match data.as_array() {
+        Some(operations) => operations,
+        None => return Err(error_response(422, "JSON Patch document must be an array of operations")),
+    };
+
+    let patched = json_patch::apply(existing_json, operations).map_err(|e| {
+        HttpResponse::new(e.status_code, HashMap::new(), Some(serde_json::json!({
+            "status_code": e.status_code,
+            "message": e.message,
+            "operation": e.index,
+            "op": operations[e.index].get("op"),
+            "path": operations[e.index].get("path")
         }).to_string()))
+    })?;
+
+    // Un parche sobre la raíz no puede dejar algo que POST y PUT rechazarían
+    if !patched.is_object() {
+        return Err(error_response(422, "JSON Patch result must be a JSON object"));
+    }
+    Ok(patched)
+}
+
+// Return one node of a document addressed by a JSON Pointer
+fn handle_get_node(document_id: &str, node: &str) -> HttpResponse {
+    let file_path = format!("./files/{}.json", document_id);
+    if ttl::is_expired(Path::new(&file_path)) {
+        return error_response(404, "File not found");
+    }
+    let document = match read_document(&file_path) {
+        Ok(document) => document,
+        Err(response) => return response,
+    };
+
+    match document.pointer(node) {
+        Some(value) => {
+            let mut headers = HashMap::new();
+            headers.insert("Content-Type".to_string(), "application/json".to_string());
+            HttpResponse::new(200, headers, Some(value.to_string()))
+        },
+        None => error_response(404, "Field not found"),
     }
 }
 
+// Replace or add one node of a document; the rest of the file is kept as it is
+fn handle_put_node(document_id: &str, node: &str, json_body: Option<&Value>, ctx: &Context) -> HttpResponse {
+    let file_path = format!("./files/{}.json", document_id);
+    let value = match json_body {
+        Some(value) => value,
+        None => return error_response(400, "Missing JSON body"),
+    };
+    if ttl::is_expired(Path::new(&file_path)) {
+        return error_response(404, "File not found");
+    }
+    let document = match read_document(&file_path) {
+        Ok(document) => document,
+        Err(response) => return response,
+    };
+
+    let updated = match pointer::set(&document, node, value) {
+        Ok(updated) => updated,
+        Err(e) => return error_response(e.status_code, &e.message),
+    };
+    update_node(&file_path, &updated, "Field updated successfully", ctx)
+}
+
+// Remove one node of a document
+fn handle_delete_node(document_id: &str, node: &str, ctx: &Context) -> HttpResponse {
+    let file_path = format!("./files/{}.json", document_id);
+    if ttl::is_expired(Path::new(&file_path)) {
+        return error_response(404, "File not found");
+    }
+    let document = match read_document(&file_path) {
+        Ok(document) => document,
+        Err(response) => return response,
+    };
+    if document.pointer(node).is_none() {
+        return error_response(404, "Field not found");
+    }
+
+    let updated = match pointer::remove(&document, node) {
+        Ok(updated) => updated,
+        Err(e) => return error_response(e.status_code, &e.message),
+    };
+    update_node(&file_path, &updated, "Field deleted successfully", ctx)
+}
+
+fn update_node(file_path: &str, updated: &Value, message: &str, ctx: &Context) -> HttpResponse {
+    if let Err(response) = validate_against_schema(file_path, updated) {
+        return response;
+    }
+
+    match store_document(file_path, updated, WriteMode::Overwrite, ctx) {
+        Ok(_) => HttpResponse::new(200, HashMap::new(), Some(serde_json::json!({
+            "status_code": 200,
+            "message": message
+        }).to_string())),
+        Err(e) if wal::is_unavailable(&e) => storage_unavailable(&e),
+        Err(e) => {
+            println!("Failed to update file: {}", e);
+            error_response(500, "Failed to update file")
+        },
+    }
+}
+
+// Read and parse a stored document
+fn read_document(file_path: &str) -> Result<Value, HttpResponse> {
+    let contents = fs::read_to_string(file_path).map_err(|e| {
+        println!("Failed to read file: {}", e);
+        error_response(500, "Failed to read file")
+    })?;
+    serde_json::from_str(&contents).map_err(|e| {
+        println!("Failed to parse existing JSON: {}", e);
+        error_response(500, "Failed to parse existing file")
+    })
+}
+
+// List the revisions kept for a document
+fn handle_get_history(id: &str) -> HttpResponse {
+    let file_path = format!("./files/{}.json", id);
+
+    match history::list(Path::new(&file_path)) {
+        Ok(entries) if entries.is_empty() && !Path::new(&file_path).exists() => error_response(404, "File not found"),
+        Ok(entries) => {
+            let mut headers = HashMap::new();
+            headers.insert("Content-Type".to_string(), "application/json".to_string());
+            HttpResponse::new(200, headers, Some(Value::Array(entries).to_string()))
+        },
+        Err(e) => {
+            println!("Failed to read history: {}", e);
+            error_response(500, "Failed to read history")
+        },
+    }
+}
+
+// Return the document as it was at a given revision
+fn handle_get_revision(file_path: &str, revision: &str) -> HttpResponse {
+    let revision: u64 = match revision.parse() {
+        Ok(revision) => revision,
+        Err(_) => return error_response(400, "Invalid revision number"),
+    };
+
+    match history::read_revision(Path::new(file_path), revision) {
+        Ok(Some(entry)) => {
+            let mut headers = HashMap::new();
+            headers.insert("Content-Type".to_string(), "application/json".to_string());
+            HttpResponse::new(200, headers, Some(entry["document"].to_string()))
+        },
+        Ok(None) => error_response(404, "Revision not found"),
+        Err(e) => {
+            println!("Failed to read revision: {}", e);
+            error_response(500, "Failed to read revision")
+        },
+    }
+}
+
+// Write an old revision back as the current document, recorded as a new revision
+fn handle_restore_revision(id: &str, revision: &str, ctx: &Context) -> HttpResponse {
+    let file_path = format!("./files/{}.json", id);
+    let revision: u64 = match revision.parse() {
+        Ok(revision) => revision,
+        Err(_) => return error_response(400, "Invalid revision number"),
+    };
+
+    let document = match history::read_revision(Path::new(&file_path), revision) {
+        Ok(Some(entry)) => entry["document"].clone(),
+        Ok(None) => return error_response(404, "Revision not found"),
+        Err(e) => {
+            println!("Failed to read revision: {}", e);
+            return error_response(500, "Failed to read revision");
+        },
+    };
+
+    // El esquema puede haber cambiado desde que se guardó la revisión
+    if let Err(response) = validate_against_schema(&file_path, &document) {
+        return response;
+    }
+
+    match store_document(&file_path, &document, WriteMode::Overwrite, ctx) {
+        Ok(_) => HttpResponse::new(200, HashMap::new(), Some(serde_json::json!({
+            "status_code": 200,
+            "message": format!("Revision {} restored", revision)
+        }).to_string())),
+        Err(e) if wal::is_unavailable(&e) => storage_unavailable(&e),
+        Err(e) => {
+            println!("Failed to restore revision: {}", e);
+            error_response(500, "Failed to restore revision")
+        },
+    }
+}
+
+// Bring a soft-deleted document back from the trash
+fn handle_undelete(id: &str, ctx: &Context) -> HttpResponse {
+    let file_path = format!("./files/{}.json", id);
+
+    let document = match trash::read_entry(Path::new(&file_path)) {
+        Ok(Some(entry)) => entry["document"].clone(),
+        Ok(None) => return error_response(404, "File not found in trash"),
+        Err(e) => {
+            println!("Failed to read trash entry: {}", e);
+            return error_response(500, "Failed to read trash entry");
+        },
+    };
+
+    // El documento vuelve y la entrada de la papelera desaparece en una sola operación del WAL
+    let restore = trash::restore_mutations(Path::new(&file_path), document.clone());
+    let mut log = match wal::shared() {
+        Ok(log) => log,
+        Err(e) => return storage_unavailable(&e),
+    };
+    match restore.and_then(|mutations| log.commit_store(mutations)) {
+        Ok(_) => {
+            events::publish(ChangeKind::Create, Path::new(&file_path), Some(&document), &ctx.session_id);
+            if let Err(e) = history::record(Path::new(&file_path), &document, &ctx.session_id, ctx.config.history_limit) {
+                println!("Failed to record revision: {}", e);
+            }
+            drop(log);
+            HttpResponse::new(200, HashMap::new(), Some(serde_json::json!({
+                "status_code": 200,
+                "message": "File restored successfully"
+            }).to_string()))
+        },
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            error_response(409, "A document with this ID already exists")
+        },
+        Err(e) if wal::is_unavailable(&e) => storage_unavailable(&e),
+        Err(e) => {
+            println!("Failed to restore file: {}", e);
+            error_response(500, "Failed to restore file")
+        },
+    }
+}
+
+// Single write path for documents: logs the write in the WAL, applies it and records the revision history
+fn store_document(file_path: &str, document: &Value, mode: WriteMode, ctx: &Context) -> io::Result<()> {
+    let path = Path::new(file_path).to_path_buf();
+
+    // Holding the log until the revision is recorded keeps concurrent writers from taking the same number
+    let mut log = wal::shared()?;
+    // An expired document the sweeper has not removed yet counts as absent
+    let exists = path.exists();
+    let is_new = !exists || ttl::is_expired(&path);
+    let expiry = ttl::expiry_mutation(&path, ctx.expires_at, is_new)?;
+
+    let mutation = match mode {
+        WriteMode::CreateNew if exists && is_new => Mutation::Put { path: path.clone(), document: document.clone() },
+        WriteMode::CreateNew => Mutation::Create { path: path.clone(), document: document.clone() },
+        WriteMode::Overwrite => {
+            // Never overwrite a document whose previous contents could not be kept
+            if !is_new {
+                history::ensure_baseline(&path, ctx.config.history_limit)?;
+            }
+            Mutation::Put { path: path.clone(), document: document.clone() }
+        },
+    };
+    // The expiry is written in the same commit as the document
+    log.commit_store([mutation].into_iter().chain(expiry).collect())?;
+    let kind = if is_new { ChangeKind::Create } else { ChangeKind::Update };
+    events::publish(kind, &path, Some(document), &ctx.session_id);
+
+    // The document is already written, a failing history must not fail the request
+    if let Err(e) = history::record(&path, document, &ctx.session_id, ctx.config.history_limit) {
+        println!("Failed to record revision: {}", e);
+    }
+    Ok(())
+}
+
+// Check a document against the `_schema.json` of its collection, if the collection has one
+pub(crate) fn validate_against_schema(file_path: &str, document: &Value) -> Result<(), HttpResponse> {
+    let schema = match schema::schema_for(Path::new(file_path)) {
+        Ok(Some(schema)) => schema,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            println!("Failed to load collection schema: {}", e);
+            return Err(error_response(500, "Failed to load collection schema"));
+        },
+    };
+
+    let violations = schema::validate(&schema, document);
+    if violations.is_empty() {
+        return Ok(());
+    }
+
+    let violations: Vec<Value> = violations.into_iter()
+        .map(|v| serde_json::json!({"path": v.path, "message": v.message}))
+        .collect();
+    Err(HttpResponse::new(422, HashMap::new(), Some(serde_json::json!({
+        "status_code": 422,
+        "message": "Document does not match the collection schema",
+        "violations": violations
+    }).to_string())))
+}
+
+// Build the JSON error body shared by every handler
+pub(crate) fn error_response(status_code: u16, message: &str) -> HttpResponse {
+    HttpResponse::new(status_code, HashMap::new(), Some(serde_json::json!({
+        "status_code": status_code,
+        "message": message
+    }).to_string()))
+}
+
+// Answer for a request that needs the write-ahead log while it can not be opened
+pub(crate) fn storage_unavailable(e: &io::Error) -> HttpResponse {
+    println!("{}", e);
+    error_response(503, "Storage unavailable, try again later")
+}
+
 // Function to handle unsupported methods
 pub fn handle_method_not_allowed() -> HttpResponse {
     HttpResponse::new(405, HashMap::new(), Some("Method not allowed".to_string()))
@@ -286,12 +796,18 @@ mod tests {
     // Import everything out of scope form tests
     use super::*;
     
+    // Remove the `./files/test_*` collection a test wrote its document to, together with
+    // the revisions and the search index kept next to it
+    fn remove_test_document(file_path: &str) {
+        let dir = Path::new(file_path).parent().expect("Test documents live in a collection");
+        fs::remove_dir_all(dir).expect("Failed to remove test collection");
+    }
 
     #[test]
     fn test_handle_get_successfully() {
         let file = "get";
 
-        let response = handle_get(file);
+        let response = handle_get(file, &HashMap::new());
         // Assert the response was successful
         assert_eq!(response.status_code, 200, "Status code should be 200");
 
@@ -299,7 +815,7 @@ mod tests {
         let file_contents = fs::read_to_string(&file_path).expect("Failed to read file");
 
         // Assert the file returned is the same
-        assert_eq!(response.body, Some(file_contents), "File contents should be the same");
+        assert_eq!(response.text().as_deref(), Some(file_contents.as_str()), "File contents should be the same");
 
     }
 
@@ -307,21 +823,30 @@ mod tests {
     fn test_handle_get_file_not_found() {
         let file = "notfound";
 
-        let response = handle_get(file);
+        let response = handle_get(file, &HashMap::new());
 
         // Assert the response gave 404
         assert_eq!(response.status_code, 404, "Status code should be 404");
     }
 
+    #[test]
+    fn test_handle_get_collection() {
+        let response = handle_get("/users", &HashMap::new());
+
+        assert_eq!(response.status_code, 200, "Status code should be 200");
+        let body: Value = serde_json::from_str(&response.text().unwrap()).expect("Failed to parse JSON");
+        assert!(body.as_array().unwrap().iter().any(|item| item["id"] == "420"), "Listing should include user 420");
+    }
+
     #[test]
     fn test_handle_post_successfully() {
-        let id = "test_post";
+        let id = "test_post/doc";
         let json_body = serde_json::json!({
             "key": "value",
             "number": 42
         });
 
-        let response = handle_post(id, Some(&json_body));
+        let response = handle_post(id, Some(&json_body), &Context::default());
 
         assert_eq!(response.status_code, 201, "Status code should be 201");
         
@@ -333,7 +858,7 @@ mod tests {
         assert_eq!(saved_json, json_body, "Saved JSON should match the input");
 
         // Clean up: remove the test file
-        fs::remove_file(file_path).expect("Failed to remove test file");
+        remove_test_document(&file_path);
     }
 
     #[test]
@@ -341,7 +866,7 @@ mod tests {
         let id = "test_invalid_json";
         let invalid_json = serde_json::Value::String("This is not a valid JSON object".to_string());
 
-        let response = handle_post(id, Some(&invalid_json));
+        let response = handle_post(id, Some(&invalid_json), &Context::default());
 
         assert_eq!(response.status_code, 400, "Status code should be 400");
     }
@@ -349,40 +874,81 @@ mod tests {
     #[test]
     fn test_handle_post_missing_json() {
         let id = "test_missing_json";
-        let response = handle_post(id, None);
+        let response = handle_post(id, None, &Context::default());
 
         assert_eq!(response.status_code, 400, "Status code should be 400");
     }
 
     #[test]
     fn test_handle_post_existing_file() {
-        let id = "test_existing_file";
+        let id = "test_existing_file/doc";
         let json_body = serde_json::json!({"key": "value"});
 
         // Create a file first
-        let _ = handle_post(id, Some(&json_body));
+        let _ = handle_post(id, Some(&json_body), &Context::default());
 
         // Try to create the same file again
-        let response = handle_post(id, Some(&json_body));
+        let response = handle_post(id, Some(&json_body), &Context::default());
 
-        assert_eq!(response.status_code, 201, "Status code should be 201");
+        assert_eq!(response.status_code, 409, "Status code should be 409");
         
         // Clean up: remove the test file
         let file_path = format!("./files/{}.json", id);
-        fs::remove_file(file_path).expect("Failed to remove test file");
+        remove_test_document(&file_path);
+    }
+
+    #[test]
+    fn test_handle_post_existing_file_with_overwrite() {
+        let id = "test_overwrite_file/doc";
+        let ctx = Context { config: Config { allow_overwrite: true, ..Config::default() }, ..Context::default() };
+
+        let _ = handle_post(id, Some(&serde_json::json!({"key": "old"})), &ctx);
+        let response = handle_post(id, Some(&serde_json::json!({"key": "new"})), &ctx);
+
+        assert_eq!(response.status_code, 201, "Status code should be 201");
+
+        let file_path = format!("./files/{}.json", id);
+        let file_contents = fs::read_to_string(&file_path).expect("Failed to read file");
+        let saved_json: Value = serde_json::from_str(&file_contents).expect("Failed to parse JSON");
+        assert_eq!(saved_json, serde_json::json!({"key": "new"}), "File should be overwritten");
+
+        // Clean up: remove the test file
+        remove_test_document(&file_path);
+    }
+
+    #[test]
+    fn test_handle_post_to_collection_generates_id() {
+        let collection = "test_post_collection";
+        fs::create_dir_all(format!("./files/{}", collection)).expect("Failed to create collection");
+        let json_body = serde_json::json!({"name": "Ana"});
+
+        let response = handle_post(collection, Some(&json_body), &Context::default());
+
+        assert_eq!(response.status_code, 201, "Status code should be 201");
+        let location = response.headers.get("Location").expect("Location header should be set");
+        let generated_id = location.strip_prefix("/test_post_collection/").expect("Location should point into the collection");
+        assert!(Uuid::parse_str(generated_id).is_ok(), "Generated ID should be a UUID");
+
+        let file_path = format!("./files/{}.json", location);
+        let file_contents = fs::read_to_string(&file_path).expect("Failed to read file");
+        let saved_json: Value = serde_json::from_str(&file_contents).expect("Failed to parse JSON");
+        assert_eq!(saved_json, json_body, "Saved JSON should match the input");
+
+        // Clean up: remove the test collection
+        fs::remove_dir_all(format!("./files/{}", collection)).expect("Failed to remove test collection");
     }
 
     #[test]
     fn test_handle_put_successfully() {
-        let id = "test_put_success";
+        let id = "test_put_success/doc";
         let initial_json = serde_json::json!({"key": "initial_value"});
         let updated_json = serde_json::json!({"key": "updated_value"});
 
         // Create a file first
-        let _ = handle_post(id, Some(&initial_json));
+        let _ = handle_post(id, Some(&initial_json), &Context::default());
 
         // Update the file
-        let response = handle_put(id, Some(&updated_json));
+        let response = handle_put(id, Some(&updated_json), &Context::default());
 
         assert_eq!(response.status_code, 200, "Status code should be 200");
 
@@ -395,7 +961,7 @@ mod tests {
         assert_eq!(saved_json, updated_json, "Saved JSON should match the updated input");
 
         // Clean up: remove the test file
-        fs::remove_file(file_path).expect("Failed to remove test file");
+        remove_test_document(&file_path);
     }
 
     #[test]
@@ -403,7 +969,7 @@ mod tests {
         let id = "test_put_invalid_json";
         let invalid_json = serde_json::Value::String("This is not a valid JSON object".to_string());
 
-        let response = handle_put(id, Some(&invalid_json));
+        let response = handle_put(id, Some(&invalid_json), &Context::default());
 
         assert_eq!(response.status_code, 400, "Status code should be 400");
     }
@@ -413,7 +979,7 @@ mod tests {
         let id = "test_put_not_found";
         let json_body = serde_json::json!({"key": "value"});
 
-        let response = handle_put(id, Some(&json_body));
+        let response = handle_put(id, Some(&json_body), &Context::default());
 
         assert_eq!(response.status_code, 404, "Status code should be 404");
     }
@@ -421,23 +987,23 @@ mod tests {
     #[test]
     fn test_handle_put_missing_json() {
         let id = "test_put_missing_json";
-        let response = handle_put(id, None);
+        let response = handle_put(id, None, &Context::default());
 
         assert_eq!(response.status_code, 400, "Status code should be 400");
-        assert!(response.body.unwrap().contains("Missing JSON body"), "Response should mention missing JSON body");
+        assert!(response.text().unwrap().contains("Missing JSON body"), "Response should mention missing JSON body");
     }
 
     #[test]
     fn test_handle_put_empty_json_object() {
-        let id = "test_put_empty_json";
+        let id = "test_put_empty_json/doc";
         let initial_json = serde_json::json!({"key": "value"});
         let empty_json = serde_json::json!({});
 
         // Create a file first
-        let _ = handle_post(id, Some(&initial_json));
+        let _ = handle_post(id, Some(&initial_json), &Context::default());
 
         // Update with empty JSON
-        let response = handle_put(id, Some(&empty_json));
+        let response = handle_put(id, Some(&empty_json), &Context::default());
 
         assert_eq!(response.status_code, 200, "Status code should be 200");
 
@@ -448,46 +1014,47 @@ mod tests {
         assert_eq!(saved_json, empty_json, "Saved JSON should be an empty object");
 
         // Clean up: remove the test file
-        fs::remove_file(file_path).expect("Failed to remove test file");
+        remove_test_document(&file_path);
     }
 
     #[test]
     fn test_handle_delete_successfully() {
-        let id = "test_delete";
+        let id = "test_delete/doc";
         let initial_json = serde_json::json!({"key": "value"});
 
         // Create a file first
-        let _ = handle_post(id, Some(&initial_json));
+        let _ = handle_post(id, Some(&initial_json), &Context::default());
 
         // Delete the file
-        let response = handle_delete(id);
+        let response = handle_delete(id, &Context::default());
 
         assert_eq!(response.status_code, 200, "Status code should be 200");
 
 
         let file_path = format!("./files/{}.json", id);
         assert!(!Path::new(&file_path).exists(), "File should not exist after deletion");
+        remove_test_document(&file_path);
     }
 
     #[test]
     fn test_handle_delete_file_not_found() {
         let id = "nonexistent_file";
-        let response = handle_delete(id);
+        let response = handle_delete(id, &Context::default());
 
         assert_eq!(response.status_code, 404, "Status code should be 404");
     }
 
     #[test]
     fn test_handle_patch_successfully() {
-        let id = "test_patch";
+        let id = "test_patch/doc";
         let initial_json = serde_json::json!({"key1": "value1", "key2": "value2"});
         let patch_json = serde_json::json!({"key2": "new_value2"});
 
         // Create a file first
-        let _ = handle_post(id, Some(&initial_json));
+        let _ = handle_post(id, Some(&initial_json), &Context::default());
 
         // Patch the file
-        let response = handle_patch(id, Some(&patch_json));
+        let response = handle_patch(id, Some(&patch_json), None, &Context::default());
 
         assert_eq!(response.status_code, 200, "Status code should be 200");
 
@@ -498,7 +1065,7 @@ mod tests {
         assert_eq!(saved_json, serde_json::json!({"key1": "value1", "key2": "new_value2"}), "Saved JSON should reflect the patch");
 
         // Clean up: remove the test file
-        fs::remove_file(file_path).expect("Failed to remove test file");
+        remove_test_document(&file_path);
     }
 
     #[test]
@@ -506,28 +1073,283 @@ mod tests {
         let id = "nonexistent_file";
         let patch_json = serde_json::json!({"key": "value"});
 
-        let response = handle_patch(id, Some(&patch_json));
+        let response = handle_patch(id, Some(&patch_json), None, &Context::default());
 
         assert_eq!(response.status_code, 404, "Status code should be 404");
     }
 
     #[test]
     fn test_handle_patch_invalid_json() {
-        let id = "test_patch_invalid";
+        let id = "test_patch_invalid/doc";
         let initial_json = serde_json::json!({"key": "value"});
         let invalid_json: Value = serde_json::from_str("{invalid_json}").unwrap_or(Value::Null);
 
         // Create a file first
-        let _ = handle_post(id, Some(&initial_json));
+        let _ = handle_post(id, Some(&initial_json), &Context::default());
 
         // Attempt to patch with invalid JSON
-        let response = handle_patch(id, Some(&invalid_json));
+        let response = handle_patch(id, Some(&invalid_json), None, &Context::default());
 
         assert_eq!(response.status_code, 400, "Status code should be 400");
 
         // Clean up: remove the test file
         let file_path = format!("./files/{}.json", id);
-        fs::remove_file(file_path).expect("Failed to remove test file");
+        remove_test_document(&file_path);
+    }
+
+    #[test]
+    fn test_handle_patch_json_patch_operations() {
+        let id = "test_json_patch/doc";
+        let initial_json = serde_json::json!({"name": "Ana", "tags": ["a"]});
+        let operations = serde_json::json!([
+            {"op": "replace", "path": "/name", "value": "Maria"},
+            {"op": "add", "path": "/tags/-", "value": "b"}
+        ]);
+
+        // Create a file first
+        let _ = handle_post(id, Some(&initial_json), &Context::default());
+
+        let response = handle_patch(id, Some(&operations), Some("application/json-patch+json"), &Context::default());
+
+        assert_eq!(response.status_code, 200, "Status code should be 200");
+
+        let file_path = format!("./files/{}.json", id);
+        let file_contents = fs::read_to_string(&file_path).expect("Failed to read file");
+        let saved_json: Value = serde_json::from_str(&file_contents).expect("Failed to parse JSON");
+        assert_eq!(saved_json, serde_json::json!({"name": "Maria", "tags": ["a", "b"]}));
+
+        // Replacing the root with something other than an object is rejected
+        let operations = serde_json::json!([{"op": "replace", "path": "", "value": "text"}]);
+        let response = handle_patch(id, Some(&operations), Some("application/json-patch+json"), &Context::default());
+        assert_eq!(response.status_code, 422, "Status code should be 422");
+        let file_contents = fs::read_to_string(&file_path).expect("Failed to read file");
+        assert_eq!(serde_json::from_str::<Value>(&file_contents).unwrap(), saved_json, "Nothing should be written");
+
+        // Clean up: remove the test file
+        remove_test_document(&file_path);
+    }
+
+    #[test]
+    fn test_handle_patch_json_patch_failed_test_writes_nothing() {
+        let id = "test_json_patch_atomic/doc";
+        let initial_json = serde_json::json!({"name": "Ana"});
+        let operations = serde_json::json!([
+            {"op": "replace", "path": "/name", "value": "Maria"},
+            {"op": "test", "path": "/name", "value": "Ana"}
+        ]);
+
+        // Create a file first
+        let _ = handle_post(id, Some(&initial_json), &Context::default());
+
+        let response = handle_patch(id, Some(&operations), Some("application/json-patch+json"), &Context::default());
+
+        assert_eq!(response.status_code, 409, "Status code should be 409");
+        let body: Value = serde_json::from_str(&response.text().unwrap()).unwrap();
+        assert_eq!(body["operation"], 1, "Error should point at the failing operation");
+
+        let file_path = format!("./files/{}.json", id);
+        let file_contents = fs::read_to_string(&file_path).expect("Failed to read file");
+        let saved_json: Value = serde_json::from_str(&file_contents).expect("Failed to parse JSON");
+        assert_eq!(saved_json, initial_json, "File should not change when a test fails");
+
+        // Clean up: remove the test file
+        remove_test_document(&file_path);
+    }
+
+    #[test]
+    fn test_handle_patch_unsupported_content_type() {
+        let id = "test_patch_unsupported";
+        let patch_json = serde_json::json!({"key": "value"});
+
+        let response = handle_patch(id, Some(&patch_json), Some("text/plain"), &Context::default());
+
+        assert_eq!(response.status_code, 415, "Status code should be 415");
+    }
+
+    #[test]
+    fn test_schema_validation_on_writes() {
+        let collection = "test_schema_collection";
+        let dir = format!("./files/{}", collection);
+        fs::create_dir_all(&dir).expect("Failed to create collection");
+        fs::write(format!("{}/_schema.json", dir), serde_json::json!({
+            "type": "object",
+            "required": ["name"],
+            "properties": {"name": {"type": "string"}, "age": {"type": "integer"}}
+        }).to_string()).expect("Failed to write schema");
+        let id = format!("{}/1", collection);
+
+        // POST with a missing required field
+        let response = handle_post(&id, Some(&serde_json::json!({"age": 3})), &Context::default());
+        assert_eq!(response.status_code, 422, "Status code should be 422");
+        let body: Value = serde_json::from_str(&response.text().unwrap()).unwrap();
+        assert_eq!(body["violations"][0]["path"], "/name");
+        assert!(!Path::new(&format!("./files/{}.json", id)).exists(), "Invalid document should not be written");
+
+        // Valid POST, then a PUT and a PATCH that break the schema
+        let response = handle_post(&id, Some(&serde_json::json!({"name": "Ana"})), &Context::default());
+        assert_eq!(response.status_code, 201, "Status code should be 201");
+        let response = handle_put(&id, Some(&serde_json::json!({"name": 5})), &Context::default());
+        assert_eq!(response.status_code, 422, "Status code should be 422");
+        let response = handle_patch(&id, Some(&serde_json::json!({"name": null})), None, &Context::default());
+        assert_eq!(response.status_code, 422, "Status code should be 422");
+
+        // The schema itself is not a document
+        let schema_id = format!("{}/_schema", collection);
+        assert_eq!(handle_get(&schema_id, &HashMap::new()).status_code, 404, "Status code should be 404");
+        let response = handle_put(&schema_id, Some(&serde_json::json!({"type": "object"})), &Context::default());
+        assert_eq!(response.status_code, 400, "Status code should be 400");
+        assert_eq!(handle_delete(&schema_id, &Context::default()).status_code, 400, "Status code should be 400");
+        assert!(Path::new(&format!("{}/_schema.json", dir)).exists(), "Schema should be kept");
+
+        // Clean up: remove the test collection
+        fs::remove_dir_all(dir).expect("Failed to remove test collection");
+    }
+
+    #[test]
+    fn test_revision_history_and_restore() {
+        let collection = "test_history_collection";
+        let id = format!("{}/doc", collection);
+        let ctx = Context {
+            config: Config { history_limit: 10, ..Config::default() },
+            session_id: "session-1".to_string(),
+            ..Context::default()
+        };
+
+        let _ = handle_post(&id, Some(&serde_json::json!({"v": 1})), &ctx);
+        let _ = handle_put(&id, Some(&serde_json::json!({"v": 2})), &ctx);
+        let _ = handle_patch(&id, Some(&serde_json::json!({"v": 3})), None, &ctx);
+
+        // Read an old revision
+        let query: HashMap<String, String> = [("rev".to_string(), "1".to_string())].into_iter().collect();
+        let response = handle_get(&id, &query);
+        assert_eq!(response.status_code, 200, "Status code should be 200");
+        assert_eq!(serde_json::from_str::<Value>(&response.text().unwrap()).unwrap(), serde_json::json!({"v": 1}));
+
+        // List the history
+        let response = handle_get(&format!("{}/_history", id), &HashMap::new());
+        let body: Value = serde_json::from_str(&response.text().unwrap()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 3, "Every write should keep a revision");
+        assert_eq!(body[2]["session_id"], "session-1");
+
+        // Restore revision 2, which becomes revision 4
+        let response = handle_post(&format!("{}/_history/2", id), None, &ctx);
+        assert_eq!(response.status_code, 200, "Status code should be 200");
+        let response = handle_get(&id, &HashMap::new());
+        assert_eq!(serde_json::from_str::<Value>(&response.text().unwrap()).unwrap(), serde_json::json!({"v": 2}));
+        assert_eq!(history::revisions(Path::new(&format!("./files/{}.json", id))).unwrap(), vec![1, 2, 3, 4]);
+
+        // A revision the current schema no longer accepts is not restored
+        fs::write(format!("./files/{}/_schema.json", collection), serde_json::json!({
+            "type": "object",
+            "properties": {"v": {"type": "string"}}
+        }).to_string()).expect("Failed to write schema");
+        let response = handle_post(&format!("{}/_history/1", id), None, &ctx);
+        assert_eq!(response.status_code, 422, "Status code should be 422");
+        assert_eq!(history::revisions(Path::new(&format!("./files/{}.json", id))).unwrap(), vec![1, 2, 3, 4]);
+
+        // Clean up: remove the test collection
+        fs::remove_dir_all(format!("./files/{}", collection)).expect("Failed to remove test collection");
+    }
+
+    #[test]
+    fn test_soft_delete_and_undelete() {
+        let collection = "test_soft_delete";
+        let id = format!("{}/doc", collection);
+        let ctx = Context {
+            config: Config { soft_delete: true, ..Config::default() },
+            session_id: "session-1".to_string(),
+            ..Context::default()
+        };
+        let _ = handle_post(&id, Some(&serde_json::json!({"key": "value"})), &ctx);
+
+        let response = handle_delete(&id, &ctx);
+        assert_eq!(response.status_code, 200, "Status code should be 200");
+
+        // Hidden from GET and from the collection listing
+        assert_eq!(handle_get(&id, &HashMap::new()).status_code, 404, "Deleted document should not be found");
+        let listing = handle_get(collection, &HashMap::new());
+        assert_eq!(listing.text().unwrap(), "[]", "Deleted document should not be listed");
+
+        // Undelete it
+        let response = handle_post(&format!("{}/_undelete", id), None, &ctx);
+        assert_eq!(response.status_code, 200, "Status code should be 200");
+        let response = handle_get(&id, &HashMap::new());
+        assert_eq!(serde_json::from_str::<Value>(&response.text().unwrap()).unwrap(), serde_json::json!({"key": "value"}));
+
+        // Nothing left in the trash
+        let response = handle_post(&format!("{}/_undelete", id), None, &ctx);
+        assert_eq!(response.status_code, 404, "Status code should be 404");
+
+        // Clean up: remove the test collection
+        fs::remove_dir_all(format!("./files/{}", collection)).expect("Failed to remove test collection");
+    }
+
+    #[test]
+    fn test_handle_sub_resources() {
+        let collection = "/test_sub_resource";
+        fs::create_dir_all(format!("./files/{}", collection)).expect("Failed to create test collection");
+        let id = format!("{}/420", collection);
+        let ctx = Context::default();
+        handle_post(&id, Some(&serde_json::json!({"name": "Ana", "email": "a@x", "address": {"city": "Lima"}})), &ctx);
+
+        // Proyección y lectura de un nodo
+        let query: HashMap<String, String> = [("fields".to_string(), "name,/address/city".to_string())].into_iter().collect();
+        let response = handle_get(&id, &query);
+        assert_eq!(serde_json::from_str::<Value>(&response.text().unwrap()).unwrap(), serde_json::json!({"name": "Ana", "address": {"city": "Lima"}}));
+        let response = handle_get(&format!("{}/address/city", id), &HashMap::new());
+        assert_eq!(response.text().unwrap(), "\"Lima\"");
+        assert_eq!(handle_get(&format!("{}/address/zip", id), &HashMap::new()).status_code, 404);
+
+        // Modificar y eliminar nodos sin tocar el resto del documento
+        let response = handle_put(&format!("{}/address/city", id), Some(&serde_json::json!("Quito")), &ctx);
+        assert_eq!(response.status_code, 200, "Status code should be 200");
+        let response = handle_delete(&format!("{}/email", id), &ctx);
+        assert_eq!(response.status_code, 200, "Status code should be 200");
+        assert_eq!(handle_delete(&format!("{}/email", id), &ctx).status_code, 404);
+
+        let response = handle_get(&id, &HashMap::new());
+        assert_eq!(serde_json::from_str::<Value>(&response.text().unwrap()).unwrap(), serde_json::json!({"name": "Ana", "address": {"city": "Quito"}}));
+
+        // Clean up: remove the test collection
+        fs::remove_dir_all(format!("./files/{}", collection)).expect("Failed to remove test collection");
+    }
+
+    #[test]
+    fn test_handle_expiring_documents() {
+        let collection = "test_ttl_collection";
+        fs::create_dir_all(format!("./files/{}", collection)).expect("Failed to create test collection");
+
+        // Un documento ya expirado queda oculto para GET y para el listado
+        let expired = Context { expires_at: Some(1), ..Context::default() };
+        let response = handle_post(&format!("{}/expired", collection), Some(&serde_json::json!({"v": 1})), &expired);
+        assert_eq!(response.status_code, 201, "Status code should be 201");
+        assert_eq!(handle_get(&format!("{}/expired", collection), &HashMap::new()).status_code, 404);
+
+        // Mientras el barrido no lo borre, cuenta como ausente para PATCH, PUT y POST
+        let response = handle_patch(&format!("{}/expired", collection), Some(&serde_json::json!({"v": 3})), None, &Context::default());
+        assert_eq!(response.status_code, 404, "Expired documents should not be patched");
+        let response = handle_put(&format!("{}/expired/v", collection), Some(&serde_json::json!(3)), &Context::default());
+        assert_eq!(response.status_code, 404, "Fields of expired documents should not be replaced");
+        let response = handle_delete(&format!("{}/expired/v", collection), &Context::default());
+        assert_eq!(response.status_code, 404, "Fields of expired documents should not be deleted");
+        let response = handle_put(&format!("{}/expired", collection), Some(&serde_json::json!({"v": 3})), &Context::default());
+        assert_eq!(response.status_code, 404, "Expired documents should not be replaced");
+        let response = handle_post(&format!("{}/expired", collection), Some(&serde_json::json!({"v": 3})), &Context::default());
+        assert_eq!(response.status_code, 201, "Expired documents should be created again");
+        assert_eq!(handle_get(&format!("{}/expired", collection), &HashMap::new()).status_code, 200, "The stale expiry should be gone");
+
+        // El resto hereda el TTL por defecto de la colección
+        fs::write(format!("./files/{}/{}", collection, ttl::TTL_FILE), r#"{"default_ttl": 3600}"#).unwrap();
+        let _ = handle_post(&format!("{}/fresh", collection), Some(&serde_json::json!({"v": 2})), &Context::default());
+        let response = handle_get(&format!("{}/fresh", collection), &HashMap::new());
+        assert_eq!(response.status_code, 200, "Status code should be 200");
+        assert!(response.headers.contains_key("Expires"), "Expiring documents should carry an Expires header");
+
+        let listing = handle_get(collection, &[("ids".to_string(), "true".to_string())].into_iter().collect());
+        assert_eq!(listing.text().unwrap(), r#"["expired","fresh"]"#);
+
+        // Clean up: remove the test collection
+        fs::remove_dir_all(format!("./files/{}", collection)).expect("Failed to remove test collection");
     }
 
     #[test]
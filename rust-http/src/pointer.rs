@@ -0,0 +1,112 @@
+use std::path::Path;
+use serde_json::{Map, Value};
+use crate::collection::field_value;
+use crate::json_patch::{self, PatchError};
+use crate::request::percent_decode;
+
+// Split a request path that reaches into a document into the document ID and a JSON Pointer:
+// `/users/420/address/city` is `/users/420` and `/address/city` when `users/420.json` exists.
+// The longest prefix naming a document wins. URL segments are percent-decoded and used as
+// pointer tokens, so `~0` and `~1` keep their JSON Pointer meaning. A `+` is a literal
+// plus in a path, not a space as in a query string.
+pub fn resolve(id: &str) -> Option<(String, String)> {
+    let segments: Vec<&str> = id.split('/').filter(|s| !s.is_empty()).collect();
+
+    (1..segments.len()).rev().find_map(|split| {
+        let document_id = format!("/{}", segments[..split].join("/"));
+        if !Path::new(&format!("./files/{}.json", document_id)).is_file() {
+            return None;
+        }
+        let pointer: String = segments[split..].iter()
+            .map(|segment| format!("/{}", percent_decode(&segment.replace('+', "%2B"))))
+            .collect();
+        Some((document_id, pointer))
+    })
+}
+
+// Keep only the listed fields of a document. Fields are top-level keys (`name`) or JSON
+// Pointers (`/address/city`), which keep their nesting in the result; missing fields are left out.
+pub fn project(document: &Value, fields: &[&str]) -> Value {
+    let mut projected = Value::Object(Map::new());
+
+    for field in fields {
+        let Some(value) = field_value(document, field) else {
+            continue;
+        };
+        if !field.starts_with('/') {
+            projected[*field] = value.clone();
+            continue;
+        }
+
+        // Recreate the objects leading to the node
+        let tokens: Vec<String> = field[1..].split('/').map(|t| t.replace("~1", "/").replace("~0", "~")).collect();
+        let mut node = &mut projected;
+        for token in &tokens[..tokens.len() - 1] {
+            if !node[token.as_str()].is_object() {
+                node[token.as_str()] = Value::Object(Map::new());
+            }
+            node = &mut node[token.as_str()];
+        }
+        node[tokens[tokens.len() - 1].as_str()] = value.clone();
+    }
+
+    projected
+}
+
+// Parse `?fields=name,email` into the list of fields, ignoring empty entries
+pub fn parse_fields(fields: &str) -> Vec<&str> {
+    fields.split(',').map(str::trim).filter(|f| !f.is_empty()).collect()
+}
+
+// Set the node at `pointer`, replacing it or adding it to an existing parent
+pub fn set(document: &Value, pointer: &str, value: &Value) -> Result<Value, PatchError> {
+    let op = if document.pointer(pointer).is_some() { "replace" } else { "add" };
+    json_patch::apply(document, &[serde_json::json!({"op": op, "path": pointer, "value": value})])
+}
+
+// Remove the node at `pointer`
+pub fn remove(document: &Value, pointer: &str) -> Result<Value, PatchError> {
+    json_patch::apply(document, &[serde_json::json!({"op": "remove", "path": pointer})])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use serde_json::json;
+
+    #[test]
+    fn test_resolve_longest_document_prefix() {
+        let dir = "./files/test_pointer_resolve";
+        fs::create_dir_all(dir).unwrap();
+        fs::write(format!("{}/420.json", dir), "{}").unwrap();
+
+        assert_eq!(resolve("/test_pointer_resolve/420/address/city"), Some(("/test_pointer_resolve/420".to_string(), "/address/city".to_string())));
+        assert_eq!(resolve("/test_pointer_resolve/420/a%20b/x~1y"), Some(("/test_pointer_resolve/420".to_string(), "/a b/x~1y".to_string())));
+        assert_eq!(resolve("/test_pointer_resolve/420/a+b"), Some(("/test_pointer_resolve/420".to_string(), "/a+b".to_string())));
+        assert_eq!(resolve("/test_pointer_resolve/421/name"), None);
+        assert_eq!(resolve("/test_pointer_resolve/420"), None, "A document path is not a sub-resource");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_project_fields() {
+        let document = json!({"name": "Ana", "email": "a@x", "address": {"city": "Lima", "zip": "15001"}, "age": 31});
+
+        let projected = project(&document, &parse_fields("name, /address/city,missing"));
+
+        assert_eq!(projected, json!({"name": "Ana", "address": {"city": "Lima"}}));
+    }
+
+    #[test]
+    fn test_set_and_remove_nodes() {
+        let document = json!({"address": {"city": "Lima"}, "tags": ["a"]});
+
+        assert_eq!(set(&document, "/address/city", &json!("Quito")).unwrap()["address"]["city"], "Quito");
+        assert_eq!(set(&document, "/tags/0", &json!("b")).unwrap()["tags"], json!(["b"]), "Existing array items are replaced");
+        assert_eq!(set(&document, "/address/zip", &json!("1")).unwrap()["address"]["zip"], "1");
+        assert_eq!(set(&document, "/missing/zip", &json!("1")).unwrap_err().status_code, 409);
+        assert_eq!(remove(&document, "/address/city").unwrap(), json!({"address": {}, "tags": ["a"]}));
+    }
+}
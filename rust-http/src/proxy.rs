@@ -0,0 +1,626 @@
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::cache::{self, CachedResponse};
+use crate::methods::error_response;
+use crate::request::{find_header, head_length, parse_head, HttpRequest};
+
+// How long the proxy waits on an upstream when the configuration does not say
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+// Failures in a row after which an upstream is taken out of rotation
+const MAX_FAILURES: u32 = 3;
+// How long an upstream that kept failing is left alone before it gets traffic again
+const FAIL_TIMEOUT: Duration = Duration::from_secs(10);
+// Largest upstream response head the proxy accepts
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+// Headers that only apply to one connection and are not passed on
+const HOP_BY_HOP: [&str; 8] = [
+    "Connection", "Keep-Alive", "Proxy-Connection", "Proxy-Authorization", "TE", "Trailer", "Upgrade", "Expect",
+];
+
+// How a route spreads requests over its upstreams
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+}
+
+impl Balance {
+    pub fn parse(name: &str) -> Option<Balance> {
+        match name {
+            "round-robin" => Some(Balance::RoundRobin),
+            "least-connections" => Some(Balance::LeastConnections),
+            _ => None,
+        }
+    }
+}
+
+// Requests below `prefix` are forwarded, unchanged, to one of the `host:port` upstreams
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyRoute {
+    pub prefix: String,
+    pub upstreams: Vec<String>,
+    pub balance: Balance,
+}
+
+// Parse routes written as `/api=127.0.0.1:9001|127.0.0.1:9002;/legacy=127.0.0.1:7000`
+pub fn parse_routes(spec: &str, balance: Balance) -> Result<Vec<ProxyRoute>, String> {
+    spec.split(';')
+        .map(str::trim)
+        .filter(|route| !route.is_empty())
+        .map(|route| {
+            let (prefix, upstreams) = route.split_once('=').ok_or_else(|| format!("Invalid route '{}': expected prefix=host:port", route))?;
+            if !prefix.starts_with('/') {
+                return Err(format!("Invalid route '{}': the prefix must start with '/'", route));
+            }
+            let upstreams: Vec<String> = upstreams.split('|').map(str::trim).filter(|u| !u.is_empty()).map(str::to_string).collect();
+            if upstreams.is_empty() || upstreams.iter().any(|upstream| !upstream.contains(':')) {
+                return Err(format!("Invalid route '{}': upstreams are host:port", route));
+            }
+            Ok(ProxyRoute { prefix: prefix.trim_end_matches('/').to_string(), upstreams, balance })
+        })
+        .collect()
+}
+
+// A target of a route with what the proxy has seen of it
+#[derive(Debug)]
+struct Upstream {
+    address: String,
+    // Requests being forwarded to it right now
+    active: AtomicUsize,
+    // Failures since the last successful request
+    failures: AtomicU32,
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Upstream {
+    fn new(address: &str) -> Self {
+        Upstream { address: address.to_string(), active: AtomicUsize::new(0), failures: AtomicU32::new(0), down_until: Mutex::new(None) }
+    }
+
+    fn is_healthy(&self) -> bool {
+        match *self.down_until.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+
+    // Passive health check: an upstream failing `MAX_FAILURES` times in a row is skipped for a while
+    fn record_failure(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if failures >= MAX_FAILURES {
+            println!("Upstream {} failed {} times, taking it out of rotation", self.address, failures);
+            *self.down_until.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Instant::now() + FAIL_TIMEOUT);
+        }
+    }
+
+    fn record_success(&self) {
+        self.failures.store(0, Ordering::SeqCst);
+        *self.down_until.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+    }
+}
+
+// Counts a request as active on an upstream for as long as it is alive
+struct ActiveRequest<'a>(&'a Upstream);
+
+impl Drop for ActiveRequest<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+struct Route {
+    prefix: String,
+    balance: Balance,
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+}
+
+impl Route {
+    fn matches(&self, path: &str) -> bool {
+        self.prefix.is_empty() || path == self.prefix || path.starts_with(&format!("{}/", self.prefix))
+    }
+
+    // Choose an upstream that has not been tried yet for this request. Healthy upstreams come
+    // first; when every one is down they are all tried rather than failing outright.
+    fn pick(&self, tried: &[usize]) -> Option<usize> {
+        let untried: Vec<usize> = (0..self.upstreams.len()).filter(|index| !tried.contains(index)).collect();
+        let healthy: Vec<usize> = untried.iter().copied().filter(|&index| self.upstreams[index].is_healthy()).collect();
+        let candidates = if healthy.is_empty() { untried } else { healthy };
+        if candidates.is_empty() {
+            return None;
+        }
+
+        // Rotating the starting point also spreads ties between equally busy upstreams
+        let start = self.next.fetch_add(1, Ordering::SeqCst);
+        let mut rotated = (0..candidates.len()).map(|offset| candidates[(start + offset) % candidates.len()]);
+        match self.balance {
+            Balance::RoundRobin => rotated.next(),
+            Balance::LeastConnections => rotated.min_by_key(|&index| self.upstreams[index].active.load(Ordering::SeqCst)),
+        }
+    }
+}
+
+// Why a request could not be forwarded, answered with 502 or 504
+enum ProxyError {
+    BadGateway(String),
+    Timeout(String),
+}
+
+impl ProxyError {
+    fn from_io(context: &str, e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ProxyError::Timeout(format!("{}: timed out", context)),
+            _ => ProxyError::BadGateway(format!("{}: {}", context, e)),
+        }
+    }
+}
+
+// Reverse proxy forwarding the routed path prefixes to their upstreams
+#[derive(Debug)]
+pub struct Proxy {
+    routes: Vec<Route>,
+    timeout: Duration,
+}
+
+impl Proxy {
+    pub fn new(routes: &[ProxyRoute], timeout: Option<Duration>) -> Self {
+        Proxy {
+            routes: routes.iter().map(|route| Route {
+                prefix: route.prefix.clone(),
+                balance: route.balance,
+                upstreams: route.upstreams.iter().map(|address| Upstream::new(address)).collect(),
+                next: AtomicUsize::new(0),
+            }).collect(),
+            timeout: timeout.unwrap_or(DEFAULT_TIMEOUT),
+        }
+    }
+
+    // Forward the request if its path belongs to a route, returning whether it did.
+    // `data` holds what was read from the client so far: the head and the start of the body,
+    // the rest of which is passed on as it arrives, just like the upstream response.
+    pub fn forward(&self, client: &mut TcpStream, request: &HttpRequest, data: &[u8]) -> bool {
+        let Some(route) = self.routes.iter().find(|route| route.matches(request.path_only())) else {
+            return false;
+        };
+        println!("Proxying {} {} under {}", request.method, request.path, route.prefix);
+
+        // Writes make what is stored for the path stale, reads may be answered from the cache
+        if request.method != "GET" && request.method != "HEAD" {
+            cache::invalidate(request.path_only());
+        } else if let Some(hit) = cache::lookup(request) {
+            if let Err(e) = client.write_all(&cached_response(request, &hit)) {
+                eprintln!("Failed to send response: {}", e);
+            }
+            return true;
+        }
+
+        if let Err(error) = self.forward_to(route, client, request, data) {
+            let response = match error {
+                ProxyError::BadGateway(message) => {
+                    println!("Bad gateway: {}", message);
+                    error_response(502, &message)
+                },
+                ProxyError::Timeout(message) => {
+                    println!("Gateway timeout: {}", message);
+                    error_response(504, &message)
+                },
+            };
+            if let Err(e) = client.write_all(&response.to_bytes()) {
+                eprintln!("Failed to send response: {}", e);
+            }
+        }
+        true
+    }
+
+    fn forward_to(&self, route: &Route, client: &mut TcpStream, request: &HttpRequest, data: &[u8]) -> Result<(), ProxyError> {
+        let head_length = head_length(data).unwrap_or(data.len());
+        let (_, headers) = parse_head(&String::from_utf8_lossy(&data[..head_length]));
+        let body_start = &data[head_length..];
+
+        // Connect, moving on to the next upstream while connections fail
+        let mut tried = Vec::new();
+        let mut last_error = ProxyError::BadGateway("No upstream available".to_string());
+        let (upstream, mut connection) = loop {
+            let Some(index) = route.pick(&tried) else {
+                return Err(last_error);
+            };
+            tried.push(index);
+            let upstream = &route.upstreams[index];
+            match self.connect(&upstream.address) {
+                Ok(connection) => break (upstream, connection),
+                Err(e) => {
+                    upstream.record_failure();
+                    last_error = ProxyError::from_io(&format!("Failed to connect to {}", upstream.address), e);
+                },
+            }
+        };
+        upstream.active.fetch_add(1, Ordering::SeqCst);
+        let _active = ActiveRequest(upstream);
+
+        let generation = cache::generation_for(request);
+        let result = self.exchange(upstream, &mut connection, client, request, &headers, body_start, generation);
+        match &result {
+            Ok(()) => upstream.record_success(),
+            Err(_) => upstream.record_failure(),
+        }
+        result
+    }
+
+    // Send the request upstream and relay the answer back to the client, keeping a copy in the
+    // cache when `generation` says the request allows it and the response allows it too.
+    // Failures after the response head went out can not be reported anymore and only end the connection.
+    #[allow(clippy::too_many_arguments)]
+    fn exchange(&self, upstream: &Upstream, connection: &mut TcpStream, client: &mut TcpStream, request: &HttpRequest, headers: &[String], body_start: &[u8], generation: Option<u64>) -> Result<(), ProxyError> {
+        let upstream_error = |e: io::Error| ProxyError::from_io(&format!("Upstream {}", upstream.address), e);
+
+        // The client would otherwise wait for the interim answer before sending its body
+        if find_header(headers, "Expect").is_some_and(|value| value.eq_ignore_ascii_case("100-continue")) {
+            client.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").map_err(|e| ProxyError::BadGateway(e.to_string()))?;
+        }
+
+        let head = forwarded_head(request, headers, &upstream.address, client.peer_addr().ok().map(|addr| addr.ip().to_string()));
+        connection.write_all(head.as_bytes()).map_err(upstream_error)?;
+        let body = Cursor::new(body_start).chain(&mut *client);
+        relay_body(headers, body, connection, false).map_err(|e| ProxyError::BadGateway(format!("Failed to forward the request body: {}", e)))?;
+        connection.flush().map_err(upstream_error)?;
+
+        // Interim responses are passed on until the final one arrives
+        let mut data = Vec::new();
+        let (head_length, status_line, status_code, headers) = loop {
+            let head_length = read_head(connection, &mut data).map_err(upstream_error)?;
+            let head = String::from_utf8_lossy(&data[..head_length]).into_owned();
+            let (status_line, headers) = parse_head(&head);
+            let status_code: u16 = status_line.split_whitespace().nth(1).and_then(|code| code.parse().ok())
+                .ok_or_else(|| ProxyError::BadGateway(format!("Invalid status line from {}: {}", upstream.address, status_line)))?;
+            if (100..200).contains(&status_code) {
+                client.write_all(&data[..head_length]).map_err(|e| ProxyError::BadGateway(e.to_string()))?;
+                data.drain(..head_length);
+                continue;
+            }
+            break (head_length, status_line.to_string(), status_code, headers);
+        };
+
+        // The client talks HTTP/1.1 to the proxy whatever version the upstream speaks
+        let reason = status_line.splitn(3, ' ').nth(2).unwrap_or_default();
+        let mut response = format!("HTTP/1.1 {} {}\r\n", status_code, reason);
+        let end_to_end: Vec<String> = headers.iter().filter(|header| !is_hop_by_hop(header)).cloned().collect();
+        for header in &end_to_end {
+            response.push_str(&format!("{}\r\n", header));
+        }
+        if generation.is_some() {
+            response.push_str("X-Cache: MISS\r\n");
+        }
+        response.push_str("Connection: close\r\n\r\n");
+
+        let store = generation.zip(cache::upstream_lifetime(status_code, &headers));
+        let mut tee = Tee { inner: &mut *client, copy: store.map(|_| Vec::new()), limit: cache::max_entry_size() };
+        let body = Cursor::new(data[head_length..].to_vec()).chain(&mut *connection);
+        let no_body = request.method == "HEAD" || status_code == 204 || status_code == 304;
+        let relayed = tee.inner.write_all(response.as_bytes())
+            .and_then(|_| if no_body { Ok(()) } else { relay_body(&headers, body, &mut tee, true) })
+            .and_then(|_| tee.flush());
+        match relayed {
+            Err(e) => println!("Failed to relay the response of {}: {}", upstream.address, e),
+            Ok(()) => if let (Some((generation, lifetime)), Some(copy)) = (store, tee.copy) {
+                cache::store_upstream(request, &end_to_end, copy, lifetime, generation);
+            },
+        }
+        Ok(())
+    }
+
+    fn connect(&self, address: &str) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} does not resolve", address));
+        for address in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, self.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    return Ok(stream);
+                },
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+// The request head as sent upstream: Host names the upstream, the original host and the
+// client address go in the `X-Forwarded-*` headers, and hop-by-hop headers are dropped
+fn forwarded_head(request: &HttpRequest, headers: &[String], upstream: &str, client_ip: Option<String>) -> String {
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", request.method, request.path, upstream);
+    let forwarded = ["Host", "X-Forwarded-For", "X-Forwarded-Host", "X-Forwarded-Proto"];
+    for header in headers.iter().filter(|header| !is_hop_by_hop(header)) {
+        let name = header.split(':').next().unwrap_or_default().trim();
+        if !forwarded.iter().any(|f| f.eq_ignore_ascii_case(name)) {
+            head.push_str(&format!("{}\r\n", header));
+        }
+    }
+
+    let forwarded_for = match (find_header(headers, "X-Forwarded-For"), client_ip) {
+        (Some(chain), Some(ip)) => Some(format!("{}, {}", chain, ip)),
+        (chain, ip) => ip.or(chain.map(str::to_string)),
+    };
+    if let Some(forwarded_for) = forwarded_for {
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+    }
+    if let Some(host) = find_header(headers, "X-Forwarded-Host").or(find_header(headers, "Host")) {
+        head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+    }
+    let proto = find_header(headers, "X-Forwarded-Proto").unwrap_or("http");
+    head.push_str(&format!("X-Forwarded-Proto: {}\r\nConnection: close\r\n\r\n", proto));
+    head
+}
+
+// A stored response as sent to the client: 304 when the client already has it
+fn cached_response(request: &HttpRequest, hit: &CachedResponse) -> Vec<u8> {
+    if hit.not_modified_for(request) {
+        let etag = hit.header("ETag").unwrap_or_default();
+        return format!("HTTP/1.1 304 Not Modified\r\nETag: {}\r\nConnection: close\r\n\r\n", etag).into_bytes();
+    }
+
+    let mut response = format!("HTTP/1.1 {} OK\r\n", hit.status_code);
+    for (name, value) in hit.headers.iter().filter(|(name, _)| !name.eq_ignore_ascii_case("Age")) {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str(&format!("Age: {}\r\nX-Cache: HIT\r\nConnection: close\r\n\r\n", hit.age()));
+    let mut response = response.into_bytes();
+    response.extend_from_slice(&hit.body);
+    response
+}
+
+// Writer passing everything on while keeping a copy for the cache, given up once it outgrows `limit`
+struct Tee<'a> {
+    inner: &'a mut TcpStream,
+    copy: Option<Vec<u8>>,
+    limit: usize,
+}
+
+impl Write for Tee<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(copy) = &mut self.copy {
+            copy.extend_from_slice(&buf[..written]);
+            if copy.len() > self.limit {
+                self.copy = None;
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn is_hop_by_hop(header: &str) -> bool {
+    let name = header.split(':').next().unwrap_or_default().trim();
+    HOP_BY_HOP.iter().any(|hop| hop.eq_ignore_ascii_case(name))
+}
+
+// Read from `stream` into `data` until a message head is complete, returning its length
+fn read_head(stream: &mut TcpStream, data: &mut Vec<u8>) -> io::Result<usize> {
+    let mut buffer = [0; 4096];
+    loop {
+        if let Some(head_length) = head_length(data) {
+            return Ok(head_length);
+        }
+        if data.len() > MAX_HEAD_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Response head too large"));
+        }
+        match stream.read(&mut buffer)? {
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed before the response")),
+            bytes_read => data.extend_from_slice(&buffer[..bytes_read]),
+        }
+    }
+}
+
+// Copy a message body as it arrives, framed the way its headers say: chunks are passed on
+// with their framing, a `Content-Length` body is copied to its length, and a response
+// without either (`until_eof`) runs until the connection ends
+fn relay_body(headers: &[String], from: impl Read, to: &mut impl Write, until_eof: bool) -> io::Result<()> {
+    let chunked = find_header(headers, "Transfer-Encoding").is_some_and(|value| value.to_ascii_lowercase().contains("chunked"));
+    if chunked {
+        return relay_chunked(from, to);
+    }
+
+    match find_header(headers, "Content-Length").and_then(|value| value.parse::<u64>().ok()) {
+        Some(length) => {
+            let copied = io::copy(&mut from.take(length), to)?;
+            if copied < length {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Body ended early"));
+            }
+            Ok(())
+        },
+        None if until_eof => io::copy(&mut { from }, to).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+fn relay_chunked(from: impl Read, to: &mut impl Write) -> io::Result<()> {
+    let mut reader = BufReader::new(from);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Chunked body ended early"));
+        }
+        to.write_all(&line)?;
+
+        let size = String::from_utf8_lossy(&line);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid chunk size: {}", size)))?;
+        if size == 0 {
+            // Trailer fields, up to the blank line
+            loop {
+                line.clear();
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    return Ok(());
+                }
+                to.write_all(&line)?;
+                if line == b"\r\n" || line == b"\n" {
+                    return Ok(());
+                }
+            }
+        }
+
+        // The chunk and the line break after it
+        if io::copy(&mut (&mut reader).take(size + 2), to)? < size + 2 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Chunk ended early"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex as StdMutex};
+    use std::thread;
+    use crate::client::Client;
+    use crate::config::Config;
+    use crate::server::Server;
+
+    // Upstream answering one connection with `response` after reading the whole request,
+    // which it hands back
+    fn upstream(response: &'static str) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut data = Vec::new();
+            let head_length = read_head(&mut stream, &mut data).unwrap();
+            let (_, headers) = parse_head(&String::from_utf8_lossy(&data[..head_length]));
+            let mut body = Vec::new();
+            relay_body(&headers, Cursor::new(data[head_length..].to_vec()).chain(&mut stream), &mut body, false).unwrap();
+            stream.write_all(response.as_bytes()).unwrap();
+            let mut request = String::from_utf8_lossy(&data[..head_length]).into_owned();
+            request.push_str(&String::from_utf8_lossy(&body));
+            sender.send(request).unwrap();
+        });
+        (address, receiver)
+    }
+
+    // Send `request` through a server configured with `routes` and return the raw response
+    fn through_proxy(routes: Vec<ProxyRoute>, timeout: Option<Duration>, request: &[u8]) -> String {
+        let config = Config { proxy_routes: routes, proxy_timeout: timeout, ..Config::default() };
+        let server = Arc::new(StdMutex::new(Server::with_config(config)));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let handler = thread::spawn(move || Client { stream }.handle(server));
+
+        client.write_all(request).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        handler.join().unwrap();
+        response
+    }
+
+    fn route(upstreams: &[&str]) -> ProxyRoute {
+        ProxyRoute { prefix: "/api".to_string(), upstreams: upstreams.iter().map(|u| u.to_string()).collect(), balance: Balance::RoundRobin }
+    }
+
+    #[test]
+    fn test_parse_routes() {
+        let routes = parse_routes("/api/=127.0.0.1:9001|127.0.0.1:9002; /legacy=old:80", Balance::LeastConnections).unwrap();
+
+        assert_eq!(routes[0], ProxyRoute {
+            prefix: "/api".to_string(),
+            upstreams: vec!["127.0.0.1:9001".to_string(), "127.0.0.1:9002".to_string()],
+            balance: Balance::LeastConnections,
+        });
+        assert_eq!(routes[1].upstreams, vec!["old:80".to_string()]);
+        assert!(parse_routes("/api=localhost", Balance::RoundRobin).is_err());
+        assert!(parse_routes("api=localhost:80", Balance::RoundRobin).is_err());
+
+        let proxy = Proxy::new(&routes, None);
+        assert!(proxy.routes[0].matches("/api") && proxy.routes[0].matches("/api/users"));
+        assert!(!proxy.routes[0].matches("/apix"));
+    }
+
+    #[test]
+    fn test_balancing_and_passive_health() {
+        let spec = route(&["a:1", "b:1", "c:1"]);
+        let proxy = Proxy::new(std::slice::from_ref(&spec), None);
+        let route = &proxy.routes[0];
+        let picks: Vec<usize> = (0..6).map(|_| route.pick(&[]).unwrap()).collect();
+        assert_eq!(picks, vec![0, 1, 2, 0, 1, 2]);
+        assert_eq!(route.pick(&[0, 1, 2]), None);
+
+        for _ in 0..MAX_FAILURES {
+            route.upstreams[1].record_failure();
+        }
+        assert!((0..6).all(|_| route.pick(&[]).unwrap() != 1), "A failing upstream is skipped");
+        assert_eq!(route.pick(&[0, 2]), Some(1), "Down upstreams are still tried as a last resort");
+        route.upstreams[1].record_success();
+        assert!(route.upstreams[1].is_healthy());
+
+        let proxy = Proxy::new(&[ProxyRoute { balance: Balance::LeastConnections, ..spec }], None);
+        let route = &proxy.routes[0];
+        route.upstreams[0].active.store(2, Ordering::SeqCst);
+        route.upstreams[2].active.store(1, Ordering::SeqCst);
+        assert!((0..4).all(|_| route.pick(&[]) == Some(1)));
+    }
+
+    #[test]
+    fn test_forward_rewrites_headers_and_streams_bodies() {
+        let (address, requests) = upstream("HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\nConnection: keep-alive\r\n\r\n2\r\nok\r\n0\r\n\r\n");
+
+        let response = through_proxy(
+            vec![route(&[&address])],
+            None,
+            b"POST /api/users?x=1 HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 10.0.0.1\r\nContent-Length: 11\r\n\r\n{\"a\": true}",
+        );
+
+        let forwarded = requests.recv().unwrap();
+        assert!(forwarded.starts_with(&format!("POST /api/users?x=1 HTTP/1.1\r\nHost: {}\r\n", address)));
+        assert!(forwarded.contains("X-Forwarded-For: 10.0.0.1, 127.0.0.1\r\n"));
+        assert!(forwarded.contains("X-Forwarded-Host: example.com\r\n"));
+        assert!(forwarded.contains("X-Forwarded-Proto: http\r\n"));
+        assert!(forwarded.ends_with("\r\n\r\n{\"a\": true}"));
+
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(response.contains("Connection: close\r\n") && !response.contains("keep-alive"));
+        assert!(response.ends_with("\r\n\r\n2\r\nok\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_upstream_failures_map_to_502_and_504() {
+        // Nothing listens on a port that was just released
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let response = through_proxy(vec![route(&[&closed])], None, b"GET /api/x HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "{}", response);
+
+        // An upstream that accepts but never answers
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = silent.local_addr().unwrap().to_string();
+        let response = through_proxy(vec![route(&[&address])], Some(Duration::from_millis(200)), b"GET /api/x HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 504 "), "{}", response);
+    }
+
+    #[test]
+    fn test_cacheable_responses_are_served_from_the_cache() {
+        // The upstream answers a single request, the second one has to come from the cache
+        let (address, requests) = upstream("HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nETag: \"v1\"\r\nContent-Length: 5\r\n\r\nhello");
+        let routes = vec![route(&[&address])];
+
+        let first = through_proxy(routes.clone(), None, b"GET /api/test_proxy_cache HTTP/1.1\r\n\r\n");
+        requests.recv().unwrap();
+        let second = through_proxy(routes.clone(), None, b"GET /api/test_proxy_cache HTTP/1.1\r\n\r\n");
+        let revalidated = through_proxy(routes, None, b"GET /api/test_proxy_cache HTTP/1.1\r\nIf-None-Match: \"v1\"\r\n\r\n");
+
+        assert!(first.contains("X-Cache: MISS\r\n") && first.ends_with("hello"), "{}", first);
+        assert!(second.starts_with("HTTP/1.1 200 OK\r\n"), "{}", second);
+        assert!(second.contains("X-Cache: HIT\r\n") && second.contains("Age: 0\r\n") && second.ends_with("\r\n\r\nhello"), "{}", second);
+        assert!(revalidated.starts_with("HTTP/1.1 304 Not Modified\r\n"), "{}", revalidated);
+    }
+}
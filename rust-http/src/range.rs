@@ -0,0 +1,338 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use uuid::Uuid;
+use crate::body::Body;
+use crate::methods::error_response;
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::ttl;
+
+// More ranges than this in one request are answered with the whole content
+const MAX_RANGES: usize = 32;
+
+// First and last byte of a satisfiable range, both included
+pub type ByteRange = (u64, u64);
+
+// What a `Range` header asks of content `length` bytes long
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    // Not a byte range request, or one that is malformed: the whole content is sent
+    Ignored,
+    Satisfiable(Vec<ByteRange>),
+    // Only ranges past the end of the content
+    Unsatisfiable,
+}
+
+// Parse `bytes=0-99,200-,-50` against the content length. Ranges that overlap or touch are
+// merged, so a client can not make the server send the same bytes over and over.
+pub fn parse_range(header: &str, length: u64) -> RangeRequest {
+    let Some(specs) = header.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Ignored;
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        let Some((first, last)) = spec.split_once('-') else {
+            return RangeRequest::Ignored;
+        };
+        let range = match (first.trim(), last.trim()) {
+            // The last `n` bytes
+            ("", suffix) => match suffix.parse::<u64>() {
+                Ok(0) => None,
+                Ok(suffix) if length > 0 => Some((length.saturating_sub(suffix), length - 1)),
+                Ok(_) => None,
+                Err(_) => return RangeRequest::Ignored,
+            },
+            (first, last) => {
+                let Ok(first) = first.parse::<u64>() else {
+                    return RangeRequest::Ignored;
+                };
+                let last = match last {
+                    "" => u64::MAX,
+                    last => match last.parse::<u64>() {
+                        Ok(last) if last >= first => last,
+                        _ => return RangeRequest::Ignored,
+                    },
+                };
+                (first < length).then(|| (first, last.min(length - 1)))
+            },
+        };
+        ranges.extend(range);
+    }
+
+    if ranges.is_empty() {
+        return if specs.trim().is_empty() { RangeRequest::Ignored } else { RangeRequest::Unsatisfiable };
+    }
+    if ranges.len() > MAX_RANGES {
+        return RangeRequest::Ignored;
+    }
+
+    ranges.sort();
+    let mut merged: Vec<ByteRange> = Vec::new();
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some(previous) if first <= previous.1.saturating_add(1) => previous.1 = previous.1.max(last),
+            _ => merged.push((first, last)),
+        }
+    }
+    RangeRequest::Satisfiable(merged)
+}
+
+// Whether `If-Range` lets the range apply: it has to name the current version of the content,
+// by strong ETag or by its exact `Last-Modified` date
+fn if_range_matches(if_range: &str, response: &HttpResponse) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return !if_range.starts_with("W/") && response.header("ETag").is_some_and(|etag| etag == if_range && !etag.starts_with("W/"));
+    }
+    match (ttl::parse_http_date(if_range), response.header("Last-Modified").and_then(ttl::parse_http_date)) {
+        (Some(date), Some(modified)) => date == modified,
+        _ => false,
+    }
+}
+
+// Advertise range support on a full response and answer a GET with a `Range` header with the
+// parts it asks for: 206 with `Content-Range` for one range, a `multipart/byteranges` body for
+// several, and 416 when none of them is inside the content
+pub fn apply(request: &HttpRequest, mut response: HttpResponse) -> HttpResponse {
+    let length = match &response.body {
+        Some(body) => body.len(),
+        None => Some(0),
+    };
+    let Some(length) = length.filter(|_| response.status_code == 200) else {
+        return response;
+    };
+    response.headers.insert("Accept-Ranges".to_string(), "bytes".to_string());
+
+    let Some(header) = request.header("Range").filter(|_| request.method == "GET") else {
+        return response;
+    };
+    if request.header("If-Range").is_some_and(|if_range| !if_range_matches(if_range, &response)) {
+        return response;
+    }
+
+    match parse_range(header, length) {
+        RangeRequest::Ignored => response,
+        RangeRequest::Unsatisfiable => {
+            let mut unsatisfiable = error_response(416, "Range not satisfiable");
+            unsatisfiable.headers.insert("Content-Range".to_string(), format!("bytes */{}", length));
+            unsatisfiable
+        },
+        RangeRequest::Satisfiable(ranges) => match partial_response(response, &ranges, length) {
+            Ok(partial) => partial,
+            Err(e) => {
+                println!("Failed to prepare a partial response: {}", e);
+                error_response(500, "Failed to read the requested range")
+            },
+        },
+    }
+}
+
+fn partial_response(mut response: HttpResponse, ranges: &[ByteRange], length: u64) -> io::Result<HttpResponse> {
+    let body = response.body.take().unwrap_or(Body::Bytes(Vec::new()));
+    response.status_code = 206;
+    response.headers.retain(|key, _| !key.eq_ignore_ascii_case("Content-Length"));
+
+    if let [(first, last)] = ranges {
+        let (first, last) = (*first, *last);
+        response.headers.insert("Content-Range".to_string(), format!("bytes {}-{}/{}", first, last, length));
+        response.body = Some(match body {
+            Body::Bytes(bytes) => Body::Bytes(bytes[first as usize..=last as usize].to_vec()),
+            Body::File { file, offset, .. } => Body::File { file, offset: offset + first, length: last - first + 1 },
+            Body::Stream(_) => unreachable!("Streams have no length to take ranges of"),
+        });
+        return Ok(response);
+    }
+
+    // Every part carries its own type and range, the response type names the separator
+    let boundary = Uuid::new_v4().simple().to_string();
+    let content_type = response.headers.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("Content-Type"))
+        .map(|(_, value)| value.clone());
+    response.headers.retain(|key, _| !key.eq_ignore_ascii_case("Content-Type"));
+    response.headers.insert("Content-Type".to_string(), format!("multipart/byteranges; boundary={}", boundary));
+
+    let mut parts: Vec<Box<dyn Read + Send>> = Vec::new();
+    let mut total = 0;
+    for (index, (first, last)) in ranges.iter().enumerate() {
+        let mut head = format!("{}--{}\r\n", if index == 0 { "" } else { "\r\n" }, boundary);
+        if let Some(content_type) = &content_type {
+            head.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
+        head.push_str(&format!("Content-Range: bytes {}-{}/{}\r\n\r\n", first, last, length));
+        total += head.len() as u64 + (last - first + 1);
+        parts.push(Box::new(Cursor::new(head.into_bytes())));
+        parts.push(match &body {
+            Body::Bytes(bytes) => Box::new(Cursor::new(bytes[*first as usize..=*last as usize].to_vec())),
+            Body::File { file, offset, .. } => Box::new(FileSection::new(file.try_clone()?, offset + first, last - first + 1)),
+            Body::Stream(_) => unreachable!("Streams have no length to take ranges of"),
+        });
+    }
+    let closing = format!("\r\n--{}--\r\n", boundary);
+    total += closing.len() as u64;
+    parts.push(Box::new(Cursor::new(closing.into_bytes())));
+
+    response.headers.insert("Content-Length".to_string(), total.to_string());
+    response.body = Some(Body::stream(Parts { parts: parts.into_iter().collect() }));
+    Ok(response)
+}
+
+// Part of a file, read from its start when first read. The handles of one file share their
+// position, which is fine as the parts of a multipart body are read one after the other.
+struct FileSection {
+    file: File,
+    offset: u64,
+    remaining: u64,
+    positioned: bool,
+}
+
+impl FileSection {
+    fn new(file: File, offset: u64, length: u64) -> Self {
+        FileSection { file, offset, remaining: length, positioned: false }
+    }
+}
+
+impl Read for FileSection {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if !self.positioned {
+            self.file.seek(SeekFrom::Start(self.offset))?;
+            self.positioned = true;
+        }
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let wanted = buffer.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let bytes_read = self.file.read(&mut buffer[..wanted])?;
+        if bytes_read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File ended before the range"));
+        }
+        self.remaining -= bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+// Readers read one after the other
+struct Parts {
+    parts: VecDeque<Box<dyn Read + Send>>,
+}
+
+impl Read for Parts {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while let Some(part) = self.parts.front_mut() {
+            match part.read(buffer)? {
+                0 => {
+                    self.parts.pop_front();
+                },
+                bytes_read => return Ok(bytes_read),
+            }
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+
+    fn get(headers: &[&str]) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            path: "/report.txt".to_string(),
+            _headers: headers.iter().map(|h| h.to_string()).collect(),
+            body: Vec::new(),
+            cookie: None,
+        }
+    }
+
+    fn text_response(body: &str) -> HttpResponse {
+        let mut headers = HashMap::new();
+        headers.insert("Content-Type".to_string(), "text/plain".to_string());
+        headers.insert("ETag".to_string(), "\"v1\"".to_string());
+        headers.insert("Last-Modified".to_string(), "Sun, 06 Nov 1994 08:49:37 GMT".to_string());
+        HttpResponse::new(200, headers, Some(body.to_string()))
+    }
+
+    fn read_body(response: HttpResponse) -> Vec<u8> {
+        let mut contents = Vec::new();
+        match response.body {
+            Some(Body::Bytes(bytes)) => contents = bytes,
+            Some(Body::Stream(mut reader)) => {
+                reader.read_to_end(&mut contents).unwrap();
+            },
+            Some(Body::File { mut file, offset, length }) => {
+                file.seek(SeekFrom::Start(offset)).unwrap();
+                file.take(length).read_to_end(&mut contents).unwrap();
+            },
+            None => {},
+        }
+        contents
+    }
+
+    #[test]
+    fn test_parse_range() {
+        use RangeRequest::*;
+        assert_eq!(parse_range("bytes=0-99", 1000), Satisfiable(vec![(0, 99)]));
+        assert_eq!(parse_range("bytes=900-", 1000), Satisfiable(vec![(900, 999)]));
+        assert_eq!(parse_range("bytes=-50", 1000), Satisfiable(vec![(950, 999)]));
+        assert_eq!(parse_range("bytes=-5000", 1000), Satisfiable(vec![(0, 999)]));
+        assert_eq!(parse_range("bytes=990-2000", 1000), Satisfiable(vec![(990, 999)]));
+        assert_eq!(parse_range("bytes=500-599, 0-9, 5-20, 21-30", 1000), Satisfiable(vec![(0, 30), (500, 599)]));
+        assert_eq!(parse_range("bytes=2000-, 5-1", 1000), Ignored, "A backwards range makes the header invalid");
+        assert_eq!(parse_range("bytes=2000-3000", 1000), Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), Unsatisfiable);
+        assert_eq!(parse_range("items=0-5", 1000), Ignored);
+        assert_eq!(parse_range("bytes=a-b", 1000), Ignored);
+    }
+
+    #[test]
+    fn test_single_and_unsatisfiable_ranges() {
+        let response = apply(&get(&[]), text_response("0123456789"));
+        assert_eq!((response.status_code, response.header("Accept-Ranges")), (200, Some("bytes")));
+
+        let response = apply(&get(&["Range: bytes=2-4"]), text_response("0123456789"));
+        assert_eq!(response.status_code, 206);
+        assert_eq!(response.header("Content-Range"), Some("bytes 2-4/10"));
+        assert!(response.head().contains("Content-Length: 3\r\n"));
+        assert_eq!(read_body(response), b"234");
+
+        let response = apply(&get(&["Range: bytes=10-"]), text_response("0123456789"));
+        assert_eq!(response.status_code, 416);
+        assert_eq!(response.header("Content-Range"), Some("bytes */10"));
+    }
+
+    #[test]
+    fn test_if_range() {
+        let range = "Range: bytes=0-0";
+        assert_eq!(apply(&get(&[range, "If-Range: \"v1\""]), text_response("abc")).status_code, 206);
+        assert_eq!(apply(&get(&[range, "If-Range: \"v0\""]), text_response("abc")).status_code, 200);
+        assert_eq!(apply(&get(&[range, "If-Range: W/\"v1\""]), text_response("abc")).status_code, 200, "Weak tags never match");
+        assert_eq!(apply(&get(&[range, "If-Range: Sun, 06 Nov 1994 08:49:37 GMT"]), text_response("abc")).status_code, 206);
+        assert_eq!(apply(&get(&[range, "If-Range: Mon, 07 Nov 1994 08:49:37 GMT"]), text_response("abc")).status_code, 200);
+    }
+
+    #[test]
+    fn test_multiple_ranges_of_a_file() {
+        let path = "./files/test_range_file.txt";
+        fs::write(path, "abcdefghijklmnopqrstuvwxyz").unwrap();
+        let mut headers = HashMap::new();
+        headers.insert("Content-Type".to_string(), "text/plain".to_string());
+        let file = HttpResponse::with_body(200, headers, Body::file(File::open(path).unwrap()).unwrap());
+
+        let response = apply(&get(&["Range: bytes=0-2,-3"]), file);
+        let boundary = response.header("Content-Type").unwrap().strip_prefix("multipart/byteranges; boundary=").unwrap().to_string();
+        let length: usize = response.header("Content-Length").unwrap().parse().unwrap();
+        let body = String::from_utf8(read_body(response)).unwrap();
+
+        assert_eq!(body, format!(
+            "--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-2/26\r\n\r\nabc\r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 23-25/26\r\n\r\nxyz\r\n--{0}--\r\n",
+            boundary
+        ));
+        assert_eq!(body.len(), length);
+
+        fs::remove_file(path).unwrap();
+    }
+}
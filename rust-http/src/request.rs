@@ -1,9 +1,203 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use serde_json::Value;
+
 // Struct to represent an HTTP request
 #[derive(Debug)]
 pub struct HttpRequest {
     pub method: String,
     pub path: String,
     pub _headers: Vec<String>,
-    pub body: String,
+    // Raw body bytes, see `text` and `json` for decoded views
+    pub body: Vec<u8>,
     pub cookie: Option<String>,
 }
+
+impl HttpRequest {
+    // Parse a raw request: the request line, the headers and the body after them
+    pub fn parse(raw_request: &[u8]) -> Option<HttpRequest> {
+        // Only the head is text, the body is kept byte for byte
+        let (head, body_part) = match head_length(raw_request) {
+            Some(head_length) => (&raw_request[..head_length - 4], &raw_request[head_length..]),
+            None => (raw_request, &[][..]),
+        };
+        let header_part = String::from_utf8_lossy(head);
+
+        if header_part.is_empty() {
+            // Malformed request: No headers
+            eprintln!("Malformed request: No headers.");
+            return None;
+        }
+
+        let body_part = body_part.to_vec();
+
+        let (request_line, _headers) = parse_head(&header_part);
+
+        let mut request_parts = request_line.split_whitespace();
+        let method = request_parts.next().unwrap_or("").to_string();
+        if method.is_empty() {
+            // Malformed request: No HTTP method
+            eprintln!("Malformed request: No HTTP method.");
+            return None;
+        }
+
+        let path = request_parts.next().unwrap_or("").to_string();
+
+        // Extract cookie from headers if present
+        let cookie_header = _headers.iter().find(|h| h.starts_with("Cookie"));
+        let cookie = cookie_header.and_then(|h| {
+            h.split('=').nth(1).map(|c| c.trim().to_string()) // Extract the sessionId value
+        });
+
+        Some(HttpRequest {
+            method,
+            path,
+            _headers,
+            body: body_part,
+            cookie, // Include the cookie if available
+        })
+    }
+
+    // Look up a header value by name, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self._headers, name)
+    }
+
+    // The body as text, with invalid UTF-8 replaced
+    pub fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
+
+    // The body parsed as JSON, `None` when it is empty or not valid JSON
+    pub fn json(&self) -> Option<Value> {
+        if self.body.is_empty() {
+            return None;
+        }
+        serde_json::from_slice(&self.body).ok()
+    }
+
+    // Path without the query string
+    pub fn path_only(&self) -> &str {
+        self.path.split('?').next().unwrap_or_default()
+    }
+
+    // Decoded query string parameters, the last value wins for repeated keys
+    pub fn query_params(&self) -> HashMap<String, String> {
+        let query = self.path.split_once('?').map(|(_, query)| query).unwrap_or_default();
+        query.split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(key), percent_decode(value))
+            })
+            .collect()
+    }
+}
+
+// Length of the head of a message, up to and including the blank line, once it has fully arrived
+pub fn head_length(data: &[u8]) -> Option<usize> {
+    data.windows(4).position(|window| window == b"\r\n\r\n").map(|position| position + 4)
+}
+
+// Split the head of a request or response into its first line and its header lines
+pub fn parse_head(head: &str) -> (&str, Vec<String>) {
+    let mut lines = head.lines();
+    let first_line = lines.next().unwrap_or_default();
+    (first_line, lines.filter(|line| !line.is_empty()).map(|line| line.to_string()).collect())
+}
+
+// Value of a header among the header lines of a message, ignoring case
+pub fn find_header<'a>(lines: &'a [String], name: &str) -> Option<&'a str> {
+    lines.iter().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
+
+// Decode `%XX` escapes and `+` as used in query strings
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    },
+                    None => decoded.push(b'%'),
+                }
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Encode a query string component, keeping only unreserved characters as they are
+pub fn percent_encode(input: &str) -> String {
+    input.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_and_query_params() {
+        let request = HttpRequest {
+            method: "GET".to_string(),
+            path: "/users?age%5Bgte%5D=18&name=Ana+Maria".to_string(),
+            _headers: vec!["content-type: application/json".to_string()],
+            body: Vec::new(),
+            cookie: None,
+        };
+
+        let query = request.query_params();
+
+        assert_eq!(request.path_only(), "/users");
+        assert_eq!(query.get("age[gte]").unwrap(), "18");
+        assert_eq!(query.get("name").unwrap(), "Ana Maria");
+        assert_eq!(request.header("Content-Type"), Some("application/json"));
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let raw = b"POST /users HTTP/1.1\r\nContent-Length: 2\r\nCookie: sessionId=abc\r\n\r\n{}";
+
+        let request = HttpRequest::parse(raw).unwrap();
+
+        assert_eq!(head_length(raw), Some(raw.len() - 2));
+        assert_eq!((request.method.as_str(), request.path.as_str(), request.text().as_ref()), ("POST", "/users", "{}"));
+        assert_eq!(request.json(), Some(serde_json::json!({})));
+        assert_eq!(request.header("content-length"), Some("2"));
+        assert_eq!(request.cookie.as_deref(), Some("abc"));
+        assert!(HttpRequest::parse(b"\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn test_parse_keeps_binary_bodies() {
+        let mut raw = b"PUT /upload HTTP/1.1\r\nContent-Length: 256\r\n\r\n".to_vec();
+        let body: Vec<u8> = (0..=255).collect();
+        raw.extend_from_slice(&body);
+
+        let request = HttpRequest::parse(&raw).unwrap();
+
+        assert_eq!(request.body, body);
+        assert_eq!(request.json(), None);
+    }
+}
@@ -1,63 +1,160 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::TcpStream;
+use serde_json::Value;
+use crate::body::Body;
 
 // Struct ro represent an HTTP response
 #[derive(Debug)]
 pub struct HttpResponse {
     pub status_code: u16,
     pub headers: HashMap<String, String>,
-    pub body: Option<String>,
+    // In memory or streamed, see `text` and `json` for decoded views of in-memory bodies
+    pub body: Option<Body>,
 }
 
 impl HttpResponse {
+    // Response with a text body, which is what most handlers answer with
     pub fn new(status_code: u16, headers: HashMap<String, String>, body: Option<String>) -> Self {
-        HttpResponse { status_code, headers, body }
-    }
-
-    pub fn to_string(&self) -> String {
-        let status_text = match self.status_code {
-            100 => "Continue",
-            101 => "Switching Protocols",
-            200 => "OK",
-            201 => "Created",
-            202 => "Accepted",
-            204 => "No Content",
-            300 => "Multiple Choices",
-            301 => "Moved Permanently",
-            302 => "Found",
-            304 => "Not Modified",
-            400 => "Bad Request",
-            401 => "Unauthorized",
-            403 => "Forbidden",
-            404 => "Not Found",
-            405 => "Method Not Allowed",
-            500 => "Internal Server Error",
-            501 => "Not Implemented",
-            502 => "Bad Gateway",
-            503 => "Service Unavailable",
-            _ => "Unknown Status",
-        };
-        let mut response = format!("HTTP/1.1 {} {}\r\n", self.status_code, status_text);
-        
+        HttpResponse { status_code, headers, body: body.map(Body::from) }
+    }
+
+    // Response with a body that is not necessarily text, such as a file
+    pub fn from_bytes(status_code: u16, headers: HashMap<String, String>, body: Vec<u8>) -> Self {
+        HttpResponse { status_code, headers, body: Some(Body::Bytes(body)) }
+    }
+
+    // Response whose body is streamed out when it is sent
+    pub fn with_body(status_code: u16, headers: HashMap<String, String>, body: Body) -> Self {
+        HttpResponse { status_code, headers, body: Some(body) }
+    }
+
+    // Look up a header value by name, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The body when it is held in memory
+    pub fn bytes(&self) -> Option<&[u8]> {
+        self.body.as_ref()?.as_bytes()
+    }
+
+    // The body as text, with invalid UTF-8 replaced
+    pub fn text(&self) -> Option<Cow<'_, str>> {
+        self.bytes().map(String::from_utf8_lossy)
+    }
+
+    // The body parsed as JSON, `None` without a body or when it is not valid JSON
+    pub fn json(&self) -> Option<Value> {
+        serde_json::from_slice(self.bytes()?).ok()
+    }
+
+    // Whether the body goes out in chunks: a stream whose length the headers do not give
+    fn is_chunked(&self) -> bool {
+        matches!(self.body, Some(Body::Stream(_))) && self.header("Content-Length").is_none()
+    }
+
+    // Status line and headers, up to and including the blank line before the body
+    pub fn head(&self) -> String {
+        let mut response = format!("HTTP/1.1 {} {}\r\n", self.status_code, status_text(self.status_code));
+
         // Create a mutable copy of headers
         let mut headers = self.headers.clone();
-        
+
         // Add Content-Length header if there's a body
-        if let Some(body) = &self.body {
-            headers.entry("Content-Length".to_string())
-                .or_insert_with(|| body.len().to_string());
+        match self.body.as_ref().map(Body::len) {
+            Some(Some(length)) if self.header("Content-Length").is_none() => {
+                headers.insert("Content-Length".to_string(), length.to_string());
+            },
+            _ if self.is_chunked() => {
+                headers.insert("Transfer-Encoding".to_string(), "chunked".to_string());
+            },
+            _ => {},
         }
-        
+
         // Add headers to the response
         for (key, value) in headers {
             response.push_str(&format!("{}: {}\r\n", key, value));
         }
-        
+
         response.push_str("\r\n");
-        if let Some(body) = &self.body {
-            response.push_str(body);
+        response
+    }
+
+    // The response as it goes on the wire, for bodies held in memory; streamed bodies are
+    // left out, `write_to` sends those
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut response = self.head().into_bytes();
+        if let Some(body) = self.bytes() {
+            response.extend_from_slice(body);
         }
         response
     }
+
+    // Send the head, then the body as it is read
+    pub fn write_to(&mut self, stream: &mut TcpStream) -> io::Result<()> {
+        let chunked = self.is_chunked();
+        stream.write_all(self.head().as_bytes())?;
+        if let Some(body) = &mut self.body {
+            body.write_to(stream, chunked)?;
+        }
+        stream.flush()
+    }
+}
+
+// Reason phrase sent after a status code
+fn status_text(status_code: u16) -> &'static str {
+    match status_code {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        300 => "Multiple Choices",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        422 => "Unprocessable Entity",
+        424 => "Failed Dependency",
+        426 => "Upgrade Required",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown Status",
+    }
+}
+
+// The response as text, for logs; use `write_to` to send it.
+// Bodies that are not UTF-8 or not in memory show up as their length.
+impl fmt::Display for HttpResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.head())?;
+        match &self.body {
+            Some(Body::Bytes(bytes)) => match std::str::from_utf8(bytes) {
+                Ok(body) => f.write_str(body),
+                Err(_) => write!(f, "<{} bytes of binary content>", bytes.len()),
+            },
+            Some(Body::Stream(_)) => f.write_str("<streamed content>"),
+            Some(Body::File { length, .. }) => write!(f, "<{} bytes of file content>", length),
+            None => Ok(()),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -78,7 +175,7 @@ mod tests {
         // Assert: verify the response values
         assert_eq!(response.status_code, status_code);
         assert_eq!(response.headers, headers);
-        assert_eq!(response.body, body);
+        assert_eq!(response.text().as_deref(), body.as_deref());
     }
 
     #[test]
@@ -114,4 +211,29 @@ mod tests {
         let expected_response = "HTTP/1.1 204 No Content\r\n\r\n";
         assert_eq!(response_string, expected_response);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_http_response_binary_body() {
+        let body: Vec<u8> = vec![0, 159, 146, 150, 255];
+        let response = HttpResponse::from_bytes(200, HashMap::new(), body.clone());
+
+        let bytes = response.to_bytes();
+
+        assert!(response.head().contains("Content-Length: 5\r\n"));
+        assert_eq!(&bytes[bytes.len() - 5..], &body[..]);
+        assert_eq!(response.json(), None);
+    }
+
+    #[test]
+    fn test_http_response_streamed_body_head() {
+        let stream = HttpResponse::with_body(200, HashMap::new(), Body::stream(std::io::empty()));
+        let mut headers = HashMap::new();
+        headers.insert("Content-Length".to_string(), "10".to_string());
+        let sized = HttpResponse::with_body(200, headers, Body::stream(std::io::empty()));
+
+        assert!(stream.head().contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!stream.head().contains("Content-Length"));
+        assert!(!sized.head().contains("Transfer-Encoding"));
+        assert_eq!(stream.to_string(), "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n<streamed content>");
+    }
+}
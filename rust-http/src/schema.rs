@@ -0,0 +1,233 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use serde_json::{Map, Value};
+
+// Name of the file holding the JSON Schema of a collection directory
+pub const SCHEMA_FILE: &str = "_schema.json";
+
+// A single place where a document does not match its schema
+#[derive(Debug, PartialEq)]
+pub struct Violation {
+    pub path: String,
+    pub message: String,
+}
+
+// Load the schema of the collection a document file belongs to, if the collection has one
+pub fn schema_for(file_path: &Path) -> io::Result<Option<Value>> {
+    let schema_path = match file_path.parent() {
+        Some(parent) => parent.join(SCHEMA_FILE),
+        None => return Ok(None),
+    };
+    if !schema_path.is_file() {
+        return Ok(None);
+    }
+
+    let contents = fs::read_to_string(&schema_path)?;
+    serde_json::from_str(&contents)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Validate a document against a schema and collect every violation.
+// Supports the commonly used keywords: type, enum, const, properties, required,
+// additionalProperties, items, min/max for numbers, strings and arrays, and uniqueItems.
+// Unknown keywords are ignored, as JSON Schema requires.
+pub fn validate(schema: &Value, instance: &Value) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    validate_at(schema, instance, "", &mut violations);
+    violations
+}
+
+fn validate_at(schema: &Value, instance: &Value, path: &str, violations: &mut Vec<Violation>) {
+    let schema = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => {
+            violations.push(violation(path, "No value is allowed here"));
+            return;
+        },
+        Value::Object(schema) => schema,
+        _ => return,
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let allowed: Vec<&str> = match expected {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        if !allowed.is_empty() && !allowed.iter().any(|name| has_type(instance, name)) {
+            violations.push(violation(path, &format!("Expected type {}, found {}", allowed.join(" or "), type_name(instance))));
+            // The remaining keywords would only report noise for a value of the wrong type
+            return;
+        }
+    }
+
+    if let Some(Value::Array(options)) = schema.get("enum") {
+        if !options.contains(instance) {
+            violations.push(violation(path, "Value is not one of the allowed values"));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != instance {
+            violations.push(violation(path, &format!("Value must be {}", expected)));
+        }
+    }
+
+    match instance {
+        Value::Object(object) => validate_object(schema, object, path, violations),
+        Value::Array(items) => validate_array(schema, items, path, violations),
+        Value::String(text) => {
+            let length = text.chars().count() as u64;
+            if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+                if length < min {
+                    violations.push(violation(path, &format!("String is shorter than {} characters", min)));
+                }
+            }
+            if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+                if length > max {
+                    violations.push(violation(path, &format!("String is longer than {} characters", max)));
+                }
+            }
+        },
+        Value::Number(number) => {
+            let value = number.as_f64().unwrap_or_default();
+            let bound = |name: &str| schema.get(name).and_then(Value::as_f64);
+            if let Some(min) = bound("minimum").filter(|min| value < *min) {
+                violations.push(violation(path, &format!("Value must be at least {}", min)));
+            }
+            if let Some(max) = bound("maximum").filter(|max| value > *max) {
+                violations.push(violation(path, &format!("Value must be at most {}", max)));
+            }
+            if let Some(min) = bound("exclusiveMinimum").filter(|min| value <= *min) {
+                violations.push(violation(path, &format!("Value must be greater than {}", min)));
+            }
+            if let Some(max) = bound("exclusiveMaximum").filter(|max| value >= *max) {
+                violations.push(violation(path, &format!("Value must be less than {}", max)));
+            }
+        },
+        _ => {},
+    }
+}
+
+fn validate_object(schema: &Map<String, Value>, object: &Map<String, Value>, path: &str, violations: &mut Vec<Violation>) {
+    if let Some(Value::Array(required)) = schema.get("required") {
+        for name in required.iter().filter_map(Value::as_str) {
+            if !object.contains_key(name) {
+                violations.push(violation(&child_path(path, name), "Required property is missing"));
+            }
+        }
+    }
+
+    let properties = schema.get("properties").and_then(Value::as_object);
+    for (name, value) in object {
+        let property_path = child_path(path, name);
+        match properties.and_then(|properties| properties.get(name)) {
+            Some(property_schema) => validate_at(property_schema, value, &property_path, violations),
+            None => match schema.get("additionalProperties") {
+                Some(Value::Bool(false)) => {
+                    violations.push(violation(&property_path, "Additional property is not allowed"));
+                },
+                Some(additional) => validate_at(additional, value, &property_path, violations),
+                None => {},
+            },
+        }
+    }
+}
+
+fn validate_array(schema: &Map<String, Value>, items: &[Value], path: &str, violations: &mut Vec<Violation>) {
+    if let Some(item_schema) = schema.get("items") {
+        for (index, item) in items.iter().enumerate() {
+            validate_at(item_schema, item, &child_path(path, &index.to_string()), violations);
+        }
+    }
+    if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+        if (items.len() as u64) < min {
+            violations.push(violation(path, &format!("Array must have at least {} items", min)));
+        }
+    }
+    if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+        if (items.len() as u64) > max {
+            violations.push(violation(path, &format!("Array must have at most {} items", max)));
+        }
+    }
+    if schema.get("uniqueItems") == Some(&Value::Bool(true)) {
+        let distinct: HashSet<String> = items.iter().map(Value::to_string).collect();
+        if distinct.len() != items.len() {
+            violations.push(violation(path, "Array items must be unique"));
+        }
+    }
+}
+
+fn has_type(instance: &Value, name: &str) -> bool {
+    match name {
+        "integer" => instance.as_f64().map(|n| n.fract() == 0.0).unwrap_or(false),
+        "number" => instance.is_number(),
+        other => type_name(instance) == other,
+    }
+}
+
+fn type_name(instance: &Value) -> &'static str {
+    match instance {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+// Append a token to a JSON Pointer, escaping it as RFC 6901 requires
+fn child_path(path: &str, token: &str) -> String {
+    format!("{}/{}", path, token.replace('~', "~0").replace('/', "~1"))
+}
+
+fn violation(path: &str, message: &str) -> Violation {
+    Violation { path: path.to_string(), message: message.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn user_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["name", "email"],
+            "additionalProperties": false,
+            "properties": {
+                "name": {"type": "string", "minLength": 1},
+                "email": {"type": "string"},
+                "age": {"type": "integer", "minimum": 0},
+                "tags": {"type": "array", "items": {"type": "string"}, "uniqueItems": true}
+            }
+        })
+    }
+
+    #[test]
+    fn test_valid_document() {
+        let document = json!({"name": "Ana", "email": "ana@example.com", "age": 30, "tags": ["a", "b"]});
+
+        assert!(validate(&user_schema(), &document).is_empty());
+    }
+
+    #[test]
+    fn test_violations_report_paths() {
+        let document = json!({"name": "", "age": -1, "tags": ["a", 2], "extra": true});
+
+        let violations = validate(&user_schema(), &document);
+        let paths: Vec<&str> = violations.iter().map(|v| v.path.as_str()).collect();
+
+        assert_eq!(paths, vec!["/email", "/age", "/extra", "/name", "/tags/1"]);
+    }
+
+    #[test]
+    fn test_wrong_root_type() {
+        let violations = validate(&json!({"type": "object"}), &json!([1, 2]));
+
+        assert_eq!(violations, vec![Violation { path: "".to_string(), message: "Expected type object, found array".to_string() }]);
+    }
+}
@@ -0,0 +1,501 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Component, Path, PathBuf};
+use serde_json::Value;
+use crate::collection::read_documents;
+use crate::methods::error_response;
+use crate::response::HttpResponse;
+use crate::ttl;
+use crate::wal::write_atomic;
+
+// Path of the search endpoint
+pub const SEARCH_PATH: &str = "/_search";
+// File, inside each collection directory, holding its full-text index
+pub const SEARCH_FILE: &str = "_search.ndjson";
+// Marker, next to it, of an index that missed a change and is rebuilt by the next write
+const STALE_FILE: &str = "_search.stale";
+// Bytes of changes the index file may gather past twice its compacted size before it is rewritten
+const COMPACT_SLACK: u64 = 64 * 1024;
+// Hits returned when the client does not send `limit`
+const DEFAULT_LIMIT: usize = 20;
+// Most hits a client may ask for
+const MAX_LIMIT: usize = 100;
+
+// Inverted index over the string values of the documents of one collection.
+// `terms` maps a token to how often it appears in each document; `documents` keeps the
+// tokens of each document so a rewrite can drop the old postings without the old contents.
+//
+// The file is a `{"size"}` header followed by one `{"id", "terms"}` line per document. A
+// write appends the new term counts of the document it changed, empty once it is gone, and
+// the file is compacted back to one line per document when it outgrows twice its `size`.
+#[derive(Debug, Default, PartialEq)]
+pub struct SearchIndex {
+    terms: BTreeMap<String, BTreeMap<String, u32>>,
+    documents: BTreeMap<String, Vec<String>>,
+}
+
+impl SearchIndex {
+    pub fn load(collection_dir: &Path) -> io::Result<SearchIndex> {
+        let file = match File::open(collection_dir.join(SEARCH_FILE)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(SearchIndex::default()),
+            Err(e) => return Err(e),
+        };
+
+        let mut index = SearchIndex::default();
+        for line in BufReader::new(file).lines().skip(1) {
+            // A change cut short by a crash ends the file; the index was marked stale then
+            let change: Value = match serde_json::from_str(&line?) {
+                Ok(change) => change,
+                Err(_) => break,
+            };
+            let Some(id) = change["id"].as_str() else {
+                break;
+            };
+            let counts = change["terms"].as_object().into_iter().flatten()
+                .filter_map(|(term, count)| Some((term.clone(), count.as_u64()? as u32)))
+                .collect();
+            index.insert_counts(id, counts);
+        }
+        Ok(index)
+    }
+
+    // Build the index of every document currently in a collection
+    pub fn build(collection_dir: &Path) -> io::Result<SearchIndex> {
+        let mut index = SearchIndex::default();
+        for (id, document) in read_documents(collection_dir)? {
+            index.insert(&id, &document);
+        }
+        Ok(index)
+    }
+
+    // Persist the index whole, removing the file once the collection has nothing indexed
+    pub fn save(&self, collection_dir: &Path) -> io::Result<()> {
+        let path = collection_dir.join(SEARCH_FILE);
+        let saved = if self.documents.is_empty() {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        } else {
+            let mut lines = String::new();
+            for (id, terms) in &self.documents {
+                let counts: BTreeMap<&String, u32> = terms.iter().map(|term| (term, self.terms[term][id])).collect();
+                lines.push_str(&serde_json::json!({"id": id, "terms": counts}).to_string());
+                lines.push('\n');
+            }
+            write_atomic(&path, format!("{}\n{}", serde_json::json!({"size": lines.len()}), lines).as_bytes())
+        };
+        saved?;
+        match fs::remove_file(collection_dir.join(STALE_FILE)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn insert(&mut self, id: &str, document: &Value) {
+        self.insert_counts(id, term_counts(document));
+    }
+
+    // Index a document by how often each term appears in it, replacing what it had before
+    fn insert_counts(&mut self, id: &str, counts: BTreeMap<String, u32>) {
+        self.remove(id);
+        if counts.is_empty() {
+            return;
+        }
+
+        for (term, count) in &counts {
+            self.terms.entry(term.clone()).or_default().insert(id.to_string(), *count);
+        }
+        self.documents.insert(id.to_string(), counts.into_keys().collect());
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        for term in self.documents.remove(id).unwrap_or_default() {
+            if let Some(postings) = self.terms.get_mut(&term) {
+                postings.remove(id);
+                if postings.is_empty() {
+                    self.terms.remove(&term);
+                }
+            }
+        }
+    }
+
+    // Score every document matching at least one query token (TF-IDF).
+    // A token ending in `*` matches every term it prefixes.
+    pub fn score(&self, query: &[QueryToken]) -> HashMap<String, f64> {
+        let total = self.documents.len() as f64;
+        let mut scores: HashMap<String, f64> = HashMap::new();
+
+        for token in query {
+            let matching: Vec<&BTreeMap<String, u32>> = if token.prefix {
+                self.terms.range(token.text.clone()..)
+                    .take_while(|(term, _)| term.starts_with(&token.text))
+                    .map(|(_, postings)| postings)
+                    .collect()
+            } else {
+                self.terms.get(&token.text).into_iter().collect()
+            };
+
+            for postings in matching {
+                let idf = (1.0 + total / postings.len() as f64).ln();
+                for (id, count) in postings {
+                    *scores.entry(id.clone()).or_default() += *count as f64 * idf;
+                }
+            }
+        }
+        scores
+    }
+}
+
+// A search term as typed by the client, case-folded
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryToken {
+    pub text: String,
+    pub prefix: bool,
+}
+
+// Split a query into tokens; `jo*` asks for every term starting with `jo`
+pub fn parse_query(query: &str) -> Vec<QueryToken> {
+    query.split_whitespace()
+        .flat_map(|word| {
+            let prefix = word.ends_with('*');
+            let mut tokens: Vec<QueryToken> = tokenize(word).into_iter()
+                .map(|(_, text)| QueryToken { text, prefix: false })
+                .collect();
+            if let Some(last) = tokens.last_mut() {
+                last.prefix = prefix;
+            }
+            tokens
+        })
+        .collect()
+}
+
+// Runs of letters and digits, lowercased, with their byte range in `text`
+pub fn tokenize(text: &str) -> Vec<((usize, usize), String)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (position, c) in text.char_indices().chain([(text.len(), ' ')]) {
+        match (start, c.is_alphanumeric()) {
+            (None, true) => start = Some(position),
+            (Some(from), false) => {
+                tokens.push(((from, position), text[from..position].to_lowercase()));
+                start = None;
+            },
+            _ => {},
+        }
+    }
+    tokens
+}
+
+// How often each term appears in the string values of a document
+fn term_counts(document: &Value) -> BTreeMap<String, u32> {
+    let mut counts: BTreeMap<String, u32> = BTreeMap::new();
+    for (_, text) in string_fields(document) {
+        for (_, token) in tokenize(text) {
+            *counts.entry(token).or_default() += 1;
+        }
+    }
+    counts
+}
+
+// Keep the full-text index of a collection in step with a write to one of its documents.
+// `relative_path` is the document file below `data_dir`; reserved `_` paths are not indexed.
+// Called by the WAL for each applied mutation, before the record is marked done so a crash
+// replays it. Failures do not fail the write: the index is marked stale instead, and the
+// next write to the collection rebuilds it.
+pub fn update(data_dir: &Path, relative_path: &Path, current: Option<&Value>) {
+    let reserved = relative_path.components().any(|c| match c {
+        Component::Normal(name) => name.to_string_lossy().starts_with(['_', '.']),
+        _ => true,
+    });
+    let id = match relative_path.file_stem().and_then(|s| s.to_str()) {
+        Some(id) if !reserved && relative_path.extension().and_then(|e| e.to_str()) == Some("json") => id,
+        _ => return,
+    };
+    let collection_dir = data_dir.join(relative_path.parent().unwrap_or(Path::new("")));
+
+    let updated = if collection_dir.join(STALE_FILE).exists() {
+        SearchIndex::build(&collection_dir).and_then(|index| index.save(&collection_dir))
+    } else {
+        append_change(&collection_dir, id, current)
+    };
+    if let Err(e) = updated {
+        println!("Failed to update the search index of {}, it is rebuilt by the next write: {}", collection_dir.display(), e);
+        if let Err(e) = fs::write(collection_dir.join(STALE_FILE), b"") {
+            println!("Failed to mark the search index of {} as stale: {}", collection_dir.display(), e);
+        }
+    }
+}
+
+// Append the term counts of one document to the index file, compacting it when it grew too long
+fn append_change(collection_dir: &Path, id: &str, current: Option<&Value>) -> io::Result<()> {
+    let counts = current.map(term_counts).unwrap_or_default();
+    let path = collection_dir.join(SEARCH_FILE);
+    if counts.is_empty() && !path.exists() {
+        return Ok(());
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(format!("{}\n", serde_json::json!({"size": 0})).as_bytes())?;
+    }
+    file.write_all(format!("{}\n", serde_json::json!({"id": id, "terms": counts})).as_bytes())?;
+    file.sync_data()?;
+
+    let mut header = String::new();
+    BufReader::new(File::open(&path)?).read_line(&mut header)?;
+    let size = serde_json::from_str::<Value>(&header).ok().and_then(|header| header["size"].as_u64())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid search index {}", path.display())))?;
+    if file.metadata()?.len() > 2 * size + COMPACT_SLACK {
+        SearchIndex::load(collection_dir)?.save(collection_dir)?;
+    }
+    Ok(())
+}
+
+// Build the index of every collection below `data_dir` that has documents but no index yet,
+// such as data written before search existed, or a stale one. Returns how many collections were indexed.
+pub fn build_missing(data_dir: &Path) -> io::Result<usize> {
+    let mut built = 0;
+    for collection_dir in collection_dirs(data_dir)? {
+        if collection_dir.join(SEARCH_FILE).exists() && !collection_dir.join(STALE_FILE).exists() {
+            continue;
+        }
+        let index = SearchIndex::build(&collection_dir)?;
+        if !index.documents.is_empty() {
+            index.save(&collection_dir)?;
+            built += 1;
+        }
+    }
+    Ok(built)
+}
+
+// Function to handle `GET /_search?q=...&collection=...&limit=...`
+// Hits are ranked by relevance and carry the matching string fields with `<em>` highlights.
+pub fn handle_search(query: &HashMap<String, String>) -> HttpResponse {
+    println!("Handling search request");
+
+    let tokens = match query.get("q").map(|q| parse_query(q)) {
+        Some(tokens) if !tokens.is_empty() => tokens,
+        _ => return error_response(400, "Missing search query: use ?q=..."),
+    };
+    let limit = match query.get("limit").map(|l| l.parse::<usize>()) {
+        None => DEFAULT_LIMIT,
+        Some(Ok(limit)) if limit > 0 => limit.min(MAX_LIMIT),
+        Some(_) => return error_response(400, "Invalid limit: must be a positive number"),
+    };
+
+    let data_dir = Path::new("./files");
+    let collections = match query.get("collection") {
+        Some(collection) => {
+            let collection = collection.trim_matches('/');
+            let dir = data_dir.join(collection);
+            let safe = Path::new(collection).components().all(|c| matches!(c, Component::Normal(_)));
+            if !safe || !dir.is_dir() {
+                return error_response(404, "Collection not found");
+            }
+            Ok(vec![dir])
+        },
+        None => collection_dirs(data_dir),
+    };
+
+    let mut hits = Vec::new();
+    let result = collections.and_then(|collections| {
+        for collection_dir in collections {
+            let index = SearchIndex::load(&collection_dir)?;
+            for (id, score) in index.score(&tokens) {
+                // Documents removed behind the index's back, or expired, are dropped
+                let file_path = collection_dir.join(format!("{}.json", id));
+                if file_path.is_file() && !ttl::is_expired(&file_path) {
+                    hits.push((score, collection_dir.clone(), id));
+                }
+            }
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        println!("Failed to search documents: {}", e);
+        return error_response(500, "Failed to search documents");
+    }
+
+    hits.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal).then_with(|| (&a.1, &a.2).cmp(&(&b.1, &b.2))));
+    let total = hits.len();
+
+    let results: Vec<Value> = hits.into_iter()
+        .take(limit)
+        .filter_map(|(score, collection_dir, id)| {
+            let contents = fs::read_to_string(collection_dir.join(format!("{}.json", id))).ok()?;
+            let document: Value = serde_json::from_str(&contents).ok()?;
+            let collection = collection_dir.strip_prefix(data_dir).unwrap_or(&collection_dir).to_string_lossy().into_owned();
+            let path = if collection.is_empty() { format!("/{}", id) } else { format!("/{}/{}", collection, id) };
+            Some(serde_json::json!({
+                "collection": collection,
+                "id": id,
+                "path": path,
+                "score": (score * 1000.0).round() / 1000.0,
+                "highlights": highlights(&document, &tokens)
+            }))
+        })
+        .collect();
+
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(), "application/json".to_string());
+    HttpResponse::new(200, headers, Some(serde_json::json!({
+        "query": query.get("q"),
+        "total": total,
+        "hits": results
+    }).to_string()))
+}
+
+// String fields of a document that contain a query token, keyed by JSON Pointer,
+// with every matching word wrapped in `<em>`
+pub fn highlights(document: &Value, query: &[QueryToken]) -> BTreeMap<String, String> {
+    let matches = |token: &str| query.iter().any(|q| if q.prefix { token.starts_with(&q.text) } else { token == q.text });
+
+    let mut fields = BTreeMap::new();
+    for (pointer, text) in string_fields(document) {
+        let mut highlighted = String::new();
+        let mut last = 0;
+        for ((from, to), token) in tokenize(text) {
+            if matches(&token) {
+                highlighted.push_str(&text[last..from]);
+                highlighted.push_str(&format!("<em>{}</em>", &text[from..to]));
+                last = to;
+            }
+        }
+        if last > 0 {
+            highlighted.push_str(&text[last..]);
+            fields.insert(pointer, highlighted);
+        }
+    }
+    fields
+}
+
+// Every string value in a document with its JSON Pointer
+fn string_fields(document: &Value) -> Vec<(String, &str)> {
+    fn walk<'a>(value: &'a Value, pointer: String, fields: &mut Vec<(String, &'a str)>) {
+        match value {
+            Value::String(text) => fields.push((pointer, text)),
+            Value::Array(items) => {
+                for (position, item) in items.iter().enumerate() {
+                    walk(item, format!("{}/{}", pointer, position), fields);
+                }
+            },
+            Value::Object(object) => {
+                for (key, item) in object {
+                    walk(item, format!("{}/{}", pointer, key.replace('~', "~0").replace('/', "~1")), fields);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    let mut fields = Vec::new();
+    walk(document, String::new(), &mut fields);
+    fields
+}
+
+// The data directory and every non-reserved directory below it
+fn collection_dirs(data_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = vec![data_dir.to_path_buf()];
+    let mut next = 0;
+    while next < dirs.len() {
+        for entry in fs::read_dir(&dirs[next])? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if entry.file_type()?.is_dir() && !name.to_string_lossy().starts_with(['_', '.']) {
+                dirs.push(entry.path());
+            }
+        }
+        next += 1;
+    }
+    Ok(dirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::wal::{Mutation, Wal};
+
+    #[test]
+    fn test_tokenize_folds_case() {
+        let tokens: Vec<String> = tokenize("Ana-María O'Neil, ÁREA 51").into_iter().map(|(_, t)| t).collect();
+
+        assert_eq!(tokens, vec!["ana", "maría", "o", "neil", "área", "51"]);
+        assert_eq!(parse_query("Mar* lima"), vec![
+            QueryToken { text: "mar".to_string(), prefix: true },
+            QueryToken { text: "lima".to_string(), prefix: false },
+        ]);
+    }
+
+    #[test]
+    fn test_index_follows_writes_and_ranks() {
+        let dir = &std::env::temp_dir().join("rust-http-test_search_writes");
+        let _ = fs::remove_dir_all(dir);
+        let mut log = Wal::open(dir).unwrap();
+        log.commit(&[
+            Mutation::Put { path: PathBuf::from("users/1.json"), document: json!({"name": "Ana Lima", "city": "Lima"}) },
+            Mutation::Put { path: PathBuf::from("users/2.json"), document: json!({"name": "Luis", "city": "Lima"}) },
+            Mutation::Put { path: PathBuf::from("users/3.json"), document: json!({"name": "Marta", "tags": ["admin"]}) },
+            Mutation::Put { path: PathBuf::from("users/_trash/4.json"), document: json!({"document": {"name": "Lima"}}) },
+        ]).unwrap();
+
+        let index = SearchIndex::load(&dir.join("users")).unwrap();
+        let scores = index.score(&parse_query("lima"));
+        assert_eq!(scores.len(), 2, "Trash entries should not be indexed");
+        assert!(scores["1"] > scores["2"], "More occurrences should rank higher");
+        assert_eq!(index.score(&parse_query("adm*")).len(), 1);
+
+        log.commit(&[Mutation::Delete { path: PathBuf::from("users/1.json") }]).unwrap();
+        log.commit(&[Mutation::Put { path: PathBuf::from("users/2.json"), document: json!({"name": "Luis"}) }]).unwrap();
+        let index = SearchIndex::load(&dir.join("users")).unwrap();
+        assert!(index.score(&parse_query("lima")).is_empty());
+        assert_eq!(index, SearchIndex::build(&dir.join("users")).unwrap(), "Incremental updates should match a rebuild");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_changes_are_appended_and_stale_index_rebuilt() {
+        let dir = &std::env::temp_dir().join("rust-http-test_search_appends");
+        let _ = fs::remove_dir_all(dir);
+        let users = dir.join("users");
+        let mut log = Wal::open(dir).unwrap();
+        for version in ["Ana", "Ana Lima", "Ana Quito"] {
+            log.commit(&[Mutation::Put { path: PathBuf::from("users/1.json"), document: json!({"name": version}) }]).unwrap();
+        }
+
+        let lines = || fs::read_to_string(users.join(SEARCH_FILE)).unwrap().lines().count();
+        assert_eq!(lines(), 4, "Each write should append one line");
+        assert!(SearchIndex::load(&users).unwrap().score(&parse_query("lima")).is_empty());
+
+        // A missed change leaves the index stale; the next write rebuilds it whole
+        fs::write(users.join("2.json"), json!({"name": "Luis Lima"}).to_string()).unwrap();
+        fs::write(users.join(STALE_FILE), b"").unwrap();
+        log.commit(&[Mutation::Put { path: PathBuf::from("users/3.json"), document: json!({"name": "Marta"}) }]).unwrap();
+
+        assert_eq!(lines(), 4);
+        assert!(!users.join(STALE_FILE).exists());
+        assert_eq!(SearchIndex::load(&users).unwrap().score(&parse_query("lima")).len(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_highlights() {
+        let document = json!({"name": "Ana Lima", "address": {"city": "lima"}, "age": 31});
+
+        let fields = highlights(&document, &parse_query("LIM*"));
+
+        assert_eq!(fields.get("/name").unwrap(), "Ana <em>Lima</em>");
+        assert_eq!(fields.get("/address/city").unwrap(), "<em>lima</em>");
+        assert_eq!(fields.len(), 2);
+    }
+
+    #[test]
+    fn test_handle_search_requires_query() {
+        assert_eq!(handle_search(&HashMap::new()).status_code, 400);
+    }
+}
@@ -1,24 +1,53 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use uuid::Uuid;
+use crate::cache;
+use crate::config::Config;
+use crate::proxy::Proxy;
 use crate::request::HttpRequest;
+use crate::transaction::{self, OpenTransaction};
 use crate::client::Client;
-use std::net::{TcpStream, TcpListener};
+use std::net::TcpListener;
 use threadpool::ThreadPool;
-use log::{error, info};
+use std::path::Path;
 use std::thread;
-use std::io::{Write, Read};
 use std::time::Duration;
+use crate::search;
+use crate::trash;
+use crate::ttl;
+use crate::wal;
+use crate::webhooks;
+
+// How often the trash purge job looks for expired documents
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+// How often the sweeper looks for expired documents
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+// How often idle transactions are rolled back
+const TRANSACTION_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+// How long a worker waits on a client that stopped sending before dropping the connection
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
 
 // Main server struct with session management
 pub struct Server {
     pub sessions: HashMap<String, String>,
+    pub config: Config,
+    pub transactions: HashMap<String, OpenTransaction>,
+    // Shared by the requests being proxied, so balancing and health checks see all of them
+    pub proxy: Arc<Proxy>,
 }
 
 impl Server {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
+        Self::with_config(Config::default())
+    }
+
+    pub fn with_config(config: Config) -> Self {
         Self {
             sessions: HashMap::new(),
+            proxy: Arc::new(Proxy::new(&config.proxy_routes, config.proxy_timeout)),
+            config,
+            transactions: HashMap::new(),
         }
     }
     pub fn handle_cookie(&mut self, request: &HttpRequest) -> String {
@@ -40,14 +69,69 @@ impl Server {
 
     pub fn run(server: Arc<Mutex<Server>>) -> Result<(), Box<dyn std::error::Error>> {
         let listener = TcpListener::bind("127.0.0.1:8080")?;
+
+        // Open the write-ahead log now so a crash is recovered before serving requests,
+        // and index for search whatever was stored before the full-text index existed
+        match wal::shared() {
+            Ok(log) => match search::build_missing(log.data_dir()) {
+                Ok(0) => {},
+                Ok(built) => println!("Built the search index of {} collections", built),
+                Err(e) => println!("Failed to build the search index: {}", e),
+            },
+            // Requests answer 503 until the log can be opened
+            Err(e) => println!("{}", e),
+        }
         println!("Server running on localhost:8080");
 
         // Create a thread pool with 4 threads
         let pool = ThreadPool::new(100);
 
+        // Empty the trash in the background once documents outlive the retention period
+        let trash_retention = server.lock().unwrap().config.trash_retention;
+        if let Some(retention) = trash_retention {
+            Self::schedule(&pool, PURGE_INTERVAL, move || {
+                match trash::purge_expired(Path::new("./files"), retention) {
+                    Ok(0) => {},
+                    Ok(purged) => println!("Purged {} documents from the trash", purged),
+                    Err(e) => println!("Failed to purge trash: {}", e),
+                }
+            });
+        }
+
+        // Remove documents whose TTL has run out
+        Self::schedule(&pool, EXPIRY_SWEEP_INTERVAL, || {
+            match ttl::sweep_expired(Path::new("./files")) {
+                Ok(0) => {},
+                Ok(removed) => println!("Removed {} expired documents", removed),
+                Err(e) => println!("Failed to sweep expired documents: {}", e),
+            }
+        });
+
+        // Roll back transactions their client abandoned
+        let sessions = Arc::clone(&server);
+        Self::schedule(&pool, TRANSACTION_SWEEP_INTERVAL, move || {
+            let pruned = transaction::prune_expired(&mut sessions.lock().unwrap().transactions);
+            if pruned > 0 {
+                println!("Rolled back {} idle transactions", pruned);
+            }
+        });
+
+        // Size the response cache before the first request fills it
+        if let Some(cache_size) = server.lock().unwrap().config.cache_size {
+            cache::configure(cache_size);
+        }
+
+        // Notify the webhooks registered on collections of every change
+        webhooks::start(&pool);
+
         for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
+                    // A partial head or a body shorter than its `Content-Length` must not hold a worker forever
+                    if let Err(e) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+                        println!("Failed to set read timeout: {}", e);
+                        continue;
+                    }
                     let server_clone = Arc::clone(&server);
                     pool.execute(move || {
                         let mut client = Client { stream };
@@ -60,13 +144,28 @@ impl Server {
 
         Ok(())
     }
+
+    // Run a background job on the thread pool every `interval`
+    fn schedule<F>(pool: &ThreadPool, interval: Duration, job: F)
+    where
+        F: Fn() + Send + Clone + 'static,
+    {
+        let pool = pool.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            pool.execute(job.clone());
+        });
+    }
 }
 
 
 // Fixed Thread Pool Tests
 #[cfg(test)]
+#[allow(unused_variables, clippy::unused_io_amount, clippy::useless_format)]
 mod tests {
     use super::*;
+    use std::net::TcpStream;
+    use std::io::{Write, Read};
 
     #[test]
     fn test_new_session_creation_without_cookie() {
@@ -78,7 +177,7 @@ mod tests {
             method: "GET".to_string(),
             path: "/".to_string(),
             _headers: vec![],
-            body: "".to_string(),
+            body: Vec::new(),
             cookie: None,
         };
 
@@ -102,7 +201,7 @@ mod tests {
             method: "GET".to_string(),
             path: "/".to_string(),
             _headers: vec![],
-            body: "".to_string(),
+            body: Vec::new(),
             cookie: Some("abc".to_string()),
         };
 
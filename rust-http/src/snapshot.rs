@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json::Value;
+use uuid::Uuid;
+use crate::cache;
+use crate::config::Config;
+use crate::methods::{error_response, storage_unavailable};
+use crate::response::HttpResponse;
+use crate::wal::{self, Wal, WAL_FILE};
+
+// Path of the snapshot admin endpoints
+pub const SNAPSHOTS_PATH: &str = "/_admin/snapshots";
+// Directory, next to `./files`, where the server keeps its snapshots
+pub const SNAPSHOT_DIR: &str = "./snapshots";
+// Compression level of `.tar.zst` snapshots
+const ZSTD_LEVEL: i32 = 3;
+
+// Archive format of a snapshot
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnapshotFormat {
+    Tar,
+    TarZstd,
+}
+
+impl SnapshotFormat {
+    pub fn parse(format: &str) -> Option<SnapshotFormat> {
+        match format {
+            "tar" => Some(SnapshotFormat::Tar),
+            "zstd" | "tar.zst" => Some(SnapshotFormat::TarZstd),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            SnapshotFormat::Tar => "tar",
+            SnapshotFormat::TarZstd => "tar.zst",
+        }
+    }
+}
+
+// A snapshot archive found in the snapshot directory
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub name: String,
+    pub format: SnapshotFormat,
+    pub path: PathBuf,
+    pub size: u64,
+    pub created_at: u64,
+}
+
+impl Snapshot {
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "name": self.name,
+            "format": self.format.extension(),
+            "size": self.size,
+            "created_at": self.created_at
+        })
+    }
+}
+
+// Archive the data directory of `log` into `snapshot_dir`.
+// The log stays locked only while every file is hard-linked into a staging directory:
+// documents are replaced through a rename, so the links keep the point-in-time contents
+// while writers go on and the archive is written.
+pub fn create_snapshot(log: impl Deref<Target = Wal>, snapshot_dir: &Path, name: Option<&str>, format: SnapshotFormat) -> io::Result<Snapshot> {
+    let name = match name {
+        Some(name) if valid_name(name) => name.to_string(),
+        Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid snapshot name")),
+        None => default_name(),
+    };
+    if find_snapshot(snapshot_dir, &name)?.is_some() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("Snapshot {} already exists", name)));
+    }
+
+    fs::create_dir_all(snapshot_dir)?;
+    let staging = snapshot_dir.join(format!(".staging-{}", Uuid::new_v4()));
+    let linked = link_tree(log.data_dir(), &staging, true);
+    drop(log);
+
+    let result = linked.and_then(|_| {
+        let path = snapshot_dir.join(format!("{}.{}", name, format.extension()));
+        let temp_path = snapshot_dir.join(format!(".{}.{}.tmp", name, format.extension()));
+        write_archive(&staging, &temp_path, format)?;
+        fs::rename(&temp_path, &path)?;
+        Ok(path)
+    });
+    fs::remove_dir_all(&staging)?;
+
+    let path = result?;
+    let metadata = fs::metadata(&path)?;
+    Ok(Snapshot { name, format, path, size: metadata.len(), created_at: modified_secs(&metadata) })
+}
+
+// Snapshots in `snapshot_dir`, oldest first
+pub fn list_snapshots(snapshot_dir: &Path) -> io::Result<Vec<Snapshot>> {
+    if !snapshot_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(snapshot_dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let parsed = [SnapshotFormat::TarZstd, SnapshotFormat::Tar].into_iter().find_map(|format| {
+            let name = file_name.strip_suffix(&format!(".{}", format.extension()))?;
+            valid_name(name).then(|| (name.to_string(), format))
+        });
+        if let Some((name, format)) = parsed {
+            let metadata = entry.metadata()?;
+            snapshots.push(Snapshot { name, format, path: entry.path(), size: metadata.len(), created_at: modified_secs(&metadata) });
+        }
+    }
+
+    snapshots.sort_by(|a, b| (a.created_at, &a.name).cmp(&(b.created_at, &b.name)));
+    Ok(snapshots)
+}
+
+pub fn find_snapshot(snapshot_dir: &Path, name: &str) -> io::Result<Option<Snapshot>> {
+    if !valid_name(name) {
+        return Ok(None);
+    }
+    Ok(list_snapshots(snapshot_dir)?.into_iter().find(|snapshot| snapshot.name == name))
+}
+
+// Replace the data directory of `log` with the contents of a snapshot.
+// The archive is unpacked next to the data directory and swapped in while the log is
+// locked, so no write lands in between. When the restored log can not be opened the old
+// directory is swapped back and `log` keeps serving it.
+pub fn restore_snapshot(log: &mut Wal, snapshot: &Snapshot) -> io::Result<()> {
+    let data_dir = log.data_dir().to_path_buf();
+    let staging = sibling(&data_dir, "restore")?;
+
+    let swapped = read_archive(&snapshot.path, &staging, snapshot.format).and_then(|_| exchange(&staging, &data_dir));
+    if let Err(e) = swapped {
+        // Best-effort cleanup, the data directory was not touched
+        let _ = fs::remove_dir_all(&staging);
+        return Err(e);
+    }
+
+    // The staging directory now holds the previous documents and their log
+    match Wal::open(&data_dir) {
+        Ok(restored) => {
+            *log = restored;
+            // Every cached response may describe the documents that were swapped out
+            cache::shared().clear();
+        },
+        Err(e) => {
+            exchange(&staging, &data_dir)?;
+            let _ = fs::remove_dir_all(&staging);
+            return Err(e);
+        },
+    }
+    fs::remove_dir_all(staging)
+}
+
+// Hidden directory next to `dir` for `purpose`: `./.files-restore-<uuid>`
+fn sibling(dir: &Path, purpose: &str) -> io::Result<PathBuf> {
+    let name = dir.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid data directory"))?
+        .to_string_lossy();
+    Ok(dir.with_file_name(format!(".{}-{}-{}", name, purpose, Uuid::new_v4().simple())))
+}
+
+// Swap two directories in one step with `renameat2`, so readers find the data directory
+// at every moment. File systems that can not exchange fall back to `rename_swap`.
+#[cfg(target_os = "linux")]
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = |path: &Path| CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+    let (c_a, c_b) = (c_path(a)?, c_path(b)?);
+    let swapped = unsafe { libc::renameat2(libc::AT_FDCWD, c_a.as_ptr(), libc::AT_FDCWD, c_b.as_ptr(), libc::RENAME_EXCHANGE) };
+    if swapped == 0 {
+        return Ok(());
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EINVAL) | Some(libc::ENOSYS) => rename_swap(a, b),
+        _ => Err(error),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    rename_swap(a, b)
+}
+
+// Swap two directories through a third name. Between the renames `b` does not exist,
+// and readers of it answer as if its documents were missing.
+fn rename_swap(a: &Path, b: &Path) -> io::Result<()> {
+    let temp = sibling(b, "swap")?;
+    fs::rename(b, &temp)?;
+    if let Err(e) = fs::rename(a, b) {
+        fs::rename(&temp, b)?;
+        return Err(e);
+    }
+    fs::rename(temp, a)
+}
+
+pub fn delete_snapshot(snapshot: &Snapshot) -> io::Result<()> {
+    fs::remove_file(&snapshot.path)
+}
+
+// Function to handle requests under `/_admin/snapshots`
+//   GET    /_admin/snapshots                        list snapshots
+//   POST   /_admin/snapshots?format=tar|zstd&name=  create a snapshot
+//   POST   /_admin/snapshots/{name}/restore         restore a snapshot
+//   DELETE /_admin/snapshots/{name}                 delete a snapshot
+// Restoring and deleting are answered with 403 unless `snapshot_admin` is enabled.
+pub fn handle_snapshot_request(method: &str, path: &str, query: &HashMap<String, String>, config: &Config) -> HttpResponse {
+    println!("Handling snapshot request: {} {}", method, path);
+
+    let snapshot_dir = Path::new(SNAPSHOT_DIR);
+    let rest = path.strip_prefix(SNAPSHOTS_PATH).unwrap_or_default().trim_matches('/');
+    let (name, action) = rest.split_once('/').unwrap_or((rest, ""));
+
+    match (method, name, action) {
+        ("GET", "", "") => match list_snapshots(snapshot_dir) {
+            Ok(snapshots) => {
+                let mut headers = HashMap::new();
+                headers.insert("Content-Type".to_string(), "application/json".to_string());
+                let snapshots: Vec<Value> = snapshots.iter().map(Snapshot::to_json).collect();
+                HttpResponse::new(200, headers, Some(Value::from(snapshots).to_string()))
+            },
+            Err(e) => {
+                println!("Failed to list snapshots: {}", e);
+                error_response(500, "Failed to list snapshots")
+            },
+        },
+        ("POST", "", "") => {
+            let format = match query.get("format").map(|format| SnapshotFormat::parse(format)) {
+                None => SnapshotFormat::TarZstd,
+                Some(Some(format)) => format,
+                Some(None) => return error_response(400, "Invalid format: must be tar or zstd"),
+            };
+
+            match wal::shared().and_then(|log| create_snapshot(log, snapshot_dir, query.get("name").map(String::as_str), format)) {
+                Ok(snapshot) => {
+                    let location = format!("{}/{}", SNAPSHOTS_PATH, snapshot.name);
+                    let mut headers = HashMap::new();
+                    headers.insert("Location".to_string(), location);
+                    let mut body = snapshot.to_json();
+                    body["status_code"] = Value::from(201);
+                    body["message"] = Value::from("Snapshot created successfully");
+                    HttpResponse::new(201, headers, Some(body.to_string()))
+                },
+                Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                    error_response(400, "Invalid snapshot name: use letters, digits, '-' and '_'")
+                },
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => error_response(409, "Snapshot already exists"),
+                Err(e) if wal::is_unavailable(&e) => storage_unavailable(&e),
+                Err(e) => {
+                    println!("Failed to create snapshot: {}", e);
+                    error_response(500, "Failed to create snapshot")
+                },
+            }
+        },
+        (_, "", _) => error_response(405, "Method not allowed"),
+        ("POST", _, "restore") | ("DELETE", _, "") if !config.snapshot_admin => {
+            error_response(403, "Restoring and deleting snapshots is disabled")
+        },
+        (method, name, action) => {
+            let snapshot = match find_snapshot(snapshot_dir, name) {
+                Ok(Some(snapshot)) => snapshot,
+                Ok(None) => return error_response(404, "Snapshot not found"),
+                Err(e) => {
+                    println!("Failed to read snapshots: {}", e);
+                    return error_response(500, "Failed to read snapshots");
+                },
+            };
+
+            match (method, action) {
+                ("POST", "restore") => match wal::shared().and_then(|mut log| restore_snapshot(&mut log, &snapshot)) {
+                    Ok(_) => error_response(200, "Snapshot restored successfully"),
+                    Err(e) if wal::is_unavailable(&e) => storage_unavailable(&e),
+                    Err(e) => {
+                        println!("Failed to restore snapshot: {}", e);
+                        error_response(500, "Failed to restore snapshot")
+                    },
+                },
+                ("DELETE", "") => match delete_snapshot(&snapshot) {
+                    Ok(_) => error_response(200, "Snapshot deleted successfully"),
+                    Err(e) => {
+                        println!("Failed to delete snapshot: {}", e);
+                        error_response(500, "Failed to delete snapshot")
+                    },
+                },
+                _ => error_response(405, "Method not allowed"),
+            }
+        },
+    }
+}
+
+// Mirror `source` into `destination` with hard links, copying when linking is not possible.
+// The WAL and temporary `.` files are left out.
+fn link_tree(source: &Path, destination: &Path, root: bool) -> io::Result<()> {
+    fs::create_dir_all(destination)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name_str = name.to_string_lossy();
+        if name_str.starts_with('.') || (root && name_str == WAL_FILE) {
+            continue;
+        }
+
+        let path = entry.path();
+        let target = destination.join(&name);
+        if entry.file_type()?.is_dir() {
+            link_tree(&path, &target, false)?;
+        } else if fs::hard_link(&path, &target).is_err() {
+            fs::copy(&path, &target)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_archive(source: &Path, path: &Path, format: SnapshotFormat) -> io::Result<()> {
+    let file = File::create(path)?;
+    match format {
+        SnapshotFormat::Tar => {
+            let mut builder = tar::Builder::new(file);
+            builder.append_dir_all(".", source)?;
+            builder.into_inner()?.sync_all()
+        },
+        SnapshotFormat::TarZstd => {
+            let mut builder = tar::Builder::new(zstd::Encoder::new(file, ZSTD_LEVEL)?);
+            builder.append_dir_all(".", source)?;
+            builder.into_inner()?.finish()?.sync_all()
+        },
+    }
+}
+
+fn read_archive(path: &Path, destination: &Path, format: SnapshotFormat) -> io::Result<()> {
+    let file = File::open(path)?;
+    fs::create_dir_all(destination)?;
+    // `unpack` refuses entries that would land outside the destination
+    match format {
+        SnapshotFormat::Tar => tar::Archive::new(file).unpack(destination),
+        SnapshotFormat::TarZstd => tar::Archive::new(zstd::Decoder::new(file)?).unpack(destination),
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn default_name() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    format!("{}-{}", now, &Uuid::new_v4().simple().to_string()[..8])
+}
+
+fn modified_secs(metadata: &fs::Metadata) -> u64 {
+    metadata.modified().ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::wal::Mutation;
+
+    fn read(path: &Path) -> Value {
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let root = Path::new("./files/test_snapshot_restore");
+        let data_dir = root.join("data");
+        let snapshot_dir = root.join("snapshots");
+        let mut log = Wal::open(&data_dir).unwrap();
+        log.commit(&[Mutation::Put { path: PathBuf::from("users/1.json"), document: json!({"v": 1}) }]).unwrap();
+
+        for format in [SnapshotFormat::Tar, SnapshotFormat::TarZstd] {
+            let name = format!("before-{}", format.extension().replace('.', "-"));
+            let snapshot = create_snapshot(&log, &snapshot_dir, Some(&name), format).unwrap();
+            assert_eq!(snapshot.name, name);
+        }
+        assert_eq!(list_snapshots(&snapshot_dir).unwrap().len(), 2);
+
+        // Writes after the snapshot replace the document through a rename, the snapshot keeps the old one
+        log.commit(&[
+            Mutation::Put { path: PathBuf::from("users/1.json"), document: json!({"v": 2}) },
+            Mutation::Put { path: PathBuf::from("users/2.json"), document: json!({"v": 2}) },
+        ]).unwrap();
+
+        for name in ["before-tar", "before-tar-zst"] {
+            let snapshot = find_snapshot(&snapshot_dir, name).unwrap().unwrap();
+            restore_snapshot(&mut log, &snapshot).unwrap();
+
+            assert_eq!(read(&data_dir.join("users/1.json")), json!({"v": 1}));
+            assert!(!data_dir.join("users/2.json").exists(), "Later documents should be gone");
+            log.commit(&[Mutation::Put { path: PathBuf::from("users/2.json"), document: json!({"v": 3}) }]).unwrap();
+        }
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_failed_restore_keeps_the_data() {
+        let root = Path::new("./files/test_snapshot_rollback");
+        let _ = fs::remove_dir_all(root);
+        let data_dir = root.join("data");
+        let snapshot_dir = root.join("snapshots");
+        let mut log = Wal::open(&data_dir).unwrap();
+        log.commit(&[Mutation::Put { path: PathBuf::from("users/1.json"), document: json!({"v": 1}) }]).unwrap();
+
+        // A snapshot whose log can not be opened
+        fs::create_dir_all(&snapshot_dir).unwrap();
+        let mut builder = tar::Builder::new(File::create(snapshot_dir.join("broken.tar")).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_size(0);
+        builder.append_data(&mut header, WAL_FILE, io::empty()).unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+
+        let snapshot = find_snapshot(&snapshot_dir, "broken").unwrap().unwrap();
+        assert!(restore_snapshot(&mut log, &snapshot).is_err());
+
+        assert_eq!(read(&data_dir.join("users/1.json")), json!({"v": 1}));
+        log.commit(&[Mutation::Put { path: PathBuf::from("users/2.json"), document: json!({"v": 2}) }]).unwrap();
+        assert_eq!(read(&data_dir.join("users/2.json")), json!({"v": 2}));
+        assert_eq!(fs::read_dir(root).unwrap().count(), 2, "Staging directories should be removed");
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_swap_directories() {
+        let root = Path::new("./files/test_snapshot_swap");
+        let _ = fs::remove_dir_all(root);
+        for name in ["a", "b"] {
+            fs::create_dir_all(root.join(name)).unwrap();
+            fs::write(root.join(name).join("from"), name).unwrap();
+        }
+
+        exchange(&root.join("a"), &root.join("b")).unwrap();
+        assert_eq!(fs::read_to_string(root.join("a/from")).unwrap(), "b");
+        rename_swap(&root.join("a"), &root.join("b")).unwrap();
+        assert_eq!(fs::read_to_string(root.join("a/from")).unwrap(), "a");
+        assert_eq!(fs::read_dir(root).unwrap().count(), 2);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_restore_and_delete_need_snapshot_admin() {
+        let config = Config::default();
+
+        let restore = handle_snapshot_request("POST", "/_admin/snapshots/any/restore", &HashMap::new(), &config);
+        let delete = handle_snapshot_request("DELETE", "/_admin/snapshots/any", &HashMap::new(), &config);
+
+        assert_eq!(restore.status_code, 403);
+        assert_eq!(delete.status_code, 403);
+    }
+
+    #[test]
+    fn test_snapshot_rejects_bad_names() {
+        let root = Path::new("./files/test_snapshot_names");
+        let log = Wal::open(&root.join("data")).unwrap();
+        let snapshot_dir = root.join("snapshots");
+
+        let error = create_snapshot(&log, &snapshot_dir, Some("../escape"), SnapshotFormat::Tar).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        create_snapshot(&log, &snapshot_dir, Some("once"), SnapshotFormat::Tar).unwrap();
+        let error = create_snapshot(&log, &snapshot_dir, Some("once"), SnapshotFormat::TarZstd).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert!(find_snapshot(&snapshot_dir, "../once").unwrap().is_none());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
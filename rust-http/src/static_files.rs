@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use crate::body::Body;
+use crate::config::Config;
+use crate::methods::error_response;
+use crate::request::{percent_decode, percent_encode};
+use crate::response::HttpResponse;
+use crate::ttl;
+
+// Directory served when the configuration names none
+pub const DEFAULT_ROOT: &str = "./static";
+// File served for a directory when it exists
+const INDEX_FILE: &str = "index.html";
+
+// Content-Type by file extension, anything else is `application/octet-stream`
+const MIME_TYPES: [(&str, &str); 33] = [
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("zst", "application/zstd"),
+    ("wasm", "application/wasm"),
+    ("mp3", "audio/mpeg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+];
+
+// Content-Type of a file, going by its extension
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
+    MIME_TYPES.iter()
+        .find(|(known, _)| *known == extension)
+        .map(|(_, mime)| *mime)
+        .unwrap_or("application/octet-stream")
+}
+
+// Whether `path` is below the prefix static files are mounted at
+pub fn is_static_path(config: &Config, path: &str) -> bool {
+    match &config.static_prefix {
+        Some(prefix) => {
+            let prefix = prefix.trim_end_matches('/');
+            path == prefix || path.starts_with(&format!("{}/", prefix))
+        },
+        None => false,
+    }
+}
+
+// File below `root` a request path names, `None` for paths trying to leave it or reach hidden files
+fn resolve(root: &Path, relative: &str) -> Option<PathBuf> {
+    let mut file_path = root.to_path_buf();
+    for segment in relative.split('/').filter(|segment| !segment.is_empty() && *segment != ".") {
+        // `+` is a plain character in paths, only query strings use it for spaces
+        let segment = percent_decode(&segment.replace('+', "%2B"));
+        if segment.starts_with('.') || segment.contains(['/', '\\', '\0']) {
+            return None;
+        }
+        file_path.push(segment);
+    }
+    Some(file_path)
+}
+
+// Function to handle GET and HEAD below the static prefix.
+// Files are returned as they are; directories get their `index.html` or, when enabled, a listing.
+pub fn handle_static(method: &str, path: &str, config: &Config) -> HttpResponse {
+    println!("Handling static request: {} {}", method, path);
+
+    if method != "GET" && method != "HEAD" {
+        let mut response = error_response(405, "Method not allowed");
+        response.headers.insert("Allow".to_string(), "GET, HEAD".to_string());
+        return response;
+    }
+
+    let (path_only, query) = match path.split_once('?') {
+        Some((path_only, query)) => (path_only, Some(query)),
+        None => (path, None),
+    };
+    let prefix = config.static_prefix.as_deref().unwrap_or_default().trim_end_matches('/');
+    let root = config.static_root.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_ROOT));
+    let Some(mut file_path) = resolve(&root, &path_only[prefix.len()..]) else {
+        return error_response(404, "File not found");
+    };
+
+    // Symbolic links must not lead out of the root either
+    match (fs::canonicalize(&root), fs::canonicalize(&file_path)) {
+        (Ok(root), Ok(target)) if target.starts_with(&root) => {},
+        (Ok(_), Ok(_)) => return error_response(403, "Forbidden"),
+        _ => return error_response(404, "File not found"),
+    }
+
+    if file_path.is_dir() {
+        // Relative links in the index and the listing need the trailing slash
+        if !path_only.ends_with('/') {
+            let mut location = format!("{}/", path_only);
+            if let Some(query) = query {
+                location.push_str(&format!("?{}", query));
+            }
+            let mut response = error_response(301, "Moved permanently");
+            response.headers.insert("Location".to_string(), location);
+            return response;
+        }
+
+        let index = file_path.join(INDEX_FILE);
+        if index.is_file() {
+            file_path = index;
+        } else if config.static_listing {
+            return match render_listing(&file_path, path_only, path_only.len() > prefix.len() + 1) {
+                Ok(listing) => file_response(method, "text/html; charset=utf-8", None, Body::from(listing)),
+                Err(e) => {
+                    println!("Failed to list {}: {}", file_path.display(), e);
+                    error_response(500, "Failed to list directory")
+                },
+            };
+        } else {
+            return error_response(403, "Directory listing is disabled");
+        }
+    }
+
+    // The file is streamed out when the response is sent rather than read in here
+    let opened = File::open(&file_path).and_then(|file| {
+        let modified = file.metadata()?.modified()?;
+        Ok((Body::file(file)?, modified))
+    });
+    let (body, modified) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            println!("Failed to read {}: {}", file_path.display(), e);
+            return error_response(500, "Failed to read file");
+        },
+    };
+    let modified = modified.duration_since(UNIX_EPOCH).ok().map(|modified| modified.as_secs());
+    file_response(method, mime_type(&file_path), modified, body)
+}
+
+// A successful response; HEAD gets the length of what GET would return, without the content
+fn file_response(method: &str, content_type: &str, modified: Option<u64>, body: Body) -> HttpResponse {
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(), content_type.to_string());
+    headers.insert("Content-Length".to_string(), body.len().unwrap_or_default().to_string());
+    // Size and modification time tell versions of a file apart, for `If-Range`
+    if let Some(modified) = modified {
+        headers.insert("Last-Modified".to_string(), ttl::format_http_date(modified));
+        headers.insert("ETag".to_string(), format!("\"{:x}-{:x}\"", body.len().unwrap_or_default(), modified));
+    }
+    if method == "HEAD" {
+        return HttpResponse::new(200, headers, None);
+    }
+    HttpResponse::with_body(200, headers, body)
+}
+
+// HTML page listing a directory: subdirectories first, then files, skipping hidden entries
+fn render_listing(dir: &Path, request_path: &str, has_parent: bool) -> std::io::Result<String> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        entries.push((!metadata.is_dir(), name, metadata.len()));
+    }
+    entries.sort();
+
+    let title = escape_html(request_path);
+    let mut html = format!("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n", title);
+    if has_parent {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (is_file, name, size) in entries {
+        let href = percent_encode(&name);
+        if is_file {
+            html.push_str(&format!("<li><a href=\"{}\">{}</a> {} bytes</li>\n", href, escape_html(&name), size));
+        } else {
+            html.push_str(&format!("<li><a href=\"{}/\">{}/</a></li>\n", href, escape_html(&name)));
+        }
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+    Ok(html)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn config(root: &str, listing: bool) -> Config {
+        Config {
+            static_prefix: Some("/assets".to_string()),
+            static_root: Some(PathBuf::from(root)),
+            static_listing: listing,
+            ..Config::default()
+        }
+    }
+
+    // What the body of a file response holds
+    fn contents(response: HttpResponse) -> Vec<u8> {
+        let mut contents = Vec::new();
+        match response.body {
+            Some(Body::File { mut file, offset: 0, .. }) => file.read_to_end(&mut contents).unwrap(),
+            body => panic!("Expected a whole file, got {:?}", body),
+        };
+        contents
+    }
+
+    #[test]
+    fn test_serves_files_with_their_mime_type() {
+        let root = "./files/test_static_files";
+        fs::create_dir_all(format!("{}/docs", root)).unwrap();
+        let binary: Vec<u8> = (0..=255).collect();
+        fs::write(format!("{}/logo.PNG", root), &binary).unwrap();
+        fs::write(format!("{}/index.html", root), "<h1>Home</h1>").unwrap();
+        fs::write(format!("{}/docs/a b.txt", root), "hello").unwrap();
+        fs::write(format!("{}/.secret", root), "hidden").unwrap();
+
+        let response = handle_static("GET", "/assets/logo.PNG", &config(root, false));
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.header("Content-Type"), Some("image/png"));
+        assert_eq!(response.header("Content-Length"), Some("256"));
+        assert!(response.header("Last-Modified").is_some());
+        assert_eq!(contents(response), binary, "Binary content must come back unchanged");
+
+        let response = handle_static("HEAD", "/assets/docs/a%20b.txt", &config(root, false));
+        assert_eq!(response.header("Content-Type"), Some("text/plain; charset=utf-8"));
+        assert_eq!(response.header("Content-Length"), Some("5"));
+        assert!(response.body.is_none());
+
+        let response = handle_static("GET", "/assets/", &config(root, false));
+        assert_eq!(contents(response), b"<h1>Home</h1>");
+        let response = handle_static("GET", "/assets?v=1", &config(root, false));
+        assert_eq!(response.status_code, 301);
+        assert_eq!(response.header("Location"), Some("/assets/?v=1"));
+
+        assert_eq!(handle_static("GET", "/assets/.secret", &config(root, false)).status_code, 404);
+        assert_eq!(handle_static("GET", "/assets/../test_static_files/logo.PNG", &config(root, false)).status_code, 404);
+        assert_eq!(handle_static("GET", "/assets/missing.css", &config(root, false)).status_code, 404);
+        assert_eq!(handle_static("PUT", "/assets/logo.PNG", &config(root, false)).status_code, 405);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_directory_listings() {
+        let root = "./files/test_static_listing";
+        fs::create_dir_all(format!("{}/sub", root)).unwrap();
+        fs::write(format!("{}/sub/<b>.txt", root), "x").unwrap();
+        fs::create_dir_all(format!("{}/sub/nested", root)).unwrap();
+
+        assert_eq!(handle_static("GET", "/assets/sub/", &config(root, false)).status_code, 403);
+
+        let response = handle_static("GET", "/assets/sub/", &config(root, true));
+        let html = response.text().unwrap();
+        assert_eq!(response.header("Content-Type"), Some("text/html; charset=utf-8"));
+        assert!(html.contains("<a href=\"../\">../</a>"));
+        assert!(html.contains("<a href=\"%3Cb%3E.txt\">&lt;b&gt;.txt</a> 1 bytes"));
+        assert!(html.find("nested/").unwrap() < html.find("&lt;b&gt;.txt").unwrap(), "Directories are listed first");
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_mime_types() {
+        assert_eq!(mime_type(Path::new("app.js")), "text/javascript; charset=utf-8");
+        assert_eq!(mime_type(Path::new("font.woff2")), "font/woff2");
+        assert_eq!(mime_type(Path::new("archive.tar.gz")), "application/gzip");
+        assert_eq!(mime_type(Path::new("README")), "application/octet-stream");
+    }
+}
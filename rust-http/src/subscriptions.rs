@@ -0,0 +1,357 @@
+use std::collections::HashSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_json::{json, Value};
+use crate::events::{self, ChangeEvent};
+use crate::websocket::{Decoder, Frame, Message, Opcode, CLOSE_NORMAL, CLOSE_UNSUPPORTED_DATA};
+
+// Path of the WebSocket subscription endpoint
+pub const WS_PATH: &str = "/ws";
+// How long the hub waits for a change before reading from the sockets again
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+// An idle connection is pinged after this long, and dropped after twice as long without an answer
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+// Unsent bytes a slow subscriber may pile up before it is disconnected
+const MAX_BACKLOG: usize = 4 * 1024 * 1024;
+// Reads per socket and pass, so a chatty client can not starve the others
+const MAX_READS: usize = 16;
+
+// How a connection served by the hub receives changes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Protocol {
+    // WebSocket frames, for the paths subscribed through commands
+    WebSocket,
+    // A `/_changes` event stream for a single prefix; the client never writes to it
+    EventStream,
+}
+
+// One connection served by the hub and the paths it listens to
+struct Subscriber {
+    stream: TcpStream,
+    protocol: Protocol,
+    decoder: Decoder,
+    outgoing: Vec<u8>,
+    paths: HashSet<String>,
+    // Last event the connection was offered, so events are never sent twice
+    seq: u64,
+    last_seen: Instant,
+    last_sent: Instant,
+    pinged: bool,
+    // A close frame was queued: nothing else is sent and the socket is shut once it is flushed
+    closing: bool,
+    open: bool,
+}
+
+impl Subscriber {
+    fn new(stream: TcpStream, protocol: Protocol, seq: u64) -> Self {
+        Subscriber {
+            stream,
+            protocol,
+            decoder: Decoder::default(),
+            outgoing: Vec::new(),
+            paths: HashSet::new(),
+            seq,
+            last_seen: Instant::now(),
+            last_sent: Instant::now(),
+            pinged: false,
+            closing: false,
+            open: true,
+        }
+    }
+
+    // Read whatever the client sent and answer the messages it completes
+    fn receive(&mut self) {
+        let mut buffer = [0; 4096];
+        for _ in 0..MAX_READS {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    self.open = false;
+                    return;
+                },
+                // Event stream clients have nothing to say, reading only notices them leaving
+                Ok(_) if self.protocol == Protocol::EventStream => {},
+                Ok(bytes_read) => {
+                    self.decoder.feed(&buffer[..bytes_read]);
+                    self.last_seen = Instant::now();
+                    self.pinged = false;
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    self.open = false;
+                    return;
+                },
+            }
+        }
+
+        while !self.closing {
+            match self.decoder.next_message() {
+                Ok(Some(message)) => self.on_message(message),
+                Ok(None) => break,
+                Err(error) => self.close(error.code, error.reason),
+            }
+        }
+    }
+
+    fn on_message(&mut self, message: Message) {
+        match message {
+            Message::Text(text) => {
+                let reply = self.on_command(&text);
+                self.send(&Frame::text(&reply.to_string()));
+            },
+            Message::Binary(_) => self.close(CLOSE_UNSUPPORTED_DATA, "Only text messages are supported"),
+            Message::Ping(payload) => self.send(&Frame::new(Opcode::Pong, payload)),
+            Message::Pong(_) => {},
+            // Echo the close the client started
+            Message::Close(code, _) => self.close(code.unwrap_or(CLOSE_NORMAL), ""),
+        }
+    }
+
+    // Handle `{"action": "subscribe", "path": "/users"}` and `{"action": "unsubscribe", ...}`
+    fn on_command(&mut self, text: &str) -> Value {
+        let command: Value = match serde_json::from_str(text) {
+            Ok(command) => command,
+            Err(_) => return json!({"type": "error", "message": "Messages must be JSON"}),
+        };
+        let Some(path) = command["path"].as_str() else {
+            return json!({"type": "error", "message": "Missing path"});
+        };
+        let path = format!("/{}", path.trim_matches('/'));
+
+        match command["action"].as_str() {
+            Some("subscribe") => {
+                self.paths.insert(path.clone());
+                json!({"type": "subscribed", "path": path})
+            },
+            Some("unsubscribe") => {
+                self.paths.remove(&path);
+                json!({"type": "unsubscribed", "path": path})
+            },
+            _ => json!({"type": "error", "message": "Action must be subscribe or unsubscribe"}),
+        }
+    }
+
+    fn notify(&mut self, event: &ChangeEvent) {
+        if event.seq <= self.seq {
+            return;
+        }
+        self.seq = event.seq;
+        if self.paths.iter().any(|path| event.matches(path)) {
+            match self.protocol {
+                Protocol::WebSocket => self.send(&Frame::text(&event.to_json().to_string())),
+                Protocol::EventStream => self.queue(event.to_sse().as_bytes()),
+            }
+        }
+    }
+
+    // Ping a client that has been quiet for a while, and give up on one that never answers.
+    // Event streams get a keep-alive comment instead, which is also how a closed one is noticed.
+    fn check_alive(&mut self) {
+        if self.protocol == Protocol::EventStream {
+            if self.last_sent.elapsed() >= events::KEEP_ALIVE {
+                self.queue(b": keep-alive\n\n");
+            }
+            return;
+        }
+        let idle = self.last_seen.elapsed();
+        if idle >= PING_INTERVAL * 2 {
+            self.open = false;
+        } else if idle >= PING_INTERVAL && !self.pinged {
+            self.pinged = true;
+            self.send(&Frame::new(Opcode::Ping, Vec::new()));
+        }
+    }
+
+    fn send(&mut self, frame: &Frame) {
+        self.queue(&frame.encode());
+    }
+
+    fn queue(&mut self, bytes: &[u8]) {
+        if self.closing {
+            return;
+        }
+        self.outgoing.extend_from_slice(bytes);
+        self.last_sent = Instant::now();
+        if self.outgoing.len() > MAX_BACKLOG {
+            println!("Dropping a subscriber that is not keeping up");
+            self.open = false;
+        }
+    }
+
+    fn close(&mut self, code: u16, reason: &str) {
+        self.send(&Frame::close(code, reason));
+        self.closing = true;
+    }
+
+    // Write as much of the queued frames as the socket takes without blocking
+    fn flush(&mut self) {
+        while self.open && !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => self.open = false,
+                Ok(written) => {
+                    self.outgoing.drain(..written);
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => self.open = false,
+            }
+        }
+        if self.closing && self.outgoing.is_empty() {
+            let _ = self.stream.shutdown(Shutdown::Both);
+            self.open = false;
+        }
+    }
+}
+
+fn hub() -> &'static Mutex<Sender<Subscriber>> {
+    static HUB: OnceLock<Mutex<Sender<Subscriber>>> = OnceLock::new();
+    HUB.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || run_hub(receiver));
+        Mutex::new(sender)
+    })
+}
+
+// Hand a connection that completed the WebSocket handshake over to the hub.
+// The hub is a single thread serving every subscriber with non-blocking sockets,
+// so an idle subscription does not keep a pool thread busy.
+pub fn attach(stream: TcpStream) -> io::Result<()> {
+    register(Subscriber::new(stream, Protocol::WebSocket, events::last_seq()))
+}
+
+// Hand a `/_changes` connection whose response head was sent over to the hub, which
+// streams the events after `seq` that match `prefix`
+pub fn attach_feed(stream: TcpStream, prefix: String, seq: u64) -> io::Result<()> {
+    let mut subscriber = Subscriber::new(stream, Protocol::EventStream, seq);
+    subscriber.paths.insert(prefix);
+    register(subscriber)
+}
+
+fn register(subscriber: Subscriber) -> io::Result<()> {
+    subscriber.stream.set_nonblocking(true)?;
+    hub().lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+        .send(subscriber)
+        .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "The subscription hub has stopped"))
+}
+
+fn run_hub(receiver: Receiver<Subscriber>) {
+    let mut subscribers: Vec<Subscriber> = Vec::new();
+    let mut last_seq = events::last_seq();
+
+    loop {
+        // Sleep until a connection arrives while there is nobody to serve
+        let mut arrived = Vec::new();
+        if subscribers.is_empty() {
+            match receiver.recv() {
+                Ok(subscriber) => arrived.push(subscriber),
+                Err(_) => return,
+            }
+            last_seq = events::last_seq();
+        }
+        arrived.extend(receiver.try_iter());
+        for mut subscriber in arrived {
+            // Catch up on what was published before the hub took the connection
+            if subscriber.seq < last_seq {
+                for event in events::wait_since(subscriber.seq, Duration::ZERO) {
+                    if event.seq <= last_seq {
+                        subscriber.notify(&event);
+                    }
+                }
+            }
+            subscribers.push(subscriber);
+        }
+
+        // Waiting for changes doubles as the pause between reads
+        let changes = events::wait_since(last_seq, POLL_INTERVAL);
+        if let Some(event) = changes.last() {
+            last_seq = event.seq;
+        }
+
+        for subscriber in subscribers.iter_mut() {
+            subscriber.receive();
+            for event in &changes {
+                subscriber.notify(event);
+            }
+            subscriber.check_alive();
+            subscriber.flush();
+        }
+        subscribers.retain(|subscriber| subscriber.open);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::path::Path;
+    use crate::events::ChangeKind;
+
+    // Read the next frame the server sent
+    fn read_frame(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> Frame {
+        loop {
+            if let Some((frame, used)) = Frame::parse(buffer, false).unwrap() {
+                buffer.drain(..used);
+                return frame;
+            }
+            let mut chunk = [0; 1024];
+            let bytes_read = stream.read(&mut chunk).unwrap();
+            assert!(bytes_read > 0, "Connection closed");
+            buffer.extend_from_slice(&chunk[..bytes_read]);
+        }
+    }
+
+    #[test]
+    fn test_subscribers_receive_changes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        attach(listener.accept().unwrap().0).unwrap();
+        let key = [1, 2, 3, 4];
+        let mut buffer = Vec::new();
+
+        client.write_all(&Frame::text(r#"{"action": "subscribe", "path": "/test_ws_feed/"}"#).encode_masked(key)).unwrap();
+        let reply: Value = serde_json::from_slice(&read_frame(&mut client, &mut buffer).payload).unwrap();
+        assert_eq!(reply, json!({"type": "subscribed", "path": "/test_ws_feed"}));
+
+        events::publish(ChangeKind::Create, Path::new("./files/test_ws_other/1.json"), None, "");
+        events::publish(ChangeKind::Create, Path::new("./files/test_ws_feed/1.json"), Some(&json!({"a": 1})), "");
+        let event: Value = serde_json::from_slice(&read_frame(&mut client, &mut buffer).payload).unwrap();
+        assert_eq!(event["path"], "/test_ws_feed/1", "Only subscribed paths are sent");
+        assert_eq!(event["document"], json!({"a": 1}));
+
+        client.write_all(&Frame::new(Opcode::Ping, b"hi".to_vec()).encode_masked(key)).unwrap();
+        assert_eq!(read_frame(&mut client, &mut buffer), Frame::new(Opcode::Pong, b"hi".to_vec()));
+
+        client.write_all(&Frame::close(CLOSE_NORMAL, "").encode_masked(key)).unwrap();
+        assert_eq!(read_frame(&mut client, &mut buffer), Frame::close(CLOSE_NORMAL, ""));
+    }
+
+    #[test]
+    fn test_event_streams_catch_up_and_follow() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // Published before the hub takes the connection, after the client's Last-Event-ID
+        let seq = events::last_seq();
+        events::publish(ChangeKind::Create, Path::new("./files/test_sse_feed/1.json"), None, "");
+        attach_feed(listener.accept().unwrap().0, "/test_sse_feed".to_string(), seq).unwrap();
+        events::publish(ChangeKind::Create, Path::new("./files/test_sse_other/1.json"), None, "");
+        events::publish(ChangeKind::Delete, Path::new("./files/test_sse_feed/1.json"), None, "");
+
+        let mut received = String::new();
+        while received.matches("\n\n").count() < 2 {
+            let mut chunk = [0; 1024];
+            let bytes_read = client.read(&mut chunk).unwrap();
+            assert!(bytes_read > 0, "Connection closed");
+            received.push_str(&String::from_utf8_lossy(&chunk[..bytes_read]));
+        }
+        let kinds: Vec<&str> = received.lines().filter_map(|line| line.strip_prefix("event: ")).collect();
+        assert_eq!(kinds, ["create", "delete"], "Only the prefix is streamed, missed events first");
+        assert!(!received.contains("test_sse_other"));
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde_json::Value;
+use uuid::Uuid;
+use crate::bulk::{item_result, parse_operations, stage_operation};
+use crate::events::{self, ChangeKind};
+use crate::history;
+use crate::methods::{is_reserved_id, error_response, storage_unavailable, Context};
+use crate::response::HttpResponse;
+use crate::server::Server;
+use crate::ttl;
+use crate::wal::{self, Mutation};
+
+// Path of the transaction endpoints
+pub const TRANSACTIONS_PATH: &str = "/_transactions";
+// Open transactions idle for longer than this are rolled back
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Why a transaction could not be committed
+#[derive(Debug)]
+pub enum TransactionError {
+    // Another writer changed this file after the transaction first saw it
+    Conflict(PathBuf),
+    Io(io::Error),
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::Conflict(path) => write!(f, "Conflicting change to {}", path.display()),
+            TransactionError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for TransactionError {
+    fn from(e: io::Error) -> Self {
+        TransactionError::Io(e)
+    }
+}
+
+// Changes to several documents that are committed together or not at all.
+// Every file the transaction touches is versioned when first seen; the commit fails
+// with a conflict if any of them changed in the meantime.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    // Version of every touched file when first seen, `None` if it did not exist
+    versions: HashMap<PathBuf, Option<String>>,
+    // Staged contents, `None` for a staged deletion
+    staged: HashMap<PathBuf, Option<Value>>,
+    // Staged files that are documents and get a revision and an expiry on commit, unlike
+    // trash entries, with the expiry their write asked for
+    documents: HashMap<PathBuf, Option<u64>>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Read a document as the transaction sees it, staged changes included
+    pub fn get(&mut self, id: &str) -> io::Result<Option<Value>> {
+        self.read_path(&document_path(id))
+    }
+
+    // Stage a new document, failing if it already exists
+    pub fn create(&mut self, id: &str, document: Value) -> io::Result<()> {
+        let path = document_path(id);
+        if self.read_path(&path)?.is_some() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", id)));
+        }
+        self.stage_document(path, Some(document), None)
+    }
+
+    // Stage the contents of a document, creating or replacing it
+    pub fn put(&mut self, id: &str, document: Value) -> io::Result<()> {
+        self.stage_document(document_path(id), Some(document), None)
+    }
+
+    // Stage the removal of a document, returning whether it existed
+    pub fn delete(&mut self, id: &str) -> io::Result<bool> {
+        let path = document_path(id);
+        let existed = self.read_path(&path)?.is_some();
+        self.stage_document(path, None, None)?;
+        Ok(existed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.staged.is_empty()
+    }
+
+    // Read a file below `./files`, staged contents first
+    pub(crate) fn read_path(&mut self, path: &Path) -> io::Result<Option<Value>> {
+        self.track(path)?;
+        if let Some(staged) = self.staged.get(path) {
+            return Ok(staged.clone());
+        }
+        // An expired document the sweeper has not removed yet counts as absent
+        if ttl::is_expired(path) {
+            return Ok(None);
+        }
+
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Stage the contents of a file below `./files`, `None` deleting it
+    pub(crate) fn stage_path(&mut self, path: PathBuf, contents: Option<Value>) -> io::Result<()> {
+        self.track(&path)?;
+        self.staged.insert(path, contents);
+        Ok(())
+    }
+
+    // Stage a document like `stage_path`, with the expiry requested for it; `None` leaves
+    // the current expiry or the collection's `default_ttl` to apply
+    pub(crate) fn stage_document(&mut self, path: PathBuf, contents: Option<Value>, expires_at: Option<u64>) -> io::Result<()> {
+        self.documents.insert(path.clone(), expires_at);
+        self.stage_path(path, contents)
+    }
+
+    // Check for conflicting writers and write every staged change as one WAL record
+    pub fn commit(self, ctx: &Context) -> Result<(), TransactionError> {
+        let limit = ctx.config.history_limit;
+
+        // Holding the log keeps other writers out between the check and the write
+        let mut log = wal::shared()?;
+        for (path, version) in &self.versions {
+            if current_version(path)? != *version {
+                return Err(TransactionError::Conflict(path.clone()));
+            }
+        }
+
+        // Documents written over nothing or over an expired document start a new life
+        let created: HashSet<&PathBuf> = self.documents.keys()
+            .filter(|path| matches!(self.staged.get(*path), Some(Some(_))))
+            .filter(|path| !path.exists() || ttl::is_expired(path))
+            .collect();
+        for path in self.documents.keys().filter(|path| !created.contains(path)) {
+            history::ensure_baseline(path, limit)?;
+        }
+        let mut mutations: Vec<Mutation> = self.staged.iter()
+            .map(|(path, contents)| match contents {
+                Some(document) => Mutation::Put { path: path.clone(), document: document.clone() },
+                None => Mutation::Delete { path: path.clone() },
+            })
+            .collect();
+        // Expiries are written in the same record, and removed with their document
+        for (path, expires_at) in &self.documents {
+            match self.staged.get(path) {
+                Some(Some(_)) => mutations.extend(ttl::expiry_mutation(path, *expires_at, created.contains(path))?),
+                Some(None) => mutations.extend(ttl::clear_mutation(path)),
+                None => {},
+            }
+        }
+        if !mutations.is_empty() {
+            log.commit_store(mutations)?;
+        }
+
+        // Revisions are numbered while the log is still held
+
+        for path in self.documents.keys() {
+            match self.staged.get(path) {
+                Some(Some(document)) => {
+                    let kind = if created.contains(path) { ChangeKind::Create } else { ChangeKind::Update };
+                    events::publish(kind, path, Some(document), &ctx.session_id);
+                    if let Err(e) = history::record(path, document, &ctx.session_id, limit) {
+                        println!("Failed to record revision: {}", e);
+                    }
+                },
+                Some(None) => {
+                    events::publish(ChangeKind::Delete, path, None, &ctx.session_id);
+                },
+                None => {},
+            }
+        }
+        drop(log);
+        Ok(())
+    }
+
+    fn track(&mut self, path: &Path) -> io::Result<()> {
+        if !self.versions.contains_key(path) {
+            let version = current_version(path)?;
+            self.versions.insert(path.to_path_buf(), version);
+        }
+        Ok(())
+    }
+}
+
+// Version of a file's contents (FNV-1a), stable across runs of the server
+pub fn version_of(contents: &[u8]) -> String {
+    let hash = contents.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+fn current_version(path: &Path) -> io::Result<Option<String>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(version_of(&contents))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn document_path(id: &str) -> PathBuf {
+    PathBuf::from(format!("./files/{}.json", id))
+}
+
+// A transaction opened over HTTP, owned by the session that started it
+#[derive(Debug)]
+pub struct OpenTransaction {
+    pub owner: String,
+    pub last_used: Instant,
+    pub transaction: Transaction,
+}
+
+// Function to handle requests under `/_transactions`
+//   POST   /_transactions              begin, answers with the transaction ID
+//   GET    /_transactions/{id}/{path}  read a document as the transaction sees it
+//   POST   /_transactions/{id}         stage operations, same format as `/_bulk`
+//   POST   /_transactions/{id}/commit  commit, 409 if another writer got in between
+//   DELETE /_transactions/{id}         roll back
+pub fn handle_transaction_request(method: &str, path: &str, body: &str, server: &Arc<Mutex<Server>>, ctx: &Context) -> HttpResponse {
+    println!("Handling transaction request: {} {}", method, path);
+
+    let rest = path.strip_prefix(TRANSACTIONS_PATH).unwrap_or_default().trim_matches('/');
+    if rest.is_empty() {
+        return match method {
+            "POST" => begin(server, ctx),
+            _ => error_response(405, "Method not allowed"),
+        };
+    }
+    let (id, action) = rest.split_once('/').unwrap_or((rest, ""));
+
+    // Take the transaction out of the registry so the server lock is not held while working on it
+    let mut open = {
+        let mut server_lock = server.lock().unwrap();
+        prune_expired(&mut server_lock.transactions);
+        match server_lock.transactions.remove(id) {
+            Some(open) if open.owner == ctx.session_id => open,
+            Some(open) => {
+                server_lock.transactions.insert(id.to_string(), open);
+                return error_response(404, "Transaction not found");
+            },
+            None => return error_response(404, "Transaction not found"),
+        }
+    };
+
+    let response = match (method, action) {
+        ("POST", "commit") => {
+            return match open.transaction.commit(ctx) {
+                Ok(_) => error_response(200, "Transaction committed"),
+                Err(TransactionError::Conflict(path)) => error_response(409, &format!("Transaction conflicts with a concurrent change to {}", path.display())),
+                Err(TransactionError::Io(e)) if wal::is_unavailable(&e) => storage_unavailable(&e),
+                Err(TransactionError::Io(e)) => {
+                    println!("Failed to commit transaction: {}", e);
+                    error_response(500, "Failed to commit transaction")
+                },
+            };
+        },
+        ("DELETE", "") => return error_response(200, "Transaction rolled back"),
+        ("POST", "") => stage(&mut open.transaction, body, ctx),
+        ("GET", document) if is_reserved_id(document) => error_response(404, "File not found"),
+        ("GET", document) if !document.is_empty() => match open.transaction.get(document) {
+            Ok(Some(document)) => {
+                let mut headers = HashMap::new();
+                headers.insert("Content-Type".to_string(), "application/json".to_string());
+                HttpResponse::new(200, headers, Some(document.to_string()))
+            },
+            Ok(None) => error_response(404, "File not found"),
+            Err(e) => {
+                println!("Failed to read file: {}", e);
+                error_response(500, "Failed to read file")
+            },
+        },
+        _ => error_response(405, "Method not allowed"),
+    };
+
+    open.last_used = Instant::now();
+    server.lock().unwrap().transactions.insert(id.to_string(), open);
+    response
+}
+
+// Roll back open transactions idle for longer than the timeout, returning how many were dropped
+pub fn prune_expired(transactions: &mut HashMap<String, OpenTransaction>) -> usize {
+    let before = transactions.len();
+    transactions.retain(|_, open| open.last_used.elapsed() < TRANSACTION_TIMEOUT);
+    before - transactions.len()
+}
+
+fn begin(server: &Arc<Mutex<Server>>, ctx: &Context) -> HttpResponse {
+    let id = Uuid::new_v4().to_string();
+    server.lock().unwrap().transactions.insert(id.clone(), OpenTransaction {
+        owner: ctx.session_id.clone(),
+        last_used: Instant::now(),
+        transaction: Transaction::new(),
+    });
+
+    let location = format!("{}/{}", TRANSACTIONS_PATH, id);
+    let mut headers = HashMap::new();
+    headers.insert("Location".to_string(), location.clone());
+    HttpResponse::new(201, headers, Some(serde_json::json!({
+        "status_code": 201,
+        "message": "Transaction started",
+        "id": id,
+        "location": location
+    }).to_string()))
+}
+
+// Stage operations into an open transaction, each item succeeding or failing on its own
+fn stage(transaction: &mut Transaction, body: &str, ctx: &Context) -> HttpResponse {
+    let operations = match parse_operations(body) {
+        Ok(operations) => operations,
+        Err(message) => return error_response(400, &message),
+    };
+
+    let items: Vec<Value> = operations.iter().enumerate()
+        .map(|(index, operation)| {
+            let response = stage_operation(transaction, operation, ctx).unwrap_or_else(|response| response);
+            item_result(index, operation, &response)
+        })
+        .collect();
+
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(), "application/json".to_string());
+    HttpResponse::new(200, headers, Some(serde_json::json!({
+        "status_code": 200,
+        "items": items
+    }).to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn read(id: &str) -> Option<Value> {
+        fs::read_to_string(document_path(id)).ok().map(|contents| serde_json::from_str(&contents).unwrap())
+    }
+
+    #[test]
+    fn test_move_between_collections() {
+        fs::create_dir_all("./files/test_tx_move").unwrap();
+        fs::write(document_path("test_tx_move/a"), json!({"name": "Ana"}).to_string()).unwrap();
+
+        let mut transaction = Transaction::new();
+        let document = transaction.get("test_tx_move/a").unwrap().unwrap();
+        transaction.put("test_tx_move/archive/a", document).unwrap();
+        assert!(transaction.delete("test_tx_move/a").unwrap());
+        assert_eq!(read("test_tx_move/archive/a"), None, "Nothing is written before the commit");
+
+        transaction.commit(&Context::default()).unwrap();
+
+        assert_eq!(read("test_tx_move/archive/a"), Some(json!({"name": "Ana"})));
+        assert_eq!(read("test_tx_move/a"), None);
+
+        fs::remove_dir_all("./files/test_tx_move").unwrap();
+    }
+
+    #[test]
+    fn test_expiries_are_committed_with_documents() {
+        let expiry = |id: &str| ttl::expires_at(&document_path(id)).unwrap();
+        fs::create_dir_all("./files/test_tx_ttl").unwrap();
+        fs::write(document_path("test_tx_ttl/old"), json!({"v": 1}).to_string()).unwrap();
+        fs::write(document_path("test_tx_ttl/gone"), json!({"v": 1}).to_string()).unwrap();
+        let mut setup = Transaction::new();
+        setup.stage_document(document_path("test_tx_ttl/old"), Some(json!({"v": 1})), Some(1)).unwrap();
+        setup.stage_document(document_path("test_tx_ttl/gone"), Some(json!({"v": 1})), Some(ttl::now() + 3600)).unwrap();
+        setup.commit(&Context::default()).unwrap();
+        assert_eq!(expiry("test_tx_ttl/old"), Some(1));
+
+        // An expired document reads as absent and its ID can be created again
+        let mut transaction = Transaction::new();
+        assert_eq!(transaction.get("test_tx_ttl/old").unwrap(), None);
+        transaction.create("test_tx_ttl/old", json!({"v": 2})).unwrap();
+        assert!(transaction.delete("test_tx_ttl/gone").unwrap());
+        transaction.commit(&Context::default()).unwrap();
+
+        assert_eq!(expiry("test_tx_ttl/old"), None, "The stale expiry should not be inherited");
+        assert_eq!(expiry("test_tx_ttl/gone"), None, "Deleting a document should drop its expiry");
+        assert!(!Path::new("./files/test_tx_ttl/_expires/gone.json").exists());
+
+        fs::remove_dir_all("./files/test_tx_ttl").unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_change_is_a_conflict() {
+        fs::create_dir_all("./files/test_tx_conflict").unwrap();
+        fs::write(document_path("test_tx_conflict/a"), json!({"v": 1}).to_string()).unwrap();
+
+        let mut transaction = Transaction::new();
+        let mut document = transaction.get("test_tx_conflict/a").unwrap().unwrap();
+        document["v"] = json!(2);
+        transaction.put("test_tx_conflict/a", document).unwrap();
+        transaction.put("test_tx_conflict/b", json!({"v": 2})).unwrap();
+
+        // Another writer changes the document before the commit
+        fs::write(document_path("test_tx_conflict/a"), json!({"v": 10}).to_string()).unwrap();
+
+        match transaction.commit(&Context::default()) {
+            Err(TransactionError::Conflict(path)) => assert_eq!(path, document_path("test_tx_conflict/a")),
+            other => panic!("Expected a conflict, got {:?}", other),
+        }
+        assert_eq!(read("test_tx_conflict/a"), Some(json!({"v": 10})));
+        assert_eq!(read("test_tx_conflict/b"), None, "No staged change should be written");
+
+        fs::remove_dir_all("./files/test_tx_conflict").unwrap();
+    }
+
+    #[test]
+    fn test_http_begin_stage_commit() {
+        let server = Arc::new(Mutex::new(Server::new()));
+        let ctx = Context { session_id: "owner".to_string(), ..Context::default() };
+
+        let response = handle_transaction_request("POST", TRANSACTIONS_PATH, "", &server, &ctx);
+        assert_eq!(response.status_code, 201);
+        let location = response.headers.get("Location").unwrap().clone();
+
+        let operations = json!([{"op": "create", "path": "test_tx_http/1", "body": {"v": 1}}]).to_string();
+        let response = handle_transaction_request("POST", &location, &operations, &server, &ctx);
+        assert_eq!(response.status_code, 200);
+
+        // Other sessions can not see the transaction
+        let stranger = Context { session_id: "stranger".to_string(), ..Context::default() };
+        let response = handle_transaction_request("GET", &format!("{}/test_tx_http/1", location), "", &server, &stranger);
+        assert_eq!(response.status_code, 404);
+
+        let response = handle_transaction_request("GET", &format!("{}/test_tx_http/1", location), "", &server, &ctx);
+        assert_eq!(response.text().unwrap(), json!({"v": 1}).to_string());
+        assert_eq!(read("test_tx_http/1"), None);
+
+        let response = handle_transaction_request("POST", &format!("{}/commit", location), "", &server, &ctx);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(read("test_tx_http/1"), Some(json!({"v": 1})));
+        assert!(server.lock().unwrap().transactions.is_empty(), "Committed transaction should be closed");
+
+        fs::remove_dir_all("./files/test_tx_http").unwrap();
+    }
+}
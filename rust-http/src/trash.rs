@@ -0,0 +1,159 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_json::Value;
+use crate::ttl;
+use crate::wal::{self, Mutation, Wal};
+
+// Directory, next to the documents of a collection, that keeps soft-deleted documents
+pub const TRASH_DIR: &str = "_trash";
+
+// Where a document goes when it is soft-deleted: `<collection>/_trash/<id>.json`
+pub fn trash_path(file_path: &Path) -> Option<PathBuf> {
+    let parent = file_path.parent()?;
+    let name = file_path.file_name()?;
+    Some(parent.join(TRASH_DIR).join(name))
+}
+
+// Move a document into the trash together with the deletion time and the deleting session.
+// A document deleted again replaces its previous trash entry. The caller holds the log, so
+// the deletion can be published before another write commits.
+pub fn move_to_trash(log: &mut Wal, file_path: &Path, session_id: &str) -> io::Result<()> {
+    let contents = fs::read_to_string(file_path)?;
+    let document: Value = serde_json::from_str(&contents)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    // The trash copy, the removal of the original and of its expiry are committed together
+    let mut mutations = trash_mutations(file_path, document, session_id)?;
+    mutations.extend(ttl::clear_mutation(file_path));
+    log.commit_store(mutations).map(|_| ())
+}
+
+// Mutations that move a document with the given contents into the trash
+pub fn trash_mutations(file_path: &Path, document: Value, session_id: &str) -> io::Result<Vec<Mutation>> {
+    let destination = trash_path(file_path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid document path"))?;
+
+    Ok(vec![
+        Mutation::Put { path: destination, document: trash_entry(document, session_id) },
+        Mutation::Delete { path: file_path.to_path_buf() },
+    ])
+}
+
+// Trash entry kept for a deleted document
+pub fn trash_entry(document: Value, session_id: &str) -> Value {
+    serde_json::json!({
+        "deleted_at": now(),
+        "session_id": if session_id.is_empty() { None } else { Some(session_id) },
+        "document": document
+    })
+}
+
+// Read the trashed version of a document, if there is one
+pub fn read_entry(file_path: &Path) -> io::Result<Option<Value>> {
+    let path = match trash_path(file_path) {
+        Some(path) if path.is_file() => path,
+        _ => return Ok(None),
+    };
+
+    let contents = fs::read_to_string(path)?;
+    serde_json::from_str(&contents)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Mutations that bring a trashed document back and drop its trash entry
+pub fn restore_mutations(file_path: &Path, document: Value) -> io::Result<Vec<Mutation>> {
+    let entry_path = trash_path(file_path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid document path"))?;
+    Ok(vec![
+        Mutation::Create { path: file_path.to_path_buf(), document },
+        Mutation::Delete { path: entry_path },
+    ])
+}
+
+// Delete every trash entry under `root` older than `retention`, returning how many were purged
+pub fn purge_expired(root: &Path, retention: Duration) -> io::Result<usize> {
+    let cutoff = now().saturating_sub(retention.as_secs());
+    let mut purged = 0;
+
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        if path.file_name().and_then(|n| n.to_str()) != Some(TRASH_DIR) {
+            purged += purge_expired(&path, retention)?;
+            continue;
+        }
+
+        // Entries are read under the log lock, so an undelete can not run between the
+        // check and the removal, and the removals are one replayable record
+        let mut log = wal::shared()?;
+        let mut mutations = Vec::new();
+        for trashed in fs::read_dir(&path)? {
+            let trashed = trashed?.path();
+            let deleted_at = fs::read_to_string(&trashed).ok()
+                .and_then(|contents| serde_json::from_str::<Value>(&contents).ok())
+                .and_then(|entry| entry["deleted_at"].as_u64());
+            if deleted_at.map(|deleted_at| deleted_at <= cutoff).unwrap_or(false) {
+                mutations.push(Mutation::Delete { path: trashed });
+            }
+        }
+        if !mutations.is_empty() {
+            purged += mutations.len();
+            log.commit_store(mutations)?;
+        }
+    }
+
+    Ok(purged)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_move_to_trash_and_read_back() {
+        let dir = Path::new("./files/test_trash_move");
+        fs::create_dir_all(dir).unwrap();
+        let file_path = dir.join("doc.json");
+        fs::write(&file_path, json!({"key": "value"}).to_string()).unwrap();
+
+        move_to_trash(&mut wal::shared().unwrap(), &file_path, "abc").unwrap();
+
+        assert!(!file_path.exists(), "Document should leave the collection");
+        let entry = read_entry(&file_path).unwrap().unwrap();
+        assert_eq!(entry["document"], json!({"key": "value"}));
+        assert_eq!(entry["session_id"], "abc");
+
+        wal::commit(restore_mutations(&file_path, entry["document"].clone()).unwrap()).unwrap();
+        assert!(read_entry(&file_path).unwrap().is_none());
+        assert!(file_path.exists(), "Document should be back in the collection");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_purge_expired_entries() {
+        let dir = Path::new("./files/test_trash_purge");
+        let trash = dir.join("nested").join(TRASH_DIR);
+        fs::create_dir_all(&trash).unwrap();
+        fs::write(trash.join("old.json"), json!({"deleted_at": 0, "document": {}}).to_string()).unwrap();
+        fs::write(trash.join("new.json"), json!({"deleted_at": now(), "document": {}}).to_string()).unwrap();
+
+        let purged = purge_expired(dir, Duration::from_secs(3600)).unwrap();
+
+        assert_eq!(purged, 1);
+        assert!(!trash.join("old.json").exists());
+        assert!(trash.join("new.json").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json::Value;
+use crate::events::{self, ChangeKind};
+use crate::wal::{self, Mutation};
+
+// Directory, next to the documents of a collection, with the expiry time of each document
+pub const EXPIRES_DIR: &str = "_expires";
+// Collection settings file with the default lifetime of new documents: `{"default_ttl": 3600}`
+pub const TTL_FILE: &str = "_ttl.json";
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// Where the expiry time of a document is kept: `<collection>/_expires/<id>.json`
+pub fn expiry_path(file_path: &Path) -> Option<PathBuf> {
+    let parent = file_path.parent()?;
+    let name = file_path.file_name()?;
+    Some(parent.join(EXPIRES_DIR).join(name))
+}
+
+// Expiry requested by a write, as Unix seconds. `X-TTL` (seconds from now) wins over `Expires`
+// (an HTTP date). An unparseable or out of range value is an error rather than a document
+// that never expires, or one that is removed at once because of a typo.
+pub fn expiry_from_headers(x_ttl: Option<&str>, expires: Option<&str>) -> Result<Option<u64>, String> {
+    if let Some(ttl) = x_ttl {
+        return match ttl.trim().parse::<u64>().ok().and_then(|seconds| now().checked_add(seconds)) {
+            Some(expires_at) => Ok(Some(expires_at)),
+            None => Err("Invalid X-TTL header: must be a number of seconds".to_string()),
+        };
+    }
+    match expires {
+        Some(date) => parse_http_date(date).map(Some).ok_or_else(|| "Invalid Expires header: must be an HTTP date".to_string()),
+        None => Ok(None),
+    }
+}
+
+// When a stored document expires, if it has an expiry
+pub fn expires_at(file_path: &Path) -> io::Result<Option<u64>> {
+    let path = match expiry_path(file_path) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(serde_json::from_str::<Value>(&contents).ok().and_then(|entry| entry["expires_at"].as_u64())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn is_expired(file_path: &Path) -> bool {
+    matches!(expires_at(file_path), Ok(Some(expires_at)) if expires_at <= now())
+}
+
+// IDs of the expired documents of a collection that the sweeper has not removed yet
+pub fn expired_ids(collection_dir: &Path) -> HashSet<String> {
+    let now = now();
+    let entries = match fs::read_dir(collection_dir.join(EXPIRES_DIR)) {
+        Ok(entries) => entries,
+        Err(_) => return HashSet::new(),
+    };
+
+    entries.filter_map(|entry| {
+        let path = entry.ok()?.path();
+        let entry: Value = serde_json::from_str(&fs::read_to_string(&path).ok()?).ok()?;
+        if entry["expires_at"].as_u64()? > now {
+            return None;
+        }
+        Some(path.file_stem()?.to_str()?.to_string())
+    }).collect()
+}
+
+// Mutation that keeps the expiry of a document in step with a write to it:
+// an explicit expiry is stored, a new document gets the collection default (or loses a
+// stale expiry left by a previous document with the same ID), and an existing document
+// written without one keeps the expiry it had.
+pub fn expiry_mutation(file_path: &Path, requested: Option<u64>, is_new: bool) -> io::Result<Option<Mutation>> {
+    let path = expiry_path(file_path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid document path"))?;
+
+    let expires_at = match (requested, is_new) {
+        (Some(expires_at), _) => Some(expires_at),
+        (None, true) => match default_ttl(file_path)? {
+            Some(seconds) => Some(now().checked_add(seconds).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Invalid default_ttl for {}", file_path.display()))
+            })?),
+            None => None,
+        },
+        (None, false) => return Ok(None),
+    };
+
+    Ok(match expires_at {
+        Some(expires_at) => Some(Mutation::Put { path, document: serde_json::json!({"expires_at": expires_at}) }),
+        None if path.exists() => Some(Mutation::Delete { path }),
+        None => None,
+    })
+}
+
+// Mutation that drops the expiry of a deleted document, if it had one
+pub fn clear_mutation(file_path: &Path) -> Option<Mutation> {
+    expiry_path(file_path)
+        .filter(|path| path.exists())
+        .map(|path| Mutation::Delete { path })
+}
+
+// Remove every expired document under `root` with its expiry entry, returning how many were removed
+pub fn sweep_expired(root: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        if path.file_name().and_then(|n| n.to_str()) != Some(EXPIRES_DIR) {
+            removed += sweep_expired(&path)?;
+            continue;
+        }
+
+        let collection_dir = path.parent().unwrap_or(root);
+        for id in expired_ids(collection_dir) {
+            let file_path = collection_dir.join(format!("{}.json", id));
+
+            // Checked again under the log lock: a write may have renewed the document meanwhile
+            let mut log = wal::shared()?;
+            if !matches!(expires_at(&file_path)?, Some(expires_at) if expires_at <= now()) {
+                continue;
+            }
+            let mut mutations = vec![Mutation::Delete { path: file_path.clone() }];
+            mutations.extend(clear_mutation(&file_path));
+            let existed = file_path.exists();
+            log.commit_store(mutations)?;
+            if existed {
+                events::publish(ChangeKind::Delete, &file_path, None, "");
+                removed += 1;
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+// Format Unix seconds as an HTTP date: `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn format_http_date(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize], day, MONTHS[month as usize - 1], year,
+        seconds / 3600, seconds / 60 % 60, seconds % 60
+    )
+}
+
+// Parse an HTTP date in the IMF-fixdate format into Unix seconds
+pub fn parse_http_date(date: &str) -> Option<u64> {
+    let (_, rest) = date.trim().split_once(", ")?;
+    let parts: Vec<&str> = rest.split(' ').collect();
+    if parts.len() != 5 || parts[4] != "GMT" {
+        return None;
+    }
+    let day: u32 = parts[0].parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == parts[1])? as u32 + 1;
+    // Four digits, which also keeps the arithmetic below from overflowing
+    if parts[2].len() != 4 {
+        return None;
+    }
+    let year: i64 = parts[2].parse().ok()?;
+    let time: Vec<u64> = parts[3].split(':').map(|p| p.parse().ok()).collect::<Option<Vec<u64>>>()?;
+    if time.len() != 3 || !(1..=31).contains(&day) || time[0] > 23 || time[1] > 59 || time[2] > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    let seconds = u64::try_from(days.checked_mul(86400)?).ok()?;
+    seconds.checked_add(time[0] * 3600 + time[1] * 60 + time[2])
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn default_ttl(file_path: &Path) -> io::Result<Option<u64>> {
+    let settings = match file_path.parent() {
+        Some(parent) => parent.join(TTL_FILE),
+        None => return Ok(None),
+    };
+    match fs::read_to_string(settings) {
+        Ok(contents) => Ok(serde_json::from_str::<Value>(&contents).ok().and_then(|s| s["default_ttl"].as_u64())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian date (Howard Hinnant's algorithm)
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_http_dates() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(format_http_date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format_http_date(951782400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+
+        assert_eq!(expiry_from_headers(Some("60"), Some("Sun, 06 Nov 1994 08:49:37 GMT")).unwrap().unwrap() - now(), 60);
+        assert_eq!(expiry_from_headers(None, Some("Sun, 06 Nov 1994 08:49:37 GMT")), Ok(Some(784111777)));
+        assert!(expiry_from_headers(Some("soon"), None).is_err());
+        assert!(expiry_from_headers(Some("18446744073709551615"), None).is_err(), "Overflowing TTLs should be rejected");
+        assert!(expiry_from_headers(None, Some("0")).is_err(), "An invalid Expires should be rejected");
+        assert_eq!(parse_http_date("Fri, 31 Dec 99999999999999 23:59:59 GMT"), None);
+        assert_eq!(parse_http_date("Fri, 31 Dec 9999 23:59:59 GMT"), Some(253402300799));
+    }
+
+    #[test]
+    fn test_expiry_mutation_rules() {
+        let dir = Path::new("./files/test_ttl_rules");
+        fs::create_dir_all(dir).unwrap();
+        let file_path = dir.join("1.json");
+
+        assert_eq!(expiry_mutation(&file_path, None, true).unwrap(), None, "No default, nothing to store");
+
+        fs::write(dir.join(TTL_FILE), json!({"default_ttl": 100}).to_string()).unwrap();
+        match expiry_mutation(&file_path, None, true).unwrap() {
+            Some(Mutation::Put { document, .. }) => assert_eq!(document["expires_at"].as_u64().unwrap() - now(), 100),
+            other => panic!("Expected the collection default, got {:?}", other),
+        }
+        assert_eq!(expiry_mutation(&file_path, None, false).unwrap(), None, "Existing documents keep their expiry");
+        assert_eq!(
+            expiry_mutation(&file_path, Some(5), false).unwrap(),
+            Some(Mutation::Put { path: dir.join(EXPIRES_DIR).join("1.json"), document: json!({"expires_at": 5}) })
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sweep_removes_expired_documents() {
+        let dir = Path::new("./files/test_ttl_sweep");
+        fs::create_dir_all(dir.join(EXPIRES_DIR)).unwrap();
+        for (id, expires_at) in [("old", 1), ("new", now() + 3600)] {
+            fs::write(dir.join(format!("{}.json", id)), "{}").unwrap();
+            fs::write(dir.join(EXPIRES_DIR).join(format!("{}.json", id)), json!({"expires_at": expires_at}).to_string()).unwrap();
+        }
+
+        assert_eq!(expired_ids(dir), HashSet::from(["old".to_string()]));
+        assert!(is_expired(&dir.join("old.json")));
+
+        let removed = sweep_expired(dir).unwrap();
+
+        assert_eq!(removed, 1);
+        assert!(!dir.join("old.json").exists());
+        assert!(!dir.join(EXPIRES_DIR).join("old.json").exists());
+        assert!(dir.join("new.json").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,610 @@
+use std::borrow::Borrow;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use serde_json::Value;
+use crate::cache;
+use crate::index;
+use crate::search;
+
+// Name of the log file kept at the root of the data directory
+pub const WAL_FILE: &str = "_wal.log";
+// Data directory served by the request handlers
+const DATA_DIR: &str = "./files";
+// Committed records after which the log is checkpointed and truncated
+const CHECKPOINT_EVERY: usize = 64;
+
+// A single change to the file store
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mutation {
+    // Write a new document, failing if it already exists
+    Create { path: PathBuf, document: Value },
+    // Write a document, replacing whatever was there
+    Put { path: PathBuf, document: Value },
+    // Remove a document, doing nothing if it is already gone
+    Delete { path: PathBuf },
+}
+
+impl Mutation {
+    pub fn path(&self) -> &Path {
+        match self {
+            Mutation::Create { path, .. } | Mutation::Put { path, .. } | Mutation::Delete { path } => path,
+        }
+    }
+}
+
+// What the files of a commit held before it, to put back if it fails: for every mutation
+// applied so far, a hard link to the file it replaced, or `None` when there was none.
+// Links rather than contents keep the rollback of a large import out of memory.
+#[derive(Debug)]
+struct Undo {
+    seq: u64,
+    previous: Vec<(PathBuf, Option<PathBuf>)>,
+}
+
+// Append-only write-ahead log.
+// Every commit is logged and flushed before it touches the documents, and marked as
+// applied afterwards; records without the mark are replayed when the log is opened.
+// A commit that fails while being applied is rolled back and marked as aborted instead,
+// so only records interrupted by a crash are ever replayed.
+pub struct Wal {
+    data_dir: PathBuf,
+    file: File,
+    next_seq: u64,
+    since_checkpoint: usize,
+    // A failed commit whose rollback did not go through yet, retried before the next commit
+    failed: Option<Undo>,
+    #[cfg(test)]
+    crash_after: Option<usize>,
+}
+
+impl Wal {
+    // Open the log of a data directory and replay whatever a crash left unapplied
+    pub fn open(data_dir: &Path) -> io::Result<Wal> {
+        fs::create_dir_all(data_dir)?;
+        let file = OpenOptions::new().create(true).append(true).open(data_dir.join(WAL_FILE))?;
+
+        let mut wal = Wal {
+            data_dir: data_dir.to_path_buf(),
+            file,
+            next_seq: 1,
+            since_checkpoint: 0,
+            failed: None,
+            #[cfg(test)]
+            crash_after: None,
+        };
+        let replayed = wal.recover()?;
+        if replayed > 0 {
+            println!("Recovered {} unapplied write-ahead log records", replayed);
+        }
+        Ok(wal)
+    }
+
+    // Replay unapplied records, then truncate the log. Returns how many records were replayed.
+    pub fn recover(&mut self) -> io::Result<usize> {
+        let mut pending: Vec<(u64, Vec<Mutation>)> = Vec::new();
+        // Rollback links a crash left behind, for every record still in the log
+        let mut backups = Vec::new();
+        let reader = BufReader::new(File::open(self.data_dir.join(WAL_FILE))?);
+
+        for line in reader.lines() {
+            // A torn or corrupt line marks the end of the log: nothing after it was acknowledged
+            let record: Value = match line.ok().and_then(|line| serde_json::from_str(&line).ok()) {
+                Some(record) => record,
+                None => break,
+            };
+
+            if let Some(done) = record.get("applied").or_else(|| record.get("aborted")).and_then(Value::as_u64) {
+                pending.retain(|(seq, _)| *seq != done);
+            } else if let (Some(seq), Some(mutations)) = (record.get("seq").and_then(Value::as_u64), record.get("mutations")) {
+                match decode_mutations(mutations) {
+                    Some(mutations) => {
+                        backups.extend(mutations.iter().enumerate().map(|(index, mutation)| self.backup_path(seq, index, mutation.path())));
+                        pending.push((seq, mutations));
+                    },
+                    None => break,
+                }
+                self.next_seq = self.next_seq.max(seq + 1);
+            }
+        }
+
+        for (_, mutations) in &pending {
+            for mutation in mutations {
+                self.apply(mutation)?;
+            }
+        }
+
+        for backup in backups.into_iter().flatten() {
+            let _ = fs::remove_file(backup);
+        }
+        self.checkpoint()?;
+        Ok(pending.len())
+    }
+
+    // Log a group of mutations and apply them; after a crash they are either all replayed or none is
+    pub fn commit(&mut self, mutations: &[Mutation]) -> io::Result<u64> {
+        self.retry_failed()?;
+        for mutation in mutations {
+            self.check_create(mutation)?;
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let record = serde_json::json!({
+            "seq": seq,
+            "mutations": mutations.iter().map(encode_mutation).collect::<Vec<Value>>()
+        });
+        self.append(&record)?;
+        self.apply_record(seq, mutations.iter().map(Ok))?;
+        Ok(seq)
+    }
+
+    // Like `commit`, for more mutations than should be held in memory: `spool` holds one
+    // encoded mutation per line and is read once to check them, once to log them and once
+    // to apply them. They still make up a single record, so they are applied all or none.
+    pub fn commit_spooled(&mut self, spool: &Path) -> io::Result<u64> {
+        self.retry_failed()?;
+        // Every line is decoded before anything is logged, so the record can not be corrupt
+        for mutation in read_spool(spool)? {
+            self.check_create(&mutation?)?;
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.append_with(|out| {
+            write!(out, "{{\"seq\":{},\"mutations\":[", seq)?;
+            for (index, line) in BufReader::new(File::open(spool)?).lines().enumerate() {
+                if index > 0 {
+                    out.write_all(b",")?;
+                }
+                out.write_all(line?.as_bytes())?;
+            }
+            out.write_all(b"]}\n")
+        })?;
+        self.apply_record(seq, read_spool(spool)?)?;
+        Ok(seq)
+    }
+
+    // Like `commit`, for mutations whose paths still start with the data directory
+    pub fn commit_store(&mut self, mutations: Vec<Mutation>) -> io::Result<u64> {
+        let mutations = mutations.into_iter()
+            .map(|mutation| -> io::Result<Mutation> {
+                Ok(match mutation {
+                    Mutation::Create { path, document } => Mutation::Create { path: self.relative_path(&path)?, document },
+                    Mutation::Put { path, document } => Mutation::Put { path: self.relative_path(&path)?, document },
+                    Mutation::Delete { path } => Mutation::Delete { path: self.relative_path(&path)? },
+                })
+            })
+            .collect::<io::Result<Vec<Mutation>>>()?;
+        self.commit(&mutations)
+    }
+
+    // Truncate the log; only valid once every record in it has been applied or aborted
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        if self.failed.is_some() {
+            return Ok(());
+        }
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.since_checkpoint = 0;
+        Ok(())
+    }
+
+    // Data directory whose documents the log protects
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    // Turn a path below the data directory into the relative path stored in the log
+    pub fn relative_path(&self, path: &Path) -> io::Result<PathBuf> {
+        path.strip_prefix(&self.data_dir)
+            .map(Path::to_path_buf)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is outside the data directory", path.display())))
+    }
+
+    // Crash injection for the recovery tests
+    #[cfg(test)]
+    fn fail_point(&self, applied: usize) -> io::Result<()> {
+        if self.crash_after == Some(applied) {
+            Err(io::Error::other("Simulated crash"))
+        } else {
+            Ok(())
+        }
+    }
+
+    #[cfg(not(test))]
+    fn fail_point(&self, _applied: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Roll back a commit whose rollback failed before, ahead of anything new
+    fn retry_failed(&mut self) -> io::Result<()> {
+        match self.failed.take() {
+            Some(undo) => self.abort(undo),
+            None => Ok(()),
+        }
+    }
+
+    // Create must fail before anything is logged
+    fn check_create(&self, mutation: &Mutation) -> io::Result<()> {
+        match mutation {
+            Mutation::Create { path, .. } if self.data_dir.join(path).exists() => {
+                Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path.display())))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    // Apply the mutations of logged record `seq` in order and mark it as applied.
+    // When one fails the ones before it are rolled back and the error is returned.
+    fn apply_record<M: Borrow<Mutation>>(&mut self, seq: u64, mutations: impl Iterator<Item = io::Result<M>>) -> io::Result<()> {
+        let mut undo = Undo { seq, previous: Vec::new() };
+        for (applied, mutation) in mutations.enumerate() {
+            // A simulated crash stops here, leaving the log as a real one would
+            self.fail_point(applied)?;
+            let result = mutation.and_then(|mutation| {
+                let mutation = mutation.borrow();
+                let backup = self.keep_previous(seq, applied, mutation.path())?;
+                undo.previous.push((mutation.path().to_path_buf(), backup));
+                self.apply(mutation)
+            });
+            if let Err(e) = result {
+                if let Err(rollback) = self.abort(undo) {
+                    println!("Failed to roll back write-ahead log record {}: {}", seq, rollback);
+                }
+                return Err(e);
+            }
+        }
+
+        self.append(&serde_json::json!({"applied": seq}))?;
+        for backup in undo.previous.into_iter().filter_map(|(_, backup)| backup) {
+            let _ = fs::remove_file(backup);
+        }
+        self.since_checkpoint += 1;
+        if self.since_checkpoint >= CHECKPOINT_EVERY {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    // Hard link the file mutation `index` of record `seq` is about to replace, copying it
+    // when linking is not possible. `None` when there is no such file.
+    fn keep_previous(&self, seq: u64, index: usize, path: &Path) -> io::Result<Option<PathBuf>> {
+        let target = self.data_dir.join(path);
+        let backup = self.backup_path(seq, index, path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid file path"))?;
+        let _ = fs::remove_file(&backup);
+        match fs::hard_link(&target, &backup) {
+            Ok(()) => Ok(Some(backup)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(_) => fs::copy(&target, &backup).map(|_| Some(backup)),
+        }
+    }
+
+    // Rollback link of a file, next to it: `a/.1.json.7-0.undo`
+    fn backup_path(&self, seq: u64, index: usize, path: &Path) -> Option<PathBuf> {
+        let file_name = path.file_name()?.to_string_lossy();
+        Some(self.data_dir.join(path).with_file_name(format!(".{}.{}-{}.undo", file_name, seq, index)))
+    }
+
+    // Put back what a failed commit changed, newest change first, and mark its record as
+    // aborted so recovery skips it. Until both went through the commit stays pending.
+    fn abort(&mut self, undo: Undo) -> io::Result<()> {
+        let result = undo.previous.iter().rev()
+            .try_for_each(|(path, backup)| self.restore(path, backup.as_deref()))
+            .and_then(|_| self.append(&serde_json::json!({"aborted": undo.seq})));
+        if result.is_err() {
+            self.failed = Some(undo);
+        }
+        result
+    }
+
+    fn restore(&self, path: &Path, backup: Option<&Path>) -> io::Result<()> {
+        let target = self.data_dir.join(path);
+        let restored = match backup {
+            Some(backup) => fs::rename(backup, &target),
+            None => fs::remove_file(&target),
+        };
+        match restored {
+            // Already put back by an earlier attempt
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {},
+        }
+        cache::invalidate_file(path);
+
+        // Indexes already took the change being undone
+        let document = fs::read(&target).ok().and_then(|contents| serde_json::from_slice::<Value>(&contents).ok());
+        index::update(&target, document.as_ref());
+        search::update(&self.data_dir, path, document.as_ref());
+        Ok(())
+    }
+
+    fn append(&mut self, record: &Value) -> io::Result<()> {
+        self.append_with(|out| writeln!(out, "{}", record))
+    }
+
+    // Append whatever `write` produces and flush it to disk. A record that could not be
+    // written whole is cut off again, so it does not hide the records appended after it.
+    fn append_with(&mut self, write: impl FnOnce(&mut BufWriter<&File>) -> io::Result<()>) -> io::Result<()> {
+        let length = self.file.metadata()?.len();
+        let mut out = BufWriter::new(&self.file);
+        let written = write(&mut out).and_then(|_| out.flush());
+        drop(out);
+        if let Err(e) = written {
+            let _ = self.file.set_len(length);
+            return Err(e);
+        }
+        self.file.sync_data()
+    }
+
+    fn apply(&self, mutation: &Mutation) -> io::Result<()> {
+        let (path, document) = match mutation {
+            Mutation::Create { path, document } | Mutation::Put { path, document } => (path, Some(document)),
+            Mutation::Delete { path } => (path, None),
+        };
+        let target = self.data_dir.join(path);
+
+        match document {
+            Some(document) => {
+                let json_string = serde_json::to_string_pretty(document)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                write_atomic(&target, json_string.as_bytes())?;
+            },
+            None => match fs::remove_file(&target) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {},
+            },
+        }
+        cache::invalidate_file(path);
+
+        index::update(&target, document);
+        search::update(&self.data_dir, path, document);
+        Ok(())
+    }
+}
+
+// Error of `shared` while the log can not be opened; handlers answer it with 503
+#[derive(Debug)]
+pub struct Unavailable(io::Error);
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Write-ahead log unavailable: {}", self.0)
+    }
+}
+
+impl Error for Unavailable {}
+
+// Whether an error comes from the shared log being unavailable rather than from the write itself
+pub fn is_unavailable(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<Unavailable>())
+}
+
+// The shared log, locked until dropped
+pub struct SharedWal(MutexGuard<'static, Option<Wal>>);
+
+impl Deref for SharedWal {
+    type Target = Wal;
+
+    fn deref(&self) -> &Wal {
+        self.0.as_ref().expect("Shared log is opened before it is handed out")
+    }
+}
+
+impl DerefMut for SharedWal {
+    fn deref_mut(&mut self) -> &mut Wal {
+        self.0.as_mut().expect("Shared log is opened before it is handed out")
+    }
+}
+
+// The log of `./files`, shared by every request handler.
+// Opening it the first time replays anything a previous crash left behind; when that
+// fails the error is returned and the next call tries again.
+pub fn shared() -> io::Result<SharedWal> {
+    static SHARED: Mutex<Option<Wal>> = Mutex::new(None);
+    let mut guard = SHARED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if guard.is_none() {
+        let wal = Wal::open(Path::new(DATA_DIR)).map_err(|e| io::Error::new(e.kind(), Unavailable(e)))?;
+        *guard = Some(wal);
+    }
+    Ok(SharedWal(guard))
+}
+
+// Commit mutations given with paths below `./files` through the shared log
+pub fn commit(mutations: Vec<Mutation>) -> io::Result<u64> {
+    shared()?.commit_store(mutations)
+}
+
+// Replace a file through a temporary file and a rename, so readers never see half a document
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file_name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid file path"))?;
+    let temp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(temp_path, path)
+}
+
+// Mutations of a spool file written with `encode_mutation`, one per line
+pub fn read_spool(spool: &Path) -> io::Result<impl Iterator<Item = io::Result<Mutation>>> {
+    let reader = BufReader::new(File::open(spool)?);
+    Ok(reader.lines().map(|line| {
+        let mutation: Value = serde_json::from_str(&line?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        decode_mutation(&mutation).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid spooled mutation"))
+    }))
+}
+
+pub fn encode_mutation(mutation: &Mutation) -> Value {
+    match mutation {
+        Mutation::Create { path, document } => serde_json::json!({"op": "create", "path": path, "document": document}),
+        Mutation::Put { path, document } => serde_json::json!({"op": "put", "path": path, "document": document}),
+        Mutation::Delete { path } => serde_json::json!({"op": "delete", "path": path}),
+    }
+}
+
+fn decode_mutations(mutations: &Value) -> Option<Vec<Mutation>> {
+    mutations.as_array()?.iter().map(decode_mutation).collect()
+}
+
+fn decode_mutation(mutation: &Value) -> Option<Mutation> {
+    let path = PathBuf::from(mutation.get("path")?.as_str()?);
+    match mutation.get("op")?.as_str()? {
+        "create" => Some(Mutation::Create { path, document: mutation.get("document")?.clone() }),
+        "put" => Some(Mutation::Put { path, document: mutation.get("document")?.clone() }),
+        "delete" => Some(Mutation::Delete { path }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn read(dir: &Path, name: &str) -> Option<Value> {
+        fs::read_to_string(dir.join(name)).ok().map(|contents| serde_json::from_str(&contents).unwrap())
+    }
+
+    fn move_mutations() -> Vec<Mutation> {
+        vec![
+            Mutation::Put { path: PathBuf::from("b/1.json"), document: json!({"moved": true}) },
+            Mutation::Delete { path: PathBuf::from("a/1.json") },
+        ]
+    }
+
+    #[test]
+    fn test_commit_applies_and_checkpoints() {
+        let dir = Path::new("./files/test_wal_commit");
+        let _ = fs::remove_dir_all(dir);
+        let mut wal = Wal::open(dir).unwrap();
+
+        wal.commit(&[Mutation::Create { path: PathBuf::from("a/1.json"), document: json!({"v": 1}) }]).unwrap();
+        let error = wal.commit(&[Mutation::Create { path: PathBuf::from("a/1.json"), document: json!({"v": 2}) }]).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(read(dir, "a/1.json"), Some(json!({"v": 1})));
+        wal.checkpoint().unwrap();
+        assert_eq!(fs::metadata(dir.join(WAL_FILE)).unwrap().len(), 0);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_crash_between_mutations_is_replayed() {
+        let dir = Path::new("./files/test_wal_crash");
+        let _ = fs::remove_dir_all(dir);
+        let mut wal = Wal::open(dir).unwrap();
+        wal.commit(&[Mutation::Put { path: PathBuf::from("a/1.json"), document: json!({"moved": true}) }]).unwrap();
+
+        // Crash after the first of two mutations was applied
+        wal.crash_after = Some(1);
+        assert!(wal.commit(&move_mutations()).is_err());
+        assert!(read(dir, "a/1.json").is_some(), "Crash should leave the store half updated");
+        drop(wal);
+
+        // Recovery finishes the operation
+        let _wal = Wal::open(dir).unwrap();
+        assert_eq!(read(dir, "b/1.json"), Some(json!({"moved": true})));
+        assert_eq!(read(dir, "a/1.json"), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_crash_before_applying_is_replayed() {
+        let dir = Path::new("./files/test_wal_crash_early");
+        let _ = fs::remove_dir_all(dir);
+        let mut wal = Wal::open(dir).unwrap();
+
+        wal.crash_after = Some(0);
+        assert!(wal.commit(&move_mutations()).is_err());
+        assert_eq!(read(dir, "b/1.json"), None);
+        drop(wal);
+
+        let _wal = Wal::open(dir).unwrap();
+        assert_eq!(read(dir, "b/1.json"), Some(json!({"moved": true})));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_apply_is_rolled_back() {
+        let dir = Path::new("./files/test_wal_abort");
+        let _ = fs::remove_dir_all(dir);
+        let mut wal = Wal::open(dir).unwrap();
+        wal.commit(&[Mutation::Put { path: PathBuf::from("a/1.json"), document: json!({"v": 1}) }]).unwrap();
+
+        // The temporary file for b/1.json can not be created
+        fs::create_dir_all(dir.join("b/.1.json.tmp")).unwrap();
+        let failing = [
+            Mutation::Put { path: PathBuf::from("a/1.json"), document: json!({"v": 2}) },
+            Mutation::Put { path: PathBuf::from("b/1.json"), document: json!({"v": 2}) },
+        ];
+        assert!(wal.commit(&failing).is_err());
+        assert_eq!(read(dir, "a/1.json"), Some(json!({"v": 1})), "Applied part should be rolled back");
+
+        // Later writes are not held up, and the failed commit is not replayed on restart
+        wal.commit(&[Mutation::Put { path: PathBuf::from("c/1.json"), document: json!({"v": 3}) }]).unwrap();
+        drop(wal);
+        fs::remove_dir_all(dir.join("b/.1.json.tmp")).unwrap();
+        let _wal = Wal::open(dir).unwrap();
+        assert_eq!(read(dir, "a/1.json"), Some(json!({"v": 1})));
+        assert_eq!(read(dir, "b/1.json"), None);
+        assert_eq!(read(dir, "c/1.json"), Some(json!({"v": 3})));
+        let leftovers = fs::read_dir(dir.join("a")).unwrap().count();
+        assert_eq!(leftovers, 1, "Rollback links should not be left behind");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_spooled_commit_is_one_record() {
+        let dir = Path::new("./files/test_wal_spooled");
+        let _ = fs::remove_dir_all(dir);
+        let mut wal = Wal::open(dir).unwrap();
+        let spool = dir.join(".spool");
+        let lines: Vec<String> = move_mutations().iter().map(|mutation| encode_mutation(mutation).to_string()).collect();
+        fs::write(&spool, lines.join("\n")).unwrap();
+
+        wal.crash_after = Some(1);
+        assert!(wal.commit_spooled(&spool).is_err());
+        drop(wal);
+
+        // The record was logged whole and is replayed like any other
+        let mut wal = Wal::open(dir).unwrap();
+        assert_eq!(read(dir, "b/1.json"), Some(json!({"moved": true})));
+        assert_eq!(fs::read_dir(dir.join("b")).unwrap().count(), 1, "Rollback links should be cleaned up");
+
+        fs::write(&spool, "{not json").unwrap();
+        assert_eq!(wal.commit_spooled(&spool).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::metadata(dir.join(WAL_FILE)).unwrap().len(), 0, "Nothing should be logged");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_record_is_ignored() {
+        let dir = Path::new("./files/test_wal_torn");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+
+        // Crash while the record itself was being appended
+        let record = serde_json::json!({"seq": 1, "mutations": move_mutations().iter().map(encode_mutation).collect::<Vec<Value>>()}).to_string();
+        fs::write(dir.join(WAL_FILE), &record[..record.len() / 2]).unwrap();
+
+        let _wal = Wal::open(dir).unwrap();
+        assert_eq!(read(dir, "b/1.json"), None, "A torn record must not be applied");
+        assert_eq!(fs::metadata(dir.join(WAL_FILE)).unwrap().len(), 0);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,571 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use threadpool::ThreadPool;
+use uuid::Uuid;
+use crate::cache;
+use crate::events::{self, ChangeEvent, ChangeKind};
+use crate::http_client::{ClientConfig, HttpClient, Url};
+use crate::methods::{error_response, storage_unavailable};
+use crate::response::HttpResponse;
+use crate::ttl;
+use crate::wal;
+
+// Name of the webhook endpoint below a collection: `/users/_webhooks`
+pub const WEBHOOKS_DIR: &str = "_webhooks";
+// File, next to the documents of a collection, with the webhooks registered for it
+pub const WEBHOOKS_FILE: &str = "_webhooks.json";
+// Deliveries that ran out of attempts, one JSON object per line
+pub const DEAD_LETTER_LOG: &str = "./files/_webhooks_dead.log";
+// Header carrying `sha256=<hex HMAC of the body>` keyed with the webhook secret
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+// How long a receiver gets to accept the connection and answer
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+// Deliveries running at once, so a slow receiver can not take over the pool
+const MAX_IN_FLIGHT: usize = 8;
+// Longest the dispatcher waits for changes before checking on retries and finished deliveries
+const DISPATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+const ALL_EVENTS: [ChangeKind; 3] = [ChangeKind::Create, ChangeKind::Update, ChangeKind::Delete];
+
+// A receiver of the changes to the documents of one collection
+#[derive(Debug, Clone, PartialEq)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<ChangeKind>,
+    pub secret: String,
+}
+
+impl Webhook {
+    // Build a webhook from a registration body: `{"url": ..., "events": [...], "secret": ...}`.
+    // Events default to all of them and a missing secret is generated.
+    pub fn from_registration(body: &Value) -> Result<Webhook, String> {
+        let url = body["url"].as_str().ok_or("Missing url")?;
+        Url::parse(url).map_err(|e| e.to_string())?;
+
+        let events = match body.get("events") {
+            None => ALL_EVENTS.to_vec(),
+            Some(Value::Array(names)) if !names.is_empty() => names.iter()
+                .map(|name| {
+                    ALL_EVENTS.iter().copied()
+                        .find(|kind| Some(kind.as_str()) == name.as_str())
+                        .ok_or_else(|| format!("Unknown event {}: expected create, update or delete", name))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            Some(_) => return Err("events must be a non-empty list".to_string()),
+        };
+        let secret = match body.get("secret") {
+            None => Uuid::new_v4().simple().to_string(),
+            Some(Value::String(secret)) if !secret.is_empty() => secret.clone(),
+            Some(_) => return Err("secret must be a non-empty string".to_string()),
+        };
+
+        Ok(Webhook { id: Uuid::new_v4().to_string(), url: url.to_string(), events, secret })
+    }
+
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "id": self.id,
+            "url": self.url,
+            "events": self.events.iter().map(|kind| kind.as_str()).collect::<Vec<_>>(),
+            "secret": self.secret
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Webhook> {
+        Some(Webhook {
+            id: value["id"].as_str()?.to_string(),
+            url: value["url"].as_str()?.to_string(),
+            events: value["events"].as_array()?.iter()
+                .filter_map(|name| ALL_EVENTS.iter().copied().find(|kind| Some(kind.as_str()) == name.as_str()))
+                .collect(),
+            secret: value["secret"].as_str()?.to_string(),
+        })
+    }
+
+    // The webhook as listed: the secret is only shown when it is registered
+    fn to_listing(&self) -> Value {
+        let mut json = self.to_json();
+        if let Some(fields) = json.as_object_mut() {
+            fields.remove("secret");
+        }
+        json
+    }
+}
+
+// Webhooks registered for the collection stored in `collection_dir`
+pub fn load_webhooks(collection_dir: &Path) -> io::Result<Vec<Webhook>> {
+    match fs::read_to_string(collection_dir.join(WEBHOOKS_FILE)) {
+        Ok(contents) => {
+            let entries: Vec<Value> = serde_json::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok(entries.iter().filter_map(Webhook::from_json).collect())
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+// Registrations are not written through the WAL, so the cached listings are dropped here
+fn save_webhooks(collection_dir: &Path, webhooks: &[Webhook]) -> io::Result<()> {
+    let path = collection_dir.join(WEBHOOKS_FILE);
+    let result = if webhooks.is_empty() {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    } else {
+        let entries: Vec<Value> = webhooks.iter().map(Webhook::to_json).collect();
+        fs::write(&path, Value::from(entries).to_string())
+    };
+    cache::invalidate_file(&path);
+    result
+}
+
+// Split `/users/_webhooks/<id>` into the collection and the webhook ID (empty for the list)
+pub fn split_webhook_path(id: &str) -> Option<(&str, &str)> {
+    let marker = format!("/{}", WEBHOOKS_DIR);
+    let position = id.find(&marker)?;
+    let (collection, rest) = (&id[..position], &id[position + marker.len()..]);
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    Some((collection, rest.trim_start_matches('/')))
+}
+
+// Function to handle `/users/_webhooks`: GET lists, POST registers,
+// and GET or DELETE on `/users/_webhooks/<id>` reads or removes one webhook
+pub fn handle_webhook_request(method: &str, collection: &str, id: &str, body: Option<&Value>) -> HttpResponse {
+    println!("Handling webhook request: {} {}/{}/{}", method, collection, WEBHOOKS_DIR, id);
+
+    let collection_dir = PathBuf::from(format!("./files/{}", collection));
+    if !collection_dir.is_dir() {
+        return error_response(404, "Collection not found");
+    }
+
+    // Holding the log keeps concurrent registrations from overwriting each other
+    let _log = match wal::shared() {
+        Ok(log) => log,
+        Err(e) => return storage_unavailable(&e),
+    };
+    let mut webhooks = match load_webhooks(&collection_dir) {
+        Ok(webhooks) => webhooks,
+        Err(e) => {
+            println!("Failed to read webhooks: {}", e);
+            return error_response(500, "Failed to read webhooks");
+        },
+    };
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(), "application/json".to_string());
+
+    match (method, id) {
+        ("GET", "") => {
+            let listing: Vec<Value> = webhooks.iter().map(Webhook::to_listing).collect();
+            HttpResponse::new(200, headers, Some(Value::from(listing).to_string()))
+        },
+        ("GET", id) => match webhooks.iter().find(|webhook| webhook.id == id) {
+            Some(webhook) => HttpResponse::new(200, headers, Some(webhook.to_listing().to_string())),
+            None => error_response(404, "Webhook not found"),
+        },
+        ("POST", "") => {
+            let webhook = match body.map(Webhook::from_registration) {
+                Some(Ok(webhook)) => webhook,
+                Some(Err(message)) => return error_response(400, &message),
+                None => return error_response(400, "Missing JSON body"),
+            };
+            webhooks.push(webhook.clone());
+            match save_webhooks(&collection_dir, &webhooks) {
+                Ok(_) => {
+                    let mut body = webhook.to_json();
+                    body["status_code"] = Value::from(201);
+                    body["message"] = Value::from("Webhook registered successfully");
+                    HttpResponse::new(201, headers, Some(body.to_string()))
+                },
+                Err(e) => {
+                    println!("Failed to save webhooks: {}", e);
+                    error_response(500, "Failed to save webhooks")
+                },
+            }
+        },
+        ("DELETE", id) if !id.is_empty() => {
+            let count = webhooks.len();
+            webhooks.retain(|webhook| webhook.id != id);
+            if webhooks.len() == count {
+                return error_response(404, "Webhook not found");
+            }
+            match save_webhooks(&collection_dir, &webhooks) {
+                Ok(_) => error_response(200, "Webhook deleted successfully"),
+                Err(e) => {
+                    println!("Failed to save webhooks: {}", e);
+                    error_response(500, "Failed to save webhooks")
+                },
+            }
+        },
+        _ => error_response(405, "Method not allowed"),
+    }
+}
+
+// `sha256=<hex>` signature of a payload, as sent in `X-Webhook-Signature`
+pub fn sign(secret: &str, payload: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    let digest: String = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256={}", digest)
+}
+
+// How many times a delivery is tried and how long to wait between tries
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        // Retries after 1, 2, 4, 8 and 16 seconds
+        RetryPolicy { max_attempts: 6, base_delay: Duration::from_secs(1) }
+    }
+}
+
+impl RetryPolicy {
+    // Wait before the next try, doubling after each failed attempt
+    pub fn delay(&self, attempts: u32) -> Duration {
+        self.base_delay * 2u32.saturating_pow(attempts.saturating_sub(1))
+    }
+}
+
+// One change on its way to one webhook
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    // Same for every attempt, so receivers can tell retries apart from new changes
+    pub id: String,
+    pub webhook: Webhook,
+    pub event: ChangeEvent,
+    pub attempts: u32,
+    pub next_attempt: Instant,
+    pub last_error: Option<String>,
+}
+
+impl Delivery {
+    pub fn new(webhook: Webhook, event: ChangeEvent) -> Self {
+        Delivery {
+            id: Uuid::new_v4().to_string(),
+            webhook,
+            event,
+            attempts: 0,
+            next_attempt: Instant::now(),
+            last_error: None,
+        }
+    }
+
+    // POST the event to the webhook URL, signed with its secret. Any 2xx answer is a success.
+    pub fn send(&self, client: &HttpClient) -> Result<(), String> {
+        let payload = self.event.to_json().to_string();
+        let response = client.post(&self.webhook.url)
+            .header("Content-Type", "application/json")
+            .header("X-Webhook-Id", &self.webhook.id)
+            .header("X-Webhook-Delivery", &self.id)
+            .header("X-Webhook-Event", self.event.kind.as_str())
+            .header(SIGNATURE_HEADER, &sign(&self.webhook.secret, payload.as_bytes()))
+            .body(payload)
+            .send()
+            .map_err(|e| e.to_string())?;
+
+        match response.status_code {
+            200..=299 => Ok(()),
+            status => Err(format!("Receiver answered {}", status)),
+        }
+    }
+}
+
+// Deliveries waiting for their next attempt
+#[derive(Debug)]
+pub struct DeliveryQueue {
+    pending: Vec<Delivery>,
+    policy: RetryPolicy,
+    dead_letter_log: PathBuf,
+}
+
+impl DeliveryQueue {
+    pub fn new(policy: RetryPolicy, dead_letter_log: PathBuf) -> Self {
+        DeliveryQueue { pending: Vec::new(), policy, dead_letter_log }
+    }
+
+    // Queue the event for every webhook of its collection that wants it
+    pub fn enqueue(&mut self, event: &ChangeEvent, data_dir: &Path) {
+        let collection = event.path.rsplit_once('/').map(|(collection, _)| collection).unwrap_or_default();
+        let collection_dir = data_dir.join(collection.trim_start_matches('/'));
+        let webhooks = match load_webhooks(&collection_dir) {
+            Ok(webhooks) => webhooks,
+            Err(e) => {
+                println!("Failed to read webhooks of {}: {}", collection, e);
+                return;
+            },
+        };
+
+        for webhook in webhooks.into_iter().filter(|webhook| webhook.events.contains(&event.kind)) {
+            self.pending.push(Delivery::new(webhook, event.clone()));
+        }
+    }
+
+    // Remove and return up to `limit` deliveries whose next attempt is due
+    pub fn take_due(&mut self, now: Instant, limit: usize) -> Vec<Delivery> {
+        let mut due = Vec::new();
+        let mut index = 0;
+        while index < self.pending.len() && due.len() < limit {
+            if self.pending[index].next_attempt <= now {
+                due.push(self.pending.remove(index));
+            } else {
+                index += 1;
+            }
+        }
+        due
+    }
+
+    // When the next pending delivery is due
+    pub fn next_due(&self) -> Option<Instant> {
+        self.pending.iter().map(|delivery| delivery.next_attempt).min()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    // Record the outcome of an attempt: failures are retried with backoff until the
+    // attempts run out, and then written to the dead-letter log
+    pub fn record(&mut self, mut delivery: Delivery, result: Result<(), String>) {
+        delivery.attempts += 1;
+        let error = match result {
+            Ok(()) => {
+                println!("Delivered {} of {} to {}", delivery.event.kind.as_str(), delivery.event.path, delivery.webhook.url);
+                return;
+            },
+            Err(error) => error,
+        };
+
+        println!("Webhook delivery to {} failed (attempt {}): {}", delivery.webhook.url, delivery.attempts, error);
+        delivery.last_error = Some(error);
+        if delivery.attempts < self.policy.max_attempts {
+            delivery.next_attempt = Instant::now() + self.policy.delay(delivery.attempts);
+            self.pending.push(delivery);
+        } else if let Err(e) = self.dead_letter(&delivery) {
+            println!("Failed to write the dead-letter log: {}", e);
+        }
+    }
+
+    fn dead_letter(&self, delivery: &Delivery) -> io::Result<()> {
+        let entry = serde_json::json!({
+            "delivery": delivery.id,
+            "webhook": delivery.webhook.id,
+            "url": delivery.webhook.url,
+            "attempts": delivery.attempts,
+            "error": delivery.last_error,
+            "failed_at": ttl::now(),
+            "event": delivery.event.to_json()
+        });
+        if let Some(parent) = self.dead_letter_log.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut log = OpenOptions::new().create(true).append(true).open(&self.dead_letter_log)?;
+        writeln!(log, "{}", entry)
+    }
+}
+
+// Start delivering webhooks: a dispatcher thread follows the change feed and queues
+// deliveries, which run on the pool. Pending deliveries live in memory only and are
+// lost if the server stops before they succeed.
+pub fn start(pool: &ThreadPool) {
+    let pool = pool.clone();
+    thread::spawn(move || {
+        let mut queue = DeliveryQueue::new(RetryPolicy::default(), PathBuf::from(DEAD_LETTER_LOG));
+        dispatch(&mut queue, &pool);
+    });
+}
+
+fn dispatch(queue: &mut DeliveryQueue, pool: &ThreadPool) {
+    // Redirects are not followed: a receiver that moved has to be registered again
+    let client = Arc::new(HttpClient::with_config(ClientConfig {
+        connect_timeout: DELIVERY_TIMEOUT,
+        timeout: Some(DELIVERY_TIMEOUT),
+        max_redirects: 0,
+        cookies: false,
+        ..ClientConfig::default()
+    }));
+    let (sender, receiver) = mpsc::channel();
+    let data_dir = Path::new("./files");
+    let mut last_seq = events::last_seq();
+    let mut in_flight = 0;
+
+    loop {
+        let wait = queue.next_due()
+            .map(|due| due.saturating_duration_since(Instant::now()))
+            .unwrap_or(DISPATCH_INTERVAL)
+            .min(DISPATCH_INTERVAL);
+        for event in events::wait_since(last_seq, wait) {
+            last_seq = event.seq;
+            queue.enqueue(&event, data_dir);
+        }
+
+        for (delivery, result) in receiver.try_iter() {
+            in_flight -= 1;
+            queue.record(delivery, result);
+        }
+
+        for delivery in queue.take_due(Instant::now(), MAX_IN_FLIGHT - in_flight) {
+            in_flight += 1;
+            let sender = sender.clone();
+            let client = Arc::clone(&client);
+            pool.execute(move || {
+                let result = delivery.send(&client);
+                let _ = sender.send((delivery, result));
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::mpsc::Receiver;
+    use serde_json::json;
+    use crate::request::HttpRequest;
+
+    // Local receiver answering each connection with the next status, handing the requests back
+    fn stub_receiver(statuses: Vec<u16>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                // Read up to the end of the JSON body
+                while !request.ends_with(b"}") {
+                    let bytes_read = stream.read(&mut buffer).unwrap();
+                    request.extend_from_slice(&buffer[..bytes_read]);
+                }
+                write!(stream, "HTTP/1.1 {} Status\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+                sender.send(String::from_utf8(request).unwrap()).unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    fn event(path: &str) -> ChangeEvent {
+        ChangeEvent {
+            seq: 1,
+            kind: ChangeKind::Update,
+            path: path.to_string(),
+            timestamp: 0,
+            session_id: None,
+            document: Some(json!({"name": "Ana"})),
+        }
+    }
+
+    #[test]
+    fn test_signature() {
+        // HMAC-SHA256 example from Wikipedia
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn test_delivery_is_signed() {
+        let (url, requests) = stub_receiver(vec![204]);
+        let webhook = Webhook::from_registration(&json!({"url": url, "secret": "s3cret"})).unwrap();
+        let delivery = Delivery::new(webhook, event("/test_webhooks/1"));
+
+        assert_eq!(delivery.send(&HttpClient::new()), Ok(()));
+
+        let request = requests.recv().unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("POST /hooks HTTP/1.1\r\n"));
+        assert!(head.contains(&format!("{}: {}", SIGNATURE_HEADER, sign("s3cret", body.as_bytes()))));
+        assert!(head.contains("X-Webhook-Event: update"));
+        assert_eq!(serde_json::from_str::<Value>(body).unwrap()["path"], "/test_webhooks/1");
+    }
+
+    #[test]
+    fn test_failed_deliveries_are_retried_then_dead_lettered() {
+        let dir = Path::new("./files/test_webhooks_retry");
+        fs::create_dir_all(dir).unwrap();
+        let (url, requests) = stub_receiver(vec![500, 503]);
+        let policy = RetryPolicy { max_attempts: 2, base_delay: Duration::from_millis(10) };
+        assert_eq!(RetryPolicy::default().delay(3), Duration::from_secs(4));
+
+        // Only webhooks of the event's collection that want the event are queued
+        let webhooks = [
+            Webhook::from_registration(&json!({"url": url})).unwrap(),
+            Webhook::from_registration(&json!({"url": url, "events": ["delete"]})).unwrap(),
+        ];
+        save_webhooks(dir, &webhooks).unwrap();
+        let mut queue = DeliveryQueue::new(policy, dir.join("dead.log"));
+        queue.enqueue(&event("/test_webhooks_retry/1"), Path::new("./files"));
+        assert_eq!(queue.len(), 1);
+
+        let delivery = queue.take_due(Instant::now(), 8).pop().unwrap();
+        let result = delivery.send(&HttpClient::new());
+        assert_eq!(result, Err("Receiver answered 500".to_string()));
+        queue.record(delivery, result);
+        assert!(queue.take_due(Instant::now(), 8).is_empty(), "The retry waits for the backoff");
+
+        thread::sleep(policy.delay(1));
+        let delivery = queue.take_due(Instant::now(), 8).pop().unwrap();
+        let result = delivery.send(&HttpClient::new());
+        queue.record(delivery, result);
+
+        assert!(queue.is_empty());
+        assert_eq!(requests.iter().take(2).count(), 2);
+        let dead: Value = serde_json::from_str(fs::read_to_string(dir.join("dead.log")).unwrap().trim()).unwrap();
+        assert_eq!(dead["attempts"], 2);
+        assert_eq!(dead["error"], "Receiver answered 503");
+        assert_eq!(dead["event"]["path"], "/test_webhooks_retry/1");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_webhook_registration() {
+        let dir = "./files/test_webhooks_registration";
+        fs::create_dir_all(dir).unwrap();
+        let collection = "/test_webhooks_registration";
+        let request = HttpRequest::parse(b"GET /test_webhooks_registration/_webhooks HTTP/1.1\r\n\r\n").unwrap();
+        let cached_listing = || cache::serve_local(&request, || handle_webhook_request("GET", collection, "", None)).text().unwrap().to_string();
+        assert_eq!(cached_listing(), "[]");
+
+        let response = handle_webhook_request("POST", collection, "", Some(&json!({"url": "http://localhost:9000/in", "events": ["create"]})));
+        assert_eq!(response.status_code, 201);
+        let created: Value = serde_json::from_str(response.text().as_deref().unwrap()).unwrap();
+        assert!(created["secret"].as_str().is_some_and(|secret| !secret.is_empty()), "A secret is generated");
+
+        let listing: Value = serde_json::from_str(handle_webhook_request("GET", collection, "", None).text().as_deref().unwrap()).unwrap();
+        assert_eq!(listing, json!([{"id": created["id"], "url": "http://localhost:9000/in", "events": ["create"]}]));
+        assert_eq!(cached_listing(), listing.to_string(), "Registering drops the cached listing");
+
+        assert_eq!(handle_webhook_request("POST", collection, "", Some(&json!({"url": "https://x"}))).status_code, 400);
+        assert_eq!(handle_webhook_request("POST", collection, "", Some(&json!({"url": "http://x", "events": ["read"]}))).status_code, 400);
+        assert_eq!(handle_webhook_request("DELETE", collection, created["id"].as_str().unwrap(), None).status_code, 200);
+        assert!(!Path::new(dir).join(WEBHOOKS_FILE).exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use crate::methods::error_response;
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+
+// Appended to the client key before hashing it into `Sec-WebSocket-Accept` (RFC 6455, section 1.3)
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Largest message, after reassembling its fragments, a peer may send
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+// Close codes used by the server
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_u8(value: u8) -> Option<Opcode> {
+        match value {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    pub fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+// A violation of the protocol, answered by closing the connection with `code`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtocolError {
+    pub code: u16,
+    pub reason: &'static str,
+}
+
+impl ProtocolError {
+    fn new(code: u16, reason: &'static str) -> Self {
+        ProtocolError { code, reason }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    // A complete, unfragmented frame
+    pub fn new(opcode: Opcode, payload: Vec<u8>) -> Self {
+        Frame { fin: true, opcode, payload }
+    }
+
+    pub fn text(text: &str) -> Self {
+        Frame::new(Opcode::Text, text.as_bytes().to_vec())
+    }
+
+    pub fn close(code: u16, reason: &str) -> Self {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        Frame::new(Opcode::Close, payload)
+    }
+
+    // The frame as the server sends it: servers never mask their frames
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_with_mask(None)
+    }
+
+    // The frame as a client sends it, masked with `key`
+    pub fn encode_masked(&self, key: [u8; 4]) -> Vec<u8> {
+        self.encode_with_mask(Some(key))
+    }
+
+    fn encode_with_mask(&self, mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.payload.len() + 14);
+        bytes.push(if self.fin { 0x80 } else { 0 } | self.opcode.as_u8());
+
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        let length = self.payload.len();
+        if length < 126 {
+            bytes.push(mask_bit | length as u8);
+        } else if length <= u16::MAX as usize {
+            bytes.push(mask_bit | 126);
+            bytes.extend_from_slice(&(length as u16).to_be_bytes());
+        } else {
+            bytes.push(mask_bit | 127);
+            bytes.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+
+        match mask {
+            Some(key) => {
+                bytes.extend_from_slice(&key);
+                let start = bytes.len();
+                bytes.extend_from_slice(&self.payload);
+                apply_mask(&mut bytes[start..], key);
+            },
+            None => bytes.extend_from_slice(&self.payload),
+        }
+        bytes
+    }
+
+    // Parse the frame at the start of `buffer`, returning it with the number of bytes it took,
+    // or `None` while the frame has not fully arrived. Frames from clients must be masked.
+    pub fn parse(buffer: &[u8], require_mask: bool) -> Result<Option<(Frame, usize)>, ProtocolError> {
+        if buffer.len() < 2 {
+            return Ok(None);
+        }
+
+        let fin = buffer[0] & 0x80 != 0;
+        if buffer[0] & 0x70 != 0 {
+            return Err(ProtocolError::new(CLOSE_PROTOCOL_ERROR, "Reserved bits must be zero"));
+        }
+        let opcode = Opcode::from_u8(buffer[0] & 0x0F)
+            .ok_or_else(|| ProtocolError::new(CLOSE_PROTOCOL_ERROR, "Unknown opcode"))?;
+        let masked = buffer[1] & 0x80 != 0;
+        if require_mask && !masked {
+            return Err(ProtocolError::new(CLOSE_PROTOCOL_ERROR, "Client frames must be masked"));
+        }
+
+        let (length, mut offset) = match buffer[1] & 0x7F {
+            126 if buffer.len() < 4 => return Ok(None),
+            126 => (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4),
+            127 if buffer.len() < 10 => return Ok(None),
+            127 => {
+                let mut length = [0; 8];
+                length.copy_from_slice(&buffer[2..10]);
+                (u64::from_be_bytes(length), 10)
+            },
+            length => (length as u64, 2),
+        };
+        if opcode.is_control() && (!fin || length > 125) {
+            return Err(ProtocolError::new(CLOSE_PROTOCOL_ERROR, "Control frames must be short and unfragmented"));
+        }
+        // Checked before waiting for the payload so an oversized frame is never buffered
+        if length > MAX_MESSAGE_SIZE as u64 {
+            return Err(ProtocolError::new(CLOSE_TOO_BIG, "Message too big"));
+        }
+        let length = length as usize;
+
+        let mask = if masked {
+            if buffer.len() < offset + 4 {
+                return Ok(None);
+            }
+            let key = [buffer[offset], buffer[offset + 1], buffer[offset + 2], buffer[offset + 3]];
+            offset += 4;
+            Some(key)
+        } else {
+            None
+        };
+        if buffer.len() < offset + length {
+            return Ok(None);
+        }
+
+        let mut payload = buffer[offset..offset + length].to_vec();
+        if let Some(key) = mask {
+            apply_mask(&mut payload, key);
+        }
+        Ok(Some((Frame { fin, opcode, payload }, offset + length)))
+    }
+}
+
+// A complete message received from a peer
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    // Close code and reason, when the peer sent one
+    Close(Option<u16>, String),
+}
+
+// Turns the bytes received from a client into messages: frames are parsed as they complete
+// and fragmented messages are reassembled, with control frames allowed in between fragments.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buffer: Vec<u8>,
+    fragments: Option<(Opcode, Vec<u8>)>,
+}
+
+impl Decoder {
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    // The next complete message, if one has arrived
+    pub fn next_message(&mut self) -> Result<Option<Message>, ProtocolError> {
+        loop {
+            let Some((frame, used)) = Frame::parse(&self.buffer, true)? else {
+                return Ok(None);
+            };
+            self.buffer.drain(..used);
+
+            let (opcode, payload) = match (frame.opcode, self.fragments.take()) {
+                (Opcode::Close, _) => {
+                    return close_message(&frame.payload).map(Some);
+                },
+                (Opcode::Ping, fragments) => {
+                    self.fragments = fragments;
+                    return Ok(Some(Message::Ping(frame.payload)));
+                },
+                (Opcode::Pong, fragments) => {
+                    self.fragments = fragments;
+                    return Ok(Some(Message::Pong(frame.payload)));
+                },
+                (Opcode::Continuation, None) => {
+                    return Err(ProtocolError::new(CLOSE_PROTOCOL_ERROR, "Continuation frame without a message"));
+                },
+                (Opcode::Continuation, Some((opcode, mut payload))) => {
+                    if payload.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                        return Err(ProtocolError::new(CLOSE_TOO_BIG, "Message too big"));
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    (opcode, payload)
+                },
+                (_, Some(_)) => {
+                    return Err(ProtocolError::new(CLOSE_PROTOCOL_ERROR, "New message before the previous one ended"));
+                },
+                (opcode, None) => (opcode, frame.payload),
+            };
+
+            if !frame.fin {
+                self.fragments = Some((opcode, payload));
+                continue;
+            }
+            return match opcode {
+                Opcode::Text => String::from_utf8(payload)
+                    .map(|text| Some(Message::Text(text)))
+                    .map_err(|_| ProtocolError::new(CLOSE_INVALID_DATA, "Text messages must be UTF-8")),
+                _ => Ok(Some(Message::Binary(payload))),
+            };
+        }
+    }
+}
+
+fn close_message(payload: &[u8]) -> Result<Message, ProtocolError> {
+    match payload.len() {
+        0 => Ok(Message::Close(None, String::new())),
+        1 => Err(ProtocolError::new(CLOSE_PROTOCOL_ERROR, "Invalid close frame")),
+        _ => {
+            let code = u16::from_be_bytes([payload[0], payload[1]]);
+            let reason = String::from_utf8(payload[2..].to_vec())
+                .map_err(|_| ProtocolError::new(CLOSE_INVALID_DATA, "Close reason must be UTF-8"))?;
+            Ok(Message::Close(Some(code), reason))
+        },
+    }
+}
+
+// XOR the payload with the 4-byte masking key, in place
+pub fn apply_mask(payload: &mut [u8], key: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= key[i % 4];
+    }
+}
+
+// Value of `Sec-WebSocket-Accept` for the `Sec-WebSocket-Key` a client sent
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+// Validate an upgrade request and build the `101 Switching Protocols` answer to it,
+// or the error response when the request is not a valid WebSocket handshake
+pub fn handshake(request: &HttpRequest) -> Result<HttpResponse, HttpResponse> {
+    let has_token = |name: &str, token: &str| {
+        request.header(name)
+            .map(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    };
+
+    if request.method != "GET" {
+        return Err(error_response(405, "WebSocket upgrades must use GET"));
+    }
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        let mut response = error_response(426, "This endpoint requires a WebSocket upgrade");
+        response.headers.insert("Upgrade".to_string(), "websocket".to_string());
+        response.headers.insert("Connection".to_string(), "Upgrade".to_string());
+        return Err(response);
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        let mut response = error_response(426, "Unsupported WebSocket version");
+        response.headers.insert("Sec-WebSocket-Version".to_string(), "13".to_string());
+        return Err(response);
+    }
+    let key = request.header("Sec-WebSocket-Key").unwrap_or_default();
+    if !matches!(STANDARD.decode(key), Ok(nonce) if nonce.len() == 16) {
+        return Err(error_response(400, "Sec-WebSocket-Key must be 16 bytes in base64"));
+    }
+
+    let mut headers = HashMap::new();
+    headers.insert("Upgrade".to_string(), "websocket".to_string());
+    headers.insert("Connection".to_string(), "Upgrade".to_string());
+    headers.insert("Sec-WebSocket-Accept".to_string(), accept_key(key));
+    Ok(HttpResponse::new(101, headers, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upgrade_request(headers: &[&str]) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            path: "/ws".to_string(),
+            _headers: headers.iter().map(|h| h.to_string()).collect(),
+            body: Vec::new(),
+            cookie: None,
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        // Example from RFC 6455, section 1.3
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let request = upgrade_request(&[
+            "Upgrade: websocket",
+            "Connection: keep-alive, Upgrade",
+            "Sec-WebSocket-Version: 13",
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==",
+        ]);
+        let response = handshake(&request).unwrap();
+        assert_eq!(response.status_code, 101);
+        assert_eq!(response.headers["Sec-WebSocket-Accept"], "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert!(response.to_string().starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+
+        let old_version = upgrade_request(&["Upgrade: websocket", "Connection: Upgrade", "Sec-WebSocket-Version: 8", "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ=="]);
+        assert_eq!(handshake(&old_version).unwrap_err().headers["Sec-WebSocket-Version"], "13");
+        let short_key = upgrade_request(&["Upgrade: websocket", "Connection: Upgrade", "Sec-WebSocket-Version: 13", "Sec-WebSocket-Key: c2hvcnQ="]);
+        assert_eq!(handshake(&short_key).unwrap_err().status_code, 400);
+        assert_eq!(handshake(&upgrade_request(&[])).unwrap_err().status_code, 426);
+    }
+
+    #[test]
+    fn test_frame_encoding_and_masking() {
+        // Examples from RFC 6455, section 5.7
+        let unmasked = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        let masked = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        assert_eq!(Frame::text("Hello").encode(), unmasked);
+        assert_eq!(Frame::text("Hello").encode_masked([0x37, 0xfa, 0x21, 0x3d]), masked);
+        assert_eq!(Frame::parse(&masked, true).unwrap(), Some((Frame::text("Hello"), masked.len())));
+        assert_eq!(Frame::parse(&masked[..8], true).unwrap(), None, "Incomplete frames wait for more bytes");
+        assert_eq!(Frame::parse(&unmasked, true).unwrap_err().code, CLOSE_PROTOCOL_ERROR);
+
+        // Extended payload lengths
+        for length in [126, 65536] {
+            let frame = Frame::new(Opcode::Binary, vec![7; length]);
+            let bytes = frame.encode_masked([1, 2, 3, 4]);
+            assert_eq!(Frame::parse(&bytes, true).unwrap(), Some((frame, bytes.len())));
+        }
+
+        let oversized = [0x82, 0xFF, 0, 0, 0, 0, 0x10, 0, 0, 0];
+        assert_eq!(Frame::parse(&oversized, true).unwrap_err().code, CLOSE_TOO_BIG);
+        let long_ping = Frame::new(Opcode::Ping, vec![0; 126]).encode_masked([0; 4]);
+        assert_eq!(Frame::parse(&long_ping, true).unwrap_err().code, CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn test_decoder_reassembles_fragments() {
+        let key = [9, 8, 7, 6];
+        let mut decoder = Decoder::default();
+        let mut bytes = Frame { fin: false, opcode: Opcode::Text, payload: b"Hel".to_vec() }.encode_masked(key);
+        bytes.extend(Frame::new(Opcode::Ping, b"?".to_vec()).encode_masked(key));
+        bytes.extend(Frame { fin: true, opcode: Opcode::Continuation, payload: b"lo".to_vec() }.encode_masked(key));
+        bytes.extend(Frame::close(CLOSE_NORMAL, "bye").encode_masked(key));
+
+        // Bytes arriving one at a time are buffered until frames complete
+        let mut messages = Vec::new();
+        for byte in bytes {
+            decoder.feed(&[byte]);
+            while let Some(message) = decoder.next_message().unwrap() {
+                messages.push(message);
+            }
+        }
+
+        assert_eq!(messages, vec![
+            Message::Ping(b"?".to_vec()),
+            Message::Text("Hello".to_string()),
+            Message::Close(Some(CLOSE_NORMAL), "bye".to_string()),
+        ]);
+
+        let mut decoder = Decoder::default();
+        decoder.feed(&Frame { fin: true, opcode: Opcode::Continuation, payload: vec![] }.encode_masked(key));
+        assert_eq!(decoder.next_message().unwrap_err().code, CLOSE_PROTOCOL_ERROR);
+
+        let mut decoder = Decoder::default();
+        decoder.feed(&Frame::new(Opcode::Text, vec![0xC3, 0x28]).encode_masked(key));
+        assert_eq!(decoder.next_message().unwrap_err().code, CLOSE_INVALID_DATA);
+    }
+}